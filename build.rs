@@ -12,14 +12,29 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
-    // Compile TivaWare USB device C files
-    compile_tivaware_usb();
+    // Compile TivaWare USB device C files, for the firmware only: the host
+    // build is the library and its unit tests
+    if env::var("TARGET").unwrap().starts_with("thumb") {
+        compile_tivaware_usb();
+    }
 
     // Only re-run if memory.x changes
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed=build.rs");
 }
 
+/// Sensor library sources used by the IMU driver
+const SENSORLIB: [&str; 8] = [
+    "i2cm_drv.c",
+    "mpu6050.c",
+    "mpu9150.c",
+    "ak8975.c",
+    "lsm303dlhc_accel.c",
+    "lsm303dlhc_mag.c",
+    "comp_dcm.c",
+    "vector.c",
+];
+
 fn compile_tivaware_usb() {
     let tivaware_path = "TivaWare_C_Series-2.2.0.295";
     
@@ -56,6 +71,9 @@ fn compile_tivaware_usb() {
     
     // Compile USB CDC serial class (for serial port functionality)
     build.file(format!("{}/usblib/device/usbdcdc.c", tivaware_path));
+
+    // Compile USB composite class (CDC serial + MIDI)
+    build.file(format!("{}/usblib/device/usbdcomp.c", tivaware_path));
     
     // Compile usblib core files
     build
@@ -79,6 +97,17 @@ fn compile_tivaware_usb() {
         .file(format!("{}/driverlib/cpu.c", tivaware_path))
         .file(format!("{}/driverlib/fpu.c", tivaware_path))
         .file(format!("{}/driverlib/systick.c", tivaware_path));
+
+    // Flash erase/program for the saved configuration
+    build.file(format!("{}/driverlib/flash.c", tivaware_path));
+
+    // Sensor library for the IMU: I2C master driver, sensor drivers and
+    // the DCM filter. Its float maths (sqrtf, atan2f, asinf) resolves to
+    // compiler_builtins.
+    build.file(format!("{}/driverlib/i2c.c", tivaware_path));
+    for file in SENSORLIB {
+        build.file(format!("{}/sensorlib/{}", tivaware_path, file));
+    }
     
     // Compile and link
     build.compile("tivaware_usb");
@@ -92,6 +121,7 @@ fn compile_tivaware_usb() {
     println!("cargo:rerun-if-changed={}/usblib/device/usbdconfig.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/usblib/device/usbdcdesc.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/usblib/device/usbdcdc.c", tivaware_path);
+    println!("cargo:rerun-if-changed={}/usblib/device/usbdcomp.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/usblib/usbmode.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/usblib/usbulpi.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/usblib/usbtick.c", tivaware_path);
@@ -106,5 +136,10 @@ fn compile_tivaware_usb() {
     println!("cargo:rerun-if-changed={}/driverlib/cpu.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/driverlib/fpu.c", tivaware_path);
     println!("cargo:rerun-if-changed={}/driverlib/systick.c", tivaware_path);
+    println!("cargo:rerun-if-changed={}/driverlib/flash.c", tivaware_path);
+    println!("cargo:rerun-if-changed={}/driverlib/i2c.c", tivaware_path);
+    for file in SENSORLIB {
+        println!("cargo:rerun-if-changed={}/sensorlib/{}", tivaware_path, file);
+    }
 }
 
@@ -1,8 +1,9 @@
 /* Memory layout for TM4C123GH6PM */
 /* 256KB Flash, 32KB SRAM */
+/* The top 8KB of flash (0x3E000) hold the saved configuration, see src/flash.rs */
 
 MEMORY
 {
-    FLASH : ORIGIN = 0x00000000, LENGTH = 256K
+    FLASH : ORIGIN = 0x00000000, LENGTH = 248K
     RAM   : ORIGIN = 0x20000000, LENGTH = 32K
 }
@@ -0,0 +1,720 @@
+//! Application State
+//!
+//! Ties the configuration and its storage, the input sources, the mapping
+//! engine or the control surface emulation, the motorised faders, the LED
+//! outputs, the display, the USB-MIDI transport, the MIDI-CI responder and
+//! the router together and implements the shell `Target` for the real
+//! hardware.
+
+use core::fmt::Write;
+
+use crate::arp::{self, NoteProcessor};
+use crate::buttons::OnboardButtons;
+use crate::ci::{self, Responder};
+use crate::clock;
+use crate::commands::Target;
+use crate::config::{Config, ConfigError};
+use crate::display::{self, Canvas, View};
+use crate::drumpads::DrumPads;
+use crate::flash::FlashStorage;
+use crate::fader::MAX_FADERS;
+use crate::hires;
+use crate::imu::Imu;
+use crate::input::{self, Input, InputEvent, InputSource};
+use crate::led::{self, Color, LedId, LedOutput};
+use crate::mapping::{Action, Defaults, MappingEngine};
+use crate::macros::{self, Player};
+use crate::mcu::{self, Surface, Update};
+use crate::midi::{self, MidiMessage, SysExBuffer, Tempo};
+use crate::monitor::Monitor;
+use crate::motion::{Reading, Sensor};
+use crate::motor::Motors;
+use crate::mpe::{self, MpeEvent};
+use crate::oled::Oled;
+use crate::pedal::{self, Kind};
+use crate::pedals::Pedals;
+use crate::rgb::RgbLed;
+use crate::router::{Port, Router};
+use crate::sequencer::{grid, ClockSource, Rate, Sequencer};
+use crate::shift::Expansion;
+use crate::strip::Strip;
+use crate::touch::TouchInputs;
+use crate::usb_device;
+use crate::usb_midi::{self, Format, UsbMidi};
+use crate::cdc;
+
+pub struct App {
+    pub config: Config,
+    pub router: Router,
+    buttons: OnboardButtons,
+    expansion: Expansion,
+    motors: Motors,
+    imu: Imu,
+    touch: TouchInputs,
+    drums: DrumPads,
+    pedals: Pedals,
+    sequencer: Sequencer,
+    /// Grid levels last sent to the LEDs, `None` while the grid is off
+    grid_drawn: Option<[u8; grid::CELLS]>,
+    notes: NoteProcessor,
+    /// Pickup LED flashing, see `update_pickup`
+    pickup_drawn: Option<LedId>,
+    macros: Player,
+    rgb: RgbLed,
+    strip: Strip,
+    oled: Oled,
+    canvas: Canvas,
+    /// What the display shows, and what it showed when last drawn
+    view: View,
+    drawn: Option<View>,
+    drawn_at: u32,
+    tempo: Tempo,
+    engine: MappingEngine,
+    /// Zones of the notes the host sends back
+    mpe: mpe::Receiver,
+    /// 14-bit controllers and NRPN/RPN parameters the host sends back
+    decoder: hires::Decoder,
+    surface: Surface,
+    usb: UsbMidi,
+    ci: Responder,
+    /// SysEx delivered locally, collected for the responder
+    sysex: SysExBuffer<{ ci::MAX_SYSEX }>,
+    /// Port the last MIDI-CI request came from
+    ci_port: Port,
+    storage: FlashStorage,
+    /// Result of loading the configuration at boot
+    boot_load: Result<(), ConfigError>,
+}
+
+impl App {
+    /// Load the saved configuration, falling back to defaults.
+    pub fn new() -> Self {
+        let storage = FlashStorage;
+        let (config, boot_load) = match Config::load(&storage) {
+            Ok(config) => (config, Ok(())),
+            Err(err) => (Config::new(), Err(err)),
+        };
+        let mut router = Router::new();
+        router.attach(Port::Local);
+        let mut sequencer = Sequencer::new();
+        sequencer.seed(cortex_m::peripheral::SYST::get_current());
+        Self {
+            config,
+            router,
+            buttons: OnboardButtons::new(),
+            expansion: Expansion::new(),
+            motors: Motors::new(),
+            imu: Imu::new(),
+            touch: TouchInputs::new(),
+            drums: DrumPads::new(),
+            pedals: Pedals::new(),
+            sequencer,
+            grid_drawn: None,
+            notes: NoteProcessor::new(),
+            pickup_drawn: None,
+            macros: Player::new(),
+            rgb: RgbLed::new(),
+            strip: Strip::new(),
+            oled: Oled::new(),
+            canvas: Canvas::new(),
+            view: View::new(),
+            drawn: None,
+            drawn_at: 0,
+            tempo: Tempo::new(),
+            engine: MappingEngine::new(),
+            mpe: mpe::Receiver::new(),
+            decoder: hires::Decoder::new(),
+            surface: Surface::new(),
+            usb: UsbMidi::new(),
+            ci: Responder::new(&usb_midi::ENDPOINT_INFO),
+            sysex: SysExBuffer::new(),
+            ci_port: Port::Usb,
+            storage,
+            boot_load,
+        }
+    }
+
+    /// Main loop work: turn control input into MIDI, exchange MIDI with the
+    /// host, consume local MIDI and feed the monitor output.
+    pub fn poll(&mut self) {
+        let now = clock::millis();
+        let Self {
+            config,
+            router,
+            buttons,
+            expansion,
+            motors,
+            imu,
+            touch,
+            drums,
+            pedals,
+            sequencer,
+            notes,
+            macros,
+            engine,
+            surface,
+            view,
+            ..
+        } = self;
+        expansion.configure(config.shift_outputs, config.shift_inputs);
+        motors.configure(config.faders);
+        if let Some(calibration) = imu.take_calibration() {
+            config.imu.calibration = calibration;
+        }
+        imu.configure(&config.imu);
+        drums.configure(&config.drums);
+        touch.configure(config.drums.pads, config.ribbon, config.touch_pads);
+        pedals.configure(&config.pedals, config.sustain);
+        surface.configure(config.surface, config.faders);
+        // Faders to move after a surface or mapping bank change; the motors
+        // are busy being polled while their events are processed
+        let mut moves = [None; MAX_FADERS];
+        let mut process = |event: InputEvent| {
+            // Grid buttons edit the pattern instead of sending anything
+            if let Some((track, step)) = grid::cell(sequencer, event.control).filter(|_| config.sequencer.grid) {
+                let track = &mut config.patterns[config.sequencer.pattern as usize].tracks[track];
+                if matches!(event.input, Input::Press(_)) && step < track.length as usize {
+                    track.toggle(step);
+                }
+                return;
+            }
+            if config.surface != mcu::Protocol::Off {
+                surface.input(event, &mut |update| match update {
+                    Update::Send(message) => router.send(now, Port::Local, message),
+                    Update::Fader(index, position) => moves[index] = Some(position),
+                    // Only the DAW lights LEDs
+                    Update::Led(..) => {}
+                });
+                return;
+            }
+            // Keymap buttons change the notes of the pads from this press on,
+            // macro buttons start or stop their macro
+            if matches!(event.input, Input::Press(_)) {
+                for mapping in engine.active(&config.mappings, event.control) {
+                    match mapping.action {
+                        Action::Keymap(op) => config.keymap.apply(op),
+                        Action::Macro(index) => macros.trigger(now, index),
+                        _ => {}
+                    }
+                }
+            }
+            let defaults = Defaults {
+                channel: config.channel,
+                velocity: config.velocity,
+                hires: config.hires,
+                mpe: config.mpe,
+                aftertouch: config.aftertouch,
+                keymap: config.keymap,
+                banks: config.banks,
+            };
+            let layer = (engine.bank(), engine.shift());
+            let mut shown = false;
+            engine.process(&config.mappings, &defaults, event, &mut |message| {
+                // The first value of a control is the one on the display
+                if let Some(level) = display::level(&message).filter(|_| !shown) {
+                    view.value = level;
+                    shown = true;
+                }
+                view.follow(config.channel, &message);
+                let key = &config.keymap.key;
+                notes.input(now, &config.arp, key, message, &mut |message| router.send(now, Port::Local, message));
+            });
+            if let Some(mapping) = engine.active(&config.mappings, event.control).next() {
+                view.control = Some((event.control, mapping.action));
+            }
+            // Faders go to the values of the new bank or layer
+            if (engine.bank(), engine.shift()) != layer {
+                for (index, position) in moves.iter_mut().enumerate().take(config.faders as usize) {
+                    let control = input::ids::FADER + index as u8;
+                    *position = engine.recall(&config.mappings, control).or(*position);
+                }
+            }
+        };
+        buttons.poll(now, &mut process);
+        expansion.poll(now, &mut process);
+        motors.poll(now, &mut process);
+        imu.poll(now, &mut process);
+        touch.poll(now, &mut process);
+        drums.poll(now, &mut process);
+        pedals.poll(now, &mut process);
+        for (index, position) in moves.iter().enumerate() {
+            if let Some(position) = *position {
+                motors.move_to(now, index, position);
+            }
+        }
+        let pattern = &config.patterns[config.sequencer.pattern as usize];
+        sequencer.poll(now, &config.sequencer, pattern, &mut |message| router.send(now, Port::Local, message));
+        let (source, bpm) = (config.sequencer.source, config.sequencer.bpm);
+        notes.poll(now, &config.arp, source, bpm, &mut |message| router.send(now, Port::Local, message));
+        if router.free(Port::Usb) >= macros::MAX_FRAGMENTS {
+            macros.poll(now, &config.macros, &mut |message| router.send(now, Port::Local, message));
+        }
+
+        self.usb.poll(now, &mut self.router);
+        self.receive(now);
+        let Self { surface, rgb, strip, expansion, .. } = self;
+        surface.poll(now, &mut |update| {
+            if let Update::Led(led, level) = update {
+                rgb.set(led, level);
+                strip.set(led, level);
+                expansion.set(led, level);
+            }
+        });
+
+        self.update_grid();
+
+        // Heartbeat on the blue LED unless incoming MIDI drives it
+        if !self.config.feedback.drives(led::ids::BLUE) {
+            let level = if (now / 500).is_multiple_of(2) { led::FULL } else { 0 };
+            self.rgb.set(led::ids::BLUE, level);
+        }
+        self.update_pickup(now);
+        self.rgb.configure(&self.config.leds);
+        self.rgb.poll(now);
+        self.strip.configure(self.config.pixels, &self.config.leds);
+        self.strip.poll(now);
+        self.update_display(now);
+
+        self.router.monitor.drain(clock::millis(), cdc::tx_free(), &mut cdc::Writer);
+    }
+
+    /// Flash the pickup LED while a control has yet to take over the remote
+    /// value; it goes dark once all have.
+    fn update_pickup(&mut self, now: u32) {
+        let led = self.config.pickup_led.filter(|_| self.engine.waiting(&self.config.mappings));
+        let levels = [(self.pickup_drawn.filter(|&drawn| Some(drawn) != led), 0), (led, led::FULL)];
+        for (led, level) in levels {
+            let Some(led) = led else {
+                continue;
+            };
+            let level = if (now / 125).is_multiple_of(2) { level } else { 0 };
+            self.rgb.set(led, level);
+            self.strip.set(led, level);
+            self.expansion.set(led, level);
+        }
+        self.pickup_drawn = led;
+    }
+
+    /// Show the step grid on the LED strip and the shift register outputs,
+    /// sending only the cells that changed; the LEDs go dark when the grid
+    /// is switched off.
+    fn update_grid(&mut self) {
+        if !self.config.sequencer.grid {
+            if self.grid_drawn.take().is_some() {
+                for cell in 0..grid::CELLS as u8 {
+                    self.strip.set(led::ids::STRIP + cell, 0);
+                    self.expansion.set(led::ids::SHIFT + cell, 0);
+                }
+            }
+            return;
+        }
+        let pattern = &self.config.patterns[self.config.sequencer.pattern as usize];
+        let levels = grid::levels(&self.sequencer, pattern);
+        for (cell, &level) in levels.iter().enumerate() {
+            if self.grid_drawn.is_none_or(|drawn| drawn[cell] != level) {
+                self.strip.set(led::ids::STRIP + cell as u8, level);
+                self.expansion.set(led::ids::SHIFT + cell as u8, level);
+            }
+        }
+        self.grid_drawn = Some(levels);
+    }
+
+    /// Redraw the status screen when it changed, at most every
+    /// `FRAME_MS`, and keep the display transfer going.
+    fn update_display(&mut self, now: u32) {
+        const FRAME_MS: u32 = 40;
+
+        self.view.tempo = self.tempo.bpm10(now);
+        let due = clock::reached(now, self.drawn_at.wrapping_add(FRAME_MS));
+        if self.config.surface != mcu::Protocol::Off {
+            // The surface screen replaces the status screen
+            if self.surface.changed() && due {
+                self.surface.render(&mut self.canvas);
+                self.drawn = None;
+                self.drawn_at = now;
+            }
+        } else if self.drawn != Some(self.view) && due {
+            display::render(&self.view, &mut self.canvas);
+            self.drawn = Some(self.view);
+            self.drawn_at = now;
+        }
+        self.oled.configure(self.config.display, &mut self.canvas);
+        self.oled.poll(now, &mut self.canvas);
+    }
+
+    /// Handle the messages delivered locally: every message can animate the
+    /// RGB LED, clock and transport drive the step sequencer and the
+    /// arpeggiator, notes and controllers drive LED feedback, motorised
+    /// faders and the toggle states of mappings (or the control surface),
+    /// SysEx goes to the control surface or the MIDI-CI responder. CI
+    /// replies go back to the port the request came from, one SysEx message
+    /// at a time and only while the USB output queue has room for a whole
+    /// one.
+    fn receive(&mut self, now: u32) {
+        const REPLY_FRAGMENTS: usize = ci::MAX_REPLY.div_ceil(3);
+
+        let Self {
+            config,
+            router,
+            engine,
+            mpe,
+            decoder,
+            surface,
+            sequencer,
+            notes,
+            rgb,
+            strip,
+            expansion,
+            motors,
+            view,
+            tempo,
+            usb,
+            ci,
+            sysex,
+            ci_port,
+            ..
+        } = self;
+        loop {
+            if router.free(Port::Usb) < REPLY_FRAGMENTS {
+                break;
+            }
+            let port = *ci_port;
+            let mut reply = |bytes: &[u8]| {
+                midi::sysex_fragments(bytes, &mut |message| router.send_to(now, Port::Local, port, message));
+            };
+            if ci.next_reply(&config.mappings, &mut reply) {
+                continue;
+            }
+            let Some((source, message)) = router.pop_with_source(Port::Local) else {
+                break;
+            };
+            rgb.midi(now, &message);
+            tempo.feed(now, &message);
+            let pattern = &config.patterns[config.sequencer.pattern as usize];
+            sequencer.midi(now, &message, &config.sequencer, pattern, &mut |message| {
+                router.send(now, Port::Local, message)
+            });
+            notes.midi(now, &message, &config.arp, config.sequencer.source, &mut |message| {
+                router.send(now, Port::Local, message)
+            });
+            view.follow(config.channel, &message);
+            let MidiMessage::SysEx { data, len } = message else {
+                if config.surface != mcu::Protocol::Off {
+                    surface.follow(&message, &mut |update| match update {
+                        Update::Send(message) => router.send_to(now, Port::Local, source, message),
+                        Update::Led(led, level) => {
+                            rgb.set(led, level);
+                            strip.set(led, level);
+                            expansion.set(led, level);
+                        }
+                        Update::Fader(index, position) => motors.move_to(now, index, position),
+                    });
+                    continue;
+                }
+                // Notes on the member channels of a zone are those of the MPE
+                // mappings, on the global channel
+                mpe.configure(&config.mpe);
+                let message = match mpe.feed(&message) {
+                    Some(MpeEvent::Note { note, velocity: 0, .. }) => {
+                        MidiMessage::NoteOff { channel: config.channel, note, velocity: 0 }
+                    }
+                    Some(MpeEvent::Note { note, velocity, .. }) => {
+                        MidiMessage::NoteOn { channel: config.channel, note, velocity }
+                    }
+                    _ => message,
+                };
+                let change = decoder.feed(&message, &config.hires);
+                let change = change.as_ref();
+                // The step grid owns its LEDs while it is shown
+                let grid = config.sequencer.grid;
+                let mappings = &config.mappings;
+                config.feedback.process(mappings, config.channel, &message, change, &mut |led, level| {
+                    if grid && grid::is_grid_led(led) {
+                        return;
+                    }
+                    rgb.set(led, level);
+                    strip.set(led, level);
+                    expansion.set(led, level);
+                });
+                let bank = engine.bank();
+                engine.follow(&config.mappings, config.channel, &config.banks, &message, change);
+                for index in 0..config.faders {
+                    let control = input::ids::FADER + index;
+                    let mut mappings = engine.active(&config.mappings, control);
+                    let curves = &config.mappings.curves;
+                    let position = match engine.bank() != bank {
+                        true => engine.recall(&config.mappings, control),
+                        false => mappings.find_map(|m| m.position(config.channel, &message, change, curves)),
+                    };
+                    if let Some(position) = position {
+                        motors.move_to(now, index as usize, position);
+                        engine.moved(&config.mappings, control, position);
+                    }
+                }
+                continue;
+            };
+            if source != *ci_port {
+                sysex.clear();
+                *ci_port = source;
+            }
+            let Some(request) = sysex.push(&data[..len as usize]) else {
+                continue;
+            };
+            let mut reply = |bytes: &[u8]| {
+                midi::sysex_fragments(bytes, &mut |message| router.send_to(now, Port::Local, source, message));
+            };
+            if surface.sysex(request, &mut reply) {
+                continue;
+            }
+            let entropy = now ^ cortex_m::peripheral::SYST::get_current();
+            match ci.handle(request, entropy, &mut config.mappings, &mut reply) {
+                Some(ci::Event::Protocol(protocol)) => usb.set_protocol(protocol),
+                Some(ci::Event::MappingsChanged) => engine.reset(),
+                None => {}
+            }
+        }
+    }
+
+    /// Give the CDC port a moment to send what is queued.
+    fn drain_output(&self) {
+        let deadline = clock::millis().wrapping_add(50);
+        while !clock::reached(clock::millis(), deadline) {
+            cdc::flush();
+        }
+    }
+}
+
+impl Target for App {
+    fn config(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    fn save(&mut self) -> Result<usize, ConfigError> {
+        self.config.save(&mut self.storage)
+    }
+
+    fn load(&mut self) -> Result<(), ConfigError> {
+        self.config = Config::load(&self.storage)?;
+        self.engine.reset();
+        let now = clock::millis();
+        let Self { notes, router, .. } = self;
+        notes.reset(&mut |message| router.send(now, Port::Local, message));
+        Ok(())
+    }
+
+    fn config_changed(&mut self) {
+        self.engine.reset();
+    }
+
+    fn monitor(&mut self) -> &mut Monitor {
+        &mut self.router.monitor
+    }
+
+    fn now(&self) -> u32 {
+        clock::millis()
+    }
+
+    fn inject(&mut self, source: Port, message: MidiMessage) {
+        self.router.send(clock::millis(), source, message);
+    }
+
+    fn color(&self) -> Color {
+        self.rgb.color()
+    }
+
+    fn set_color(&mut self, color: Color) {
+        self.rgb.set_color(color);
+    }
+
+    fn pixel(&self, index: usize) -> Color {
+        self.strip.pixel(index)
+    }
+
+    fn set_pixel(&mut self, index: usize, color: Color) {
+        self.strip.set_pixel(index, color);
+    }
+
+    fn motion(&self) -> Option<Reading> {
+        self.imu.reading()
+    }
+
+    fn calibrate_motion(&mut self) {
+        self.imu.calibrate();
+    }
+
+    fn sequencer(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+
+    fn notes(&mut self) -> &mut NoteProcessor {
+        &mut self.notes
+    }
+
+    fn macros(&mut self) -> &mut Player {
+        &mut self.macros
+    }
+
+    fn status(&mut self, out: &mut dyn Write) {
+        let ms = clock::millis();
+        let _ = write!(
+            out,
+            "firmware  {} {}\r\nuptime    {}.{:03} s\r\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            ms / 1000,
+            ms % 1000
+        );
+        let _ = match self.boot_load {
+            Ok(()) => out.write_str("config    loaded from flash\r\n"),
+            Err(err) => write!(out, "config    defaults ({})\r\n", err.as_str()),
+        };
+        let _ = match self.usb.format() {
+            Format::Off => out.write_str("usb midi  not configured\r\n"),
+            Format::EventPackets => out.write_str("usb midi  USB-MIDI 1.0\r\n"),
+            Format::Ump => write!(out, "usb midi  USB-MIDI 2.0, {} protocol\r\n", self.usb.protocol().name()),
+        };
+        let _ = match self.ci.muid() {
+            Some(muid) => write!(out, "midi-ci   MUID {:07X}\r\n", muid),
+            None => out.write_str("midi-ci   idle\r\n"),
+        };
+        let _ = match self.config.surface {
+            mcu::Protocol::Off => out.write_str("surface   off\r\n"),
+            protocol => write!(out, "surface   {}, faders on strip {}\r\n", protocol, self.surface.bank() + 1),
+        };
+        let _ = match self.config.imu.sensor {
+            Sensor::None => out.write_str("imu       none\r\n"),
+            sensor if self.imu.calibrating() => write!(out, "imu       {}, calibrating\r\n", sensor),
+            sensor if self.imu.running() => write!(out, "imu       {}\r\n", sensor),
+            sensor => write!(out, "imu       {} not responding\r\n", sensor),
+        };
+        let _ = match self.config.drums.pads {
+            0 => out.write_str("drums     none\r\n"),
+            pads => write!(
+                out,
+                "drums     {} {}, {} frames dropped\r\n",
+                pads,
+                self.config.drums.sensor,
+                self.drums.dropped()
+            ),
+        };
+        for (index, (mode, jack)) in self.config.pedals.iter().zip(self.pedals.jacks()).enumerate() {
+            let _ = match (mode, jack.kind()) {
+                (pedal::Mode::Off, _) => write!(out, "pedal {}   off\r\n", index + 1),
+                (_, Some(Kind::Expression)) => {
+                    let (heel, toe) = jack.range();
+                    write!(out, "pedal {}   expression pedal, heel {} toe {}\r\n", index + 1, heel, toe)
+                }
+                (_, Some(kind)) => write!(out, "pedal {}   {}\r\n", index + 1, kind),
+                (_, None) => write!(out, "pedal {}   nothing detected\r\n", index + 1),
+            };
+        }
+        for (index, closed) in self.pedals.polarity().enumerate() {
+            let _ = match closed {
+                Some(true) => write!(out, "sustain {} normally closed\r\n", index + 1),
+                Some(false) => write!(out, "sustain {} normally open\r\n", index + 1),
+                None => write!(out, "sustain {} not read yet\r\n", index + 1),
+            };
+        }
+        let options = &self.config.sequencer;
+        let _ = write!(
+            out,
+            "sequencer {}, pattern {}, ",
+            if self.sequencer.playing() { "playing" } else { "stopped" },
+            options.pattern + 1
+        );
+        let _ = match options.source {
+            ClockSource::Internal => write!(out, "{} BPM", options.bpm),
+            ClockSource::External => out.write_str("external clock"),
+        };
+        let _ = match options.grid {
+            true => write!(out, ", grid page {}\r\n", self.sequencer.page() + 1),
+            false => out.write_str("\r\n"),
+        };
+        let options = &self.config.arp;
+        let _ = match options.mode {
+            arp::Mode::Off => out.write_str("arp       off"),
+            mode => write!(
+                out,
+                "arp       {}, {} octave(s), {}{}",
+                mode,
+                options.octaves,
+                Rate(options.rate),
+                if options.latch { ", latched" } else { "" }
+            ),
+        };
+        let _ = write!(out, "; chord {}\r\n", options.chord);
+        let options = &self.config.keymap;
+        let _ = write!(
+            out,
+            "keymap    {}, {} columns, octave {}, {}\r\n",
+            options.layout, options.columns, options.octave, options.key
+        );
+        let _ = write!(
+            out,
+            "banks     bank {} of {}{}",
+            self.engine.bank(),
+            self.config.banks.count,
+            if self.engine.shift() { ", shift" } else { "" }
+        );
+        let _ = match self.config.banks.cc {
+            Some(cc) => write!(out, ", follows CC {}\r\n", cc),
+            None => out.write_str("\r\n"),
+        };
+        let _ = match self.macros.playing() {
+            Some(index) => write!(out, "macros    {} playing\r\n", index),
+            None => out.write_str("macros    idle\r\n"),
+        };
+        let _ = write!(
+            out,
+            "monitor   {}\r\noverflows {}\r\n",
+            if self.router.monitor.enabled { "on" } else { "off" },
+            self.router.overflows
+        );
+    }
+
+    fn reset(&mut self) {
+        self.drain_output();
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    fn enter_bootloader(&mut self) {
+        self.drain_output();
+        unsafe { jump_to_usb_bootloader() }
+    }
+}
+
+/// Hand control to the USB DFU boot loader in ROM (ROM_UpdateUSB).
+///
+/// # Safety
+/// Never returns; all interrupts and the USB stack are shut down first.
+unsafe fn jump_to_usb_bootloader() -> ! {
+    const ROM_APITABLE: *const u32 = 0x0100_0010 as *const u32;
+    const ROM_USBTABLE_INDEX: usize = 16;
+    const ROM_UPDATE_USB_INDEX: usize = 58;
+
+    cortex_m::interrupt::disable();
+
+    // Stop SysTick and disable every peripheral interrupt at the NVIC
+    let syst = &*cortex_m::peripheral::SYST::PTR;
+    syst.csr.write(0);
+    let nvic = &*cortex_m::peripheral::NVIC::PTR;
+    for icer in nvic.icer.iter() {
+        icer.write(0xFFFF_FFFF);
+    }
+
+    // Reset the USB controller so the host sees a disconnect, then give it
+    // about a second before the boot loader re-enumerates as DFU
+    usb_device::SysCtlPeripheralReset(usb_device::sysctl_periph::SYSCTL_PERIPH_USB0);
+    cortex_m::asm::delay(usb_device::SysCtlClockGet());
+
+    cortex_m::interrupt::enable();
+
+    let usb_table = *ROM_APITABLE.add(ROM_USBTABLE_INDEX) as *const u32;
+    let update_usb: extern "C" fn(*const u8) =
+        core::mem::transmute(*usb_table.add(ROM_UPDATE_USB_INDEX) as usize);
+    update_usb(core::ptr::null());
+
+    loop {
+        cortex_m::asm::nop();
+    }
+}
@@ -0,0 +1,649 @@
+//! Note Processing
+//!
+//! An optional stage between the mappings and the MIDI output that works
+//! on the notes played:
+//!
+//! 1. Scale quantisation moves every note to the nearest note of the key,
+//!    the one the pad keymap uses (see `keymap`).
+//! 2. Chord memory plays the stored chord on every note instead, the
+//!    intervals above the played note, so one finger plays a whole chord.
+//! 3. The arpeggiator plays the held notes (chords included) one after the
+//!    other instead of together: upwards, downwards, in random order or in
+//!    the order they were pressed, over one to four octaves, a note every
+//!    `rate` MIDI clocks. With latch on the notes keep playing after they
+//!    were let go, until a new chord is pressed.
+//!
+//! The arpeggiator follows the sequencer's clock (see `sequencer::Clock`),
+//! internal or received, and starts with the first note pressed. Other
+//! messages pass through untouched.
+
+use core::fmt;
+
+use crate::midi::MidiMessage;
+use crate::scale::Key;
+use crate::sequencer::{ClockSource, Clock};
+
+/// Notes held at once
+pub const MAX_HELD: usize = 16;
+/// Notes of a chord, the played note included
+pub const MAX_CHORD: usize = 6;
+pub const MAX_OCTAVES: u8 = 4;
+/// Notes the arpeggiator goes through per octave
+const MAX_POOL: usize = 32;
+
+/// Order of the arpeggio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Held notes play together
+    Off = 0,
+    Up = 1,
+    Down = 2,
+    Random = 3,
+    /// In the order pressed
+    AsPlayed = 4,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 5] = [Mode::Off, Mode::Up, Mode::Down, Mode::Random, Mode::AsPlayed];
+
+    pub fn from_u8(value: u8) -> Option<Mode> {
+        Mode::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Off => "off",
+            Mode::Up => "up",
+            Mode::Down => "down",
+            Mode::Random => "random",
+            Mode::AsPlayed => "as played",
+        })
+    }
+}
+
+/// Intervals in semitones above the played note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chord {
+    pub intervals: [u8; MAX_CHORD - 1],
+    pub len: u8,
+}
+
+impl Chord {
+    /// The played note alone
+    pub const NONE: Chord = Chord { intervals: [0; MAX_CHORD - 1], len: 0 };
+    /// Widest interval
+    pub const MAX_INTERVAL: u8 = 36;
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.intervals[..self.len as usize]
+    }
+
+    /// The chord of `notes` above the lowest of them, `None` when it has
+    /// more notes or a wider span than a chord can hold.
+    pub fn from_notes(notes: impl Iterator<Item = u8> + Clone) -> Option<Chord> {
+        let lowest = notes.clone().min()?;
+        let mut chord = Chord::NONE;
+        for interval in 1..=Self::MAX_INTERVAL {
+            if notes.clone().any(|note| note.checked_sub(lowest) == Some(interval)) {
+                chord.add(interval)?;
+            }
+        }
+        if notes.clone().any(|note| note - lowest > Self::MAX_INTERVAL) {
+            return None;
+        }
+        Some(chord)
+    }
+
+    /// Add an interval, `None` when the chord is full.
+    pub fn add(&mut self, interval: u8) -> Option<()> {
+        let slot = self.intervals.get_mut(self.len as usize)?;
+        *slot = interval;
+        self.len += 1;
+        Some(())
+    }
+
+    /// Notes of the chord on `note`, those above 127 left out
+    pub fn notes(&self, note: u8) -> impl Iterator<Item = u8> + '_ {
+        core::iter::once(0).chain(self.as_slice().iter().copied()).filter_map(move |interval| {
+            note.checked_add(interval).filter(|&note| note < 128)
+        })
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.len == 0 {
+            return f.write_str("off");
+        }
+        f.write_str("0")?;
+        for interval in self.as_slice() {
+            write!(f, " {}", interval)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the note processing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    /// Octaves the arpeggio spans
+    pub octaves: u8,
+    /// MIDI clocks per arpeggio note, 6 for sixteenth notes
+    pub rate: u8,
+    /// Note length in percent of `rate`
+    pub gate: u8,
+    pub latch: bool,
+    pub chord: Chord,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self {
+            mode: Mode::Off,
+            octaves: 1,
+            rate: 6,
+            gate: 50,
+            latch: false,
+            chord: Chord::NONE,
+        }
+    }
+}
+
+/// A note being held
+#[derive(Clone, Copy)]
+struct Held {
+    /// As played
+    played: u8,
+    /// After quantisation
+    note: u8,
+    channel: u8,
+    velocity: u8,
+    /// Chord it was pressed with
+    chord: Chord,
+    /// Its notes were sent when pressed, rather than arpeggiated
+    direct: bool,
+    /// Let go, kept by the latch
+    released: bool,
+}
+
+pub struct NoteProcessor {
+    held: [Option<Held>; MAX_HELD],
+    clock: Clock,
+    /// Clocks since the last arpeggio note
+    clocks: u32,
+    /// Next arpeggio note plays on the next poll
+    due: bool,
+    /// Arpeggio notes played since the first note was pressed
+    position: u32,
+    /// Arpeggio note sounding, with its channel and when it ends
+    sounding: Option<(u8, u8, u32)>,
+    rng: u32,
+}
+
+impl NoteProcessor {
+    pub const fn new() -> Self {
+        Self {
+            held: [None; MAX_HELD],
+            clock: Clock::new(),
+            clocks: 0,
+            due: false,
+            position: 0,
+            sounding: None,
+            rng: 0x9E37_79B9,
+        }
+    }
+
+    /// Notes held, quantised, in the order pressed
+    pub fn held(&self) -> impl Iterator<Item = u8> + Clone + '_ {
+        self.held.iter().flatten().map(|held| held.note)
+    }
+
+    /// Take a message from the mappings and send what it turns into.
+    pub fn input(
+        &mut self,
+        now: u32,
+        options: &Options,
+        key: &Key,
+        message: MidiMessage,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        match message {
+            MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 => {
+                let (played, note, chord) = (note, key.quantize(note), options.chord);
+                let direct = options.mode == Mode::Off;
+                let held = Held { played, note, channel, velocity, chord, direct, released: false };
+                self.press(now, held, out)
+            }
+            MidiMessage::NoteOn { channel, note, .. } | MidiMessage::NoteOff { channel, note, .. } => {
+                self.release(options, channel, note, out)
+            }
+            message => out(message),
+        }
+    }
+
+    fn press(&mut self, now: u32, held: Held, out: &mut dyn FnMut(MidiMessage)) {
+        let Held { note, channel, velocity, direct, .. } = held;
+        // A new chord after the latched one was let go replaces it
+        let arpeggiated = |held: &Held| !held.direct && !held.released;
+        if !direct && !self.held.iter().flatten().any(arpeggiated) {
+            for slot in self.held.iter_mut().filter(|slot| slot.is_some_and(|held| held.released)) {
+                *slot = None;
+            }
+        }
+        let Some(slot) = self.held.iter_mut().find(|slot| slot.is_none()) else {
+            return;
+        };
+        *slot = Some(held);
+        if direct {
+            for note in held.chord.notes(note) {
+                out(MidiMessage::NoteOn { channel, note, velocity });
+            }
+        } else if self.held.iter().flatten().filter(|held| !held.direct).count() == 1 {
+            // The first note starts the arpeggio
+            self.due = true;
+            self.position = 0;
+            self.clock.restart(now);
+        }
+    }
+
+    fn release(&mut self, options: &Options, channel: u8, played: u8, out: &mut dyn FnMut(MidiMessage)) {
+        let matching = |held: &Held| held.channel == channel && held.played == played && !held.released;
+        let Some(slot) = self.held.iter_mut().find(|slot| slot.as_ref().is_some_and(matching)) else {
+            return;
+        };
+        let Some(held) = slot.as_mut() else {
+            return;
+        };
+        if held.direct {
+            for note in held.chord.notes(held.note) {
+                out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+            }
+            *slot = None;
+        } else if options.latch {
+            held.released = true;
+        } else {
+            *slot = None;
+        }
+    }
+
+    /// Let go of everything, e.g. when the settings change.
+    pub fn reset(&mut self, out: &mut dyn FnMut(MidiMessage)) {
+        for held in self.held.iter_mut().filter_map(Option::take).filter(|held| held.direct) {
+            for note in held.chord.notes(held.note) {
+                out(MidiMessage::NoteOff { channel: held.channel, note, velocity: 0 });
+            }
+        }
+        if let Some((channel, note, _)) = self.sounding.take() {
+            out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+        }
+    }
+
+    /// Run the internal clock and play the arpeggio.
+    pub fn poll(
+        &mut self,
+        now: u32,
+        options: &Options,
+        source: ClockSource,
+        bpm: u16,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        // Notes kept for an arpeggio no longer played are dropped
+        let kept = |held: Held| held.released && !options.latch || !held.direct && options.mode == Mode::Off;
+        for slot in self.held.iter_mut().filter(|slot| slot.is_some_and(kept)) {
+            *slot = None;
+        }
+        if source == ClockSource::Internal {
+            let due = self.clock.internal(now, bpm);
+            self.tick(due, options);
+        }
+        self.play(now, options, out);
+    }
+
+    /// Follow clock received, when it is the clock source.
+    pub fn midi(
+        &mut self,
+        now: u32,
+        message: &MidiMessage,
+        options: &Options,
+        source: ClockSource,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        if source == ClockSource::External && *message == MidiMessage::Clock {
+            self.clock.received(now);
+            self.tick(1, options);
+            self.play(now, options, out);
+        }
+    }
+
+    fn tick(&mut self, clocks: u32, options: &Options) {
+        let rate = options.rate.max(1) as u32;
+        self.clocks += clocks;
+        if self.clocks >= rate {
+            self.clocks %= rate;
+            self.due = true;
+        }
+    }
+
+    /// End the note whose gate is over, start the next one when due.
+    fn play(&mut self, now: u32, options: &Options, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some((channel, note, off_at)) = self.sounding {
+            if now.wrapping_sub(off_at) as i32 >= 0 {
+                self.sounding = None;
+                out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+            }
+        }
+        if !self.due || options.mode == Mode::Off {
+            return;
+        }
+        self.due = false;
+        self.clocks = 0;
+        let Some((channel, note, velocity)) = self.next(options) else {
+            return;
+        };
+        if let Some((channel, note, _)) = self.sounding.take() {
+            out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+        }
+        out(MidiMessage::NoteOn { channel, note, velocity });
+        let gate = (self.clock.span(options.rate.max(1) as u32) * options.gate as u32 / 100).max(1);
+        self.sounding = Some((channel, note, now.wrapping_add(gate)));
+    }
+
+    /// Next note of the arpeggio, with its channel and velocity
+    fn next(&mut self, options: &Options) -> Option<(u8, u8, u8)> {
+        // Every note of every chord held, in the order pressed
+        let mut pool = [(0u8, 0u8, 0u8); MAX_POOL];
+        let mut len = 0;
+        for held in self.held.iter().flatten().filter(|held| !held.direct) {
+            for note in held.chord.notes(held.note) {
+                if len < MAX_POOL && !pool[..len].iter().any(|&(_, n, _)| n == note) {
+                    pool[len] = (held.channel, note, held.velocity);
+                    len += 1;
+                }
+            }
+        }
+        if len == 0 {
+            return None;
+        }
+        let pool = &mut pool[..len];
+        match options.mode {
+            Mode::Off | Mode::Up | Mode::Random => pool.sort_unstable_by_key(|&(_, note, _)| note),
+            Mode::Down => pool.sort_unstable_by_key(|&(_, note, _)| 127 - note),
+            Mode::AsPlayed => {}
+        }
+        let octaves = options.octaves.clamp(1, MAX_OCTAVES) as u32;
+        let total = len as u32 * octaves;
+        let index = match options.mode {
+            Mode::Random => {
+                // xorshift32
+                let mut x = self.rng;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.rng = x;
+                x % total
+            }
+            _ => self.position % total,
+        };
+        self.position = self.position.wrapping_add(1);
+        let (channel, note, velocity) = pool[(index % len as u32) as usize];
+        let octave = match options.mode {
+            Mode::Down => octaves - 1 - index / len as u32,
+            _ => index / len as u32,
+        };
+        // Octaves above the top of the MIDI range fold back down
+        let mut note = note as u32 + 12 * octave;
+        while note > 127 {
+            note -= 12;
+        }
+        Some((channel, note as u8, velocity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The processor on a virtual clock at 120 BPM: arpeggio notes every
+    /// 125 ms at rate 6
+    struct Rig {
+        processor: NoteProcessor,
+        options: Options,
+        key: Key,
+        now: u32,
+        log: Vec<(u32, MidiMessage)>,
+    }
+
+    impl Rig {
+        fn new(mode: Mode, octaves: u8, latch: bool) -> Self {
+            let options = Options { mode, octaves, latch, ..Options::new() };
+            Rig { processor: NoteProcessor::new(), options, key: Key::new(), now: 0, log: Vec::new() }
+        }
+
+        fn input(&mut self, message: MidiMessage) {
+            let (now, log) = (self.now, &mut self.log);
+            self.processor.input(now, &self.options, &self.key, message, &mut |m| log.push((now, m)));
+        }
+
+        fn press(&mut self, notes: &[u8]) {
+            for &note in notes {
+                self.input(MidiMessage::NoteOn { channel: 0, note, velocity: 100 });
+            }
+        }
+
+        fn release(&mut self, notes: &[u8]) {
+            for &note in notes {
+                self.input(MidiMessage::NoteOff { channel: 0, note, velocity: 0 });
+            }
+        }
+
+        fn run(&mut self, ms: u32) {
+            for _ in 0..ms {
+                let (now, log) = (self.now, &mut self.log);
+                self.processor.poll(now, &self.options, ClockSource::Internal, 120, &mut |m| log.push((now, m)));
+                self.now += 1;
+            }
+        }
+
+        /// Notes started, and when, since the last call
+        fn ons(&mut self) -> Vec<(u32, u8)> {
+            let on = |&(now, message): &(u32, MidiMessage)| match message {
+                MidiMessage::NoteOn { note, .. } => Some((now, note)),
+                _ => None,
+            };
+            let ons = self.log.iter().filter_map(on).collect();
+            self.log.clear();
+            ons
+        }
+
+        fn notes(&mut self) -> Vec<u8> {
+            self.ons().into_iter().map(|(_, note)| note).collect()
+        }
+    }
+
+    #[test]
+    fn plays_in_order() {
+        let mut rig = Rig::new(Mode::Up, 1, false);
+        rig.press(&[64, 60, 67]);
+        assert!(rig.log.is_empty());
+        rig.run(750);
+        assert_eq!(rig.ons(), [(0, 60), (125, 64), (250, 67), (375, 60), (500, 64), (625, 67)]);
+
+        // Each note ends on its gate, before the next one
+        let mut rig = Rig::new(Mode::Down, 1, false);
+        rig.press(&[64, 60, 67]);
+        rig.run(375);
+        let off = |&(now, message): &(u32, MidiMessage)| matches!(message, MidiMessage::NoteOff { .. }).then_some(now);
+        let offs: Vec<u32> = rig.log.iter().filter_map(off).collect();
+        assert_eq!(offs, [62, 187, 312]);
+        assert_eq!(rig.notes(), [67, 64, 60]);
+
+        let mut rig = Rig::new(Mode::AsPlayed, 1, false);
+        rig.press(&[64, 60, 67]);
+        rig.run(750);
+        assert_eq!(rig.notes(), [64, 60, 67, 64, 60, 67]);
+
+        let mut rig = Rig::new(Mode::Random, 1, false);
+        rig.press(&[64, 60, 67]);
+        rig.run(125 * 30);
+        let notes = rig.notes();
+        assert_eq!(notes.len(), 30);
+        for note in [60, 64, 67] {
+            assert!(notes.iter().filter(|&&n| n == note).count() >= 5, "{notes:?}");
+        }
+        assert!(notes.iter().all(|note| [60, 64, 67].contains(note)));
+    }
+
+    #[test]
+    fn spans_octaves() {
+        let mut rig = Rig::new(Mode::Up, 2, false);
+        rig.press(&[60, 64]);
+        rig.run(125 * 5);
+        assert_eq!(rig.notes(), [60, 64, 72, 76, 60]);
+
+        let mut rig = Rig::new(Mode::Down, 3, false);
+        rig.press(&[60, 64]);
+        rig.run(125 * 7);
+        assert_eq!(rig.notes(), [88, 84, 76, 72, 64, 60, 88]);
+
+        // Chord notes join the pool, a note already there only once
+        let mut rig = Rig::new(Mode::Up, 1, false);
+        rig.options.chord = Chord::from_notes([60, 64, 67].into_iter()).unwrap();
+        rig.press(&[60, 64]);
+        rig.run(125 * 5);
+        assert_eq!(rig.notes(), [60, 64, 67, 68, 71]);
+
+        // Octaves past the top of the range fold back down
+        let mut rig = Rig::new(Mode::Up, MAX_OCTAVES, false);
+        rig.press(&[100, 110]);
+        rig.run(125 * 8);
+        assert_eq!(rig.notes(), [100, 110, 112, 122, 124, 122, 124, 122]);
+    }
+
+    #[test]
+    fn follows_the_held_notes() {
+        let mut rig = Rig::new(Mode::Up, 1, false);
+        rig.press(&[60, 64, 67]);
+        rig.run(250);
+        rig.release(&[64]);
+        rig.run(375);
+        assert_eq!(rig.notes(), [60, 64, 60, 67, 60]);
+        // A note added joins in, the arpeggio goes on where it was
+        rig.press(&[62]);
+        rig.run(250);
+        assert_eq!(rig.notes(), [67, 60]);
+
+        // Let go of everything: the note sounding ends on its gate and
+        // nothing follows
+        rig.run(10);
+        assert_eq!(rig.ons(), [(875, 62)]);
+        rig.release(&[60, 62, 67]);
+        rig.run(1000);
+        assert_eq!(rig.log, [(937, MidiMessage::NoteOff { channel: 0, note: 62, velocity: 0 })]);
+        assert!(rig.processor.held().next().is_none());
+
+        // The next note pressed starts over at once, off the clock
+        rig.run(40);
+        rig.press(&[65]);
+        let start = rig.now;
+        rig.run(300);
+        assert_eq!(rig.ons(), [(start, 65), (start + 125, 65), (start + 250, 65)]);
+    }
+
+    #[test]
+    fn latches() {
+        let mut rig = Rig::new(Mode::Up, 1, true);
+        rig.press(&[60, 64]);
+        rig.run(100);
+        rig.release(&[60, 64]);
+        rig.run(400);
+        assert_eq!(rig.notes(), [60, 64, 60, 64]);
+        assert_eq!(rig.processor.held().collect::<Vec<_>>(), [60, 64]);
+
+        // A new chord replaces the latched one, notes added while it is
+        // held join it
+        rig.press(&[67]);
+        rig.run(125);
+        rig.press(&[72]);
+        rig.run(250);
+        assert_eq!(rig.notes(), [67, 72, 67]);
+        rig.release(&[67]);
+        rig.press(&[69]);
+        rig.run(375);
+        assert_eq!(rig.notes(), [67, 69, 72]);
+
+        // Latch switched off lets go of the released notes on the next poll
+        rig.release(&[69, 72]);
+        rig.options.latch = false;
+        rig.run(500);
+        assert!(rig.notes().is_empty());
+        assert!(rig.processor.held().next().is_none());
+    }
+
+    #[test]
+    fn switches_modes() {
+        // Off plays notes and chords as they come
+        let mut rig = Rig::new(Mode::Off, 1, false);
+        rig.options.chord = Chord::from_notes([0, 4, 7].into_iter()).unwrap();
+        rig.press(&[60]);
+        rig.run(500);
+        assert_eq!(rig.ons(), [(0, 60), (0, 64), (0, 67)]);
+
+        // Notes held from before the arpeggio keep sounding, outside it
+        rig.options.mode = Mode::Up;
+        rig.options.chord = Chord::NONE;
+        rig.press(&[48]);
+        rig.run(250);
+        assert_eq!(rig.notes(), [48, 48]);
+        rig.release(&[60]);
+        let offs: Vec<u8> = rig.log.iter().filter_map(|(_, m)| match m {
+            MidiMessage::NoteOff { note, .. } => Some(*note),
+            _ => None,
+        }).collect();
+        assert_eq!(offs, [60, 64, 67]);
+
+        // Back to off drops the arpeggio
+        rig.log.clear();
+        rig.options.mode = Mode::Off;
+        rig.run(500);
+        assert!(rig.notes().is_empty());
+        assert!(rig.processor.held().next().is_none());
+
+        // Reset ends what is sounding
+        rig.options.mode = Mode::Up;
+        rig.press(&[50]);
+        rig.options.mode = Mode::Off;
+        rig.press(&[52]);
+        rig.options.mode = Mode::Up;
+        rig.run(1);
+        rig.log.clear();
+        let mut offs = Vec::new();
+        rig.processor.reset(&mut |m| offs.push(m));
+        let off = |note| MidiMessage::NoteOff { channel: 0, note, velocity: 0 };
+        assert_eq!(offs, [off(52), off(50)]);
+    }
+
+    #[test]
+    fn follows_the_external_clock() {
+        let mut rig = Rig::new(Mode::Up, 1, false);
+        rig.press(&[60, 64]);
+        // Two clocks per arpeggio note, at 100 BPM
+        rig.options.rate = 2;
+        let mut log = Vec::new();
+        for clock in 0..12 {
+            let now = clock * 25;
+            let (processor, options) = (&mut rig.processor, &rig.options);
+            processor.midi(now, &MidiMessage::Clock, options, ClockSource::External, &mut |m| log.push((now, m)));
+            // The internal clock is not running
+            processor.poll(now + 1, options, ClockSource::External, 120, &mut |m| log.push((now + 1, m)));
+        }
+        let ons: Vec<(u32, u8)> = log.iter().filter_map(|&(now, m)| match m {
+            MidiMessage::NoteOn { note, .. } => Some((now, note)),
+            _ => None,
+        }).collect();
+        assert_eq!(ons, [(0, 60), (50, 64), (100, 60), (150, 64), (200, 60), (250, 64)]);
+    }
+}
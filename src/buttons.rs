@@ -0,0 +1,71 @@
+//! On-board Buttons
+//!
+//! The two LaunchPad user buttons as an input source.
+
+use tm4c123x::{GPIO_PORTF, SYSCTL};
+
+use crate::input::{ids, Input, InputEvent, InputSource};
+
+/// PF4 = SW1, PF0 = SW2, both active low
+const SW1: u32 = 0x10;
+const SW2: u32 = 0x01;
+
+/// Time a button must be stable before a change is reported
+const DEBOUNCE_MS: u32 = 10;
+
+/// The two LaunchPad user buttons
+pub struct OnboardButtons {
+    stable: u32,
+    last_raw: u32,
+    changed_at: u32,
+}
+
+impl OnboardButtons {
+    /// Configure PF0 and PF4 as inputs with pull-ups.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let portf = unsafe { &*GPIO_PORTF::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 5)) });
+        while sysctl.prgpio.read().bits() & (1 << 5) == 0 {}
+
+        // PF0 is an NMI pin and locked after reset
+        portf.lock.write(|w| unsafe { w.bits(0x4C4F_434B) });
+        portf.cr.modify(|r, w| unsafe { w.bits(r.bits() | SW2) });
+        portf.lock.write(|w| unsafe { w.bits(0) });
+
+        portf.dir.modify(|r, w| unsafe { w.bits(r.bits() & !(SW1 | SW2)) });
+        portf.pur.modify(|r, w| unsafe { w.bits(r.bits() | SW1 | SW2) });
+        portf.den.modify(|r, w| unsafe { w.bits(r.bits() | SW1 | SW2) });
+
+        Self { stable: 0, last_raw: 0, changed_at: 0 }
+    }
+
+    /// Pressed buttons as a bit mask
+    fn read() -> u32 {
+        let portf = unsafe { &*GPIO_PORTF::ptr() };
+        !portf.data.read().bits() & (SW1 | SW2)
+    }
+}
+
+impl InputSource for OnboardButtons {
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        let raw = Self::read();
+        if raw != self.last_raw {
+            self.last_raw = raw;
+            self.changed_at = now;
+            return;
+        }
+        if raw == self.stable || now.wrapping_sub(self.changed_at) < DEBOUNCE_MS {
+            return;
+        }
+        let changed = raw ^ self.stable;
+        self.stable = raw;
+        for (index, mask) in [SW1, SW2].into_iter().enumerate() {
+            if changed & mask != 0 {
+                let input = if raw & mask != 0 { Input::Press(None) } else { Input::Release };
+                emit(InputEvent { control: ids::ONBOARD + index as u8, input });
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Capacitive Sensing
+//!
+//! Hardware independent part of the touch inputs: electrodes read as a
+//! charge time that grows when a finger is near. Every electrode is
+//! filtered against noise and compared with a baseline that follows slow
+//! drift (temperature, humidity) while untouched; a touch held for very
+//! long is taken for drift too, so a pad can never stay stuck on.
+//!
+//! Touch pads are single electrodes that press and release a control. The
+//! ribbon is a row of electrodes along a strip (two interleaved triangles
+//! work as well): the position of a finger is the centroid of the
+//! electrode signals, the pressure the total signal, which grows with the
+//! contact area of a finger pressed harder. Its controls, offsets from
+//! `input::ids::RIBBON`, are in `ribbon`.
+
+use crate::input::{self, Input, InputEvent, FULL_SCALE};
+
+/// Electrodes, pads and ribbon together
+pub const MAX_CHANNELS: usize = 8;
+
+/// Ribbon controls, offsets from `input::ids::RIBBON`
+pub mod ribbon {
+    /// Position along the strip, absolute
+    pub const POSITION: u8 = 0;
+    /// Pressure, absolute and 0 once released
+    pub const PRESSURE: u8 = 1;
+    /// Press with the first pressure as velocity, then pressure, release
+    pub const TOUCH: u8 = 2;
+}
+
+/// Ribbon controls
+pub const RIBBON_CONTROLS: usize = 3;
+
+/// Signals are rises over the baseline in 1/256 of the baseline
+const TOUCH_THRESHOLD: u32 = 64;
+const RELEASE_THRESHOLD: u32 = 32;
+/// Signal of an electrode below this is noise, left out of the centroid
+const NOISE_FLOOR: u32 = 8;
+/// Total ribbon signal of full pressure
+const FULL_PRESSURE: u32 = 4 * TOUCH_THRESHOLD;
+/// A touch longer than this is drift, the baseline takes it over
+const STUCK_MS: u32 = 20_000;
+/// Change of a ribbon value before it is reported again
+const HYSTERESIS: u16 = 64;
+
+/// One electrode
+pub struct Channel {
+    /// Filtered reading and baseline, times 16
+    filtered: u32,
+    baseline: u32,
+}
+
+impl Channel {
+    pub const fn new() -> Self {
+        Self { filtered: 0, baseline: 0 }
+    }
+
+    /// Filter a reading, return the signal.
+    pub fn sample(&mut self, reading: u32) -> u32 {
+        let reading = reading * 16;
+        if self.baseline == 0 {
+            self.filtered = reading;
+            self.baseline = reading.max(16);
+        }
+        // Rises go in quickly, single spikes are mostly filtered out
+        self.filtered = (self.filtered * 3 + reading) / 4;
+        self.filtered.saturating_sub(self.baseline) * 256 / self.baseline
+    }
+
+    /// Follow drift while untouched: slowly up, drops at once.
+    pub fn follow(&mut self) {
+        self.baseline = match self.filtered < self.baseline {
+            true => self.filtered.max(16),
+            false => self.baseline + (self.filtered - self.baseline) / 64,
+        };
+    }
+
+    /// Take the current reading as untouched.
+    pub fn rebase(&mut self) {
+        self.baseline = self.filtered.max(16);
+    }
+}
+
+/// Touch decision on a signal, with hysteresis
+pub struct Detector {
+    touched: bool,
+    since: u32,
+}
+
+impl Detector {
+    pub const fn new() -> Self {
+        Self { touched: false, since: 0 }
+    }
+
+    /// Whether touched now, and whether the touch was held so long that it
+    /// has to be drift.
+    pub fn update(&mut self, now: u32, signal: u32) -> (bool, bool) {
+        let touched = match self.touched {
+            true => signal > RELEASE_THRESHOLD,
+            false => signal > TOUCH_THRESHOLD,
+        };
+        if touched && !self.touched {
+            self.since = now;
+        }
+        let stuck = touched && now.wrapping_sub(self.since) >= STUCK_MS;
+        self.touched = touched && !stuck;
+        (self.touched, stuck)
+    }
+}
+
+/// Touch pads, one control each
+pub struct Pads {
+    channels: [Channel; MAX_CHANNELS],
+    detectors: [Detector; MAX_CHANNELS],
+}
+
+impl Pads {
+    pub const fn new() -> Self {
+        Self {
+            channels: [const { Channel::new() }; MAX_CHANNELS],
+            detectors: [const { Detector::new() }; MAX_CHANNELS],
+        }
+    }
+
+    /// Take one reading per pad, report the pads touched and released.
+    pub fn update(&mut self, now: u32, readings: &[u32], emit: &mut dyn FnMut(InputEvent)) {
+        for (index, &reading) in readings.iter().enumerate().take(MAX_CHANNELS) {
+            let (channel, detector) = (&mut self.channels[index], &mut self.detectors[index]);
+            let was_touched = detector.touched;
+            let (touched, stuck) = detector.update(now, channel.sample(reading));
+            match (touched, stuck) {
+                (_, true) => channel.rebase(),
+                (false, _) => channel.follow(),
+                _ => {}
+            }
+            if touched != was_touched {
+                let input = if touched { Input::Press(None) } else { Input::Release };
+                emit(InputEvent { control: input::ids::TOUCH + index as u8, input });
+            }
+        }
+    }
+}
+
+/// A ribbon strip made of a row of electrodes
+pub struct Ribbon {
+    channels: [Channel; MAX_CHANNELS],
+    detector: Detector,
+    /// Position and pressure while touched, as last reported
+    touch: Option<(u16, u16)>,
+}
+
+impl Ribbon {
+    pub const fn new() -> Self {
+        Self { channels: [const { Channel::new() }; MAX_CHANNELS], detector: Detector::new(), touch: None }
+    }
+
+    /// Take one reading per electrode, first electrode at position 0.
+    pub fn update(&mut self, now: u32, readings: &[u32], emit: &mut dyn FnMut(InputEvent)) {
+        let channels = &mut self.channels[..readings.len().min(MAX_CHANNELS)];
+        let (mut total, mut weight, mut moment) = (0, 0, 0);
+        for (index, (channel, &reading)) in channels.iter_mut().zip(readings).enumerate() {
+            let signal = channel.sample(reading);
+            total += signal;
+            weight += signal.saturating_sub(NOISE_FLOOR);
+            moment += signal.saturating_sub(NOISE_FLOOR) * index as u32;
+        }
+        // The whole strip is touched or not, so the baselines of electrodes
+        // next to the finger do not creep up to it
+        let (touched, stuck) = self.detector.update(now, total);
+        for channel in channels.iter_mut() {
+            match (touched, stuck) {
+                (_, true) => channel.rebase(),
+                (false, _) => channel.follow(),
+                _ => {}
+            }
+        }
+        let control = |offset| input::ids::RIBBON + offset;
+
+        if !touched {
+            if self.touch.take().is_some() {
+                emit(InputEvent { control: control(ribbon::PRESSURE), input: Input::Absolute(0) });
+                emit(InputEvent { control: control(ribbon::TOUCH), input: Input::Release });
+            }
+            return;
+        }
+        let span = (channels.len() as u32).saturating_sub(1).max(1);
+        let position = match weight {
+            0 => 0,
+            _ => (moment * FULL_SCALE as u32 / (span * weight)).min(FULL_SCALE as u32) as u16,
+        };
+        let pressure = (total * FULL_SCALE as u32 / FULL_PRESSURE).clamp(1, FULL_SCALE as u32) as u16;
+
+        let Some((reported_position, reported_pressure)) = self.touch else {
+            self.touch = Some((position, pressure));
+            emit(InputEvent { control: control(ribbon::POSITION), input: Input::Absolute(position) });
+            emit(InputEvent { control: control(ribbon::PRESSURE), input: Input::Absolute(pressure) });
+            emit(InputEvent { control: control(ribbon::TOUCH), input: Input::Press(Some(pressure)) });
+            return;
+        };
+        // Ends of the range are always reported, like a fader's
+        let moved = |value: u16, last: u16| {
+            value.abs_diff(last) > HYSTERESIS || ((value == 0 || value == FULL_SCALE) && value != last)
+        };
+        if moved(position, reported_position) {
+            self.touch = Some((position, reported_pressure));
+            emit(InputEvent { control: control(ribbon::POSITION), input: Input::Absolute(position) });
+        }
+        if moved(pressure, reported_pressure) {
+            self.touch = self.touch.map(|(position, _)| (position, pressure));
+            emit(InputEvent { control: control(ribbon::PRESSURE), input: Input::Absolute(pressure) });
+            emit(InputEvent { control: control(ribbon::TOUCH), input: Input::Pressure(pressure) });
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! CDC Serial Port
+//!
+//! Buffered byte I/O on top of the TivaWare CDC class. Received packets are
+//! drained into a ring buffer from the RX callback; transmit data is queued
+//! and pushed out from the main loop by `flush()`.
+
+use core::ffi::c_void;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::ring::ByteRing;
+use crate::usb_device::{
+    self, USBDCDCPacketRead, USBDCDCPacketWrite, USBDCDCRxPacketAvailable,
+    USBDCDCTxPacketAvailable,
+};
+
+/// Max packet size of the CDC bulk endpoints
+const PACKET_SIZE: usize = 64;
+
+static INSTANCE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Set on disconnect; the main loop, the only consumer of TX, drops the
+/// pending output
+static DISCARD: AtomicBool = AtomicBool::new(false);
+
+static RX: ByteRing<256> = ByteRing::new();
+static TX: ByteRing<1024> = ByteRing::new();
+
+/// Remember the instance returned by `USBDCDCInit`.
+pub fn init(instance: *mut c_void) {
+    INSTANCE.store(instance, Ordering::Release);
+}
+
+/// True while a host has the device configured.
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Acquire)
+}
+
+/// Handle a control callback event from the CDC class.
+pub fn on_control_event(event: u32) {
+    match event {
+        usb_device::usb_events::USB_EVENT_CONNECTED => CONNECTED.store(true, Ordering::Release),
+        usb_device::usb_events::USB_EVENT_DISCONNECTED => {
+            CONNECTED.store(false, Ordering::Release);
+            // Nobody is listening anymore, have the main loop throw away
+            // pending output
+            DISCARD.store(true, Ordering::Release);
+        }
+        _ => {}
+    }
+}
+
+/// Drain received packets into the RX ring. Called from the RX callback.
+///
+/// # Safety
+/// Must only be called from USB interrupt context after `init()`.
+pub unsafe fn on_rx_available() {
+    let instance = INSTANCE.load(Ordering::Acquire);
+    if instance.is_null() {
+        return;
+    }
+
+    let mut packet = [0u8; PACKET_SIZE];
+    loop {
+        let available = USBDCDCRxPacketAvailable(instance) as usize;
+        // Leave the packet with the library if it does not fit yet; it is
+        // offered again on the next tick.
+        if available == 0 || available > RX.free() {
+            break;
+        }
+        let read = USBDCDCPacketRead(instance, packet.as_mut_ptr(), PACKET_SIZE as u32, true) as usize;
+        if read == 0 {
+            break;
+        }
+        for &byte in &packet[..read] {
+            RX.push(byte);
+        }
+    }
+}
+
+/// Next received byte, if any.
+pub fn read_byte() -> Option<u8> {
+    RX.pop()
+}
+
+/// Drop output queued before a disconnect.
+fn discard() {
+    if DISCARD.swap(false, Ordering::AcqRel) {
+        while TX.pop().is_some() {}
+    }
+}
+
+/// Queue bytes for transmission. Returns how many were accepted.
+pub fn write(data: &[u8]) -> usize {
+    discard();
+    if !is_connected() {
+        return data.len();
+    }
+    let mut written = 0;
+    for &byte in data {
+        if !TX.push(byte) {
+            break;
+        }
+        written += 1;
+    }
+    written
+}
+
+/// Free space in the transmit queue.
+pub fn tx_free() -> usize {
+    discard();
+    TX.free()
+}
+
+/// Push queued output to the host, one packet at a time.
+pub fn flush() {
+    discard();
+    let instance = INSTANCE.load(Ordering::Acquire);
+    if instance.is_null() || !is_connected() {
+        return;
+    }
+
+    let mut packet = [0u8; PACKET_SIZE];
+    unsafe {
+        while !TX.is_empty() {
+            let space = (USBDCDCTxPacketAvailable(instance) as usize).min(PACKET_SIZE);
+            if space == 0 {
+                break;
+            }
+            let mut len = 0;
+            while len < space {
+                match TX.pop() {
+                    Some(byte) => {
+                        packet[len] = byte;
+                        len += 1;
+                    }
+                    None => break,
+                }
+            }
+            USBDCDCPacketWrite(instance, packet.as_ptr(), len as u32, true);
+        }
+    }
+}
+
+/// `fmt::Write` adapter for the transmit queue. Output that does not fit is
+/// dropped rather than blocking the main loop.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
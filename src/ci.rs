@@ -0,0 +1,1150 @@
+//! MIDI Capability Inquiry
+//!
+//! Responder side of MIDI-CI (message version 1.2). `Responder::handle` takes
+//! one complete CI SysEx message and answers it:
+//!
+//! - discovery and endpoint inquiry, with MUID collision handling
+//! - protocol negotiation between MIDI 1.0 and MIDI 2.0
+//! - profile inquiry: there are no profiles, requests to enable one are
+//!   answered with a disabled report
+//! - property exchange with the `ResourceList` and `DeviceInfo` resources,
+//!   `X-Mappings`, the control mappings as JSON, and `X-Curves`, the user
+//!   curves
+//!
+//! Replies are produced one SysEx message at a time so the caller can pace
+//! them: `handle` sends at most one message, the remaining chunks of a long
+//! property reply come from `next_reply`. Nothing in here touches hardware;
+//! CI sessions can be replayed against the responder on the host.
+
+use core::fmt::{self, Write};
+
+use crate::curve::{self, Curve};
+use crate::input::ControlId;
+use crate::json::{self, ArrayReader, Value, Window};
+use crate::mapping::{Action, Mapping, MappingTable, Takeover, DEFAULT_CHANNEL, MAX_BANKS};
+use crate::ump::{EndpointInfo, Protocol};
+
+/// Largest CI message accepted, reported to initiators as our limit
+pub const MAX_SYSEX: usize = 512;
+
+/// Largest message sent; long property data is split into chunks so one
+/// reply fits a router output queue
+pub const MAX_REPLY: usize = 160;
+
+/// Message header, request id, header length, chunk fields and F7
+const PROPERTY_OVERHEAD: usize = 14 + 1 + 2 + 6 + 1;
+
+/// Property header of a reply
+const MAX_PROPERTY_HEADER: usize = MAX_REPLY - PROPERTY_OVERHEAD;
+
+/// Property data per reply chunk, after the `{"status":200}` header
+const CHUNK_DATA: usize = 96;
+
+/// Property data of a set request kept between chunks: the cut off entry
+/// plus one chunk of the largest message we accept
+const SET_TEXT: usize = 256 + MAX_SYSEX;
+
+const VERSION: u8 = 0x02;
+
+/// MUID addressing every device
+pub const BROADCAST: u32 = 0x0FFF_FFFF;
+
+/// Device id addressing the whole function block
+const FUNCTION_BLOCK: u8 = 0x7F;
+
+/// Our function block number in discovery replies
+const BLOCK_NUMBER: u8 = 0;
+
+/// Authority level in protocol negotiation
+const AUTHORITY: u8 = 0x30;
+
+/// Categories supported: protocol negotiation, profiles, property exchange
+const CATEGORIES: u8 = 0x02 | 0x04 | 0x08;
+
+/// NAK status code for messages we do not implement
+const NAK_NOT_SUPPORTED: u8 = 0x01;
+
+/// Name of the mapping resource
+pub const MAPPINGS_RESOURCE: &str = "X-Mappings";
+
+/// Name of the user curve resource
+pub const CURVES_RESOURCE: &str = "X-Curves";
+
+/// Sub-ID#2 of the messages handled or sent
+pub mod sub_id {
+    pub const PROTOCOL_NEGOTIATION: u8 = 0x10;
+    pub const PROTOCOL_REPLY: u8 = 0x11;
+    pub const SET_PROTOCOL: u8 = 0x12;
+    pub const TEST_PROTOCOL: u8 = 0x13;
+    pub const TEST_PROTOCOL_REPLY: u8 = 0x14;
+    pub const CONFIRM_PROTOCOL: u8 = 0x15;
+    pub const PROFILE_INQUIRY: u8 = 0x20;
+    pub const PROFILE_REPLY: u8 = 0x21;
+    pub const SET_PROFILE_ON: u8 = 0x22;
+    pub const SET_PROFILE_OFF: u8 = 0x23;
+    pub const PROFILE_DISABLED: u8 = 0x25;
+    pub const PE_CAPABILITIES: u8 = 0x30;
+    pub const PE_CAPABILITIES_REPLY: u8 = 0x31;
+    pub const PE_GET: u8 = 0x34;
+    pub const PE_GET_REPLY: u8 = 0x35;
+    pub const PE_SET: u8 = 0x36;
+    pub const PE_SET_REPLY: u8 = 0x37;
+    pub const PE_SUBSCRIBE: u8 = 0x38;
+    pub const PE_SUBSCRIBE_REPLY: u8 = 0x39;
+    pub const DISCOVERY: u8 = 0x70;
+    pub const DISCOVERY_REPLY: u8 = 0x71;
+    pub const ENDPOINT_INFO: u8 = 0x72;
+    pub const ENDPOINT_INFO_REPLY: u8 = 0x73;
+    pub const ACK: u8 = 0x7D;
+    pub const INVALIDATE_MUID: u8 = 0x7E;
+    pub const NAK: u8 = 0x7F;
+}
+
+/// Property exchange status codes
+mod status {
+    pub const OK: u16 = 200;
+    pub const BAD_REQUEST: u16 = 400;
+    pub const NOT_FOUND: u16 = 404;
+    pub const NOT_SUPPORTED: u16 = 405;
+    pub const TOO_LARGE: u16 = 413;
+}
+
+/// Something the rest of the controller has to act on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The initiator switched to a new protocol
+    Protocol(Protocol),
+    /// A property set replaced mappings
+    MappingsChanged,
+}
+
+/// Sender of a request, where the reply goes
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    device: u8,
+    muid: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    List,
+    DeviceInfo,
+    /// All mappings, or the ones of one control (`resId`)
+    Mappings(Option<ControlId>),
+    Curves,
+}
+
+/// Property reply with chunks left to send
+struct PendingGet {
+    peer: Peer,
+    request_id: u8,
+    resource: Resource,
+    chunk: u16,
+    chunks: u16,
+}
+
+/// Property set waiting for more chunks. The mappings or curves are parsed
+/// as the chunks arrive; text of an entry cut off at the end of a chunk is
+/// kept until the next one.
+struct PendingSet {
+    peer: Peer,
+    request_id: u8,
+    resource: Resource,
+    chunks: u16,
+    next: u16,
+    reader: ArrayReader,
+    /// Bytes kept in `Responder::set_text`
+    len: usize,
+}
+
+impl PendingSet {
+    /// Parse the entries completed by one more chunk of property data
+    fn receive(
+        &mut self,
+        data: &[u8],
+        last: bool,
+        text: &mut [u8; SET_TEXT],
+        staged: &mut MappingTable,
+    ) -> Result<(), (u16, json::Error)> {
+        let end = self.len + data.len();
+        if end > SET_TEXT {
+            return Err((status::TOO_LARGE, "mapping too long"));
+        }
+        text[self.len..end].copy_from_slice(data);
+
+        // A chunk may end inside a UTF-8 sequence
+        let valid = match core::str::from_utf8(&text[..end]) {
+            Ok(valid) => valid,
+            Err(err) if err.error_len().is_none() => core::str::from_utf8(&text[..err.valid_up_to()]).unwrap_or(""),
+            Err(_) => return Err((status::BAD_REQUEST, "invalid UTF-8")),
+        };
+        let resource = self.resource;
+        let used = self
+            .reader
+            .feed(valid, &mut |value| match (resource, value) {
+                (Resource::Mappings(res_id), Value::Object(object)) => {
+                    let mapping = parse_mapping(object, res_id)?;
+                    staged.add(mapping).map_err(|_| "too many mappings")
+                }
+                (Resource::Curves, Value::Object(object)) => {
+                    let (index, table) = parse_curve(object)?;
+                    staged.curves[index] = table;
+                    Ok(())
+                }
+                (Resource::Curves, _) => Err("expected an array of curves"),
+                _ => Err("expected an array of mappings"),
+            })
+            .map_err(|message| (status::BAD_REQUEST, message))?;
+        text.copy_within(used..end, 0);
+        self.len = end - used;
+
+        if last && (!self.reader.finished() || text[..self.len].iter().any(|b| !b.is_ascii_whitespace())) {
+            return Err((status::BAD_REQUEST, "incomplete JSON"));
+        }
+        Ok(())
+    }
+}
+
+pub struct Responder {
+    info: &'static EndpointInfo,
+    /// `BROADCAST` until the first message arrives
+    muid: u32,
+    seed: u32,
+    /// Receive limit of the last initiator that ran discovery
+    peer_max_sysex: usize,
+    get: Option<PendingGet>,
+    set: Option<PendingSet>,
+    set_text: [u8; SET_TEXT],
+    /// Mappings as they will be once the set in progress succeeds
+    staged: MappingTable,
+}
+
+impl Responder {
+    pub const fn new(info: &'static EndpointInfo) -> Self {
+        Self {
+            info,
+            muid: BROADCAST,
+            seed: 0x2545_F491,
+            peer_max_sysex: MAX_REPLY,
+            get: None,
+            set: None,
+            set_text: [0; SET_TEXT],
+            staged: MappingTable::new(),
+        }
+    }
+
+    /// Our MUID, once one was picked
+    pub fn muid(&self) -> Option<u32> {
+        (self.muid != BROADCAST).then_some(self.muid)
+    }
+
+    /// Answer one complete SysEx message. Anything that is not MIDI-CI or
+    /// not addressed to us is ignored. `entropy` seeds a new MUID.
+    pub fn handle(
+        &mut self,
+        message: &[u8],
+        entropy: u32,
+        mappings: &mut MappingTable,
+        out: &mut dyn FnMut(&[u8]),
+    ) -> Option<Event> {
+        let (header, payload) = parse(message)?;
+        if self.muid == BROADCAST {
+            self.new_muid(entropy);
+        }
+        if header.source == self.muid {
+            // Another device picked our MUID: give it up and say so
+            if header.sub_id != sub_id::INVALIDATE_MUID {
+                let everyone = Peer { device: FUNCTION_BLOCK, muid: BROADCAST };
+                let mut reply = Reply::new(sub_id::INVALIDATE_MUID, self.muid, everyone);
+                reply.u28(self.muid);
+                reply.send(out);
+                self.new_muid(entropy);
+            }
+            return None;
+        }
+        if header.dest != self.muid && header.dest != BROADCAST {
+            return None;
+        }
+        let peer = Peer { device: header.device, muid: header.source };
+        match header.sub_id {
+            sub_id::DISCOVERY => self.discovery(peer, payload, out),
+            sub_id::ENDPOINT_INFO => {
+                // Status 0 is the only one defined: product instance id
+                if payload.first() == Some(&0) {
+                    let id = self.info.product_instance_id.as_bytes();
+                    let mut reply = Reply::new(sub_id::ENDPOINT_INFO_REPLY, self.muid, peer);
+                    reply.byte(0);
+                    reply.u14(id.len() as u16);
+                    reply.bytes(id);
+                    reply.send(out);
+                }
+            }
+            sub_id::INVALIDATE_MUID => {
+                let target = u28(payload.get(..4)?);
+                if target == self.muid {
+                    self.new_muid(entropy);
+                } else {
+                    self.forget(target);
+                }
+            }
+            sub_id::PROTOCOL_NEGOTIATION => {
+                let mut reply = Reply::new(sub_id::PROTOCOL_REPLY, self.muid, peer);
+                reply.byte(AUTHORITY);
+                reply.byte(2);
+                for protocol in [Protocol::Midi2, Protocol::Midi1] {
+                    reply.bytes(&[protocol.stream_value(), 0, 0, 0, 0]);
+                }
+                reply.send(out);
+            }
+            sub_id::SET_PROTOCOL => {
+                let protocol = Protocol::from_stream_value(*payload.get(1)?)?;
+                return Some(Event::Protocol(protocol));
+            }
+            sub_id::TEST_PROTOCOL => {
+                let data = payload.get(1..49)?;
+                let mut reply = Reply::new(sub_id::TEST_PROTOCOL_REPLY, self.muid, peer);
+                reply.byte(AUTHORITY);
+                reply.bytes(data);
+                reply.send(out);
+            }
+            sub_id::CONFIRM_PROTOCOL => {}
+            sub_id::PROFILE_INQUIRY => {
+                // No profiles enabled, none disabled
+                let mut reply = Reply::new(sub_id::PROFILE_REPLY, self.muid, peer);
+                reply.u14(0);
+                reply.u14(0);
+                reply.send(out);
+            }
+            sub_id::SET_PROFILE_ON | sub_id::SET_PROFILE_OFF => {
+                let profile = payload.get(..5)?;
+                let mut reply = Reply::new(sub_id::PROFILE_DISABLED, self.muid, peer);
+                reply.bytes(profile);
+                reply.u14(0);
+                reply.send(out);
+            }
+            sub_id::PE_CAPABILITIES => {
+                // One request at a time, property exchange version 1.0
+                let mut reply = Reply::new(sub_id::PE_CAPABILITIES_REPLY, self.muid, peer);
+                reply.bytes(&[1, 0, 0]);
+                reply.send(out);
+            }
+            sub_id::PE_GET => self.property_get(peer, payload, mappings, out),
+            sub_id::PE_SET => return self.property_set(peer, payload, mappings, out),
+            sub_id::PE_SUBSCRIBE => {
+                let request = PropertyRequest::parse(payload)?;
+                self.property_status(sub_id::PE_SUBSCRIBE_REPLY, peer, request.request_id, status::NOT_SUPPORTED, "", out);
+            }
+            sub_id::ACK | sub_id::NAK => {}
+            other => {
+                if header.dest == self.muid {
+                    let mut reply = Reply::new(sub_id::NAK, self.muid, peer);
+                    reply.bytes(&[other, NAK_NOT_SUPPORTED, 0, 0, 0, 0, 0, 0]);
+                    reply.u14(0);
+                    reply.send(out);
+                }
+            }
+        }
+        None
+    }
+
+    /// Send the next chunk of a property reply, returns false if there is
+    /// none. The resource is rendered again for every chunk.
+    pub fn next_reply(&mut self, mappings: &MappingTable, out: &mut dyn FnMut(&[u8])) -> bool {
+        let size = self.chunk_size();
+        let Some(get) = self.get.as_mut() else {
+            return false;
+        };
+        let mut data = [0u8; CHUNK_DATA];
+        let mut window = Window::new(&mut data[..size], (get.chunk - 1) as usize * size);
+        let _ = render(self.info, get.resource, mappings, &mut window);
+        let len = window.len();
+
+        let mut reply = Reply::new(sub_id::PE_GET_REPLY, self.muid, get.peer);
+        reply.byte(get.request_id);
+        if get.chunk == 1 {
+            reply.header(status::OK, "");
+        } else {
+            reply.u14(0);
+        }
+        reply.u14(get.chunks);
+        reply.u14(get.chunk);
+        reply.u14(len as u16);
+        reply.bytes(&data[..len]);
+        reply.send(out);
+
+        if get.chunk == get.chunks {
+            self.get = None;
+        } else {
+            get.chunk += 1;
+        }
+        true
+    }
+
+    fn new_muid(&mut self, entropy: u32) {
+        self.get = None;
+        self.set = None;
+        // xorshift32, never zero
+        let mut x = (self.seed ^ entropy) | 1;
+        loop {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            let muid = x & BROADCAST;
+            // 0x0FFFFF00 and up are reserved
+            if muid < 0x0FFF_FF00 && muid != self.muid {
+                self.muid = muid;
+                self.seed = x;
+                return;
+            }
+        }
+    }
+
+    /// Drop requests in progress of a MUID that went away
+    fn forget(&mut self, muid: u32) {
+        if self.get.as_ref().is_some_and(|get| get.peer.muid == muid) {
+            self.get = None;
+        }
+        if self.set.as_ref().is_some_and(|set| set.peer.muid == muid) {
+            self.set = None;
+        }
+    }
+
+    /// Property data per chunk within the initiator's receive limit
+    fn chunk_size(&self) -> usize {
+        self.peer_max_sysex.saturating_sub(MAX_REPLY - CHUNK_DATA).clamp(16, CHUNK_DATA)
+    }
+
+    fn discovery(&mut self, peer: Peer, payload: &[u8], out: &mut dyn FnMut(&[u8])) {
+        // Identity (11 bytes) and categories, then the initiator's receive
+        // limit and, since version 1.2, an output path id to echo
+        if let Some(size) = payload.get(12..16) {
+            self.peer_max_sysex = u28(size) as usize;
+        }
+        let output_path = payload.get(16).copied().unwrap_or(0);
+
+        let info = self.info;
+        let mut reply = Reply::new(sub_id::DISCOVERY_REPLY, self.muid, peer);
+        reply.bytes(&info.manufacturer);
+        reply.u14(info.family);
+        reply.u14(info.model);
+        reply.bytes(&info.revision);
+        reply.byte(CATEGORIES);
+        reply.u28(MAX_SYSEX as u32);
+        reply.byte(output_path);
+        reply.byte(BLOCK_NUMBER);
+        reply.send(out);
+    }
+
+    fn property_get(&mut self, peer: Peer, payload: &[u8], mappings: &MappingTable, out: &mut dyn FnMut(&[u8])) {
+        let Some(request) = PropertyRequest::parse(payload) else {
+            return;
+        };
+        self.get = None;
+        match resource(request.header) {
+            Ok(resource) => {
+                let mut window = Window::new(&mut [], 0);
+                let _ = render(self.info, resource, mappings, &mut window);
+                let chunks = window.total().div_ceil(self.chunk_size()).max(1) as u16;
+                self.get = Some(PendingGet { peer, request_id: request.request_id, resource, chunk: 1, chunks });
+                self.next_reply(mappings, out);
+            }
+            Err(code) => self.property_status(sub_id::PE_GET_REPLY, peer, request.request_id, code, "", out),
+        }
+    }
+
+    fn property_set(
+        &mut self,
+        peer: Peer,
+        payload: &[u8],
+        mappings: &mut MappingTable,
+        out: &mut dyn FnMut(&[u8]),
+    ) -> Option<Event> {
+        let request = PropertyRequest::parse(payload)?;
+        let reply = sub_id::PE_SET_REPLY;
+        if request.chunk == 1 {
+            self.set = None;
+            match resource(request.header) {
+                Ok(resource @ (Resource::Mappings(_) | Resource::Curves)) => {
+                    // Work on a copy so a bad request changes nothing
+                    self.staged = mappings.clone();
+                    match resource {
+                        Resource::Mappings(Some(control)) => {
+                            self.staged.remove(control);
+                        }
+                        Resource::Mappings(None) => self.staged.clear(),
+                        // Curves left out of the request stay as they are
+                        _ => {}
+                    }
+                    let chunks = request.chunks.max(1);
+                    self.set = Some(PendingSet {
+                        peer,
+                        request_id: request.request_id,
+                        resource,
+                        chunks,
+                        next: 1,
+                        reader: ArrayReader::new(),
+                        len: 0,
+                    });
+                }
+                Ok(_) => {
+                    self.property_status(reply, peer, request.request_id, status::NOT_SUPPORTED, "", out);
+                    return None;
+                }
+                Err(code) => {
+                    self.property_status(reply, peer, request.request_id, code, "", out);
+                    return None;
+                }
+            }
+        }
+
+        // Later chunks must continue the set in progress
+        let Self { set, set_text, staged, .. } = self;
+        let pending = set.as_mut().filter(|set| {
+            set.peer.muid == peer.muid && set.request_id == request.request_id && set.next == request.chunk
+        })?;
+        pending.next += 1;
+        let last = request.chunk >= pending.chunks;
+        let result = pending.receive(request.data, last, set_text, staged);
+        let request_id = pending.request_id;
+        if result.is_ok() && !last {
+            return None;
+        }
+        self.set = None;
+        match result {
+            Ok(()) => {
+                *mappings = self.staged.clone();
+                self.property_status(reply, peer, request_id, status::OK, "", out);
+                Some(Event::MappingsChanged)
+            }
+            Err((code, message)) => {
+                self.property_status(reply, peer, request_id, code, message, out);
+                None
+            }
+        }
+    }
+
+    /// Property reply with a header only
+    fn property_status(&self, sub_id: u8, peer: Peer, request_id: u8, code: u16, message: &str, out: &mut dyn FnMut(&[u8])) {
+        let mut reply = Reply::new(sub_id, self.muid, peer);
+        reply.byte(request_id);
+        reply.header(code, message);
+        reply.u14(1);
+        reply.u14(1);
+        reply.u14(0);
+        reply.send(out);
+    }
+}
+
+// ============================================================================
+// Message format
+// ============================================================================
+
+/// Fields every CI message starts with
+struct Header {
+    device: u8,
+    sub_id: u8,
+    source: u32,
+    dest: u32,
+}
+
+/// F0 7E device 0D sub-ID version source(4) destination(4)
+const HEADER_LEN: usize = 14;
+
+/// Split a CI message into header and payload (without the F7)
+fn parse(message: &[u8]) -> Option<(Header, &[u8])> {
+    if message.len() <= HEADER_LEN
+        || message[..2] != [0xF0, 0x7E]
+        || message[3] != 0x0D
+        || message.last() != Some(&0xF7)
+    {
+        return None;
+    }
+    let header = Header {
+        device: message[2],
+        sub_id: message[4],
+        source: u28(&message[6..10]),
+        dest: u28(&message[10..14]),
+    };
+    Some((header, &message[HEADER_LEN..message.len() - 1]))
+}
+
+/// 7-bit groups, least significant first
+fn u28(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 7 | (byte & 0x7F) as u32)
+}
+
+fn u14(bytes: &[u8]) -> u16 {
+    u28(bytes) as u16
+}
+
+/// Property exchange request fields
+struct PropertyRequest<'a> {
+    request_id: u8,
+    header: &'a [u8],
+    chunks: u16,
+    chunk: u16,
+    data: &'a [u8],
+}
+
+impl<'a> PropertyRequest<'a> {
+    fn parse(payload: &'a [u8]) -> Option<Self> {
+        let request_id = *payload.first()?;
+        let header_end = 3 + u14(payload.get(1..3)?) as usize;
+        let header = payload.get(3..header_end)?;
+        let rest = &payload[header_end..];
+        let data_len = u14(rest.get(4..6)?) as usize;
+        Some(Self {
+            request_id,
+            header,
+            chunks: u14(&rest[0..2]),
+            chunk: u14(&rest[2..4]),
+            data: rest.get(6..6 + data_len)?,
+        })
+    }
+}
+
+/// Outgoing message being built; bytes past `MAX_REPLY` are cut off
+struct Reply {
+    bytes: [u8; MAX_REPLY],
+    len: usize,
+}
+
+impl Reply {
+    fn new(sub_id: u8, source: u32, to: Peer) -> Self {
+        let mut reply = Self { bytes: [0; MAX_REPLY], len: 1 };
+        reply.bytes[0] = 0xF0;
+        reply.bytes(&[0x7E, to.device, 0x0D, sub_id, VERSION]);
+        reply.u28(source);
+        reply.u28(to.muid);
+        reply
+    }
+
+    fn byte(&mut self, byte: u8) {
+        // Keep room for the F7
+        if self.len < MAX_REPLY - 1 {
+            self.bytes[self.len] = byte & 0x7F;
+            self.len += 1;
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.byte(byte);
+        }
+    }
+
+    fn u14(&mut self, value: u16) {
+        self.bytes(&[value as u8, (value >> 7) as u8]);
+    }
+
+    fn u28(&mut self, value: u32) {
+        for shift in [0, 7, 14, 21] {
+            self.byte((value >> shift) as u8);
+        }
+    }
+
+    /// Property header with its length
+    fn header(&mut self, code: u16, message: &str) {
+        let mut text = [0u8; MAX_PROPERTY_HEADER];
+        let mut window = Window::new(&mut text, 0);
+        let _ = match message {
+            "" => write!(window, "{{\"status\":{}}}", code),
+            message => write!(window, "{{\"status\":{},\"message\":{}}}", code, json::Str(message)),
+        };
+        let len = window.len();
+        self.u14(len as u16);
+        self.bytes(&text[..len]);
+    }
+
+    fn send(mut self, out: &mut dyn FnMut(&[u8])) {
+        self.bytes[self.len] = 0xF7;
+        out(&self.bytes[..self.len + 1]);
+    }
+}
+
+// ============================================================================
+// Resources
+// ============================================================================
+
+/// Resource named in a request header, or the status code to reply with
+fn resource(header: &[u8]) -> Result<Resource, u16> {
+    let text = core::str::from_utf8(header).map_err(|_| status::BAD_REQUEST)?;
+    let (mut name, mut res_id) = (None, None);
+    json::object(text, &mut |key, value| {
+        match (key, value) {
+            ("resource", Value::Str(s)) => name = Some(s),
+            ("resId", Value::Str(s)) => res_id = Some(s),
+            _ => {}
+        }
+        Ok(())
+    })
+    .map_err(|_| status::BAD_REQUEST)?;
+    match name.ok_or(status::BAD_REQUEST)? {
+        "ResourceList" => Ok(Resource::List),
+        "DeviceInfo" => Ok(Resource::DeviceInfo),
+        MAPPINGS_RESOURCE => match res_id {
+            None => Ok(Resource::Mappings(None)),
+            Some(id) => id.parse().map(|control| Resource::Mappings(Some(control))).map_err(|_| status::NOT_FOUND),
+        },
+        CURVES_RESOURCE => Ok(Resource::Curves),
+        _ => Err(status::NOT_FOUND),
+    }
+}
+
+fn render(info: &EndpointInfo, resource: Resource, mappings: &MappingTable, w: &mut dyn Write) -> fmt::Result {
+    match resource {
+        Resource::List => write!(
+            w,
+            "[{{\"resource\":\"DeviceInfo\"}},{{\"resource\":\"{}\",\"canSet\":\"full\"}},\
+             {{\"resource\":\"{}\",\"canSet\":\"full\"}}]",
+            MAPPINGS_RESOURCE, CURVES_RESOURCE
+        ),
+        Resource::DeviceInfo => {
+            let [m0, m1, m2] = info.manufacturer;
+            let [r0, r1, r2, r3] = info.revision;
+            write!(
+                w,
+                "{{\"manufacturerId\":[{},{},{}],\"familyId\":[{},{}],\"modelId\":[{},{}],\"versionId\":[{},{},{},{}],",
+                m0,
+                m1,
+                m2,
+                info.family & 0x7F,
+                info.family >> 7 & 0x7F,
+                info.model & 0x7F,
+                info.model >> 7 & 0x7F,
+                r0,
+                r1,
+                r2,
+                r3
+            )?;
+            write!(
+                w,
+                "\"manufacturer\":\"\",\"family\":\"\",\"model\":{},\"version\":{}}}",
+                json::Str(info.name),
+                json::Str(env!("CARGO_PKG_VERSION"))
+            )
+        }
+        Resource::Mappings(res_id) => {
+            w.write_str("[")?;
+            let selected = mappings.iter().filter(|m| res_id.is_none_or(|control| m.control == control));
+            for (i, m) in selected.enumerate() {
+                if i > 0 {
+                    w.write_str(",")?;
+                }
+                let channel = if m.channel == DEFAULT_CHANNEL { 0 } else { m.channel + 1 };
+                write!(
+                    w,
+                    "{{\"control\":{},\"action\":\"{}\",\"param\":{},\"channel\":{},\"min\":{},\"max\":{},\
+                     \"curve\":\"{}\",\"invert\":{},\"toggle\":{},\"takeover\":\"{}\",\"bank\":{},\
+                     \"shift\":{}}}",
+                    m.control,
+                    m.action.name(),
+                    m.action.param(),
+                    channel,
+                    m.min,
+                    m.max,
+                    m.curve.name(),
+                    m.invert,
+                    m.toggle,
+                    m.takeover.name(),
+                    m.bank,
+                    m.shift
+                )?;
+            }
+            w.write_str("]")
+        }
+        Resource::Curves => {
+            w.write_str("[")?;
+            for (i, curve) in Curve::ALL.iter().filter(|c| c.user().is_some()).enumerate() {
+                if i > 0 {
+                    w.write_str(",")?;
+                }
+                write!(w, "{{\"curve\":\"{}\",\"points\":[", curve.name())?;
+                for (j, point) in mappings.curves[i].points.iter().enumerate() {
+                    write!(w, "{}{}", if j > 0 { "," } else { "" }, point)?;
+                }
+                w.write_str("]}")?;
+            }
+            w.write_str("]")
+        }
+    }
+}
+
+/// One user curve: `curve` names it, `points` gives all of its points
+fn parse_curve(text: &str) -> Result<(usize, curve::Table), json::Error> {
+    let (mut index, mut points) = (None, None);
+    json::object(text, &mut |key, value| {
+        match (key, value) {
+            ("curve", Value::Str(name)) => {
+                index = Some(Curve::from_name(name).and_then(Curve::user).ok_or("invalid curve")?);
+            }
+            ("points", Value::Array(array)) => points = Some(array),
+            ("curve" | "points", _) => return Err("invalid value type"),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    let mut table = curve::Table::LINEAR;
+    let mut count = 0;
+    json::array(points.ok_or("missing points")?, &mut |value| {
+        let point = match value {
+            Value::Number(n) => u8::try_from(n).ok().filter(|&p| p <= curve::POINT_MAX),
+            _ => None,
+        };
+        *table.points.get_mut(count).ok_or("too many points")? = point.ok_or("invalid point")?;
+        count += 1;
+        Ok(())
+    })?;
+    if count < curve::POINTS {
+        return Err("too few points");
+    }
+    Ok((index.ok_or("missing curve")?, table))
+}
+
+/// One mapping object; only `action` is required, and `control` unless a
+/// `resId` names it. Other fields default like `map` in the shell.
+fn parse_mapping(text: &str, res_id: Option<ControlId>) -> Result<Mapping, json::Error> {
+    let mut control = res_id.map(i32::from);
+    let mut action = None;
+    let mut param = 0;
+    let mut channel = 0;
+    let (mut min, mut max) = (None, None);
+    let mut curve = Curve::Linear;
+    let (mut invert, mut toggle) = (false, false);
+    let mut takeover = Takeover::Jump;
+    let (mut bank, mut shift) = (0, false);
+    json::object(text, &mut |key, value| {
+        match (key, value) {
+            ("control", Value::Number(n)) => {
+                if res_id.is_some_and(|id| i32::from(id) != n) {
+                    return Err("control does not match resId");
+                }
+                control = Some(n);
+            }
+            ("action", Value::Str(name)) => action = Some(name),
+            ("param", Value::Number(n)) => param = n,
+            ("channel", Value::Number(n)) => channel = n,
+            ("min", Value::Number(n)) => min = Some(n),
+            ("max", Value::Number(n)) => max = Some(n),
+            ("curve", Value::Str(name)) => curve = Curve::from_name(name).ok_or("invalid curve")?,
+            ("invert", Value::Bool(b)) => invert = b,
+            ("toggle", Value::Bool(b)) => toggle = b,
+            ("takeover", Value::Str(name)) => takeover = Takeover::from_name(name).ok_or("invalid takeover")?,
+            ("bank", Value::Number(n)) => bank = n,
+            ("shift", Value::Bool(b)) => shift = b,
+            (
+                "control" | "action" | "param" | "channel" | "min" | "max" | "curve" | "invert" | "toggle"
+                | "takeover" | "bank" | "shift",
+                _,
+            ) => {
+                return Err("invalid value type");
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let control = control.and_then(|n| ControlId::try_from(n).ok()).ok_or("invalid control")?;
+    let action = u16::try_from(param)
+        .ok()
+        .zip(action)
+        .and_then(|(param, name)| Action::from_name(name, param))
+        .ok_or("invalid action")?;
+    let channel = match channel {
+        0 => DEFAULT_CHANNEL,
+        1..=16 => channel as u8 - 1,
+        _ => return Err("invalid channel"),
+    };
+    let bank = u8::try_from(bank).ok().filter(|&bank| bank <= MAX_BANKS).ok_or("invalid bank")?;
+    let mut mapping =
+        Mapping { channel, curve, invert, toggle, takeover, bank, shift, ..Mapping::new(control, action) };
+    for (value, field) in [(min, &mut mapping.min), (max, &mut mapping.max)] {
+        if let Some(value) = value {
+            *field = u16::try_from(value).ok().filter(|&v| v <= action.limit()).ok_or("invalid range")?;
+        }
+    }
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static INFO: EndpointInfo = EndpointInfo {
+        name: "Test Controller",
+        product_instance_id: "0042",
+        manufacturer: [0x7D, 0, 0],
+        family: 0x0102,
+        model: 3,
+        revision: [0, 0, 1, 2],
+        block_name: "Controls",
+        ci_version: 1,
+    };
+
+    const HOST: u32 = 0x0123_4567;
+    const MAPPINGS: &str = r#"{"resource":"X-Mappings"}"#;
+
+    fn m28(value: u32) -> [u8; 4] {
+        [0, 7, 14, 21].map(|shift| (value >> shift & 0x7F) as u8)
+    }
+
+    fn m14(value: usize) -> [u8; 2] {
+        [(value & 0x7F) as u8, (value >> 7 & 0x7F) as u8]
+    }
+
+    /// CI message from `source` to `dest`
+    fn message(sub_id: u8, source: u32, dest: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xF0, 0x7E, 0x7F, 0x0D, sub_id, VERSION];
+        bytes.extend(m28(source));
+        bytes.extend(m28(dest));
+        bytes.extend(payload);
+        bytes.push(0xF7);
+        bytes
+    }
+
+    /// Property exchange request, one chunk of `chunks`
+    fn property(sub_id: u8, dest: u32, request_id: u8, header: &str, chunks: u16, chunk: u16, data: &[u8]) -> Vec<u8> {
+        let mut payload = vec![request_id];
+        payload.extend(m14(header.len()));
+        payload.extend(header.bytes());
+        payload.extend(m14(chunks as usize));
+        payload.extend(m14(chunk as usize));
+        payload.extend(m14(data.len()));
+        payload.extend(data);
+        message(sub_id, HOST, dest, &payload)
+    }
+
+    /// Property reply: header text, number of chunks, chunk and data
+    fn property_reply(reply: &[u8]) -> (String, u16, u16, Vec<u8>) {
+        let payload = &reply[HEADER_LEN..reply.len() - 1];
+        let header_end = 3 + u14(&payload[1..3]) as usize;
+        let header = String::from_utf8(payload[3..header_end].to_vec()).unwrap();
+        let rest = &payload[header_end..];
+        let data_len = u14(&rest[4..6]) as usize;
+        (header, u14(&rest[0..2]), u14(&rest[2..4]), rest[6..6 + data_len].to_vec())
+    }
+
+    /// Hand a message to the responder and collect every reply to it
+    fn replay(
+        responder: &mut Responder,
+        mappings: &mut MappingTable,
+        message: &[u8],
+    ) -> (Vec<Vec<u8>>, Option<Event>) {
+        let mut replies = Vec::new();
+        let event = responder.handle(message, 1234, mappings, &mut |reply| replies.push(reply.to_vec()));
+        while responder.next_reply(mappings, &mut |reply| replies.push(reply.to_vec())) {}
+        for reply in &replies {
+            assert!(reply.len() <= MAX_REPLY);
+            assert_eq!((reply[0], reply[reply.len() - 1]), (0xF0, 0xF7));
+            assert!(reply[1..reply.len() - 1].iter().all(|&b| b < 0x80));
+        }
+        (replies, event)
+    }
+
+    /// Discovery from the host, returns the MUID the responder picked
+    fn discover(responder: &mut Responder, mappings: &mut MappingTable, max_sysex: u32) -> u32 {
+        let mut payload = vec![0x7D, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, CATEGORIES];
+        payload.extend(m28(max_sysex));
+        payload.push(3);
+        let (replies, _) = replay(responder, mappings, &message(sub_id::DISCOVERY, HOST, BROADCAST, &payload));
+        assert_eq!(replies.len(), 1);
+        responder.muid().unwrap()
+    }
+
+    /// Set a resource in chunks of `size` bytes, returns the replies
+    fn set_chunked(
+        responder: &mut Responder,
+        mappings: &mut MappingTable,
+        muid: u32,
+        header: &str,
+        data: &[u8],
+        size: usize,
+    ) -> (Vec<Vec<u8>>, Option<Event>) {
+        let chunks: Vec<&[u8]> = data.chunks(size).collect();
+        let (mut replies, mut event) = (Vec::new(), None);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let header = if i == 0 { header } else { "" };
+            let request = property(sub_id::PE_SET, muid, 9, header, chunks.len() as u16, i as u16 + 1, chunk);
+            let (mut more, result) = replay(responder, mappings, &request);
+            replies.append(&mut more);
+            event = event.or(result);
+        }
+        (replies, event)
+    }
+
+    #[test]
+    fn answers_discovery() {
+        let mut responder = Responder::new(&INFO);
+        let mut mappings = MappingTable::new();
+        assert_eq!(responder.muid(), None);
+
+        let mut payload = vec![0x7D, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, CATEGORIES];
+        payload.extend(m28(512));
+        payload.push(3);
+        let discovery = message(sub_id::DISCOVERY, HOST, BROADCAST, &payload);
+        let (replies, event) = replay(&mut responder, &mut mappings, &discovery);
+        let muid = responder.muid().unwrap();
+        assert!(muid < 0x0FFF_FF00);
+        let mut expected = vec![0xF0, 0x7E, 0x7F, 0x0D, sub_id::DISCOVERY_REPLY, VERSION];
+        expected.extend(m28(muid));
+        expected.extend(m28(HOST));
+        expected.extend([0x7D, 0, 0, 0x02, 0x02, 3, 0, 0, 0, 1, 2, CATEGORIES]);
+        expected.extend(m28(MAX_SYSEX as u32));
+        expected.extend([3, BLOCK_NUMBER, 0xF7]);
+        assert_eq!((replies, event), (vec![expected], None));
+
+        // Endpoint info, then messages for another device or not CI at all
+        let (replies, _) = replay(&mut responder, &mut mappings, &message(sub_id::ENDPOINT_INFO, HOST, muid, &[0]));
+        assert_eq!(replies[0][HEADER_LEN..], [0, 4, 0, b'0', b'0', b'4', b'2', 0xF7]);
+        let other = message(sub_id::PROFILE_INQUIRY, HOST, muid ^ 1, &[]);
+        assert!(replay(&mut responder, &mut mappings, &other).0.is_empty());
+        assert!(replay(&mut responder, &mut mappings, &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]).0.is_empty());
+
+        // Unknown messages to us are refused, broadcast ones ignored
+        let (replies, _) = replay(&mut responder, &mut mappings, &message(0x40, HOST, muid, &[]));
+        assert_eq!(replies[0][4..6], [sub_id::NAK, VERSION]);
+        assert_eq!(replies[0][HEADER_LEN..HEADER_LEN + 2], [0x40, NAK_NOT_SUPPORTED]);
+        assert!(replay(&mut responder, &mut mappings, &message(0x40, HOST, BROADCAST, &[])).0.is_empty());
+
+        let set_protocol = message(sub_id::SET_PROTOCOL, HOST, muid, &[AUTHORITY, 1, 0, 0, 0, 0]);
+        let event = Some(Event::Protocol(Protocol::Midi1));
+        assert_eq!(replay(&mut responder, &mut mappings, &set_protocol), (vec![], event));
+    }
+
+    #[test]
+    fn gives_up_a_colliding_muid() {
+        let mut responder = Responder::new(&INFO);
+        let mut mappings = MappingTable::new();
+        let muid = discover(&mut responder, &mut mappings, 512);
+
+        // Another device discovers with our MUID: invalidate it, pick another
+        let (replies, _) = replay(&mut responder, &mut mappings, &message(sub_id::DISCOVERY, muid, BROADCAST, &[]));
+        let mut expected = vec![0xF0, 0x7E, 0x7F, 0x0D, sub_id::INVALIDATE_MUID, VERSION];
+        expected.extend(m28(muid));
+        expected.extend(m28(BROADCAST));
+        expected.extend(m28(muid));
+        expected.push(0xF7);
+        assert_eq!(replies, [expected]);
+        let second = responder.muid().unwrap();
+        assert_ne!(second, muid);
+
+        // Requests to the old MUID go unanswered
+        let inquiry = |muid| message(sub_id::PROFILE_INQUIRY, HOST, muid, &[]);
+        assert!(replay(&mut responder, &mut mappings, &inquiry(muid)).0.is_empty());
+        assert_eq!(replay(&mut responder, &mut mappings, &inquiry(second)).0.len(), 1);
+
+        // An initiator invalidating our MUID makes us pick a new one quietly
+        let invalidate = message(sub_id::INVALIDATE_MUID, HOST, BROADCAST, &m28(second));
+        assert!(replay(&mut responder, &mut mappings, &invalidate).0.is_empty());
+        assert!(![muid, second].contains(&responder.muid().unwrap()));
+    }
+
+    #[test]
+    fn gets_and_sets_mappings_in_chunks() {
+        let mut responder = Responder::new(&INFO);
+        let mut mappings = MappingTable::new();
+        for control in 0..40 {
+            mappings.add(Mapping { channel: 3, ..Mapping::new(control, Action::Cc(control)) }).unwrap();
+        }
+        let muid = discover(&mut responder, &mut mappings, 512);
+
+        let get = property(sub_id::PE_GET, muid, 5, MAPPINGS, 1, 1, &[]);
+        let (replies, _) = replay(&mut responder, &mut mappings, &get);
+        assert!(replies.len() > 10);
+        let mut text = Vec::new();
+        for (i, reply) in replies.iter().enumerate() {
+            let (header, chunks, chunk, mut data) = property_reply(reply);
+            assert_eq!(header, if i == 0 { r#"{"status":200}"# } else { "" });
+            assert_eq!((chunks as usize, chunk as usize), (replies.len(), i + 1));
+            assert!(data.len() <= CHUNK_DATA);
+            text.append(&mut data);
+        }
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with(r#"[{"control":0,"action":"cc","param":0,"channel":4,"min":0,"max":127,"#));
+        assert!(text.ends_with(r#""invert":false,"toggle":false,"takeover":"jump","bank":0,"shift":false}]"#));
+
+        // A small receive limit makes the chunks smaller
+        let mut small = Responder::new(&INFO);
+        discover(&mut small, &mut mappings, 100);
+        let muid_small = small.muid().unwrap();
+        let get = property(sub_id::PE_GET, muid_small, 5, MAPPINGS, 1, 1, &[]);
+        let (more, _) = replay(&mut small, &mut mappings, &get);
+        assert!(more.iter().all(|reply| reply.len() <= 100));
+        assert!(more.len() > replies.len());
+
+        // Set back into another device in chunks of any size, with whitespace
+        let spaced = format!(" \n{}\r\n", text.replace(',', ", "));
+        for size in [1, 7, 64, 300] {
+            let mut other = Responder::new(&INFO);
+            let mut copy = MappingTable::new();
+            let muid = discover(&mut other, &mut copy, 512);
+            let (replies, event) = set_chunked(&mut other, &mut copy, muid, MAPPINGS, spaced.as_bytes(), size);
+            assert_eq!(replies.len(), 1, "chunks of {size}");
+            assert_eq!(property_reply(&replies[0]).0, r#"{"status":200}"#);
+            assert_eq!(event, Some(Event::MappingsChanged));
+            assert!(copy.iter().eq(mappings.iter()), "chunks of {size}");
+        }
+    }
+
+    #[test]
+    fn joins_utf8_split_across_chunks() {
+        let text = r#"[{"control":1,"action":"cc","param":7,"label":"Lautstärke ♪"}]"#;
+        let note = text.find('♪').unwrap();
+        // Inside the two byte sequence, after each byte of the three byte
+        // one, then one byte per chunk
+        for (split, size) in [(text.find('ä').unwrap() + 1, 0), (note + 1, 0), (note + 2, 0), (0, 1)] {
+            let mut responder = Responder::new(&INFO);
+            let mut mappings = MappingTable::new();
+            let muid = discover(&mut responder, &mut mappings, 512);
+            let size = if size == 0 { split } else { size };
+            let (replies, event) = set_chunked(&mut responder, &mut mappings, muid, MAPPINGS, text.as_bytes(), size);
+            assert_eq!(property_reply(&replies[0]).0, r#"{"status":200}"#, "chunks of {size}");
+            assert_eq!(event, Some(Event::MappingsChanged));
+            assert_eq!(mappings.iter().map(|m| m.action).collect::<Vec<_>>(), [Action::Cc(7)]);
+        }
+
+        // Bytes that are not UTF-8 at all are refused
+        let mut responder = Responder::new(&INFO);
+        let mut mappings = MappingTable::new();
+        let muid = discover(&mut responder, &mut mappings, 512);
+        let mut bad = br#"[{"control":1,"action":"cc","label":""#.to_vec();
+        bad.extend([0xC3, b'(']);
+        bad.extend(br#""}]"#);
+        let set = property(sub_id::PE_SET, muid, 4, MAPPINGS, 1, 1, &bad);
+        let (replies, event) = replay(&mut responder, &mut mappings, &set);
+        let (header, ..) = property_reply(&replies[0]);
+        assert_eq!((header.as_str(), event), (r#"{"status":400,"message":"invalid UTF-8"}"#, None));
+    }
+
+    #[test]
+    fn refuses_bad_sets() {
+        let mut responder = Responder::new(&INFO);
+        let mut mappings = MappingTable::new();
+        mappings.add(Mapping::new(1, Action::Note(60))).unwrap();
+        let muid = discover(&mut responder, &mut mappings, 512);
+        for (data, message) in [
+            (&br#"[{"control":1,"action":"cc","param":200}]"#[..], "invalid action"),
+            (br#"[{"control":1,"action":"cc","max":200}]"#, "invalid range"),
+            (br#"[{"control":1,"action":"cc","channel":17}]"#, "invalid channel"),
+            (br#"[{"control":300,"action":"cc"}]"#, "invalid control"),
+            (br#"[{"control":1,"action":"cc""#, "incomplete JSON"),
+            (br#"{"control":1}"#, "invalid JSON"),
+        ] {
+            let request = property(sub_id::PE_SET, muid, 6, MAPPINGS, 1, 1, data);
+            let (replies, event) = replay(&mut responder, &mut mappings, &request);
+            let expected = format!(r#"{{"status":400,"message":"{message}"}}"#);
+            assert_eq!((property_reply(&replies[0]).0, event), (expected, None));
+        }
+        let request = property(sub_id::PE_SET, muid, 7, r#"{"resource":"DeviceInfo"}"#, 1, 1, b"{}");
+        assert_eq!(property_reply(&replay(&mut responder, &mut mappings, &request).0[0]).0, r#"{"status":405}"#);
+        let request = property(sub_id::PE_GET, muid, 8, r#"{"resource":"Nope"}"#, 1, 1, &[]);
+        assert_eq!(property_reply(&replay(&mut responder, &mut mappings, &request).0[0]).0, r#"{"status":404}"#);
+
+        // A chunk out of order ends nothing, the set just never completes
+        let first = property(sub_id::PE_SET, muid, 9, MAPPINGS, 2, 1, b"[");
+        let skipped = property(sub_id::PE_SET, muid, 9, "", 3, 3, b"]");
+        assert_eq!(replay(&mut responder, &mut mappings, &first), (vec![], None));
+        assert_eq!(replay(&mut responder, &mut mappings, &skipped), (vec![], None));
+        assert_eq!(mappings.iter().map(|m| m.action).collect::<Vec<_>>(), [Action::Note(60)]);
+    }
+}
@@ -0,0 +1,433 @@
+//! Command Line Shell
+//!
+//! Line-based command interpreter in the spirit of TivaWare's utils/cmdline.c.
+//! Bytes come in one at a time, are collected by a line editor (backspace is
+//! the only editing supported, there is no history) and complete lines are
+//! split into whitespace separated arguments and dispatched through a command
+//! table.
+//!
+//! Nothing in here touches hardware: the shell is generic over the context
+//! type handed to commands and writes through `core::fmt::Write`, so it can be
+//! driven by the CDC port on the device or by a fake console on the host.
+
+use core::fmt::{self, Write};
+
+/// Maximum number of arguments on one line, including the command itself
+pub const MAX_ARGS: usize = 24;
+
+/// Prompt printed before every line
+pub const PROMPT: &str = "> ";
+
+/// Errors reported back to the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CliError {
+    /// First word does not match any command
+    UnknownCommand,
+    /// More than `MAX_ARGS` words on the line
+    TooManyArgs,
+    /// Line did not fit in the line editor buffer
+    LineTooLong,
+    /// A required argument was not given
+    MissingArgument(&'static str),
+    /// An argument could not be parsed
+    InvalidArgument(&'static str),
+    /// A numeric argument was outside its allowed range
+    OutOfRange(&'static str),
+    /// The command ran but failed
+    Failed(&'static str),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::UnknownCommand => f.write_str("unknown command, try 'help'"),
+            CliError::TooManyArgs => f.write_str("too many arguments"),
+            CliError::LineTooLong => f.write_str("line too long"),
+            CliError::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            CliError::InvalidArgument(name) => write!(f, "invalid value for <{}>", name),
+            CliError::OutOfRange(name) => write!(f, "<{}> out of range", name),
+            CliError::Failed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+// ============================================================================
+// Argument Parsing
+// ============================================================================
+
+/// Parse a decimal (optionally negative) or `0x` prefixed hexadecimal number.
+pub fn parse_int(s: &str) -> Option<i32> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (radix, digits) = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => (16, hex),
+        None => (10, digits),
+    };
+    // The std parsers take a sign of their own
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// Arguments of one command line. `argv[0]` is the command name; the cursor
+/// starts at the first real argument.
+pub struct Args<'a> {
+    argv: [&'a str; MAX_ARGS],
+    argc: usize,
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    /// Split a line on whitespace.
+    pub fn parse(line: &'a str) -> Result<Self, CliError> {
+        let mut argv = [""; MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_ascii_whitespace() {
+            if argc == MAX_ARGS {
+                return Err(CliError::TooManyArgs);
+            }
+            argv[argc] = word;
+            argc += 1;
+        }
+        Ok(Self { argv, argc, pos: 1 })
+    }
+
+    /// The command word, empty for a blank line.
+    pub fn command(&self) -> &'a str {
+        self.argv[0]
+    }
+
+    /// Arguments not consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.argc.saturating_sub(self.pos)
+    }
+
+    /// Next argument, if present.
+    pub fn next_opt(&mut self) -> Option<&'a str> {
+        if self.pos < self.argc {
+            self.pos += 1;
+            Some(self.argv[self.pos - 1])
+        } else {
+            None
+        }
+    }
+
+    /// Next argument without consuming it.
+    pub fn peek(&self) -> Option<&'a str> {
+        (self.pos < self.argc).then(|| self.argv[self.pos])
+    }
+
+    /// Next argument; `name` is used in the error message if it is missing.
+    pub fn next_str(&mut self, name: &'static str) -> Result<&'a str, CliError> {
+        self.next_opt().ok_or(CliError::MissingArgument(name))
+    }
+
+    /// Next argument as an integer within `min..=max`.
+    pub fn next_int(&mut self, name: &'static str, min: i32, max: i32) -> Result<i32, CliError> {
+        let value = parse_int(self.next_str(name)?).ok_or(CliError::InvalidArgument(name))?;
+        if value < min || value > max {
+            return Err(CliError::OutOfRange(name));
+        }
+        Ok(value)
+    }
+
+    /// Fails if arguments are left over.
+    pub fn finish(&self) -> Result<(), CliError> {
+        if self.remaining() > 0 {
+            Err(CliError::TooManyArgs)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// ============================================================================
+// Line Editor
+// ============================================================================
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_C: u8 = 0x03;
+
+/// Collects bytes into a line, echoing them back to the terminal.
+pub struct LineEditor<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+    last_cr: bool,
+}
+
+/// Result of feeding one byte to the editor
+pub enum Line<'a> {
+    /// Line is not complete yet
+    Pending,
+    /// Enter was pressed
+    Complete(&'a str),
+    /// Enter was pressed but the line did not fit
+    Overflow,
+    /// Line was thrown away with Ctrl-C
+    Cancelled,
+}
+
+impl<const N: usize> LineEditor<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0, overflow: false, last_cr: false }
+    }
+
+    /// Feed one received byte.
+    pub fn feed(&mut self, byte: u8, echo: &mut dyn Write) -> Line<'_> {
+        // Treat CR, LF and CR LF all as a single line ending
+        let after_cr = core::mem::replace(&mut self.last_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => Line::Pending,
+            b'\r' | b'\n' => {
+                let _ = echo.write_str("\r\n");
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) {
+                    return Line::Overflow;
+                }
+                // Only printable ASCII is ever stored
+                Line::Complete(core::str::from_utf8(&self.buf[..len]).unwrap_or(""))
+            }
+            BACKSPACE | DELETE => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    let _ = echo.write_str("\x08 \x08");
+                }
+                Line::Pending
+            }
+            CTRL_C => {
+                self.len = 0;
+                self.overflow = false;
+                let _ = echo.write_str("^C\r\n");
+                Line::Cancelled
+            }
+            0x20..=0x7e => {
+                if self.len < N {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                    let _ = echo.write_char(byte as char);
+                } else {
+                    self.overflow = true;
+                }
+                Line::Pending
+            }
+            _ => Line::Pending,
+        }
+    }
+}
+
+// ============================================================================
+// Command Dispatch
+// ============================================================================
+
+/// Handler signature: context, arguments (cursor after the command name) and
+/// the console to print to.
+pub type Handler<C> = fn(&mut C, &mut Args, &mut dyn Write) -> Result<(), CliError>;
+
+/// One entry of a command table
+pub struct Command<C: 'static> {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: Handler<C>,
+}
+
+/// Line editor plus command table. `help` is built in.
+pub struct Shell<C: 'static, const N: usize> {
+    editor: LineEditor<N>,
+    commands: &'static [Command<C>],
+}
+
+impl<C: 'static, const N: usize> Shell<C, N> {
+    pub const fn new(commands: &'static [Command<C>]) -> Self {
+        Self { editor: LineEditor::new(), commands }
+    }
+
+    /// Print the prompt.
+    pub fn prompt(&self, out: &mut dyn Write) {
+        let _ = out.write_str(PROMPT);
+    }
+
+    /// Feed one received byte; runs the command when a line completes.
+    pub fn input(&mut self, byte: u8, ctx: &mut C, out: &mut dyn Write) {
+        let commands = self.commands;
+        let result = match self.editor.feed(byte, out) {
+            Line::Pending => return,
+            Line::Cancelled => Ok(()),
+            Line::Overflow => Err(CliError::LineTooLong),
+            Line::Complete(line) => Self::dispatch(commands, line, ctx, out),
+        };
+        if let Err(err) = result {
+            let _ = write!(out, "error: {}\r\n", err);
+        }
+        self.prompt(out);
+    }
+
+    /// Run one complete line against a command table.
+    pub fn dispatch(
+        commands: &[Command<C>],
+        line: &str,
+        ctx: &mut C,
+        out: &mut dyn Write,
+    ) -> Result<(), CliError> {
+        let mut args = Args::parse(line)?;
+        match args.command() {
+            "" => Ok(()),
+            "help" | "?" => Self::help(commands, &mut args, out),
+            name => {
+                let command = commands
+                    .iter()
+                    .find(|c| c.name == name)
+                    .ok_or(CliError::UnknownCommand)?;
+                (command.run)(ctx, &mut args, out)
+            }
+        }
+    }
+
+    fn help(commands: &[Command<C>], args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+        if let Some(name) = args.next_opt() {
+            let command = commands
+                .iter()
+                .find(|c| c.name == name)
+                .ok_or(CliError::UnknownCommand)?;
+            let _ = write!(out, "usage: {} {}\r\n  {}\r\n", command.name, command.usage, command.help);
+            return Ok(());
+        }
+        let _ = out.write_str("commands:\r\n  help        [command]\r\n");
+        for command in commands {
+            let _ = write!(out, "  {:<12}{}\r\n", command.name, command.usage);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_int("42"), Some(42));
+        assert_eq!(parse_int("-7"), Some(-7));
+        assert_eq!(parse_int("0x7F"), Some(127));
+        assert_eq!(parse_int("-0X10"), Some(-16));
+        assert_eq!(parse_int("0x"), None);
+        assert_eq!(parse_int("12a"), None);
+        assert_eq!(parse_int(""), None);
+        for signed in ["--5", "-+5", "+5", "0x-5", "-0x-5", "0x+5"] {
+            assert_eq!(parse_int(signed), None, "{signed}");
+        }
+    }
+
+    #[test]
+    fn splits_arguments() {
+        let mut args = Args::parse("  map 3\tcc  7 ").unwrap();
+        assert_eq!(args.command(), "map");
+        assert_eq!(args.remaining(), 3);
+        assert_eq!(args.next_int("index", 0, 63), Ok(3));
+        assert_eq!(args.peek(), Some("cc"));
+        assert_eq!(args.next_str("kind"), Ok("cc"));
+        assert_eq!(args.finish(), Err(CliError::TooManyArgs));
+        assert_eq!(args.next_int("cc", 0, 6), Err(CliError::OutOfRange("cc")));
+        assert_eq!(args.next_str("value"), Err(CliError::MissingArgument("value")));
+        assert_eq!(args.finish(), Ok(()));
+
+        let mut args = Args::parse("set x").unwrap();
+        assert_eq!(args.next_int("value", 0, 1), Err(CliError::InvalidArgument("value")));
+        assert_eq!(Args::parse("").unwrap().command(), "");
+
+        let line = "a ".repeat(MAX_ARGS);
+        assert!(Args::parse(&line).is_ok());
+        assert_eq!(Args::parse(&(line + "a")).err(), Some(CliError::TooManyArgs));
+    }
+
+    /// Feed bytes, returning what completed and the echo
+    fn type_into<const N: usize>(editor: &mut LineEditor<N>, bytes: &[u8]) -> (Vec<String>, String) {
+        let mut echo = String::new();
+        let mut lines = Vec::new();
+        for &byte in bytes {
+            match editor.feed(byte, &mut echo) {
+                Line::Pending => {}
+                Line::Complete(line) => lines.push(line.to_string()),
+                Line::Overflow => lines.push("<overflow>".to_string()),
+                Line::Cancelled => lines.push("<cancelled>".to_string()),
+            }
+        }
+        (lines, echo)
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut editor = LineEditor::<8>::new();
+        assert_eq!(type_into(&mut editor, b"ab\r\ncd\nef\r").0, ["ab", "cd", "ef"]);
+        assert_eq!(type_into(&mut editor, b"\n\n").0, [""]);
+
+        let (lines, echo) = type_into(&mut editor, b"lxy\x08\x7fs\x1b\r");
+        assert_eq!(lines, ["ls"]);
+        assert_eq!(echo, "lxy\x08 \x08\x08 \x08s\r\n");
+        assert_eq!(type_into(&mut editor, b"\x08\r").1, "\r\n");
+
+        assert_eq!(type_into(&mut editor, b"123456789\r").0, ["<overflow>"]);
+        assert_eq!(type_into(&mut editor, b"12345678\r").0, ["12345678"]);
+        assert_eq!(type_into(&mut editor, b"123456789\x03ok\r").0, ["<cancelled>", "ok"]);
+    }
+
+    fn add(total: &mut i32, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+        while args.remaining() > 0 {
+            *total += args.next_int("value", -100, 100)?;
+        }
+        let _ = write!(out, "total {}\r\n", total);
+        Ok(())
+    }
+
+    fn fail(_: &mut i32, args: &mut Args, _: &mut dyn Write) -> Result<(), CliError> {
+        args.finish()?;
+        Err(CliError::Failed("no"))
+    }
+
+    static COMMANDS: [Command<i32>; 2] = [
+        Command { name: "add", usage: "<value>...", help: "Add values to the total", run: add },
+        Command { name: "fail", usage: "", help: "Always fails", run: fail },
+    ];
+
+    fn run(shell: &mut Shell<i32, 16>, total: &mut i32, input: &str) -> String {
+        let mut out = String::new();
+        for byte in input.bytes() {
+            shell.input(byte, total, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn dispatches_commands() {
+        let mut shell = Shell::<i32, 16>::new(&COMMANDS);
+        let mut total = 0;
+        assert_eq!(run(&mut shell, &mut total, "add 1 0x10\r"), "add 1 0x10\r\ntotal 17\r\n> ");
+        assert_eq!(total, 17);
+        assert_eq!(run(&mut shell, &mut total, "add -20\r"), "add -20\r\ntotal -3\r\n> ");
+        assert_eq!(run(&mut shell, &mut total, "\r"), "\r\n> ");
+        assert_eq!(run(&mut shell, &mut total, "sub 1\r"), "sub 1\r\nerror: unknown command, try 'help'\r\n> ");
+        assert_eq!(run(&mut shell, &mut total, "add 200\r"), "add 200\r\nerror: <value> out of range\r\n> ");
+        assert_eq!(run(&mut shell, &mut total, "fail 1\r"), "fail 1\r\nerror: too many arguments\r\n> ");
+        assert_eq!(run(&mut shell, &mut total, "fail\r"), "fail\r\nerror: no\r\n> ");
+        assert!(run(&mut shell, &mut total, "add 1 1 1 1 1 1 1\r").ends_with("error: line too long\r\n> "));
+        assert_eq!(total, -3);
+    }
+
+    #[test]
+    fn prints_help() {
+        let mut out = String::new();
+        Shell::<i32, 16>::dispatch(&COMMANDS, "help", &mut 0, &mut out).unwrap();
+        assert_eq!(out, "commands:\r\n  help        [command]\r\n  add         <value>...\r\n  fail        \r\n");
+        out.clear();
+        Shell::<i32, 16>::dispatch(&COMMANDS, "? add", &mut 0, &mut out).unwrap();
+        assert_eq!(out, "usage: add <value>...\r\n  Add values to the total\r\n");
+        let result = Shell::<i32, 16>::dispatch(&COMMANDS, "help sub", &mut 0, &mut out);
+        assert_eq!(result, Err(CliError::UnknownCommand));
+    }
+}
@@ -0,0 +1,25 @@
+//! System Clock
+//!
+//! Millisecond time base driven by the SysTick interrupt.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// SysTick interrupt rate in Hz (one tick per millisecond)
+pub const TICK_HZ: u32 = 1000;
+
+static MILLIS: AtomicU32 = AtomicU32::new(0);
+
+/// Advance the clock by one tick. Called from the SysTick handler only.
+pub fn tick() {
+    MILLIS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Milliseconds since boot. Wraps after ~49 days.
+pub fn millis() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+/// Returns true once `deadline` has been reached, wrap-safe.
+pub fn reached(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) < 0x8000_0000
+}
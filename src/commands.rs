@@ -0,0 +1,1336 @@
+//! Shell Commands
+//!
+//! The command table served on the CDC port. Commands operate on a `Target`,
+//! which the firmware implements on its application state and a host test can
+//! implement on a fake.
+
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+
+use crate::arp::{Chord, NoteProcessor};
+use crate::cli::{Args, CliError, Command};
+use crate::config::{self, Config, ConfigError};
+use crate::curve::{self, Curve};
+use crate::feedback::{Feedback, FeedbackError, Source};
+use crate::keymap::KeyOp;
+use crate::led::{self, Color};
+use crate::macros::{self, Macro, Player, MAX_MACROS};
+use crate::mapping::{self, Action, Mapping, MappingError, Takeover, MAX_TEMPLATES, TEMPLATE_LEN};
+use crate::midi::{Kind, MidiMessage, Parser};
+use crate::monitor::Monitor;
+use crate::motion::{Reading, Sensor};
+use crate::mpe::Zone;
+use crate::router::Port;
+use crate::scale;
+use crate::sequencer::{self, ClockSource, Pattern, Rate, Sequencer, Step, Track};
+
+/// What the shell needs from the device
+pub trait Target {
+    /// Live configuration
+    fn config(&mut self) -> &mut Config;
+    /// Write the live configuration to flash, returns bytes used
+    fn save(&mut self) -> Result<usize, ConfigError>;
+    /// Replace the live configuration with the saved one
+    fn load(&mut self) -> Result<(), ConfigError>;
+    /// The live configuration was edited; drop state derived from it
+    fn config_changed(&mut self);
+    /// MIDI monitor settings
+    fn monitor(&mut self) -> &mut Monitor;
+    /// Milliseconds since boot
+    fn now(&self) -> u32;
+    /// Feed a message into the router as if it came from `source`
+    fn inject(&mut self, source: Port, message: MidiMessage);
+    /// Colour of the RGB LED
+    fn color(&self) -> Color;
+    fn set_color(&mut self, color: Color);
+    /// Frame buffer of the LED strip
+    fn pixel(&self, index: usize) -> Color;
+    fn set_pixel(&mut self, index: usize, color: Color);
+    /// Latest IMU sample, `None` while no sensor answers
+    fn motion(&self) -> Option<Reading>;
+    /// Measure the IMU rest position and gyroscope offsets into the live
+    /// configuration
+    fn calibrate_motion(&mut self);
+    /// Step sequencer transport and playhead
+    fn sequencer(&mut self) -> &mut Sequencer;
+    /// Arpeggiator and chord memory, with the notes held
+    fn notes(&mut self) -> &mut NoteProcessor;
+    /// Macro playing
+    fn macros(&mut self) -> &mut Player;
+    /// Print a status report
+    fn status(&mut self, out: &mut dyn Write);
+    /// Reboot the device
+    fn reset(&mut self);
+    /// Jump to the ROM USB DFU boot loader
+    fn enter_bootloader(&mut self);
+}
+
+/// Command table for a `Target`
+pub struct Commands<T>(PhantomData<T>);
+
+impl<T: Target + 'static> Commands<T> {
+    pub const TABLE: &'static [Command<T>] = &[
+        Command {
+            name: "status",
+            usage: "",
+            help: "show device status",
+            run: status::<T>,
+        },
+        Command {
+            name: "get",
+            usage: "[name]",
+            help: "show one or all settings",
+            run: get::<T>,
+        },
+        Command {
+            name: "set",
+            usage: "<name> <value>",
+            help: "change a setting (not saved until 'save')",
+            run: set::<T>,
+        },
+        Command {
+            name: "save",
+            usage: "",
+            help: "write settings to flash",
+            run: save::<T>,
+        },
+        Command {
+            name: "load",
+            usage: "",
+            help: "discard changes and reload settings from flash",
+            run: load::<T>,
+        },
+        Command {
+            name: "defaults",
+            usage: "",
+            help: "restore factory settings (not saved until 'save')",
+            run: defaults::<T>,
+        },
+        Command {
+            name: "map",
+            usage: "[<control> [clear | [add] <action> [option]...]]",
+            help: "show or change control mappings; action: none, note <n>, mpe <n>, mpepitch <n>, \
+                   mpetimbre <n>, cc <n>, \
+                   cc14 <n>, nrpn <n>, rpn <n>, program [n], bend, sysex <slot>, pad <n>, \
+                   keymap <octdown|octup|down|up|scale|layout>, bank <n|next|prev>, shift, macro <n>; \
+                   options: ch <1-16>, min <v>, max <v>, invert, toggle, \
+                   curve <lin|log|exp|s|fixed|user1-4>, takeover <jump|pickup|scale>, \
+                   bank <1-4>, shift; a new action replaces the control's mappings \
+                   of the same bank and layer",
+            run: map::<T>,
+        },
+        Command {
+            name: "sysex",
+            usage: "[<slot> [clear | <hex|v|msb|ch>...]]",
+            help: "show or change SysEx templates; v, msb and ch are replaced by the \
+                   value (low 7 bits), its high 7 bits and the channel",
+            run: sysex::<T>,
+        },
+        Command {
+            name: "curve",
+            usage: "[user<n> [<0-127>... | lin|log|exp|s|fixed]]",
+            help: "show or change the user curves: 16 points from no to full input, 0-127 for no to \
+                   full output, or a copy of a built-in curve to start from",
+            run: curve::<T>,
+        },
+        Command {
+            name: "macro",
+            usage: "[<n> [clear | run | [add] <hex | wait <ms>>...] | stop]",
+            help: "show, change, play or stop the macros: MIDI messages as hex bytes, each \
+                   from its status byte, with waits in milliseconds between them; add continues \
+                   a macro, also in the middle of a message",
+            run: macro_cmd::<T>,
+        },
+        Command {
+            name: "mpe",
+            usage: "[announce]",
+            help: "show the MPE zones (set with mpelower/mpeupper) or send them as MCMs",
+            run: mpe::<T>,
+        },
+        Command {
+            name: "feedback",
+            usage: "[<led> [clear | note <n> [ch <1-16>] | cc <n> [ch <1-16>] | control <id>]]",
+            help: "show or change what incoming MIDI an LED shows: a note, a controller or \
+                   the notes and controllers mapped to a control; LEDs 0-2 are the on-board \
+                   red, green and blue, 16 and up the LED strip pixels, 96 and up \
+                   the shift register outputs",
+            run: feedback::<T>,
+        },
+        Command {
+            name: "color",
+            usage: "[<r> <g> <b>]",
+            help: "show or set the RGB LED colour (0-255 each) until feedback or the \
+                   heartbeat changes it; see ledbright and ledanim",
+            run: color::<T>,
+        },
+        Command {
+            name: "pixel",
+            usage: "[<n>|all [<r> <g> <b>]]",
+            help: "show or set LED strip pixels (0-255 each); see the pixels setting",
+            run: pixel::<T>,
+        },
+        Command {
+            name: "imu",
+            usage: "[cal]",
+            help: "show the IMU orientation, or calibrate it: lay it level and still, \
+                   the new rest position is not saved until 'save'; see imu and imurange",
+            run: imu::<T>,
+        },
+        Command {
+            name: "seq",
+            usage: "[start|stop|continue | page <n> | track <t> [option]... | step <t> <s> off|<note> [option]... \
+                    | copy <from> <to> | clear [pattern]]",
+            help: "show or edit the step sequencer pattern; track options: ch <1-16>, len <1-64>, \
+                   rate <clocks> (6 = 1/16), mute, unmute; step options: vel <1-127>, gate <1-100 %>, \
+                   prob <0-100 %>, ratchet <1-4>; copy and clear work on patterns; see seqclock, \
+                   seqbpm, seqswing, seqpattern and seqgrid",
+            run: seq::<T>,
+        },
+        Command {
+            name: "chord",
+            usage: "[off | learn | <semitones>...]",
+            help: "show or set the chord memory: every note played becomes the chord, given as \
+                   semitones above the note, or learnt from the notes held now",
+            run: chord::<T>,
+        },
+        Command {
+            name: "keymap",
+            usage: "[octdown|octup|down|up|scale|layout]",
+            help: "show the notes of the mapped pads, or do what a keymap button does; see layout, \
+                   columns, octave, scale and root",
+            run: keymap::<T>,
+        },
+        Command {
+            name: "monitor",
+            usage: "[on|off | port|channel|type all|<name>... | rate <n>]",
+            help: "print MIDI traffic; filter by port (usb local), channel (1-16) \
+                   or type (note poly cc program pressure bend sysex clock system)",
+            run: monitor::<T>,
+        },
+        Command {
+            name: "send",
+            usage: "<port> <hex byte>...",
+            help: "inject raw MIDI bytes into the router as if received on <port>",
+            run: send::<T>,
+        },
+        Command {
+            name: "reset",
+            usage: "",
+            help: "reboot the controller",
+            run: reset::<T>,
+        },
+        Command {
+            name: "bootloader",
+            usage: "",
+            help: "reboot into the USB DFU boot loader for a firmware update",
+            run: bootloader::<T>,
+        },
+    ];
+}
+
+fn status<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    target.status(out);
+    Ok(())
+}
+
+fn print_param(param: &config::Param, config: &Config, out: &mut dyn Write) {
+    let _ = write!(
+        out,
+        "  {:<10} {:>5}  ({}..{}) {}\r\n",
+        param.name,
+        (param.get)(config),
+        param.min,
+        param.max,
+        param.help
+    );
+}
+
+fn get<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let name = args.next_opt();
+    args.finish()?;
+    let config = target.config();
+    match name {
+        Some(name) => {
+            let param = config::find_param(name).ok_or(CliError::InvalidArgument("name"))?;
+            print_param(param, config, out);
+        }
+        None => {
+            for param in config::PARAMS {
+                print_param(param, config, out);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let name = args.next_str("name")?;
+    let param = config::find_param(name).ok_or(CliError::InvalidArgument("name"))?;
+    let value = args.next_int("value", param.min, param.max)?;
+    args.finish()?;
+    let previous = (param.get)(target.config());
+    (param.set)(target.config(), value);
+    if target.config().wipers_shared() {
+        (param.set)(target.config(), previous);
+        return Err(CliError::Failed("a fader and a pedal jack share that input"));
+    }
+    target.config_changed();
+    let _ = write!(out, "{} = {}\r\n", param.name, value);
+    Ok(())
+}
+
+fn save<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    let used = target.save().map_err(|e| CliError::Failed(e.as_str()))?;
+    let _ = write!(out, "saved ({} bytes)\r\n", used);
+    Ok(())
+}
+
+fn load<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    target.load().map_err(|e| CliError::Failed(e.as_str()))?;
+    let _ = out.write_str("loaded\r\n");
+    Ok(())
+}
+
+fn defaults<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    *target.config() = Config::new();
+    target.config_changed();
+    let _ = out.write_str("factory settings restored\r\n");
+    Ok(())
+}
+
+fn print_mappings(config: &Config, control: Option<u8>, out: &mut dyn Write) {
+    let mut shown = 0;
+    for mapping in config.mappings.iter().filter(|m| control.is_none_or(|c| m.control == c)) {
+        let _ = write!(out, "  {}\r\n", mapping);
+        shown += 1;
+    }
+    if shown == 0 {
+        let _ = out.write_str("  no mappings\r\n");
+    }
+}
+
+fn parse_action(word: &str, args: &mut Args) -> Result<Action, CliError> {
+    let byte = |args: &mut Args, name| args.next_int(name, 0, 127).map(|n| n as u8);
+    Ok(match word {
+        "none" => Action::None,
+        "note" => Action::Note(byte(args, "note")?),
+        "mpe" => Action::MpeNote(byte(args, "note")?),
+        "mpepitch" => Action::MpePitch(byte(args, "note")?),
+        "mpetimbre" => Action::MpeTimbre(byte(args, "note")?),
+        "cc" => Action::Cc(byte(args, "controller")?),
+        "cc14" => Action::Cc14(args.next_int("controller", 0, 31)? as u8),
+        "nrpn" => Action::Nrpn(args.next_int("parameter", 0, 16383)? as u16),
+        "rpn" => Action::Rpn(args.next_int("parameter", 0, 16383)? as u16),
+        "program" => Action::Program,
+        "bend" => Action::PitchBend,
+        "sysex" => Action::SysEx(args.next_int("slot", 0, MAX_TEMPLATES as i32 - 1)? as u8),
+        "pad" => Action::Pad(byte(args, "pad")?),
+        "keymap" => {
+            let op = KeyOp::from_name(args.next_str("operation")?);
+            Action::Keymap(op.ok_or(CliError::InvalidArgument("operation"))?)
+        }
+        "bank" => Action::Bank(match args.next_str("bank")? {
+            "next" => mapping::NEXT_BANK,
+            "prev" => mapping::PREVIOUS_BANK,
+            bank => match crate::cli::parse_int(bank) {
+                Some(n @ 1..) if n <= mapping::MAX_BANKS as i32 => n as u8,
+                _ => return Err(CliError::InvalidArgument("bank")),
+            },
+        }),
+        "shift" => Action::Shift,
+        "macro" => Action::Macro(args.next_int("macro", 0, MAX_MACROS as i32 - 1)? as u8),
+        _ => return Err(CliError::InvalidArgument("action")),
+    })
+}
+
+fn map<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let Some(control) = args.next_opt() else {
+        print_mappings(target.config(), None, out);
+        return Ok(());
+    };
+    let control = match crate::cli::parse_int(control) {
+        Some(c @ 0..=255) => c as u8,
+        _ => return Err(CliError::InvalidArgument("control")),
+    };
+    let table = &mut target.config().mappings;
+    match args.next_opt() {
+        None => {}
+        Some("clear") => {
+            args.finish()?;
+            table.remove(control);
+        }
+        Some(word) => {
+            let add = word == "add";
+            let action = if add { args.next_str("action")? } else { word };
+            let mut mapping = Mapping::new(control, parse_action(action, args)?);
+            // A program number given with the action fixes min and max
+            if mapping.action == Action::Program && args.peek().and_then(crate::cli::parse_int).is_some() {
+                let program = args.next_int("program", 0, 127)? as u16;
+                mapping.min = program;
+                mapping.max = program;
+            }
+            let limit = mapping.action.limit() as i32;
+            while let Some(option) = args.next_opt() {
+                match option {
+                    "ch" => mapping.channel = args.next_int("channel", 1, 16)? as u8 - 1,
+                    "min" => mapping.min = args.next_int("min", 0, limit)? as u16,
+                    "max" => mapping.max = args.next_int("max", 0, limit)? as u16,
+                    "invert" => mapping.invert = true,
+                    "toggle" => mapping.toggle = true,
+                    "curve" => {
+                        mapping.curve = Curve::from_name(args.next_str("curve")?)
+                            .ok_or(CliError::InvalidArgument("curve"))?
+                    }
+                    "takeover" => {
+                        mapping.takeover = Takeover::from_name(args.next_str("takeover")?)
+                            .ok_or(CliError::InvalidArgument("takeover"))?
+                    }
+                    "bank" => mapping.bank = args.next_int("bank", 1, mapping::MAX_BANKS as i32)? as u8,
+                    "shift" => mapping.shift = true,
+                    _ => return Err(CliError::InvalidArgument("option")),
+                }
+            }
+            if !add {
+                let (bank, shift) = (mapping.bank, mapping.shift);
+                table.retain(|m| m.control != control || m.bank != bank || m.shift != shift);
+            }
+            table.add(mapping).map_err(|MappingError::Full| CliError::Failed("mapping table full"))?;
+        }
+    }
+    target.config_changed();
+    print_mappings(target.config(), Some(control), out);
+    Ok(())
+}
+
+fn print_template(slot: usize, template: &mapping::SysExTemplate, out: &mut dyn Write) {
+    let _ = write!(out, "  {}:", slot);
+    for &byte in template.as_slice() {
+        let _ = match byte {
+            mapping::placeholder::VALUE => out.write_str(" v"),
+            mapping::placeholder::VALUE_MSB => out.write_str(" msb"),
+            mapping::placeholder::CHANNEL => out.write_str(" ch"),
+            byte => write!(out, " {:02X}", byte),
+        };
+    }
+    let _ = out.write_str("\r\n");
+}
+
+fn sysex<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let templates = &mut target.config().mappings.templates;
+    let Some(slot) = args.next_opt() else {
+        for (slot, template) in templates.iter().enumerate() {
+            print_template(slot, template, out);
+        }
+        return Ok(());
+    };
+    let slot = match crate::cli::parse_int(slot) {
+        Some(s) if (0..MAX_TEMPLATES as i32).contains(&s) => s as usize,
+        _ => return Err(CliError::InvalidArgument("slot")),
+    };
+    if args.remaining() > 0 {
+        let mut template = mapping::SysExTemplate::EMPTY;
+        if args.peek() == Some("clear") {
+            args.next_opt();
+            args.finish()?;
+        } else {
+            while let Some(word) = args.next_opt() {
+                if template.len as usize == TEMPLATE_LEN {
+                    return Err(CliError::Failed("template too long"));
+                }
+                template.bytes[template.len as usize] = match word {
+                    "v" => mapping::placeholder::VALUE,
+                    "msb" => mapping::placeholder::VALUE_MSB,
+                    "ch" => mapping::placeholder::CHANNEL,
+                    _ => match u8::from_str_radix(word, 16) {
+                        Ok(byte) if byte < 0x80 || byte == 0xF0 || byte == 0xF7 => byte,
+                        _ => return Err(CliError::InvalidArgument("hex byte")),
+                    },
+                };
+                template.len += 1;
+            }
+            let valid = match template.as_slice() {
+                [0xF0, body @ .., 0xF7] => !body.iter().any(|&b| b == 0xF0 || b == 0xF7),
+                _ => false,
+            };
+            if !valid {
+                return Err(CliError::Failed("template must be F0 <data>... F7"));
+            }
+        }
+        templates[slot] = template;
+    }
+    print_template(slot, &target.config().mappings.templates[slot], out);
+    Ok(())
+}
+
+fn print_curve(curve: Curve, table: &curve::Table, out: &mut dyn Write) {
+    let _ = write!(out, "  {}:", curve.name());
+    for point in table.points {
+        let _ = write!(out, " {}", point);
+    }
+    let _ = out.write_str("\r\n");
+}
+
+fn curve<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let tables = &mut target.config().mappings.curves;
+    let Some(name) = args.next_opt() else {
+        for curve in Curve::ALL {
+            if let Some(index) = curve.user() {
+                print_curve(curve, &tables[index], out);
+            }
+        }
+        return Ok(());
+    };
+    let curve = Curve::from_name(name).filter(|c| c.user().is_some()).ok_or(CliError::InvalidArgument("curve"))?;
+    let index = curve.user().unwrap_or(0);
+    match args.peek() {
+        None => {}
+        Some(name) if crate::cli::parse_int(name).is_none() => {
+            let shape = Curve::from_name(name).filter(|c| c.user().is_none());
+            let shape = shape.ok_or(CliError::InvalidArgument("curve"))?;
+            args.next_opt();
+            args.finish()?;
+            tables[index] = curve::Table::from_curve(shape);
+        }
+        Some(_) => {
+            let mut table = curve::Table::LINEAR;
+            for point in table.points.iter_mut() {
+                *point = args.next_int("point", 0, curve::POINT_MAX as i32)? as u8;
+            }
+            args.finish()?;
+            tables[index] = table;
+        }
+    }
+    print_curve(curve, &tables[index], out);
+    Ok(())
+}
+
+fn print_macro(index: usize, m: &Macro, playing: bool, out: &mut dyn Write) {
+    let _ = write!(out, "  {}:", index);
+    if m.len == 0 {
+        let _ = out.write_str(" empty");
+    }
+    for step in m.steps() {
+        match step {
+            macros::Step::Message(bytes) => {
+                for byte in bytes {
+                    let _ = write!(out, " {:02X}", byte);
+                }
+            }
+            macros::Step::Wait(ms) => {
+                let _ = write!(out, " wait {}", ms);
+            }
+        }
+    }
+    let unfinished = &m.as_slice()[m.complete()..];
+    for byte in unfinished {
+        let _ = write!(out, " {:02X}", byte);
+    }
+    if !unfinished.is_empty() {
+        let _ = out.write_str("  (unfinished)");
+    }
+    let _ = out.write_str(if playing { "  (playing)\r\n" } else { "\r\n" });
+}
+
+fn macro_cmd<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let index = match args.next_opt() {
+        None => {
+            let playing = target.macros().playing();
+            for (index, m) in target.config().macros.iter().enumerate() {
+                print_macro(index, m, playing == Some(index as u8), out);
+            }
+            return Ok(());
+        }
+        Some("stop") => {
+            args.finish()?;
+            target.macros().stop();
+            let _ = out.write_str("stopped\r\n");
+            return Ok(());
+        }
+        Some(word) => match crate::cli::parse_int(word) {
+            Some(n) if (0..MAX_MACROS as i32).contains(&n) => n as usize,
+            _ => return Err(CliError::InvalidArgument("macro")),
+        },
+    };
+    match args.peek() {
+        None => {}
+        Some("run") => {
+            args.next_opt();
+            args.finish()?;
+            let now = target.now();
+            target.macros().start(now, index as u8);
+        }
+        Some(first) => {
+            let mut m = Macro::EMPTY;
+            if first == "clear" {
+                args.next_opt();
+                args.finish()?;
+            } else if first == "add" {
+                args.next_opt();
+                m = target.config().macros[index];
+            }
+            while let Some(word) = args.next_opt() {
+                let fits = match word {
+                    "wait" => m.push_wait(args.next_int("wait", 1, 60_000)? as u32),
+                    _ => match u8::from_str_radix(word, 16) {
+                        Ok(macros::WAIT) | Err(_) => return Err(CliError::InvalidArgument("hex byte")),
+                        Ok(byte) => m.push(&[byte]),
+                    },
+                };
+                if !fits {
+                    return Err(CliError::Failed("macro too long"));
+                }
+            }
+            if !m.is_valid() {
+                return Err(CliError::Failed("macro must be MIDI messages with their status bytes"));
+            }
+            // The old bytes may be half way through being played
+            if target.macros().playing() == Some(index as u8) {
+                target.macros().stop();
+            }
+            target.config().macros[index] = m;
+        }
+    }
+    let playing = target.macros().playing() == Some(index as u8);
+    print_macro(index, &target.config().macros[index], playing, out);
+    Ok(())
+}
+
+fn mpe<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let announce = match args.next_opt() {
+        None => false,
+        Some("announce") => true,
+        Some(_) => return Err(CliError::InvalidArgument("mode")),
+    };
+    args.finish()?;
+    let layout = target.config().mpe;
+    for zone in [Zone::Lower, Zone::Upper] {
+        let _ = match layout.members(zone) {
+            0 => write!(out, "  {:<6} off\r\n", zone.name()),
+            members => write!(
+                out,
+                "  {:<6} manager ch{}, {} member channel(s)\r\n",
+                zone.name(),
+                zone.manager() + 1,
+                members
+            ),
+        };
+    }
+    let _ = write!(out, "  bend {} semitones, steal {}\r\n", layout.bend_range, layout.steal.name());
+    if announce {
+        layout.announce(&mut |message| target.inject(Port::Local, message));
+        let _ = out.write_str("sent\r\n");
+    }
+    Ok(())
+}
+
+fn print_feedback(config: &Config, led: Option<u8>, out: &mut dyn Write) {
+    let mut shown = 0;
+    for entry in config.feedback.iter().filter(|e| led.is_none_or(|l| e.led == l)) {
+        let _ = write!(out, "  {}\r\n", entry);
+        shown += 1;
+    }
+    if shown == 0 {
+        let _ = out.write_str("  no feedback\r\n");
+    }
+}
+
+fn feedback<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let Some(led) = args.next_opt() else {
+        print_feedback(target.config(), None, out);
+        return Ok(());
+    };
+    let led = match crate::cli::parse_int(led) {
+        Some(l) if (0..=led::MAX_LED as i32).contains(&l) => l as u8,
+        _ => return Err(CliError::InvalidArgument("led")),
+    };
+    let table = &mut target.config().feedback;
+    match args.next_opt() {
+        None => {}
+        Some("clear") => {
+            args.finish()?;
+            table.remove(led);
+        }
+        Some("control") => {
+            let control = args.next_int("control", 0, 255)? as u8;
+            args.finish()?;
+            let entry = Feedback { led, source: Source::Control(control) };
+            table.set(entry).map_err(|FeedbackError::Full| CliError::Failed("feedback table full"))?;
+        }
+        Some(kind @ ("note" | "cc")) => {
+            let number = args.next_int(if kind == "note" { "note" } else { "controller" }, 0, 127)? as u8;
+            let channel = match args.next_opt() {
+                None => mapping::DEFAULT_CHANNEL,
+                Some("ch") => args.next_int("channel", 1, 16)? as u8 - 1,
+                Some(_) => return Err(CliError::InvalidArgument("option")),
+            };
+            args.finish()?;
+            let source = match kind {
+                "note" => Source::Note { channel, note: number },
+                _ => Source::Cc { channel, control: number },
+            };
+            let entry = Feedback { led, source };
+            table.set(entry).map_err(|FeedbackError::Full| CliError::Failed("feedback table full"))?;
+        }
+        Some(_) => return Err(CliError::InvalidArgument("source")),
+    }
+    print_feedback(target.config(), Some(led), out);
+    Ok(())
+}
+
+fn color<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    if args.peek().is_some() {
+        let r = args.next_int("red", 0, 255)? as u8;
+        let g = args.next_int("green", 0, 255)? as u8;
+        let b = args.next_int("blue", 0, 255)? as u8;
+        args.finish()?;
+        target.set_color(Color { r, g, b });
+    }
+    let _ = write!(out, "  {}\r\n", target.color());
+    Ok(())
+}
+
+fn pixel<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let len = target.config().pixels as usize;
+    let range = match args.next_opt() {
+        None | Some("all") => 0..len,
+        Some(index) => match crate::cli::parse_int(index) {
+            Some(i) if (0..len as i32).contains(&i) => i as usize..i as usize + 1,
+            _ => return Err(CliError::InvalidArgument("pixel")),
+        },
+    };
+    if args.peek().is_some() {
+        let r = args.next_int("red", 0, 255)? as u8;
+        let g = args.next_int("green", 0, 255)? as u8;
+        let b = args.next_int("blue", 0, 255)? as u8;
+        args.finish()?;
+        for index in range.clone() {
+            target.set_pixel(index, Color { r, g, b });
+        }
+    }
+    if range.is_empty() {
+        let _ = out.write_str("  no pixels\r\n");
+    }
+    for index in range {
+        let _ = write!(out, "  {:>2}  {}\r\n", index, target.pixel(index));
+    }
+    Ok(())
+}
+
+/// Hundredths with a sign and two decimals
+struct Hundredths(i32);
+
+impl fmt::Display for Hundredths {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{}{}.{:02}", sign, self.0.unsigned_abs() / 100, self.0.unsigned_abs() % 100)
+    }
+}
+
+fn imu<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let calibrate = match args.next_opt() {
+        None => false,
+        Some("cal") => true,
+        Some(_) => return Err(CliError::InvalidArgument("mode")),
+    };
+    args.finish()?;
+    let options = target.config().imu;
+    if options.sensor == Sensor::None {
+        return Err(CliError::Failed("no IMU configured"));
+    }
+    let Some(reading) = target.motion() else {
+        let _ = write!(out, "  {} not responding\r\n", options.sensor);
+        return Ok(());
+    };
+    let _ = write!(
+        out,
+        "  {}, range {} degrees\r\n  pitch {}  roll {}  yaw {} degrees\r\n",
+        options.sensor,
+        options.range,
+        Hundredths(reading.pitch - options.calibration.pitch as i32),
+        Hundredths(reading.roll - options.calibration.roll as i32),
+        Hundredths(reading.yaw)
+    );
+    let [x, y, z] = reading.accel;
+    let _ = write!(out, "  accel {} {} {} mg\r\n", x, y, z);
+    let calibration = options.calibration;
+    let [gx, gy, gz] = calibration.gyro.map(|rate| Hundredths(rate as i32));
+    let _ = write!(
+        out,
+        "  rest  pitch {}  roll {}, gyro {} {} {} deg/s\r\n",
+        Hundredths(calibration.pitch as i32),
+        Hundredths(calibration.roll as i32),
+        gx,
+        gy,
+        gz
+    );
+    if calibrate {
+        target.calibrate_motion();
+        let _ = out.write_str("calibrating, keep the sensor level and still for a second\r\n");
+    }
+    Ok(())
+}
+
+fn print_sequencer(
+    config: &Config,
+    playing: bool,
+    position: Option<[u8; sequencer::MAX_TRACKS]>,
+    out: &mut dyn Write,
+) {
+    let options = &config.sequencer;
+    let _ = match options.source {
+        ClockSource::Internal => write!(out, "  pattern {}, {} BPM internal clock", options.pattern + 1, options.bpm),
+        ClockSource::External => write!(out, "  pattern {}, external clock", options.pattern + 1),
+    };
+    if options.swing > sequencer::MIN_SWING {
+        let _ = write!(out, ", {}% swing", options.swing);
+    }
+    let _ = out.write_str(if playing { ", playing\r\n" } else { ", stopped\r\n" });
+    let pattern = &config.patterns[options.pattern as usize];
+    for (index, track) in pattern.tracks.iter().enumerate() {
+        let _ = write!(
+            out,
+            "  {}: ch{:<2} {:>2} steps of {}{}\r\n     ",
+            index + 1,
+            track.channel + 1,
+            track.length,
+            Rate(track.rate),
+            if track.muted { ", muted" } else { "" }
+        );
+        // One character per step, the playhead in upper case
+        for (number, step) in track.steps[..track.length as usize].iter().enumerate() {
+            let here = position.is_some_and(|position| position[index] as usize == number);
+            let _ = out.write_char(match (step.is_rest(), here) {
+                (true, false) => '.',
+                (true, true) => 'O',
+                (false, false) => 'x',
+                (false, true) => 'X',
+            });
+        }
+        let _ = out.write_str("\r\n");
+    }
+}
+
+fn print_step(track: usize, number: usize, step: &Step, out: &mut dyn Write) {
+    let _ = match step.is_rest() {
+        true => write!(out, "  track {} step {}: rest\r\n", track + 1, number + 1),
+        false => write!(
+            out,
+            "  track {} step {}: note {} vel {} gate {}% prob {}% ratchet {}\r\n",
+            track + 1,
+            number + 1,
+            step.note,
+            step.velocity,
+            step.gate,
+            step.probability,
+            step.ratchet
+        ),
+    };
+}
+
+fn seq<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let track_arg = |args: &mut Args| args.next_int("track", 1, sequencer::MAX_TRACKS as i32).map(|t| t as usize - 1);
+    let current = target.config().sequencer.pattern as usize;
+    match args.next_opt() {
+        None => {}
+        Some("start") => target.sequencer().start(),
+        Some("stop") => target.sequencer().stop(),
+        Some("continue") => target.sequencer().resume(),
+        Some("page") => {
+            let pages = (sequencer::MAX_STEPS / sequencer::grid::COLUMNS) as i32;
+            let page = args.next_int("page", 1, pages)? as u8;
+            target.sequencer().set_page(page - 1);
+        }
+        Some("track") => {
+            let index = track_arg(args)?;
+            // Edit a copy, a bad option leaves the track as it was
+            let mut track = target.config().patterns[current].tracks[index];
+            while let Some(option) = args.next_opt() {
+                match option {
+                    "ch" => track.channel = args.next_int("channel", 1, 16)? as u8 - 1,
+                    "len" => track.length = args.next_int("length", 1, sequencer::MAX_STEPS as i32)? as u8,
+                    "rate" => track.rate = args.next_int("rate", 1, sequencer::MAX_RATE as i32)? as u8,
+                    "mute" => track.muted = true,
+                    "unmute" => track.muted = false,
+                    _ => return Err(CliError::InvalidArgument("option")),
+                }
+            }
+            args.finish()?;
+            target.config().patterns[current].tracks[index] = track;
+        }
+        Some("step") => {
+            let index = track_arg(args)?;
+            let number = args.next_int("step", 1, sequencer::MAX_STEPS as i32)? as usize - 1;
+            let mut step = target.config().patterns[current].tracks[index].steps[number];
+            match args.next_str("note")? {
+                "off" => step.velocity = 0,
+                note => {
+                    let note = crate::cli::parse_int(note).filter(|n| (0..128).contains(n));
+                    step.note = note.ok_or(CliError::InvalidArgument("note"))? as u8;
+                    if step.is_rest() {
+                        step.velocity = Track::GRID_VELOCITY;
+                    }
+                    while let Some(option) = args.next_opt() {
+                        match option {
+                            "vel" => step.velocity = args.next_int("velocity", 1, 127)? as u8,
+                            "gate" => step.gate = args.next_int("gate", 1, 100)? as u8,
+                            "prob" => step.probability = args.next_int("probability", 0, 100)? as u8,
+                            "ratchet" => {
+                                step.ratchet = args.next_int("ratchet", 1, sequencer::MAX_RATCHET as i32)? as u8
+                            }
+                            _ => return Err(CliError::InvalidArgument("option")),
+                        }
+                    }
+                }
+            }
+            args.finish()?;
+            target.config().patterns[current].tracks[index].steps[number] = step;
+            print_step(index, number, &step, out);
+            return Ok(());
+        }
+        Some("copy") => {
+            let from = args.next_int("from", 1, sequencer::MAX_PATTERNS as i32)? as usize - 1;
+            let to = args.next_int("to", 1, sequencer::MAX_PATTERNS as i32)? as usize - 1;
+            args.finish()?;
+            let patterns = &mut target.config().patterns;
+            patterns[to] = patterns[from];
+        }
+        Some("clear") => {
+            let pattern = match args.next_opt() {
+                None => current,
+                Some(pattern) => match crate::cli::parse_int(pattern) {
+                    Some(p @ 1..) if p <= sequencer::MAX_PATTERNS as i32 => p as usize - 1,
+                    _ => return Err(CliError::InvalidArgument("pattern")),
+                },
+            };
+            args.finish()?;
+            target.config().patterns[pattern] = Pattern::new();
+        }
+        Some(_) => return Err(CliError::InvalidArgument("mode")),
+    }
+    args.finish()?;
+    let sequencer = target.sequencer();
+    let (playing, position) = (sequencer.playing(), sequencer.position());
+    print_sequencer(target.config(), playing, position, out);
+    Ok(())
+}
+
+fn chord<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    match args.peek() {
+        None => {}
+        Some("off") => {
+            args.next_opt();
+            args.finish()?;
+            target.config().arp.chord = Chord::NONE;
+        }
+        Some("learn") => {
+            args.next_opt();
+            args.finish()?;
+            let notes = target.notes().held();
+            if notes.clone().next().is_none() {
+                return Err(CliError::Failed("hold the notes of the chord"));
+            }
+            let chord = Chord::from_notes(notes).ok_or(CliError::Failed("too many notes or too wide"))?;
+            target.config().arp.chord = chord;
+        }
+        Some(_) => {
+            let mut chord = Chord::NONE;
+            while args.remaining() > 0 {
+                let interval = args.next_int("semitones", 0, Chord::MAX_INTERVAL as i32)? as u8;
+                if interval > 0 && !chord.as_slice().contains(&interval) {
+                    chord.add(interval).ok_or(CliError::Failed("too many notes"))?;
+                }
+            }
+            chord.intervals[..chord.len as usize].sort_unstable();
+            target.config().arp.chord = chord;
+        }
+    }
+    let _ = write!(out, "  chord {}\r\n", target.config().arp.chord);
+    Ok(())
+}
+
+fn keymap<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    if let Some(name) = args.next_opt() {
+        let op = KeyOp::from_name(name).ok_or(CliError::InvalidArgument("operation"))?;
+        target.config().keymap.apply(op);
+    }
+    args.finish()?;
+    let config = target.config();
+    let options = &config.keymap;
+    let _ = write!(
+        out,
+        "  {}, {} columns, octave {}, {}\r\n",
+        options.layout, options.columns, options.octave, options.key
+    );
+    // Rows of the mapped pads, top row first
+    let pads = config.mappings.iter().filter_map(|mapping| match mapping.action {
+        Action::Pad(pad) => Some(pad),
+        _ => None,
+    });
+    let Some(last) = pads.max() else {
+        return Ok(());
+    };
+    let columns = options.columns.max(1);
+    for row in (0..=last / columns).rev() {
+        let _ = out.write_str(" ");
+        for pad in row * columns..(row + 1) * columns {
+            let _ = match options.note(pad) {
+                Some(note) => write!(out, "  {:>2}{}", scale::pitch_name(note), note / 12),
+                None => out.write_str("    -"),
+            };
+        }
+        let _ = out.write_str("\r\n");
+    }
+    Ok(())
+}
+
+/// Collect a bit mask from `all` or a list of names
+fn parse_mask(
+    args: &mut Args,
+    name: &'static str,
+    all: u16,
+    lookup: impl Fn(&str) -> Option<u16>,
+) -> Result<u16, CliError> {
+    let mut mask = 0;
+    while let Some(word) = args.next_opt() {
+        mask |= match word {
+            "all" => all,
+            _ => lookup(word).ok_or(CliError::InvalidArgument(name))?,
+        };
+    }
+    if mask == 0 {
+        return Err(CliError::MissingArgument(name));
+    }
+    Ok(mask)
+}
+
+fn monitor<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let now = target.now();
+    let monitor = target.monitor();
+    match args.next_opt() {
+        None => {}
+        Some("on") => monitor.set_enabled(true, now),
+        Some("off") => monitor.set_enabled(false, now),
+        Some("port") => {
+            let mask = parse_mask(args, "port", 0xFF, |w| Port::from_name(w).map(|p| p.bit() as u16))?;
+            monitor.filter.ports = mask as u8;
+        }
+        Some("channel") => {
+            monitor.filter.channels = parse_mask(args, "channel", 0xFFFF, |w| match crate::cli::parse_int(w) {
+                Some(ch @ 1..=16) => Some(1 << (ch - 1)),
+                _ => None,
+            })?;
+        }
+        Some("type") => {
+            monitor.filter.kinds = parse_mask(args, "type", 0xFFFF, |w| Kind::from_name(w).map(Kind::bit))?;
+        }
+        Some("rate") => {
+            monitor.rate = args.next_int("lines/s", 1, 1000)? as u16;
+            args.finish()?;
+        }
+        Some(_) => return Err(CliError::InvalidArgument("mode")),
+    }
+    args.finish()?;
+    monitor.describe(out);
+    Ok(())
+}
+
+fn send<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    let port = Port::from_name(args.next_str("port")?).ok_or(CliError::InvalidArgument("port"))?;
+    if args.remaining() == 0 {
+        return Err(CliError::MissingArgument("hex byte"));
+    }
+    let mut parser = Parser::new();
+    let mut sent = 0;
+    while let Some(word) = args.next_opt() {
+        let byte = u8::from_str_radix(word, 16).map_err(|_| CliError::InvalidArgument("hex byte"))?;
+        if let Some(message) = parser.feed(byte) {
+            target.inject(port, message);
+            sent += 1;
+        }
+    }
+    let _ = write!(out, "{} message(s) sent\r\n", sent);
+    Ok(())
+}
+
+fn reset<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    let _ = out.write_str("resetting\r\n");
+    target.reset();
+    Ok(())
+}
+
+fn bootloader<T: Target>(target: &mut T, args: &mut Args, out: &mut dyn Write) -> Result<(), CliError> {
+    args.finish()?;
+    let _ = out.write_str("entering boot loader\r\n");
+    target.enter_bootloader();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Shell;
+    use crate::config::{Storage, StorageError};
+    use crate::pedal;
+
+    /// Flash region as the erase/program cycle sees it
+    struct Flash(Vec<u8>);
+
+    impl Storage for Flash {
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+
+        fn erase(&mut self) -> Result<(), StorageError> {
+            self.0.fill(0xFF);
+            Ok(())
+        }
+
+        fn program(&mut self, offset: usize, word: u32) -> Result<(), StorageError> {
+            assert_eq!(offset % 4, 0);
+            let bytes = &mut self.0[offset..offset + 4];
+            assert!(bytes.iter().all(|&b| b == 0xFF), "programmed twice at {offset}");
+            bytes.copy_from_slice(&word.to_le_bytes());
+            Ok(())
+        }
+
+        fn contents(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    struct Fake {
+        config: Config,
+        flash: Flash,
+        changed: usize,
+        monitor: Monitor,
+        injected: Vec<(Port, MidiMessage)>,
+        color: Color,
+        pixels: [Color; 8],
+        sequencer: Sequencer,
+        notes: NoteProcessor,
+        macros: Player,
+        resets: usize,
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            Self {
+                config: Config::new(),
+                flash: Flash(vec![0xFF; 8192]),
+                changed: 0,
+                monitor: Monitor::new(),
+                injected: Vec::new(),
+                color: Color::OFF,
+                pixels: [Color::OFF; 8],
+                sequencer: Sequencer::new(),
+                notes: NoteProcessor::new(),
+                macros: Player::new(),
+                resets: 0,
+            }
+        }
+    }
+
+    impl Target for Fake {
+        fn config(&mut self) -> &mut Config {
+            &mut self.config
+        }
+
+        fn save(&mut self) -> Result<usize, ConfigError> {
+            self.config.save(&mut self.flash)
+        }
+
+        fn load(&mut self) -> Result<(), ConfigError> {
+            self.config = Config::load(&self.flash)?;
+            Ok(())
+        }
+
+        fn config_changed(&mut self) {
+            self.changed += 1;
+        }
+
+        fn monitor(&mut self) -> &mut Monitor {
+            &mut self.monitor
+        }
+
+        fn now(&self) -> u32 {
+            1000
+        }
+
+        fn inject(&mut self, source: Port, message: MidiMessage) {
+            self.injected.push((source, message));
+        }
+
+        fn color(&self) -> Color {
+            self.color
+        }
+
+        fn set_color(&mut self, color: Color) {
+            self.color = color;
+        }
+
+        fn pixel(&self, index: usize) -> Color {
+            self.pixels[index]
+        }
+
+        fn set_pixel(&mut self, index: usize, color: Color) {
+            self.pixels[index] = color;
+        }
+
+        fn motion(&self) -> Option<Reading> {
+            None
+        }
+
+        fn calibrate_motion(&mut self) {}
+
+        fn sequencer(&mut self) -> &mut Sequencer {
+            &mut self.sequencer
+        }
+
+        fn notes(&mut self) -> &mut NoteProcessor {
+            &mut self.notes
+        }
+
+        fn macros(&mut self) -> &mut Player {
+            &mut self.macros
+        }
+
+        fn status(&mut self, out: &mut dyn Write) {
+            let _ = out.write_str("all good\r\n");
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+
+        fn enter_bootloader(&mut self) {}
+    }
+
+    /// Run one line through the command table, returning the output
+    fn run(fake: &mut Fake, line: &str) -> Result<String, CliError> {
+        let mut out = String::new();
+        Shell::<Fake, 80>::dispatch(Commands::<Fake>::TABLE, line, fake, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn sets_saves_and_loads() {
+        let mut fake = Fake::new();
+        assert_eq!(run(&mut fake, "load"), Err(CliError::Failed(ConfigError::Empty.as_str())));
+        assert_eq!(run(&mut fake, "set channel 5").unwrap(), "channel = 5\r\n");
+        assert_eq!(fake.config.channel, 4);
+        assert_eq!(fake.changed, 1);
+        assert!(run(&mut fake, "save").unwrap().starts_with("saved ("));
+
+        run(&mut fake, "set channel 0x10").unwrap();
+        assert_eq!(fake.config.channel, 15);
+        assert!(run(&mut fake, "get channel").unwrap().starts_with("  channel       16  (1..16) "));
+        assert_eq!(run(&mut fake, "load").unwrap(), "loaded\r\n");
+        assert_eq!(fake.config.channel, 4);
+
+        assert_eq!(run(&mut fake, "defaults").unwrap(), "factory settings restored\r\n");
+        assert_eq!(fake.config.channel, 0);
+        let all = run(&mut fake, "get").unwrap();
+        assert_eq!(all.lines().count(), config::PARAMS.len());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let mut fake = Fake::new();
+        assert_eq!(run(&mut fake, "set channel 17"), Err(CliError::OutOfRange("value")));
+        assert_eq!(run(&mut fake, "set channel"), Err(CliError::MissingArgument("value")));
+        assert_eq!(run(&mut fake, "set chanel 1"), Err(CliError::InvalidArgument("name")));
+        assert_eq!(run(&mut fake, "set channel 1 2"), Err(CliError::TooManyArgs));
+        assert_eq!(run(&mut fake, "get chanel"), Err(CliError::InvalidArgument("name")));
+        assert_eq!(run(&mut fake, "save now"), Err(CliError::TooManyArgs));
+        assert_eq!(fake.changed, 0);
+        assert_eq!(fake.config.channel, 0);
+    }
+
+    #[test]
+    fn keeps_faders_off_the_pedal_jacks() {
+        let mut fake = Fake::new();
+        let shared = Err(CliError::Failed("a fader and a pedal jack share that input"));
+        run(&mut fake, "set faders 1").unwrap();
+        assert_eq!(run(&mut fake, "set pedal2 2"), shared);
+        assert_eq!(run(&mut fake, "set pedal1 2").unwrap(), "pedal1 = 2\r\n");
+        assert_eq!(run(&mut fake, "set faders 2"), shared);
+        assert_eq!((fake.config.faders, fake.config.pedals[1]), (1, pedal::Mode::Off));
+
+        // Nor is a saved configuration that has both taken
+        fake.config.pedals[1] = pedal::Mode::Switch;
+        run(&mut fake, "save").unwrap();
+        assert_eq!(run(&mut fake, "load"), Err(CliError::Failed(ConfigError::Invalid.as_str())));
+    }
+
+    #[test]
+    fn edits_mappings() {
+        let mut fake = Fake::new();
+        run(&mut fake, "defaults").unwrap();
+        run(&mut fake, "map 3 cc14 7 ch 2 curve log").unwrap();
+        run(&mut fake, "map 3 add nrpn 300 min 10 max 1000 invert").unwrap();
+        let mapped = |fake: &Fake| fake.config.mappings.iter().filter(|m| m.control == 3).count();
+        assert_eq!(mapped(&fake), 2);
+        let nrpn = fake.config.mappings.iter().find(|m| m.action == Action::Nrpn(300)).unwrap();
+        assert_eq!((nrpn.min, nrpn.max, nrpn.invert), (10, 1000, true));
+
+        // A new action replaces the control's mappings
+        run(&mut fake, "map 3 program 5").unwrap();
+        assert_eq!(mapped(&fake), 1);
+        let program = fake.config.mappings.iter().find(|m| m.control == 3).unwrap();
+        assert_eq!((program.action, program.min, program.max), (Action::Program, 5, 5));
+
+        assert_eq!(run(&mut fake, "map 3 cc 7 min 200"), Err(CliError::OutOfRange("min")));
+        assert_eq!(run(&mut fake, "map 3 foo"), Err(CliError::InvalidArgument("action")));
+        assert_eq!(run(&mut fake, "map 3 cc 7 loud"), Err(CliError::InvalidArgument("option")));
+        assert_eq!(run(&mut fake, "map 300 cc 7"), Err(CliError::InvalidArgument("control")));
+        assert_eq!(mapped(&fake), 1);
+
+        assert_eq!(run(&mut fake, "map 3 clear").unwrap(), "  no mappings\r\n");
+        assert_eq!(mapped(&fake), 0);
+    }
+
+    #[test]
+    fn sends_messages() {
+        let mut fake = Fake::new();
+        assert_eq!(run(&mut fake, "send usb 90 3c 64 40 64 b1 7 7f").unwrap(), "3 message(s) sent\r\n");
+        let note = |note, velocity| MidiMessage::NoteOn { channel: 0, note, velocity };
+        let cc = MidiMessage::ControlChange { channel: 1, control: 7, value: 127 };
+        assert_eq!(fake.injected, [(Port::Usb, note(60, 100)), (Port::Usb, note(64, 100)), (Port::Usb, cc)]);
+        assert_eq!(run(&mut fake, "send usb"), Err(CliError::MissingArgument("hex byte")));
+        assert_eq!(run(&mut fake, "send usb 90 zz"), Err(CliError::InvalidArgument("hex byte")));
+        assert_eq!(run(&mut fake, "send moon 90"), Err(CliError::InvalidArgument("port")));
+    }
+
+    #[test]
+    fn edits_sequencer_tracks() {
+        let mut fake = Fake::new();
+        run(&mut fake, "seq track 1 ch 3 len 8").unwrap();
+        run(&mut fake, "seq step 1 2 60 vel 90 gate 50").unwrap();
+        let before = fake.config.patterns[0].tracks[0];
+        assert_eq!((before.channel, before.length), (2, 8));
+        assert_eq!((before.steps[1].note, before.steps[1].velocity, before.steps[1].gate), (60, 90, 50));
+
+        // A bad option leaves the track and step as they were
+        assert_eq!(run(&mut fake, "seq track 1 ch 4 bogus"), Err(CliError::InvalidArgument("option")));
+        assert_eq!(run(&mut fake, "seq step 1 2 62 vel 100 bogus"), Err(CliError::InvalidArgument("option")));
+        assert_eq!(run(&mut fake, "seq step 1 2 off 5"), Err(CliError::TooManyArgs));
+        assert_eq!(fake.config.patterns[0].tracks[0], before);
+    }
+
+    #[test]
+    fn runs_device_commands() {
+        let mut fake = Fake::new();
+        assert_eq!(run(&mut fake, "status").unwrap(), "all good\r\n");
+        assert_eq!(run(&mut fake, "reset").unwrap(), "resetting\r\n");
+        assert_eq!(fake.resets, 1);
+        let help = run(&mut fake, "help").unwrap();
+        for command in Commands::<Fake>::TABLE {
+            assert!(help.contains(&format!("\r\n  {:<12}", command.name)), "{} not listed", command.name);
+        }
+    }
+}
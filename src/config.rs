@@ -0,0 +1,1108 @@
+//! Persistent Configuration
+//!
+//! All user settings live in one `Config` value that is saved to and loaded
+//! from a reserved flash region. The stored image is
+//!
+//! ```text
+//! magic u32 | version u16 | length u16 | crc32 u32 | section* (length bytes)
+//! section  = tag u8 | len u16 | payload (len bytes)
+//! ```
+//!
+//! all little endian. Unknown sections are skipped on load, so adding a new
+//! section does not invalidate configurations saved by older firmware.
+//!
+//! Storage is accessed through the `Storage` trait so the encoding can be
+//! exercised on the host against a RAM-backed fake.
+
+use crate::arp;
+use crate::capsense;
+use crate::display;
+use crate::drum;
+use crate::fader;
+use crate::feedback::FeedbackTable;
+use crate::hires;
+use crate::input;
+use crate::keymap::{self, Layout};
+use crate::led::{self, LedId};
+use crate::macros::{Macro, MAX_MACROS};
+use crate::mapping::{self, Aftertouch, Banks, MappingTable};
+use crate::mcu;
+use crate::motion;
+use crate::pedal;
+use crate::mpe;
+use crate::scale::Scale;
+use crate::sequencer::{self, Pattern, Step};
+
+/// Stored for no LED
+const NO_LED: u8 = 0xFF;
+/// Stored for no bank controller
+const NO_CC: u8 = 0xFF;
+
+/// "TVCF"
+const MAGIC: u32 = 0x4643_5654;
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 12;
+
+/// Section tags
+pub mod tags {
+    pub const SETTINGS: u8 = 0x01;
+    pub const MAPPINGS: u8 = 0x02;
+    pub const SYSEX_TEMPLATES: u8 = 0x03;
+    pub const HIRES: u8 = 0x04;
+    pub const MPE: u8 = 0x05;
+    pub const FEEDBACK: u8 = 0x06;
+    pub const LEDS: u8 = 0x07;
+    pub const STRIP: u8 = 0x08;
+    pub const SHIFT: u8 = 0x09;
+    pub const DISPLAY: u8 = 0x0A;
+    pub const FADERS: u8 = 0x0B;
+    pub const SURFACE: u8 = 0x0C;
+    pub const IMU: u8 = 0x0D;
+    pub const TOUCH: u8 = 0x0E;
+    pub const DRUMS: u8 = 0x0F;
+    pub const AFTERTOUCH: u8 = 0x10;
+    pub const PEDALS: u8 = 0x11;
+    pub const SEQUENCER: u8 = 0x12;
+    pub const ARP: u8 = 0x13;
+    pub const KEYMAP: u8 = 0x14;
+    pub const CURVES: u8 = 0x15;
+    pub const TAKEOVER: u8 = 0x16;
+    pub const BANKS: u8 = 0x17;
+    pub const MACROS: u8 = 0x18;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageError {
+    /// Erase of the region failed
+    Erase,
+    /// Programming a word failed
+    Program,
+    /// Image does not fit the region
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    /// No configuration has been saved yet
+    Empty,
+    /// Header or CRC mismatch
+    Corrupt,
+    /// Saved by a newer, incompatible firmware
+    Version,
+    /// A section ended early
+    Truncated,
+    /// A value was out of range
+    Invalid,
+    Storage(StorageError),
+}
+
+impl From<StorageError> for ConfigError {
+    fn from(err: StorageError) -> Self {
+        ConfigError::Storage(err)
+    }
+}
+
+impl ConfigError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::Empty => "no saved configuration",
+            ConfigError::Corrupt => "saved configuration is corrupt",
+            ConfigError::Version => "saved configuration is from a newer firmware",
+            ConfigError::Truncated => "saved configuration is truncated",
+            ConfigError::Invalid => "saved configuration has invalid values",
+            ConfigError::Storage(StorageError::Erase) => "flash erase failed",
+            ConfigError::Storage(StorageError::Program) => "flash program failed",
+            ConfigError::Storage(StorageError::Full) => "configuration does not fit in flash",
+        }
+    }
+}
+
+/// Word-programmable non-volatile region
+pub trait Storage {
+    /// Size of the region in bytes
+    fn capacity(&self) -> usize;
+    /// Erase the whole region to 0xFF
+    fn erase(&mut self) -> Result<(), StorageError>;
+    /// Program one word at a word aligned offset
+    fn program(&mut self, offset: usize, word: u32) -> Result<(), StorageError>;
+    /// Current contents of the region
+    fn contents(&self) -> &[u8];
+}
+
+// ============================================================================
+// Encoding
+// ============================================================================
+
+/// CRC-32 (IEEE 802.3), bitwise to keep flash usage down
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Streaming encoder. In measuring mode (no storage) it only counts bytes,
+/// which is how section lengths are found before writing them.
+pub struct Writer<'a> {
+    storage: Option<&'a mut dyn Storage>,
+    offset: usize,
+    word: [u8; 4],
+    fill: usize,
+    crc: u32,
+    error: Option<StorageError>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(storage: Option<&'a mut dyn Storage>, offset: usize) -> Self {
+        Self { storage, offset, word: [0xFF; 4], fill: 0, crc: 0xFFFF_FFFF, error: None }
+    }
+
+    /// Writer that only counts bytes
+    pub fn measure() -> Writer<'static> {
+        Writer::new(None, 0)
+    }
+
+    /// Bytes written so far
+    pub fn len(&self) -> usize {
+        self.offset + self.fill
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.crc = crc32_update(self.crc, &[value]);
+        let Some(storage) = self.storage.as_deref_mut() else {
+            self.offset += 1;
+            return;
+        };
+        self.word[self.fill] = value;
+        self.fill += 1;
+        if self.fill == 4 {
+            if self.offset + 4 > storage.capacity() {
+                self.error.get_or_insert(StorageError::Full);
+            } else if let Err(err) = storage.program(self.offset, u32::from_le_bytes(self.word)) {
+                self.error.get_or_insert(err);
+            }
+            self.offset += 4;
+            self.fill = 0;
+            self.word = [0xFF; 4];
+        }
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.u8(byte);
+        }
+    }
+
+    /// Write a tagged section. `body` is run twice: once to measure, once to
+    /// write.
+    pub fn section(&mut self, tag: u8, body: impl Fn(&mut Writer)) {
+        let mut measure = Writer::measure();
+        body(&mut measure);
+        self.u8(tag);
+        self.u16(measure.len() as u16);
+        body(self);
+    }
+
+    /// Pad the last partial word and report the first error.
+    fn finish(mut self) -> Result<(usize, u32), StorageError> {
+        let len = self.len();
+        while self.fill != 0 {
+            // Padding is not part of the CRC
+            let crc = self.crc;
+            self.u8(0xFF);
+            self.crc = crc;
+        }
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok((len, !self.crc)),
+        }
+    }
+}
+
+/// Bounds-checked decoder over a byte slice
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], ConfigError> {
+        if self.remaining() < len {
+            return Err(ConfigError::Truncated);
+        }
+        let out = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ConfigError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ConfigError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ConfigError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Value that must be below `limit`
+    pub fn u8_below(&mut self, limit: u8) -> Result<u8, ConfigError> {
+        let value = self.u8()?;
+        if value < limit {
+            Ok(value)
+        } else {
+            Err(ConfigError::Invalid)
+        }
+    }
+}
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// Everything that survives a power cycle
+#[derive(Clone)]
+pub struct Config {
+    /// Default MIDI channel, 0-based
+    pub channel: u8,
+    /// Note-on velocity used by controls without a velocity source
+    pub velocity: u8,
+    /// 14-bit controller and NRPN/RPN format
+    pub hires: hires::Options,
+    /// MPE zones, off by default
+    pub mpe: mpe::Layout,
+    /// Control to MIDI mappings
+    pub mappings: MappingTable,
+    /// Incoming MIDI to LEDs
+    pub feedback: FeedbackTable,
+    /// Brightness and animation of the RGB LED
+    pub leds: led::Options,
+    /// Pixels on the addressable LED strip, 0 without one
+    pub pixels: u8,
+    /// 74HC595 chips on the expansion port
+    pub shift_outputs: u8,
+    /// 74HC165 chips on the expansion port
+    pub shift_inputs: u8,
+    /// OLED on I2C0
+    pub display: display::Controller,
+    /// Motorised faders fitted
+    pub faders: u8,
+    /// Control surface protocol in place of the mappings
+    pub surface: mcu::Protocol,
+    /// IMU on I2C3, its tilt range and calibration
+    pub imu: motion::Options,
+    /// Capacitive ribbon electrodes, on the first touch pins
+    pub ribbon: u8,
+    /// Capacitive touch pads, on the touch pins after the ribbon
+    pub touch_pads: u8,
+    /// Drum pads on the ADC
+    pub drums: drum::Options,
+    /// Pressure on held notes outside MPE zones
+    pub aftertouch: Aftertouch,
+    /// Use of the expression jacks
+    pub pedals: [pedal::Mode; pedal::JACKS],
+    /// Sustain jacks fitted
+    pub sustain: u8,
+    /// Step sequencer clock, tempo and grid
+    pub sequencer: sequencer::Options,
+    /// Step sequencer presets
+    pub patterns: [Pattern; sequencer::MAX_PATTERNS],
+    /// Arpeggiator and chord memory of the notes played
+    pub arp: arp::Options,
+    /// Notes of pad mappings, and the key played notes are quantised to
+    pub keymap: keymap::Options,
+    /// LED that flashes while a control has yet to take over the remote value
+    pub pickup_led: Option<LedId>,
+    /// Bank buttons and the bank controller
+    pub banks: Banks,
+    /// Message lists sent by macro buttons
+    pub macros: [Macro; MAX_MACROS],
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            channel: 0,
+            velocity: 100,
+            hires: hires::Options::new(),
+            mpe: mpe::Layout::new(),
+            mappings: MappingTable::factory(),
+            feedback: FeedbackTable::factory(),
+            leds: led::Options::new(),
+            pixels: 0,
+            shift_outputs: 0,
+            shift_inputs: 0,
+            display: display::Controller::None,
+            faders: 0,
+            surface: mcu::Protocol::Off,
+            imu: motion::Options::new(),
+            ribbon: 0,
+            touch_pads: 0,
+            drums: drum::Options::new(),
+            aftertouch: Aftertouch::Off,
+            pedals: [pedal::Mode::Off; pedal::JACKS],
+            sustain: 0,
+            sequencer: sequencer::Options::new(),
+            patterns: [Pattern::new(); sequencer::MAX_PATTERNS],
+            arp: arp::Options::new(),
+            keymap: keymap::Options::new(),
+            pickup_led: Some(led::ids::BLUE),
+            banks: Banks::new(),
+            macros: [Macro::EMPTY; MAX_MACROS],
+        }
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        w.section(tags::SETTINGS, |w| {
+            w.u8(self.channel);
+            w.u8(self.velocity);
+        });
+        w.section(tags::HIRES, |w| {
+            w.u8(self.hires.lsb_first as u8);
+            w.u8(self.hires.null_terminate as u8);
+        });
+        w.section(tags::MPE, |w| {
+            w.u8(self.mpe.lower);
+            w.u8(self.mpe.upper);
+            w.u8(self.mpe.bend_range);
+            w.u8(self.mpe.steal as u8);
+        });
+        w.section(tags::MAPPINGS, |w| self.mappings.encode(w));
+        w.section(tags::SYSEX_TEMPLATES, |w| self.mappings.encode_templates(w));
+        w.section(tags::CURVES, |w| self.mappings.encode_curves(w));
+        w.section(tags::FEEDBACK, |w| self.feedback.encode(w));
+        w.section(tags::LEDS, |w| {
+            w.u8(self.leds.brightness);
+            w.u8(self.leds.animation as u8);
+        });
+        w.section(tags::STRIP, |w| w.u8(self.pixels));
+        w.section(tags::SHIFT, |w| {
+            w.u8(self.shift_outputs);
+            w.u8(self.shift_inputs);
+        });
+        w.section(tags::DISPLAY, |w| w.u8(self.display as u8));
+        w.section(tags::FADERS, |w| w.u8(self.faders));
+        w.section(tags::SURFACE, |w| w.u8(self.surface as u8));
+        w.section(tags::IMU, |w| {
+            let calibration = &self.imu.calibration;
+            w.u8(self.imu.sensor as u8);
+            w.u8(self.imu.range);
+            for value in [calibration.pitch, calibration.roll].iter().chain(&calibration.gyro) {
+                w.u16(*value as u16);
+            }
+        });
+        w.section(tags::TOUCH, |w| {
+            w.u8(self.ribbon);
+            w.u8(self.touch_pads);
+        });
+        w.section(tags::DRUMS, |w| {
+            w.u8(self.drums.pads);
+            w.u8(self.drums.sensor as u8);
+            w.u16(self.drums.threshold);
+            w.u8(self.drums.mask);
+            w.u8(self.drums.crosstalk);
+        });
+        w.section(tags::AFTERTOUCH, |w| w.u8(self.aftertouch as u8));
+        w.section(tags::PEDALS, |w| {
+            for mode in self.pedals {
+                w.u8(mode as u8);
+            }
+            w.u8(self.sustain);
+        });
+        w.section(tags::SEQUENCER, |w| {
+            w.u8(self.sequencer.source as u8);
+            w.u16(self.sequencer.bpm);
+            w.u8(self.sequencer.pattern);
+            w.u8(self.sequencer.grid as u8);
+            // Steps past the end of a track are not kept
+            for track in self.patterns.iter().flat_map(|pattern| &pattern.tracks) {
+                w.u8(track.channel);
+                w.u8(track.length);
+                w.u8(track.rate);
+                w.u8(track.muted as u8);
+                for step in &track.steps[..track.length as usize] {
+                    w.bytes(&[step.note, step.velocity, step.gate, step.probability, step.ratchet]);
+                }
+            }
+            w.u8(self.sequencer.swing);
+        });
+        w.section(tags::ARP, |w| {
+            w.u8(self.arp.mode as u8);
+            w.u8(self.arp.octaves);
+            w.u8(self.arp.rate);
+            w.u8(self.arp.gate);
+            w.u8(self.arp.latch as u8);
+            w.u8(self.arp.chord.len);
+            w.bytes(self.arp.chord.as_slice());
+        });
+        w.section(tags::KEYMAP, |w| {
+            w.u8(self.keymap.layout as u8);
+            w.u8(self.keymap.columns);
+            w.u8(self.keymap.octave);
+            w.u8(self.keymap.key.scale as u8);
+            w.u8(self.keymap.key.root);
+        });
+        w.section(tags::TAKEOVER, |w| w.u8(self.pickup_led.unwrap_or(NO_LED)));
+        w.section(tags::BANKS, |w| {
+            w.u8(self.banks.count);
+            w.u8(self.banks.cc.unwrap_or(NO_CC));
+        });
+        w.section(tags::MACROS, |w| {
+            for m in &self.macros {
+                m.encode(w);
+            }
+        });
+    }
+
+    fn decode_section(&mut self, tag: u8, r: &mut Reader) -> Result<(), ConfigError> {
+        match tag {
+            tags::SETTINGS => {
+                self.channel = r.u8_below(16)?;
+                self.velocity = r.u8_below(128)?;
+            }
+            tags::HIRES => {
+                self.hires.lsb_first = r.u8_below(2)? != 0;
+                self.hires.null_terminate = r.u8_below(2)? != 0;
+            }
+            tags::MPE => {
+                let lower = r.u8_below(mpe::MAX_MEMBERS + 1)?;
+                let upper = r.u8_below(mpe::MAX_MEMBERS + 1)?;
+                if lower + upper > 14 && lower.min(upper) > 0 {
+                    return Err(ConfigError::Invalid);
+                }
+                self.mpe.lower = lower;
+                self.mpe.upper = upper;
+                self.mpe.bend_range = r.u8_below(97)?;
+                self.mpe.steal = mpe::Steal::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+            }
+            tags::MAPPINGS => self.mappings.decode(r)?,
+            tags::SYSEX_TEMPLATES => self.mappings.decode_templates(r)?,
+            tags::CURVES => self.mappings.decode_curves(r)?,
+            tags::FEEDBACK => self.feedback.decode(r)?,
+            tags::LEDS => {
+                self.leds.brightness = r.u8()?;
+                self.leds.animation = led::Animation::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+            }
+            tags::STRIP => self.pixels = r.u8_below(led::MAX_PIXELS as u8 + 1)?,
+            tags::SHIFT => {
+                self.shift_outputs = r.u8_below(led::MAX_SHIFT_OUTPUTS as u8 / 8 + 1)?;
+                self.shift_inputs = r.u8_below(input::MAX_SHIFT_INPUTS as u8 / 8 + 1)?;
+            }
+            tags::DISPLAY => self.display = display::Controller::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?,
+            tags::FADERS => self.faders = r.u8_below(fader::MAX_FADERS as u8 + 1)?,
+            tags::SURFACE => self.surface = mcu::Protocol::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?,
+            tags::IMU => {
+                self.imu.sensor = motion::Sensor::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.imu.range = match r.u8()? {
+                    range @ 1..=motion::MAX_RANGE => range,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                let calibration = &mut self.imu.calibration;
+                calibration.pitch = r.u16()? as i16;
+                calibration.roll = r.u16()? as i16;
+                for rate in calibration.gyro.iter_mut() {
+                    *rate = r.u16()? as i16;
+                }
+            }
+            tags::TOUCH => {
+                self.ribbon = r.u8_below(capsense::MAX_CHANNELS as u8 + 1)?;
+                self.touch_pads = r.u8_below(capsense::MAX_CHANNELS as u8 + 1)?;
+            }
+            tags::DRUMS => {
+                self.drums.pads = r.u8_below(drum::MAX_PADS as u8 + 1)?;
+                self.drums.sensor = drum::Sensor::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.drums.threshold = match r.u16()? {
+                    threshold @ 1..drum::FULL_LEVEL => threshold,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.drums.mask = r.u8_below(drum::MAX_MASK_MS + 1)?;
+                self.drums.crosstalk = r.u8_below(101)?;
+            }
+            tags::AFTERTOUCH => self.aftertouch = Aftertouch::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?,
+            tags::PEDALS => {
+                for mode in self.pedals.iter_mut() {
+                    *mode = pedal::Mode::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                }
+                self.sustain = r.u8_below(pedal::MAX_SUSTAIN as u8 + 1)?;
+            }
+            tags::SEQUENCER => {
+                self.sequencer.source = sequencer::ClockSource::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.sequencer.bpm = match r.u16()? {
+                    bpm @ sequencer::MIN_BPM..=sequencer::MAX_BPM => bpm,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.sequencer.pattern = r.u8_below(sequencer::MAX_PATTERNS as u8)?;
+                self.sequencer.grid = r.u8_below(2)? != 0;
+                for (index, track) in self.patterns.iter_mut().flat_map(|pattern| &mut pattern.tracks).enumerate() {
+                    let mut decoded = Pattern::new().tracks[index % sequencer::MAX_TRACKS];
+                    decoded.channel = r.u8_below(16)?;
+                    decoded.length = r.u8()?;
+                    decoded.rate = r.u8()?;
+                    if !(1..=sequencer::MAX_STEPS).contains(&(decoded.length as usize))
+                        || !(1..=sequencer::MAX_RATE).contains(&decoded.rate)
+                    {
+                        return Err(ConfigError::Invalid);
+                    }
+                    decoded.muted = r.u8_below(2)? != 0;
+                    for step in decoded.steps[..decoded.length as usize].iter_mut() {
+                        let b = r.bytes(5)?;
+                        *step = Step { note: b[0], velocity: b[1], gate: b[2], probability: b[3], ratchet: b[4] };
+                        if !step.is_valid() {
+                            return Err(ConfigError::Invalid);
+                        }
+                    }
+                    *track = decoded;
+                }
+                self.sequencer.swing = match r.u8()? {
+                    swing @ sequencer::MIN_SWING..=sequencer::MAX_SWING => swing,
+                    _ => return Err(ConfigError::Invalid),
+                };
+            }
+            tags::ARP => {
+                self.arp.mode = arp::Mode::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.arp.octaves = match r.u8()? {
+                    octaves @ 1..=arp::MAX_OCTAVES => octaves,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.arp.rate = match r.u8()? {
+                    rate @ 1..=sequencer::MAX_RATE => rate,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.arp.gate = match r.u8()? {
+                    gate @ 1..=100 => gate,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.arp.latch = r.u8_below(2)? != 0;
+                let mut chord = arp::Chord::NONE;
+                for _ in 0..r.u8_below(arp::MAX_CHORD as u8)? {
+                    match r.u8()? {
+                        interval @ 1..=arp::Chord::MAX_INTERVAL => chord.add(interval),
+                        _ => return Err(ConfigError::Invalid),
+                    };
+                }
+                self.arp.chord = chord;
+            }
+            tags::KEYMAP => {
+                self.keymap.layout = Layout::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.keymap.columns = match r.u8()? {
+                    columns @ 1..=keymap::MAX_COLUMNS => columns,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.keymap.octave = r.u8_below(keymap::MAX_OCTAVE + 1)?;
+                self.keymap.key.scale = Scale::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+                self.keymap.key.root = r.u8_below(12)?;
+            }
+            tags::TAKEOVER => {
+                self.pickup_led = match r.u8()? {
+                    NO_LED => None,
+                    led if led <= led::MAX_LED => Some(led),
+                    _ => return Err(ConfigError::Invalid),
+                }
+            }
+            tags::BANKS => {
+                self.banks.count = match r.u8()? {
+                    count @ 1..=mapping::MAX_BANKS => count,
+                    _ => return Err(ConfigError::Invalid),
+                };
+                self.banks.cc = match r.u8()? {
+                    NO_CC => None,
+                    cc if cc < 128 => Some(cc),
+                    _ => return Err(ConfigError::Invalid),
+                };
+            }
+            tags::MACROS => {
+                for m in self.macros.iter_mut() {
+                    *m = Macro::decode(r)?;
+                }
+            }
+            // Sections from a newer firmware are ignored
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether a motorised fader and an expression jack both claim a wiper
+    /// input: jack 1 shares PE2 with fader 1, jack 2 PE3 with fader 0.
+    pub fn wipers_shared(&self) -> bool {
+        (0..self.faders as usize).any(|fader| self.pedals[pedal::JACKS - 1 - fader] != pedal::Mode::Off)
+    }
+
+    /// Erase the region and write this configuration to it.
+    pub fn save(&self, storage: &mut dyn Storage) -> Result<usize, ConfigError> {
+        storage.erase()?;
+        // The header is programmed last so an interrupted save reads as empty
+        let mut w = Writer::new(Some(&mut *storage), HEADER_LEN);
+        self.encode(&mut w);
+        let (end, crc) = w.finish()?;
+        let length = end - HEADER_LEN;
+        if length > u16::MAX as usize {
+            return Err(ConfigError::Storage(StorageError::Full));
+        }
+        storage.program(0, MAGIC)?;
+        storage.program(4, VERSION as u32 | (length as u32) << 16)?;
+        storage.program(8, crc)?;
+        Ok(end)
+    }
+
+    /// Read a configuration saved by `save`.
+    pub fn load(storage: &dyn Storage) -> Result<Self, ConfigError> {
+        let mut header = Reader::new(storage.contents());
+        let magic = header.u32().map_err(|_| ConfigError::Empty)?;
+        if magic == 0xFFFF_FFFF {
+            return Err(ConfigError::Empty);
+        }
+        if magic != MAGIC {
+            return Err(ConfigError::Corrupt);
+        }
+        let version = header.u16()?;
+        let length = header.u16()? as usize;
+        let crc = header.u32()?;
+        if version > VERSION {
+            return Err(ConfigError::Version);
+        }
+        let payload = header.bytes(length).map_err(|_| ConfigError::Corrupt)?;
+        if !crc32_update(0xFFFF_FFFF, payload) != crc {
+            return Err(ConfigError::Corrupt);
+        }
+
+        let mut config = Config::new();
+        let mut r = Reader::new(payload);
+        while r.remaining() > 0 {
+            let tag = r.u8()?;
+            let len = r.u16()? as usize;
+            let mut section = Reader::new(r.bytes(len)?);
+            config.decode_section(tag, &mut section)?;
+        }
+        if config.wipers_shared() {
+            return Err(ConfigError::Invalid);
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Named Parameters
+// ============================================================================
+
+/// A scalar setting reachable by name from the shell
+pub struct Param {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub min: i32,
+    pub max: i32,
+    pub get: fn(&Config) -> i32,
+    pub set: fn(&mut Config, i32),
+}
+
+pub const PARAMS: &[Param] = &[
+    Param {
+        name: "channel",
+        help: "default MIDI channel",
+        min: 1,
+        max: 16,
+        get: |c| c.channel as i32 + 1,
+        set: |c, v| c.channel = (v - 1) as u8,
+    },
+    Param {
+        name: "velocity",
+        help: "fixed note-on velocity",
+        min: 1,
+        max: 127,
+        get: |c| c.velocity as i32,
+        set: |c, v| c.velocity = v as u8,
+    },
+    Param {
+        name: "lsbfirst",
+        help: "send 14-bit values LSB first",
+        min: 0,
+        max: 1,
+        get: |c| c.hires.lsb_first as i32,
+        set: |c, v| c.hires.lsb_first = v != 0,
+    },
+    Param {
+        name: "rpnnull",
+        help: "end NRPN/RPN updates with RPN null",
+        min: 0,
+        max: 1,
+        get: |c| c.hires.null_terminate as i32,
+        set: |c, v| c.hires.null_terminate = v != 0,
+    },
+    Param {
+        name: "mpelower",
+        help: "MPE lower zone member channels (0 = off)",
+        min: 0,
+        max: 15,
+        get: |c| c.mpe.lower as i32,
+        set: |c, v| c.mpe.set_members(mpe::Zone::Lower, v as u8),
+    },
+    Param {
+        name: "mpeupper",
+        help: "MPE upper zone member channels (0 = off)",
+        min: 0,
+        max: 15,
+        get: |c| c.mpe.upper as i32,
+        set: |c, v| c.mpe.set_members(mpe::Zone::Upper, v as u8),
+    },
+    Param {
+        name: "mpebend",
+        help: "MPE per-note pitch bend range in semitones",
+        min: 1,
+        max: 96,
+        get: |c| c.mpe.bend_range as i32,
+        set: |c, v| c.mpe.bend_range = v as u8,
+    },
+    Param {
+        name: "mpesteal",
+        help: "MPE voice stealing: 0 oldest, 1 lowest, 2 highest, 3 never",
+        min: 0,
+        max: 3,
+        get: |c| c.mpe.steal as i32,
+        set: |c, v| c.mpe.steal = mpe::Steal::from_u8(v as u8).unwrap_or(mpe::Steal::Oldest),
+    },
+    Param {
+        name: "ledbright",
+        help: "RGB LED brightness",
+        min: 0,
+        max: 255,
+        get: |c| c.leds.brightness as i32,
+        set: |c, v| c.leds.brightness = v as u8,
+    },
+    Param {
+        name: "ledanim",
+        help: "RGB LED animation: 0 steady, 1 breathe, 2 clock beat, 3 flash on MIDI",
+        min: 0,
+        max: 3,
+        get: |c| c.leds.animation as i32,
+        set: |c, v| c.leds.animation = led::Animation::from_u8(v as u8).unwrap_or(led::Animation::Steady),
+    },
+    Param {
+        name: "pixels",
+        help: "WS2812 strip length on PA5 (0 = none)",
+        min: 0,
+        max: led::MAX_PIXELS as i32,
+        get: |c| c.pixels as i32,
+        set: |c, v| c.pixels = v as u8,
+    },
+    Param {
+        name: "srout",
+        help: "74HC595 output chips on SSI2 (8 LEDs each)",
+        min: 0,
+        max: led::MAX_SHIFT_OUTPUTS as i32 / 8,
+        get: |c| c.shift_outputs as i32,
+        set: |c, v| c.shift_outputs = v as u8,
+    },
+    Param {
+        name: "srin",
+        help: "74HC165 input chips on SSI2 (8 buttons each)",
+        min: 0,
+        max: input::MAX_SHIFT_INPUTS as i32 / 8,
+        get: |c| c.shift_inputs as i32,
+        set: |c, v| c.shift_inputs = v as u8,
+    },
+    Param {
+        name: "display",
+        help: "OLED on I2C0: 0 none, 1 SSD1306, 2 SH1106",
+        min: 0,
+        max: 2,
+        get: |c| c.display as i32,
+        set: |c, v| c.display = display::Controller::from_u8(v as u8).unwrap_or(display::Controller::None),
+    },
+    Param {
+        name: "faders",
+        help: "Motorised faders on PE2-PE5, PC4/PC5 and PA6/PA7",
+        min: 0,
+        max: fader::MAX_FADERS as i32,
+        get: |c| c.faders as i32,
+        set: |c, v| c.faders = v as u8,
+    },
+    Param {
+        name: "surface",
+        help: "Control surface protocol: 0 off (use mappings), 1 Mackie Control, 2 HUI",
+        min: 0,
+        max: 2,
+        get: |c| c.surface as i32,
+        set: |c, v| c.surface = mcu::Protocol::from_u8(v as u8).unwrap_or(mcu::Protocol::Off),
+    },
+    Param {
+        name: "imu",
+        help: "IMU on I2C3 (PD0/PD1): 0 none, 1 MPU6050, 2 MPU9150, 3 LSM303DLHC",
+        min: 0,
+        max: 3,
+        get: |c| c.imu.sensor as i32,
+        set: |c, v| c.imu.sensor = motion::Sensor::from_u8(v as u8).unwrap_or(motion::Sensor::None),
+    },
+    Param {
+        name: "imurange",
+        help: "IMU tilt in degrees either way for the full pitch and roll range",
+        min: 1,
+        max: motion::MAX_RANGE as i32,
+        get: |c| c.imu.range as i32,
+        set: |c, v| c.imu.range = v as u8,
+    },
+    Param {
+        name: "ribbon",
+        help: "Capacitive ribbon electrodes, from the first touch pin (PE0 PE1 PD2 PD3 PC6 PC7 PB0 PB1)",
+        min: 0,
+        max: capsense::MAX_CHANNELS as i32,
+        get: |c| c.ribbon as i32,
+        set: |c, v| c.ribbon = v as u8,
+    },
+    Param {
+        name: "touchpads",
+        help: "Capacitive touch pads, on the touch pins after the ribbon",
+        min: 0,
+        max: capsense::MAX_CHANNELS as i32,
+        get: |c| c.touch_pads as i32,
+        set: |c, v| c.touch_pads = v as u8,
+    },
+    Param {
+        name: "drums",
+        help: "Drum pads on PE0 PE1 PD2 PD3 (AIN3 AIN2 AIN5 AIN4), taking those touch pins",
+        min: 0,
+        max: drum::MAX_PADS as i32,
+        get: |c| c.drums.pads as i32,
+        set: |c, v| c.drums.pads = v as u8,
+    },
+    Param {
+        name: "drumsensor",
+        help: "Drum pad sensors: 0 piezo, 1 FSR (with aftertouch)",
+        min: 0,
+        max: 1,
+        get: |c| c.drums.sensor as i32,
+        set: |c, v| c.drums.sensor = drum::Sensor::from_u8(v as u8).unwrap_or(drum::Sensor::Piezo),
+    },
+    Param {
+        name: "drumthresh",
+        help: "Drum pad level that starts a hit (ADC counts)",
+        min: 1,
+        max: drum::FULL_LEVEL as i32 - 1,
+        get: |c| c.drums.threshold as i32,
+        set: |c, v| c.drums.threshold = v as u16,
+    },
+    Param {
+        name: "drummask",
+        help: "Drum pad retrigger mask in ms",
+        min: 0,
+        max: drum::MAX_MASK_MS as i32,
+        get: |c| c.drums.mask as i32,
+        set: |c, v| c.drums.mask = v as u8,
+    },
+    Param {
+        name: "drumxtalk",
+        help: "Drum pad crosstalk rejection, percent of a neighbour's peak (0 = off)",
+        min: 0,
+        max: 100,
+        get: |c| c.drums.crosstalk as i32,
+        set: |c, v| c.drums.crosstalk = v as u8,
+    },
+    Param {
+        name: "aftertouch",
+        help: "Pressure on held notes: 0 off, 1 polyphonic, 2 channel",
+        min: 0,
+        max: 2,
+        get: |c| c.aftertouch as i32,
+        set: |c, v| c.aftertouch = Aftertouch::from_u8(v as u8).unwrap_or(Aftertouch::Off),
+    },
+    Param {
+        name: "pickupled",
+        help: "LED that flashes while a control waits to pick up the remote value, -1 none",
+        min: -1,
+        max: led::MAX_LED as i32,
+        get: |c| c.pickup_led.map_or(-1, i32::from),
+        set: |c, v| c.pickup_led = u8::try_from(v).ok(),
+    },
+    Param {
+        name: "banks",
+        help: "Banks the next and previous bank buttons step through",
+        min: 1,
+        max: mapping::MAX_BANKS as i32,
+        get: |c| c.banks.count as i32,
+        set: |c, v| c.banks.count = v as u8,
+    },
+    Param {
+        name: "bankcc",
+        help: "Controller on the global channel that reports and selects the bank (value bank - 1), -1 off",
+        min: -1,
+        max: 127,
+        get: |c| c.banks.cc.map_or(-1, i32::from),
+        set: |c, v| c.banks.cc = u8::try_from(v).ok(),
+    },
+    Param {
+        name: "pedal1",
+        help: "Expression jack 1 on PE2 (fader 1 wiper): 0 off, 1 auto, 2 expression pedal, 3 switch",
+        min: 0,
+        max: 3,
+        get: |c| c.pedals[0] as i32,
+        set: |c, v| c.pedals[0] = pedal::Mode::from_u8(v as u8).unwrap_or(pedal::Mode::Off),
+    },
+    Param {
+        name: "pedal2",
+        help: "Expression jack 2 on PE3 (fader 0 wiper): 0 off, 1 auto, 2 expression pedal, 3 switch",
+        min: 0,
+        max: 3,
+        get: |c| c.pedals[1] as i32,
+        set: |c, v| c.pedals[1] = pedal::Mode::from_u8(v as u8).unwrap_or(pedal::Mode::Off),
+    },
+    Param {
+        name: "sustain",
+        help: "Sustain jacks on PA2 and PA3",
+        min: 0,
+        max: pedal::MAX_SUSTAIN as i32,
+        get: |c| c.sustain as i32,
+        set: |c, v| c.sustain = v as u8,
+    },
+    Param {
+        name: "seqclock",
+        help: "Step sequencer clock: 0 internal (sent as MIDI clock), 1 external MIDI clock",
+        min: 0,
+        max: 1,
+        get: |c| c.sequencer.source as i32,
+        set: |c, v| {
+            c.sequencer.source =
+                sequencer::ClockSource::from_u8(v as u8).unwrap_or(sequencer::ClockSource::Internal)
+        },
+    },
+    Param {
+        name: "seqbpm",
+        help: "Step sequencer tempo of the internal clock",
+        min: sequencer::MIN_BPM as i32,
+        max: sequencer::MAX_BPM as i32,
+        get: |c| c.sequencer.bpm as i32,
+        set: |c, v| c.sequencer.bpm = v as u16,
+    },
+    Param {
+        name: "seqpattern",
+        help: "Step sequencer pattern played and edited",
+        min: 1,
+        max: sequencer::MAX_PATTERNS as i32,
+        get: |c| c.sequencer.pattern as i32 + 1,
+        set: |c, v| c.sequencer.pattern = (v - 1) as u8,
+    },
+    Param {
+        name: "seqgrid",
+        help: "Shift register buttons and LEDs are the step grid (4 rows of 16)",
+        min: 0,
+        max: 1,
+        get: |c| c.sequencer.grid as i32,
+        set: |c, v| c.sequencer.grid = v != 0,
+    },
+    Param {
+        name: "seqswing",
+        help: "Step sequencer swing: percent of a pair of steps taken by the first, 50 straight, 66 triplet feel",
+        min: sequencer::MIN_SWING as i32,
+        max: sequencer::MAX_SWING as i32,
+        get: |c| c.sequencer.swing as i32,
+        set: |c, v| c.sequencer.swing = v as u8,
+    },
+    Param {
+        name: "arp",
+        help: "Arpeggiator on played notes: 0 off, 1 up, 2 down, 3 random, 4 as played",
+        min: 0,
+        max: 4,
+        get: |c| c.arp.mode as i32,
+        set: |c, v| c.arp.mode = arp::Mode::from_u8(v as u8).unwrap_or(arp::Mode::Off),
+    },
+    Param {
+        name: "arpoct",
+        help: "Arpeggiator range in octaves",
+        min: 1,
+        max: arp::MAX_OCTAVES as i32,
+        get: |c| c.arp.octaves as i32,
+        set: |c, v| c.arp.octaves = v as u8,
+    },
+    Param {
+        name: "arprate",
+        help: "Arpeggiator MIDI clocks per note (6 = 1/16, 12 = 1/8), on the sequencer clock",
+        min: 1,
+        max: sequencer::MAX_RATE as i32,
+        get: |c| c.arp.rate as i32,
+        set: |c, v| c.arp.rate = v as u8,
+    },
+    Param {
+        name: "arpgate",
+        help: "Arpeggiator note length in percent of a step",
+        min: 1,
+        max: 100,
+        get: |c| c.arp.gate as i32,
+        set: |c, v| c.arp.gate = v as u8,
+    },
+    Param {
+        name: "arplatch",
+        help: "Arpeggiator keeps playing released notes until a new chord",
+        min: 0,
+        max: 1,
+        get: |c| c.arp.latch as i32,
+        set: |c, v| c.arp.latch = v != 0,
+    },
+    Param {
+        name: "scale",
+        help: "Quantise played notes: 0 chromatic (off), 1 major, 2 minor, 3 dorian, 4 phrygian, \
+               5 lydian, 6 mixolydian, 7 locrian, 8 harmonic minor, 9 major pentatonic, 10 minor pentatonic",
+        min: 0,
+        max: Scale::ALL.len() as i32 - 1,
+        get: |c| c.keymap.key.scale as i32,
+        set: |c, v| c.keymap.key.scale = Scale::from_u8(v as u8).unwrap_or(Scale::Chromatic),
+    },
+    Param {
+        name: "root",
+        help: "Root of the scale and of pad 0: 0 C, 1 C#, ... 11 B",
+        min: 0,
+        max: 11,
+        get: |c| c.keymap.key.root as i32,
+        set: |c, v| c.keymap.key.root = v as u8,
+    },
+    Param {
+        name: "layout",
+        help: "Pad notes: 0 chromatic, 1 in key (rows a fourth apart), 2 fourths",
+        min: 0,
+        max: Layout::ALL.len() as i32 - 1,
+        get: |c| c.keymap.layout as i32,
+        set: |c, v| c.keymap.layout = Layout::from_u8(v as u8).unwrap_or(Layout::Chromatic),
+    },
+    Param {
+        name: "columns",
+        help: "Pads per row of the pad keymap",
+        min: 1,
+        max: keymap::MAX_COLUMNS as i32,
+        get: |c| c.keymap.columns as i32,
+        set: |c, v| c.keymap.columns = v as u8,
+    },
+    Param {
+        name: "octave",
+        help: "Octave of pad 0, from note 0 (3 = note 36, 5 = middle C)",
+        min: 0,
+        max: keymap::MAX_OCTAVE as i32,
+        get: |c| c.keymap.octave as i32,
+        set: |c, v| c.keymap.octave = v as u8,
+    },
+];
+
+pub fn find_param(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|p| p.name == name)
+}
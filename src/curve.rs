@@ -0,0 +1,248 @@
+//! Value Curves
+//!
+//! Transfer functions applied to control values before they are scaled to
+//! their MIDI range: velocities, pots, faders, pedals and pressure alike.
+//! Values are 14-bit (`0..=FULL_SCALE`) and all maths is integer only.
+//!
+//! Besides the built-in shapes there are `USER_CURVES` tables of `POINTS`
+//! points, evenly spaced over the input range and joined by straight
+//! lines. They are kept with the mappings and edited with `curve` in the
+//! shell or as the `X-Curves` MIDI-CI property.
+
+use crate::config::{ConfigError, Reader, Writer};
+use crate::input::FULL_SCALE;
+
+/// User-defined tables
+pub const USER_CURVES: usize = 4;
+/// Points of a user table
+pub const POINTS: usize = 16;
+/// Largest point value
+pub const POINT_MAX: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Curve {
+    Linear = 0,
+    /// Fast rise, fine control at the top
+    Log = 1,
+    /// Slow rise, fine control at the bottom
+    Exp = 2,
+    /// Fine control at both ends
+    Sigmoid = 3,
+    /// Full scale for anything but 0, so velocity comes from `max`
+    Fixed = 4,
+    User1 = 5,
+    User2 = 6,
+    User3 = 7,
+    User4 = 8,
+}
+
+impl Curve {
+    pub const ALL: [Curve; 9] = [
+        Curve::Linear,
+        Curve::Log,
+        Curve::Exp,
+        Curve::Sigmoid,
+        Curve::Fixed,
+        Curve::User1,
+        Curve::User2,
+        Curve::User3,
+        Curve::User4,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Curve::Linear => "lin",
+            Curve::Log => "log",
+            Curve::Exp => "exp",
+            Curve::Sigmoid => "s",
+            Curve::Fixed => "fixed",
+            Curve::User1 => "user1",
+            Curve::User2 => "user2",
+            Curve::User3 => "user3",
+            Curve::User4 => "user4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Curve> {
+        Curve::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    pub fn from_u8(value: u8) -> Option<Curve> {
+        Curve::ALL.get(value as usize).copied()
+    }
+
+    /// Index of a user table, `None` for the built-in curves
+    pub fn user(self) -> Option<usize> {
+        (self as usize).checked_sub(Curve::User1 as usize)
+    }
+
+    /// Map `x` in `0..=FULL_SCALE` onto the curve, user curves through
+    /// their table in `tables`.
+    pub fn apply(self, x: u16, tables: &[Table; USER_CURVES]) -> u16 {
+        let f = FULL_SCALE as u64;
+        let x = x.min(FULL_SCALE) as u64;
+        let y = match self {
+            Curve::Linear => x,
+            Curve::Log => f - (f - x) * (f - x) / f,
+            Curve::Exp => x * x / f,
+            // Smoothstep 3t^2 - 2t^3
+            Curve::Sigmoid => (3 * x * x * f - 2 * x * x * x) / (f * f),
+            Curve::Fixed if x > 0 => f,
+            Curve::Fixed => 0,
+            Curve::User1 | Curve::User2 | Curve::User3 | Curve::User4 => {
+                tables[self as usize - Curve::User1 as usize].apply(x as u16) as u64
+            }
+        };
+        y as u16
+    }
+}
+
+/// User curve: the output at `POINTS` evenly spaced inputs, 0 to
+/// `POINT_MAX` for no to full output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Table {
+    pub points: [u8; POINTS],
+}
+
+impl Table {
+    /// A straight line, what new tables start as
+    pub const LINEAR: Table = Table { points: [0, 8, 17, 25, 34, 42, 51, 59, 68, 76, 85, 93, 102, 110, 119, 127] };
+
+    /// Table following a built-in curve, as a starting point for editing
+    pub fn from_curve(curve: Curve) -> Table {
+        let mut table = Table::LINEAR;
+        let tables = [Table::LINEAR; USER_CURVES];
+        for (i, point) in table.points.iter_mut().enumerate() {
+            let x = (i as u32 * FULL_SCALE as u32 / (POINTS as u32 - 1)) as u16;
+            let y = curve.apply(x, &tables) as u32;
+            *point = ((y * POINT_MAX as u32 + FULL_SCALE as u32 / 2) / FULL_SCALE as u32) as u8;
+        }
+        table
+    }
+
+    /// Output for `x`, interpolated between the two points around it.
+    pub fn apply(&self, x: u16) -> u16 {
+        let full = FULL_SCALE as i32;
+        let segments = POINTS as i32 - 1;
+        // Position in segments, in FULL_SCALE steps per segment
+        let position = x.min(FULL_SCALE) as i32 * segments;
+        let index = (position / full).min(segments - 1);
+        let fraction = position - index * full;
+        let (a, b) = (self.points[index as usize] as i32, self.points[index as usize + 1] as i32);
+        // Point value times FULL_SCALE, then scaled to 14 bits and rounded
+        let y = a * full + (b - a) * fraction;
+        ((y + POINT_MAX as i32 / 2) / POINT_MAX as i32) as u16
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        w.bytes(&self.points);
+    }
+
+    pub fn decode(r: &mut Reader) -> Result<Self, ConfigError> {
+        let mut points = [0; POINTS];
+        for point in points.iter_mut() {
+            *point = r.u8_below(POINT_MAX + 1)?;
+        }
+        Ok(Self { points })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Input on point `i`
+    fn at(i: u32) -> u16 {
+        (i * FULL_SCALE as u32).div_ceil(POINTS as u32 - 1) as u16
+    }
+
+    /// User tables following every built-in curve, and a falling one
+    fn tables() -> [Table; USER_CURVES] {
+        let mut falling = Table::LINEAR;
+        falling.points.reverse();
+        [Table::from_curve(Curve::Log), Table::from_curve(Curve::Exp), Table::from_curve(Curve::Sigmoid), falling]
+    }
+
+    #[test]
+    fn keeps_the_ends() {
+        let tables = [Table::LINEAR; USER_CURVES];
+        for curve in Curve::ALL {
+            assert_eq!(curve.apply(0, &tables), 0, "{}", curve.name());
+            assert_eq!(curve.apply(FULL_SCALE, &tables), FULL_SCALE, "{}", curve.name());
+            // Out of range input is held to full scale
+            assert_eq!(curve.apply(u16::MAX, &tables), FULL_SCALE, "{}", curve.name());
+        }
+        assert_eq!(Curve::Fixed.apply(1, &tables), FULL_SCALE);
+    }
+
+    #[test]
+    fn rises() {
+        let tables = tables();
+        for curve in &Curve::ALL[..Curve::ALL.len() - 1] {
+            let mut last = 0;
+            for x in 0..=FULL_SCALE {
+                let y = curve.apply(x, &tables);
+                assert!(y >= last && y <= FULL_SCALE, "{} at {}", curve.name(), x);
+                last = y;
+            }
+        }
+
+        // The shapes lie where they should against a straight line
+        let half = FULL_SCALE / 2;
+        for x in [1000, 4000, half, 12000, 15000] {
+            assert!(Curve::Log.apply(x, &tables) > x);
+            assert!(Curve::Exp.apply(x, &tables) < x);
+        }
+        assert!(Curve::Sigmoid.apply(4000, &tables) < 4000);
+        assert!(Curve::Sigmoid.apply(12000, &tables) > 12000);
+        assert!(Curve::Sigmoid.apply(half, &tables).abs_diff(half) <= 1);
+    }
+
+    #[test]
+    fn interpolates_tables() {
+        // On the points, rounded to 14 bits and to the input nearest them
+        let table = tables()[1];
+        for (i, &point) in table.points.iter().enumerate() {
+            let y = (point as u32 * FULL_SCALE as u32 + POINT_MAX as u32 / 2) / POINT_MAX as u32;
+            assert!((table.apply(at(i as u32)) as u32).abs_diff(y) <= 1, "point {}", i);
+        }
+
+        // Straight lines between them
+        let mut steps = Table::LINEAR;
+        steps.points[3] = 20;
+        steps.points[4] = 100;
+        let full = FULL_SCALE as f64;
+        for x in at(3)..at(4) {
+            let point = 20.0 + 80.0 * (x as f64 * (POINTS - 1) as f64 / full - 3.0);
+            let y = (point * full / POINT_MAX as f64).round() as u16;
+            assert!(steps.apply(x).abs_diff(y) <= 1, "{}", x);
+        }
+
+        // The linear table is a straight line, within the rounding of its points
+        for x in (0..=FULL_SCALE).step_by(97) {
+            assert!(Table::LINEAR.apply(x).abs_diff(x) <= FULL_SCALE / 254 + 1, "{}", x);
+        }
+        assert_eq!(Table::from_curve(Curve::Linear), Table::LINEAR);
+
+        // A falling table falls, out of range input stays on the last point
+        let falling = tables()[3];
+        assert_eq!((falling.apply(0), falling.apply(FULL_SCALE), falling.apply(u16::MAX)), (FULL_SCALE, 0, 0));
+        assert!(Curve::User4.apply(4000, &tables()) > Curve::User4.apply(12000, &tables()));
+    }
+
+    #[test]
+    fn decodes_tables() {
+        let mut measure = Writer::measure();
+        Table::LINEAR.encode(&mut measure);
+        assert_eq!(measure.len(), POINTS);
+
+        let table = Table::from_curve(Curve::Sigmoid);
+        assert_eq!(Table::decode(&mut Reader::new(&table.points)), Ok(table));
+
+        let mut bytes = table.points;
+        bytes[7] = POINT_MAX + 1;
+        assert_eq!(Table::decode(&mut Reader::new(&bytes)), Err(ConfigError::Invalid));
+        assert_eq!(Table::decode(&mut Reader::new(&table.points[..POINTS - 1])), Err(ConfigError::Truncated));
+    }
+}
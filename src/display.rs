@@ -0,0 +1,384 @@
+//! Display
+//!
+//! A 128x64 monochrome frame buffer with just enough drawing for the status
+//! screen: filled rectangles and text in a 5x7 font, optionally scaled. The
+//! buffer is laid out like SSD1306/SH1106 memory, eight pages of 128 column
+//! bytes with bit 0 at the top, so the driver can send a page as it is.
+//! Every page keeps a checksum of what was last sent; only pages that
+//! changed are sent again.
+//!
+//! The status screen shows the last touched control with its mapping and
+//! value, the current program (preset) and the tempo of incoming MIDI clock.
+
+use core::fmt::{self, Write};
+
+use crate::capsense;
+use crate::drum;
+use crate::input::{self, ControlId, FULL_SCALE};
+use crate::mapping::Action;
+use crate::midi::MidiMessage;
+use crate::motion;
+use crate::pedal;
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+pub const PAGES: usize = HEIGHT / 8;
+
+/// Display controller on I2C
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Controller {
+    None = 0,
+    Ssd1306 = 1,
+    /// 132 columns wide, the picture starts at column 2
+    Sh1106 = 2,
+}
+
+impl Controller {
+    pub const ALL: [Controller; 3] = [Controller::None, Controller::Ssd1306, Controller::Sh1106];
+
+    pub fn from_u8(value: u8) -> Option<Controller> {
+        Controller::ALL.get(value as usize).copied()
+    }
+}
+
+// ============================================================================
+// Frame buffer
+// ============================================================================
+
+pub struct Canvas {
+    pixels: [[u8; WIDTH]; PAGES],
+    /// Checksums of the pages as last sent
+    sent: [u32; PAGES],
+}
+
+impl Canvas {
+    pub const fn new() -> Self {
+        // No page matches, so the first frame is sent in full
+        Self { pixels: [[0; WIDTH]; PAGES], sent: [u32::MAX; PAGES] }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = [[0; WIDTH]; PAGES];
+    }
+
+    /// Set pixels in a rectangle, clipped to the screen
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, on: bool) {
+        for y in y..(y + height).min(HEIGHT) {
+            let bit = 1 << (y % 8);
+            for column in &mut self.pixels[y / 8][x.min(WIDTH)..(x + width).min(WIDTH)] {
+                if on {
+                    *column |= bit;
+                } else {
+                    *column &= !bit;
+                }
+            }
+        }
+    }
+
+    /// Outline of a rectangle
+    pub fn frame(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.fill(x, y, width, 1, true);
+        self.fill(x, y + height - 1, width, 1, true);
+        self.fill(x, y, 1, height, true);
+        self.fill(x + width - 1, y, 1, height, true);
+    }
+
+    /// Draw one character at `scale` times the font size, returns the
+    /// advance.
+    pub fn glyph(&mut self, x: usize, y: usize, c: char, scale: usize) -> usize {
+        let index = match c {
+            ' '..='~' => c as usize - ' ' as usize,
+            _ => '?' as usize - ' ' as usize,
+        };
+        for (i, &column) in FONT[index].iter().enumerate() {
+            for row in (0..7).filter(|row| column & (1 << row) != 0) {
+                self.fill(x + i * scale, y + row * scale, scale, scale, true);
+            }
+        }
+        6 * scale
+    }
+
+    /// Text writer starting at `x`, `y`
+    pub fn text(&mut self, x: usize, y: usize, scale: usize) -> Text<'_> {
+        Text { canvas: self, x, y, scale }
+    }
+
+    pub fn page(&self, page: usize) -> &[u8; WIDTH] {
+        &self.pixels[page]
+    }
+
+    /// First page that changed since it was last sent
+    pub fn changed(&self) -> Option<usize> {
+        (0..PAGES).find(|&page| checksum(&self.pixels[page]) != self.sent[page])
+    }
+
+    /// The page went out as it is now.
+    pub fn mark_sent(&mut self, page: usize) {
+        self.sent[page] = checksum(&self.pixels[page]);
+    }
+
+    /// The display lost its contents; send everything again.
+    pub fn invalidate(&mut self) {
+        self.sent = [u32::MAX; PAGES];
+    }
+}
+
+/// FNV-1a over a page; a blank page does not hash to `u32::MAX`
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Draws what is written to it, clipped at the right edge
+pub struct Text<'a> {
+    canvas: &'a mut Canvas,
+    x: usize,
+    y: usize,
+    scale: usize,
+}
+
+impl Write for Text<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.x + 5 * self.scale > WIDTH {
+                break;
+            }
+            self.x += self.canvas.glyph(self.x, self.y, c, self.scale);
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Status screen
+// ============================================================================
+
+/// Everything the status screen shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    /// Last touched control and its first mapping
+    pub control: Option<(ControlId, Action)>,
+    /// Value it sent, `0..=FULL_SCALE`
+    pub value: u16,
+    /// Last program change on the default channel
+    pub preset: Option<u8>,
+    /// Beats per minute times 10
+    pub tempo: Option<u16>,
+}
+
+impl View {
+    pub const fn new() -> Self {
+        Self { control: None, value: 0, preset: None, tempo: None }
+    }
+
+    /// Take the preset from a program change on `channel`, sent or received.
+    pub fn follow(&mut self, channel: u8, message: &MidiMessage) {
+        if let MidiMessage::ProgramChange { channel: ch, program } = *message {
+            if ch == channel {
+                self.preset = Some(program);
+            }
+        }
+    }
+}
+
+/// Name of a control on the screen
+pub struct ControlName(pub ControlId);
+
+impl fmt::Display for ControlName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            id @ input::ids::ONBOARD..=1 => write!(f, "SW{}", id - input::ids::ONBOARD + 1),
+            id if id >= input::ids::SHIFT && ((id - input::ids::SHIFT) as usize) < input::MAX_SHIFT_INPUTS => {
+                write!(f, "Key {}", id - input::ids::SHIFT + 1)
+            }
+            id if id >= input::ids::MOTION && ((id - input::ids::MOTION) as usize) < motion::CONTROLS => {
+                f.write_str(["Pitch", "Roll", "Yaw", "Shake"][(id - input::ids::MOTION) as usize])
+            }
+            id if id >= input::ids::TOUCH && ((id - input::ids::TOUCH) as usize) < capsense::MAX_CHANNELS => {
+                write!(f, "Pad {}", id - input::ids::TOUCH + 1)
+            }
+            id if id >= input::ids::RIBBON && ((id - input::ids::RIBBON) as usize) < capsense::RIBBON_CONTROLS => {
+                f.write_str(["Ribbon", "Ribbon press", "Ribbon touch"][(id - input::ids::RIBBON) as usize])
+            }
+            id if id >= input::ids::DRUM && ((id - input::ids::DRUM) as usize) < drum::MAX_PADS => {
+                write!(f, "Drum {}", id - input::ids::DRUM + 1)
+            }
+            id if id >= input::ids::PEDAL && ((id - input::ids::PEDAL) as usize) < pedal::JACKS => {
+                write!(f, "Pedal {}", id - input::ids::PEDAL + 1)
+            }
+            id if id >= input::ids::SUSTAIN && ((id - input::ids::SUSTAIN) as usize) < pedal::MAX_SUSTAIN => {
+                write!(f, "Sustain {}", id - input::ids::SUSTAIN + 1)
+            }
+            id => write!(f, "Control {}", id),
+        }
+    }
+}
+
+/// Value of a message a control sent, scaled to `0..=FULL_SCALE`
+pub fn level(message: &MidiMessage) -> Option<u16> {
+    let seven = |value: u8| (value as u32 * FULL_SCALE as u32 / 127) as u16;
+    match *message {
+        MidiMessage::NoteOn { velocity, .. } => Some(seven(velocity)),
+        MidiMessage::NoteOff { .. } => Some(0),
+        MidiMessage::ControlChange { value, .. } => Some(seven(value)),
+        MidiMessage::ProgramChange { program, .. } => Some(seven(program)),
+        MidiMessage::ChannelPressure { pressure, .. } | MidiMessage::PolyPressure { pressure, .. } => {
+            Some(seven(pressure))
+        }
+        MidiMessage::PitchBend { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+/// Draw the status screen.
+pub fn render(view: &View, canvas: &mut Canvas) {
+    canvas.clear();
+
+    // Top line: program left, tempo right
+    let _ = match view.preset {
+        Some(program) => write!(canvas.text(0, 0, 1), "PGM {}", program + 1),
+        None => write!(canvas.text(0, 0, 1), "PGM -"),
+    };
+    let tempo = |f: &mut dyn Write| match view.tempo {
+        Some(bpm10) => write!(f, "{}.{} BPM", bpm10 / 10, bpm10 % 10),
+        None => f.write_str("--- BPM"),
+    };
+    let mut width = 0;
+    let _ = tempo(&mut WidthOf(&mut width));
+    let _ = tempo(&mut canvas.text(WIDTH - width, 0, 1));
+    canvas.fill(0, 10, WIDTH, 1, true);
+
+    let Some((control, action)) = view.control else {
+        return;
+    };
+    let _ = write!(canvas.text(0, 14, 1), "{}", ControlName(control));
+    let _ = write!(canvas.text(0, 24, 1), "{}", action);
+
+    // Value large, as MIDI would show it
+    let value = match action {
+        Action::Cc14(_)
+        | Action::Nrpn(_)
+        | Action::Rpn(_)
+        | Action::PitchBend
+        | Action::MpePitch(_)
+        | Action::SysEx(_) => view.value as u32,
+        _ => (view.value as u32 * 127 + FULL_SCALE as u32 / 2) / FULL_SCALE as u32,
+    };
+    let mut width = 0;
+    let _ = write!(WidthOf(&mut width), "{}", value);
+    let _ = write!(canvas.text(WIDTH - 2 * width, 34, 2), "{}", value);
+
+    // Bar
+    canvas.frame(0, 54, WIDTH, 10);
+    let fill = (view.value as usize * (WIDTH - 4)) / FULL_SCALE as usize;
+    canvas.fill(2, 56, fill, 6, true);
+}
+
+/// Counts the width of text written to it at scale 1
+struct WidthOf<'a>(&'a mut usize);
+
+impl Write for WidthOf<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        *self.0 += s.chars().count() * 6;
+        Ok(())
+    }
+}
+
+/// 5x7 font, ' ' to '~', one byte per column with bit 0 at the top
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x01, 0x01], // F
+    [0x3E, 0x41, 0x41, 0x51, 0x32], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x04, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x7F, 0x20, 0x18, 0x20, 0x7F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x08, 0x14, 0x54, 0x54, 0x3C], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x00, 0x7F, 0x10, 0x28, 0x44], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
@@ -0,0 +1,94 @@
+//! Micro DMA
+//!
+//! Basic-mode memory to peripheral transfers on the uDMA controller. Only
+//! the primary control structures are used; the table sits in RAM with the
+//! 1 KB alignment the controller requires.
+
+use core::cell::UnsafeCell;
+
+use tm4c123x::{SYSCTL, UDMA};
+
+/// Channel control structure: source end, destination end, control word
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    source_end: u32,
+    dest_end: u32,
+    control: u32,
+    unused: u32,
+}
+
+#[repr(C, align(1024))]
+struct ControlTable(UnsafeCell<[Descriptor; 32]>);
+
+// Only touched with the channel disabled
+unsafe impl Sync for ControlTable {}
+
+static TABLE: ControlTable = ControlTable(UnsafeCell::new(
+    [Descriptor { source_end: 0, dest_end: 0, control: 0, unused: 0 }; 32],
+));
+
+/// Largest transfer in items
+pub const MAX_TRANSFER: usize = 1024;
+
+/// Control word: destination address fixed, 8-bit items, source increments
+/// by a byte, arbitrate every 4 items, basic mode
+const BYTES_TO_PERIPHERAL: u32 = (3 << 30) | (2 << 14) | 1;
+
+/// Enable the controller.
+pub fn init() {
+    let sysctl = unsafe { &*SYSCTL::ptr() };
+    let udma = unsafe { &*UDMA::ptr() };
+
+    sysctl.rcgcdma.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+    while sysctl.prdma.read().bits() & 1 == 0 {}
+
+    unsafe {
+        udma.cfg.write(|w| w.bits(1));
+        udma.ctlbase.write(|w| w.bits(TABLE.0.get() as u32));
+    }
+}
+
+/// Assign `channel` to the peripheral selected by `encoding` (see the
+/// channel assignment table of the data sheet).
+pub fn assign(channel: u8, encoding: u32) {
+    let udma = unsafe { &*UDMA::ptr() };
+    let shift = (channel as u32 % 8) * 4;
+    let update = |bits: u32| (bits & !(0xF << shift)) | (encoding << shift);
+    unsafe {
+        match channel / 8 {
+            0 => udma.chmap0.modify(|r, w| w.bits(update(r.bits()))),
+            1 => udma.chmap1.modify(|r, w| w.bits(update(r.bits()))),
+            2 => udma.chmap2.modify(|r, w| w.bits(update(r.bits()))),
+            _ => udma.chmap3.modify(|r, w| w.bits(update(r.bits()))),
+        }
+        let bit = 1 << channel;
+        udma.altclr.write(|w| w.bits(bit));
+        udma.prioclr.write(|w| w.bits(bit));
+        udma.reqmaskclr.write(|w| w.bits(bit));
+    }
+}
+
+/// Whether a transfer on `channel` is still running
+pub fn busy(channel: u8) -> bool {
+    let udma = unsafe { &*UDMA::ptr() };
+    udma.enaset.read().bits() & (1 << channel) != 0
+}
+
+/// Send `data` to the peripheral register at `register`, one byte per
+/// request.
+///
+/// # Safety
+/// `data` must stay untouched until `busy` returns false.
+pub unsafe fn send(channel: u8, data: &[u8], register: *const u32) {
+    debug_assert!(!data.is_empty() && data.len() <= MAX_TRANSFER && !busy(channel));
+    let udma = &*UDMA::ptr();
+    let table = &mut *TABLE.0.get();
+    table[channel as usize] = Descriptor {
+        source_end: data.as_ptr() as u32 + data.len() as u32 - 1,
+        dest_end: register as u32,
+        control: BYTES_TO_PERIPHERAL | ((data.len() as u32 - 1) << 4),
+        unused: 0,
+    };
+    udma.enaset.write(|w| w.bits(1 << channel));
+}
@@ -0,0 +1,396 @@
+//! Drum Pads
+//!
+//! Hardware independent part of the velocity sensitive pads: piezo discs
+//! or force sensing resistors read by the ADC at `SAMPLE_HZ`. A strike
+//! crosses the threshold, the highest sample of the next `SCAN` samples is
+//! its peak and the peak its velocity. Pads are controls `input::ids::DRUM`
+//! and up, pressed with that velocity.
+//!
+//! - Crosstalk: a strike shakes the pads mounted next to it, which see a
+//!   weaker copy of it. A hit is dropped when another pad peaked within
+//!   `CROSSTALK` samples and this one reached less than `crosstalk` percent
+//!   of it.
+//! - Retrigger masking: a pad rings after a strike. For `mask` ms after a
+//!   hit the pad cannot trigger again, after that a new hit must exceed
+//!   half the last peak, halved again every `RETRIGGER_HALF_LIFE`, or the
+//!   threshold if higher.
+//! - Aftertouch: an FSR keeps a level while held. The pad stays pressed
+//!   until the level drops below half the threshold and reports it as
+//!   pressure; a piezo only senses the strike and releases after the mask.
+//!
+//! Time counts in samples, so recorded waveforms can be replayed on the
+//! host. Sampling is in `drumpads`.
+
+use core::fmt;
+
+use crate::input::{self, Input, InputEvent, FULL_SCALE};
+
+/// Pads, one ADC input each
+pub const MAX_PADS: usize = 4;
+
+/// Samples per second of every pad
+pub const SAMPLE_HZ: u32 = 4000;
+
+/// Highest sample, 12-bit ADC
+pub const FULL_LEVEL: u16 = 4095;
+
+/// Longest retrigger mask in ms
+pub const MAX_MASK_MS: u8 = 250;
+
+const fn samples(ms: u32) -> u32 {
+    ms * SAMPLE_HZ / 1000
+}
+
+/// Samples after the threshold crossing searched for the peak
+const SCAN: u32 = samples(2);
+/// Hits on different pads this close together may be crosstalk
+const CROSSTALK: u32 = samples(5);
+/// The retrigger level halves this often once the mask is over
+const RETRIGGER_HALF_LIFE: u32 = samples(10);
+/// Pressure of a held FSR is looked at this often
+const PRESSURE_INTERVAL: u32 = samples(10);
+/// Change of the pressure before it is reported again
+const HYSTERESIS: u16 = 64;
+
+/// What the pads are made of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Sensor {
+    /// Strike only, released after the mask
+    Piezo = 0,
+    /// Held while pressed, with aftertouch
+    Fsr = 1,
+}
+
+impl Sensor {
+    pub const ALL: [Sensor; 2] = [Sensor::Piezo, Sensor::Fsr];
+
+    pub fn from_u8(value: u8) -> Option<Sensor> {
+        Sensor::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sensor::Piezo => "piezo",
+            Sensor::Fsr => "FSR",
+        })
+    }
+}
+
+/// Settings of the drum pads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    /// Pads fitted
+    pub pads: u8,
+    pub sensor: Sensor,
+    /// Sample level that starts a hit
+    pub threshold: u16,
+    /// Time after a hit in which the pad cannot trigger again, ms
+    pub mask: u8,
+    /// Percentage of a neighbour's peak below which a hit is crosstalk,
+    /// 0 keeps every hit
+    pub crosstalk: u8,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { pads: 0, sensor: Sensor::Piezo, threshold: 200, mask: 30, crosstalk: 50 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Above the threshold, looking for the peak
+    Scan { since: u32, peak: u16 },
+    /// Struck at `since`; `sounding` unless it was crosstalk, with the
+    /// pressure last reported
+    Hit { since: u32, sounding: bool, pressure: u16 },
+}
+
+struct Pad {
+    state: State,
+    /// Time and peak of the last hit, crosstalk included
+    hit_at: u32,
+    hit_peak: u16,
+}
+
+impl Pad {
+    const fn new() -> Self {
+        Self { state: State::Idle, hit_at: 0, hit_peak: 0 }
+    }
+
+    /// Level a sample must exceed to start a hit
+    fn retrigger_level(&self, now: u32, threshold: u16, mask: u32) -> u16 {
+        let halvings = now.wrapping_sub(self.hit_at).saturating_sub(mask) / RETRIGGER_HALF_LIFE + 1;
+        let level = self.hit_peak.checked_shr(halvings).unwrap_or(0);
+        level.max(threshold)
+    }
+
+    /// How loud the pad was around `now`, for crosstalk
+    fn loudness(&self, now: u32) -> u16 {
+        match self.state {
+            State::Scan { peak, .. } => peak,
+            _ if now.wrapping_sub(self.hit_at) <= CROSSTALK => self.hit_peak,
+            _ => 0,
+        }
+    }
+}
+
+/// Level above the threshold on the full value range
+fn scale(level: u16, threshold: u16) -> u16 {
+    let span = (FULL_LEVEL - threshold.min(FULL_LEVEL - 1)) as u32;
+    (level.saturating_sub(threshold) as u32 * FULL_SCALE as u32 / span).min(FULL_SCALE as u32) as u16
+}
+
+/// Hit detection on all pads
+pub struct Drums {
+    pads: [Pad; MAX_PADS],
+    /// Samples taken
+    now: u32,
+}
+
+impl Drums {
+    pub const fn new() -> Self {
+        Self { pads: [const { Pad::new() }; MAX_PADS], now: 0 }
+    }
+
+    /// Release pads still held and start over, e.g. when pads are removed.
+    pub fn reset(&mut self, emit: &mut dyn FnMut(InputEvent)) {
+        for (index, pad) in self.pads.iter_mut().enumerate() {
+            if let State::Hit { sounding: true, .. } = pad.state {
+                emit(InputEvent { control: input::ids::DRUM + index as u8, input: Input::Release });
+            }
+            *pad = Pad::new();
+        }
+    }
+
+    /// Take one sample per pad, report hits, pressure and releases.
+    pub fn update(&mut self, frame: &[u16], options: &Options, emit: &mut dyn FnMut(InputEvent)) {
+        self.now = self.now.wrapping_add(1);
+        let now = self.now;
+        let threshold = options.threshold.clamp(1, FULL_LEVEL - 1);
+        let mask = samples(options.mask as u32);
+        let frame = &frame[..frame.len().min(MAX_PADS)];
+        let control = |index: usize| input::ids::DRUM + index as u8;
+
+        for (index, (pad, &level)) in self.pads.iter_mut().zip(frame).enumerate() {
+            match pad.state {
+                State::Idle if level > pad.retrigger_level(now, threshold, mask) => {
+                    pad.state = State::Scan { since: now, peak: level };
+                }
+                State::Idle => {}
+                State::Scan { since, peak } => pad.state = State::Scan { since, peak: peak.max(level) },
+                State::Hit { since, sounding, pressure } => {
+                    let held = now.wrapping_sub(since);
+                    if held >= mask && level < threshold / 2 {
+                        pad.state = State::Idle;
+                        if sounding {
+                            emit(InputEvent { control: control(index), input: Input::Release });
+                        }
+                    } else if sounding && options.sensor == Sensor::Fsr && held % PRESSURE_INTERVAL == 0 {
+                        let value = scale(level, threshold);
+                        if value.abs_diff(pressure) > HYSTERESIS {
+                            pad.state = State::Hit { since, sounding, pressure: value };
+                            emit(InputEvent { control: control(index), input: Input::Pressure(value) });
+                        }
+                    }
+                }
+            }
+        }
+
+        // Peaks found once every pad has this frame's sample, so a hit is
+        // weighed against neighbours struck at the same time
+        let mut loudness = [0; MAX_PADS];
+        for (loudness, pad) in loudness.iter_mut().zip(&self.pads) {
+            *loudness = pad.loudness(now);
+        }
+        for (index, pad) in self.pads[..frame.len()].iter_mut().enumerate() {
+            let State::Scan { since, peak } = pad.state else {
+                continue;
+            };
+            if now.wrapping_sub(since) < SCAN {
+                continue;
+            }
+            let crosstalk = options.crosstalk as u32;
+            let louder = |(other, &loud): (usize, &u16)| {
+                other != index && (peak as u32) * 100 < loud as u32 * crosstalk
+            };
+            let sounding = !loudness.iter().enumerate().any(louder);
+            let velocity = scale(peak, threshold).max(1);
+            pad.state = State::Hit { since: now, sounding, pressure: velocity };
+            pad.hit_at = now;
+            pad.hit_peak = peak;
+            if sounding {
+                emit(InputEvent { control: control(index), input: Input::Press(Some(velocity)) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A piezo pad struck hard, sampled at `SAMPLE_HZ`: the first peak,
+    /// then ringing that decays over some 20 ms
+    const STRIKE: [u16; 96] = [
+        0, 720, 1266, 1487, 1277, 454, 353, 1084, 1687, 2122, 2365, 2409,
+        2262, 1949, 1502, 965, 386, 188, 713, 1150, 1472, 1659, 1705, 1616,
+        1407, 1100, 726, 318, 88, 465, 782, 1019, 1162, 1206, 1154, 1014,
+        804, 544, 257, 31, 300, 530, 704, 813, 852, 822, 730, 587,
+        406, 205, 0, 192, 358, 486, 568, 601, 586, 525, 427, 302,
+        161, 15, 121, 241, 335, 397, 424, 417, 377, 310, 224, 125,
+        22, 75, 162, 230, 277, 299, 296, 270, 225, 165, 96, 23,
+        46, 108, 158, 192, 210, 210, 194, 163, 122, 73, 22, 27,
+    ];
+
+    /// The pad next to it during that strike
+    const NEIGHBOUR: [u16; 96] = [
+        0, 0, 0, 216, 379, 446, 383, 136, 105, 325, 506, 636,
+        709, 722, 678, 584, 450, 289, 115, 56, 213, 345, 441, 497,
+        511, 484, 422, 330, 217, 95, 26, 139, 234, 305, 348, 361,
+        346, 304, 241, 163, 77, 9, 90, 159, 211, 243, 255, 246,
+        219, 176, 121, 61, 0, 57, 107, 145, 170, 180, 175, 157,
+        128, 90, 48, 4, 36, 72, 100, 119, 127, 125, 113, 93,
+        67, 37, 6, 22, 48, 69, 83, 89, 88, 81, 67, 49,
+        28, 6, 13, 32, 47, 57, 63, 63, 58, 48, 36, 21,
+    ];
+
+    const OPTIONS: Options = Options { pads: 2, sensor: Sensor::Piezo, threshold: 200, mask: 30, crosstalk: 50 };
+
+    /// `len` samples of a pad: waveforms at `percent` of their level,
+    /// starting at a sample
+    fn signal(len: usize, waves: &[(usize, &[u16], u32)]) -> Vec<u16> {
+        let mut samples = vec![0; len];
+        for &(start, wave, percent) in waves {
+            for (sample, &level) in samples[start..].iter_mut().zip(wave) {
+                *sample = (*sample).max((level as u32 * percent / 100) as u16);
+            }
+        }
+        samples
+    }
+
+    /// Replay two pads, returns the events with the sample they came at
+    fn replay(drums: &mut Drums, options: &Options, pad0: &[u16], pad1: &[u16]) -> Vec<(usize, InputEvent)> {
+        let mut events = Vec::new();
+        for (i, (&a, &b)) in pad0.iter().zip(pad1).enumerate() {
+            drums.update(&[a, b], options, &mut |event| events.push((i, event)));
+        }
+        events
+    }
+
+    fn velocities(events: &[(usize, InputEvent)]) -> Vec<(u8, u16)> {
+        let press = |(_, event): &(usize, InputEvent)| match event.input {
+            Input::Press(Some(velocity)) => Some((event.control - input::ids::DRUM, velocity)),
+            _ => None,
+        };
+        events.iter().filter_map(press).collect()
+    }
+
+    /// Velocity of a strike crossing the threshold at sample `crossing`
+    fn velocity(wave: &[u16], crossing: usize) -> u16 {
+        scale(*wave[crossing..=crossing + SCAN as usize].iter().max().unwrap(), OPTIONS.threshold)
+    }
+
+    #[test]
+    fn finds_the_peak() {
+        let quiet = vec![0; 400];
+        let mut last = u16::MAX;
+        for percent in [100, 60, 30] {
+            let mut drums = Drums::new();
+            let pad = signal(400, &[(10, &STRIKE, percent)]);
+            let crossing = pad.iter().position(|&level| level > OPTIONS.threshold).unwrap();
+            let events = replay(&mut drums, &OPTIONS, &pad, &quiet);
+            let hit = InputEvent { control: input::ids::DRUM, input: Input::Press(Some(velocity(&pad, crossing))) };
+            assert_eq!(events[0], (crossing + SCAN as usize, hit), "{percent}%");
+            assert!(velocity(&pad, crossing) < last);
+            last = velocity(&pad, crossing);
+
+            // Ringing does not retrigger, the pad lets go once the mask is
+            // over and it is quiet
+            let release = InputEvent { control: input::ids::DRUM, input: Input::Release };
+            assert_eq!(events.len(), 2, "{percent}%");
+            assert_eq!(events[1].1, release);
+            assert!(events[1].0 >= events[0].0 + samples(OPTIONS.mask as u32) as usize);
+        }
+    }
+
+    #[test]
+    fn masks_retriggers() {
+        let quiet = vec![0; 600];
+        let hard = velocity(&STRIKE, 1);
+        let mask = samples(OPTIONS.mask as u32) as usize;
+
+        // A second strike within the mask is lost
+        let pad = signal(600, &[(0, &STRIKE, 100), (60, &STRIKE, 100)]);
+        assert_eq!(velocities(&replay(&mut Drums::new(), &OPTIONS, &pad, &quiet)), [(0, hard)]);
+
+        // Right after the mask one has to be over half as loud as the first
+        let soon = 140;
+        assert!(soon > mask + SCAN as usize);
+        let pad = signal(600, &[(0, &STRIKE, 100), (soon, &STRIKE, 40)]);
+        assert_eq!(velocities(&replay(&mut Drums::new(), &OPTIONS, &pad, &quiet)), [(0, hard)]);
+        let pad = signal(600, &[(0, &STRIKE, 100), (soon, &STRIKE, 60)]);
+        assert_eq!(velocities(&replay(&mut Drums::new(), &OPTIONS, &pad, &quiet)).len(), 2);
+
+        // That level halves every 10 ms
+        let later = soon + 2 * RETRIGGER_HALF_LIFE as usize;
+        let pad = signal(600, &[(0, &STRIKE, 100), (later, &STRIKE, 40)]);
+        let found = velocities(&replay(&mut Drums::new(), &OPTIONS, &pad, &quiet));
+        assert_eq!(found.len(), 2);
+        assert!(found[1].1 < found[0].1);
+    }
+
+    #[test]
+    fn rejects_crosstalk() {
+        let struck = signal(400, &[(5, &STRIKE, 100)]);
+        let shaken = signal(400, &[(5, &NEIGHBOUR, 100)]);
+        let mut drums = Drums::new();
+        assert_eq!(velocities(&replay(&mut drums, &OPTIONS, &struck, &shaken)), [(0, velocity(&STRIKE, 1))]);
+        // Also when the neighbour is the first pad
+        let found = velocities(&replay(&mut Drums::new(), &OPTIONS, &shaken, &struck));
+        assert_eq!(found, [(1, velocity(&STRIKE, 1))]);
+
+        // Both pads struck, one a little softer: both sound
+        let softer = signal(400, &[(6, &STRIKE, 70)]);
+        let found = velocities(&replay(&mut Drums::new(), &OPTIONS, &struck, &softer));
+        assert_eq!(found.iter().map(|&(pad, _)| pad).collect::<Vec<_>>(), [0, 1]);
+
+        // Rejection off keeps the neighbour too
+        let keep = Options { crosstalk: 0, ..OPTIONS };
+        assert_eq!(velocities(&replay(&mut Drums::new(), &keep, &struck, &shaken)).len(), 2);
+
+        // Long after the strike the neighbour is played on its own
+        let alone = signal(400, &[(200, &STRIKE, 30)]);
+        let found = velocities(&replay(&mut Drums::new(), &OPTIONS, &struck, &alone));
+        assert_eq!(found.iter().map(|&(pad, _)| pad).collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn holds_an_fsr() {
+        let fsr = Options { sensor: Sensor::Fsr, ..OPTIONS };
+        let mut press: Vec<u16> = (0..8).map(|i| i * 300).collect();
+        press.extend([2000; 400]);
+        press.extend((0..400).map(|i| 2000 + i * 4));
+        press.extend([0; 10]);
+        let quiet = vec![0; press.len()];
+        let events = replay(&mut Drums::new(), &fsr, &quiet, &press);
+        let control = input::ids::DRUM + 1;
+        assert!(matches!(events[0].1, InputEvent { control: c, input: Input::Press(Some(_)) } if c == control));
+        let (last, held) = events[1..].split_last().unwrap();
+        assert!(held.len() > 5);
+        assert!(held.iter().all(|(_, event)| matches!(event.input, Input::Pressure(_))));
+        assert!(matches!(held.last().unwrap().1.input, Input::Pressure(p) if p > 13000));
+        assert_eq!(last.1, InputEvent { control, input: Input::Release });
+
+        // Reset lets go of a held pad
+        let mut drums = Drums::new();
+        replay(&mut drums, &fsr, &quiet[..100], &press[..100]);
+        let mut released = Vec::new();
+        drums.reset(&mut |event| released.push(event));
+        assert_eq!(released, [InputEvent { control, input: Input::Release }]);
+    }
+}
@@ -0,0 +1,183 @@
+//! Drum Pad Driver
+//!
+//! Up to four piezo or FSR pads on the analog inputs of the first touch
+//! pins: PE0 (AIN3), PE1 (AIN2), PD2 (AIN5) and PD3 (AIN4), in that order.
+//! Pads take these pins from the touch inputs. TIMER2A triggers ADC1
+//! sequencer 1 at `drum::SAMPLE_HZ`, which samples every pad once; its
+//! interrupt hands the frame to the main loop through a ring buffer, so
+//! peaks shorter than a pass of the main loop are still caught and the
+//! timing of every sample is exact.
+//!
+//! Hit detection is in `drum` and runs in the main loop.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use tm4c123x::{gpio_porta, ADC1, GPIO_PORTD, GPIO_PORTE, SYSCTL, TIMER2};
+
+use crate::drum::{Drums, Options, MAX_PADS, SAMPLE_HZ};
+use crate::input::{InputEvent, InputSource};
+use crate::ring::ByteRing;
+use crate::usb_device;
+
+/// Pad pins, port, pin mask and ADC input
+const PINS: [(*const gpio_porta::RegisterBlock, u32, u32); MAX_PADS] = [
+    (GPIO_PORTE::ptr(), 0x01, 3),
+    (GPIO_PORTE::ptr(), 0x02, 2),
+    (GPIO_PORTD::ptr(), 0x04, 5),
+    (GPIO_PORTD::ptr(), 0x08, 4),
+];
+
+/// Sequencer 1, four steps
+const SEQUENCER: u32 = 1;
+
+/// Frames from the ADC interrupt, two bytes per pad, low byte first; 64 ms
+/// of four pads
+static SAMPLES: ByteRing<2048> = ByteRing::new();
+/// Frames lost because the main loop fell behind
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+pub struct DrumPads {
+    drums: Drums,
+    options: Options,
+    /// Pads sampled
+    count: usize,
+    /// Pads still held when the pads changed are released on the next poll
+    restart: bool,
+}
+
+impl DrumPads {
+    /// Clock the ADC, ports and timer; nothing is sampled until
+    /// `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let adc = unsafe { &*ADC1::ptr() };
+        let timer = unsafe { &*TIMER2::ptr() };
+
+        // Ports D and E
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | 0x18) });
+        while sysctl.prgpio.read().bits() & 0x18 != 0x18 {}
+        sysctl.rcgcadc.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        while sysctl.pradc.read().bits() & (1 << 1) == 0 {}
+        sysctl.rcgctimer.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 2)) });
+        while sysctl.prtimer.read().bits() & (1 << 2) == 0 {}
+
+        let clock = unsafe { usb_device::SysCtlClockGet() };
+        unsafe {
+            // Sequencer 1 starts on the timer
+            adc.actss.modify(|r, w| w.bits(r.bits() & !(1 << SEQUENCER)));
+            adc.emux.modify(|r, w| w.bits((r.bits() & !0xF0) | 0x50));
+            adc.im.modify(|r, w| w.bits(r.bits() | (1 << SEQUENCER)));
+            usb_device::IntRegister(usb_device::ints::INT_ADC1SS1, sampled);
+            usb_device::IntEnable(usb_device::ints::INT_ADC1SS1);
+
+            // Periodic 32-bit timer A, triggering the ADC
+            timer.ctl.write(|w| w.bits(0));
+            timer.cfg.write(|w| w.bits(0));
+            timer.tamr.write(|w| w.bits(0x2));
+            timer.tailr.write(|w| w.bits(clock / SAMPLE_HZ - 1));
+        }
+
+        Self { drums: Drums::new(), options: Options::new(), count: 0, restart: false }
+    }
+
+    /// Follow the settings: pads fitted and how hits are detected.
+    pub fn configure(&mut self, options: &Options) {
+        self.options = *options;
+        let count = (options.pads as usize).min(MAX_PADS);
+        if count == self.count {
+            return;
+        }
+        let adc = unsafe { &*ADC1::ptr() };
+        let timer = unsafe { &*TIMER2::ptr() };
+        unsafe {
+            timer.ctl.write(|w| w.bits(0));
+            adc.actss.modify(|r, w| w.bits(r.bits() & !(1 << SEQUENCER)));
+        }
+        // Frames of the old size are no use any more
+        while SAMPLES.pop().is_some() {}
+
+        for (index, &(port, pin, _)) in PINS.iter().enumerate() {
+            let port = unsafe { &*port };
+            if index < count {
+                port.dir.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+                port.den.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+                port.afsel.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+                port.amsel.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+            } else if index < self.count {
+                // Back to a plain input for the touch inputs
+                port.amsel.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+                port.afsel.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+                port.den.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+            }
+        }
+        self.count = count;
+        self.restart = true;
+        if count == 0 {
+            return;
+        }
+
+        // One step per pad, the last one ends the sequence and interrupts
+        let mux = PINS[..count].iter().enumerate().fold(0, |mux, (step, pin)| mux | pin.2 << (4 * step));
+        unsafe {
+            adc.ssmux1.write(|w| w.bits(mux));
+            adc.ssctl1.write(|w| w.bits(0x6 << (4 * (count - 1))));
+            adc.isc.write(|w| w.bits(1 << SEQUENCER));
+            adc.actss.modify(|r, w| w.bits(r.bits() | (1 << SEQUENCER)));
+            // Enable, with the ADC trigger output
+            timer.ctl.write(|w| w.bits(0x21));
+        }
+    }
+
+    /// Frames lost since boot
+    pub fn dropped(&self) -> u32 {
+        DROPPED.load(Ordering::Relaxed)
+    }
+}
+
+impl InputSource for DrumPads {
+    fn poll(&mut self, _now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        if self.restart {
+            self.restart = false;
+            self.drums.reset(emit);
+        }
+        let count = self.count;
+        if count == 0 {
+            return;
+        }
+        let mut frame = [0u16; MAX_PADS];
+        while SAMPLES.len() >= 2 * count {
+            for level in frame[..count].iter_mut() {
+                let low = SAMPLES.pop().unwrap_or(0) as u16;
+                let high = SAMPLES.pop().unwrap_or(0) as u16;
+                *level = low | high << 8;
+            }
+            self.drums.update(&frame[..count], &self.options, emit);
+        }
+    }
+}
+
+/// ADC interrupt: queue the frame, whole or not at all.
+unsafe extern "C" fn sampled() {
+    let adc = &*ADC1::ptr();
+    adc.isc.write(|w| w.bits(1 << SEQUENCER));
+
+    let mut frame = [0u16; MAX_PADS];
+    let mut count = 0;
+    // FIFO empty flag
+    while adc.ssfstat1.read().bits() & (1 << 8) == 0 {
+        let level = adc.ssfifo1.read().bits() as u16 & 0xFFF;
+        if count < MAX_PADS {
+            frame[count] = level;
+            count += 1;
+        }
+    }
+    // One slot of the ring always stays free
+    if SAMPLES.len() + 2 * count >= 2048 {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    for level in &frame[..count] {
+        SAMPLES.push(*level as u8);
+        SAMPLES.push((*level >> 8) as u8);
+    }
+}
@@ -0,0 +1,357 @@
+//! Motorised Faders
+//!
+//! Hardware independent part of a motorised fader: a position controller
+//! that drives the motor toward the value last received from the host, touch
+//! detection on the knob, and the rules that keep the two apart. While the
+//! knob is touched the motor is off and movement is reported; while the
+//! motor moves nothing is reported, so a value from the host does not come
+//! back to it as if the user had moved the fader.
+//!
+//! Positions are 14-bit (`0..=FULL_SCALE`), motor drive is signed per mille
+//! of full power, positive toward the top. Everything runs on plain numbers:
+//! `motor` only reads the wipers and touch pins and sets the PWM, so the
+//! loop can be run against a simulated fader on the host.
+
+use crate::input::FULL_SCALE;
+
+pub const MAX_FADERS: usize = 2;
+
+/// Full motor power
+pub const FULL_DRIVE: i16 = 1000;
+
+/// Close enough to the target to stop, about 0.3% of the travel
+const DEADBAND: i32 = 48;
+/// Drive per unit of position error: full power from 1/8 of the travel
+const KP: (i32, i32) = (FULL_DRIVE as i32, FULL_SCALE as i32 / 8);
+/// Braking per unit of speed (position change per step)
+const KD: i32 = 6;
+/// Least drive that still moves the knob against friction
+const MIN_DRIVE: i32 = 250;
+/// Give up a move that takes longer than this, the knob may be blocked
+const STALL_MS: u32 = 800;
+/// Steps within the deadband before a move counts as finished
+const SETTLE_STEPS: u8 = 5;
+
+/// Movement needed before a position is reported: small while touched,
+/// larger otherwise so noise and motor settling stay quiet
+const TOUCHED_HYSTERESIS: u16 = 24;
+const IDLE_HYSTERESIS: u16 = 160;
+
+/// PD position controller
+pub struct Servo {
+    last_position: Option<u16>,
+    settled: u8,
+}
+
+impl Servo {
+    pub const fn new() -> Self {
+        Self { last_position: None, settled: 0 }
+    }
+
+    /// Motor drive for one step toward `target`; `None` once the knob has
+    /// settled there.
+    pub fn step(&mut self, target: u16, position: u16) -> Option<i16> {
+        let error = target as i32 - position as i32;
+        let speed = position as i32 - self.last_position.unwrap_or(position) as i32;
+        self.last_position = Some(position);
+
+        if error.abs() <= DEADBAND {
+            self.settled += 1;
+            if self.settled >= SETTLE_STEPS {
+                self.reset();
+                return None;
+            }
+            return Some(0);
+        }
+        self.settled = 0;
+        let drive = error * KP.0 / KP.1 - speed * KD;
+        // Never push away from the target, never too weak to move
+        let drive = match error.signum() {
+            1 => drive.max(MIN_DRIVE),
+            _ => drive.min(-MIN_DRIVE),
+        };
+        Some(drive.clamp(-(FULL_DRIVE as i32), FULL_DRIVE as i32) as i16)
+    }
+
+    pub fn reset(&mut self) {
+        self.last_position = None;
+        self.settled = 0;
+    }
+}
+
+/// Touch on a capacitive knob from charge time readings: touched while the
+/// reading is well above the untouched baseline, which follows slow drift.
+pub struct TouchSense {
+    /// Untouched reading times 16
+    baseline: u32,
+    touched: bool,
+}
+
+impl TouchSense {
+    /// Reading above the baseline that counts as touch, in 1/16 of the
+    /// baseline; release happens at half of it
+    const THRESHOLD: u32 = 4;
+
+    pub const fn new() -> Self {
+        Self { baseline: 0, touched: false }
+    }
+
+    pub fn update(&mut self, reading: u32) -> bool {
+        let reading = reading * 16;
+        if self.baseline == 0 {
+            self.baseline = reading;
+        }
+        let margin = self.baseline / 16 * Self::THRESHOLD;
+        let rise = reading.saturating_sub(self.baseline);
+        self.touched = if self.touched { rise > margin / 2 } else { rise > margin };
+        if !self.touched {
+            // Follow drift slowly, drops at once
+            self.baseline = if reading < self.baseline { reading } else { self.baseline + (reading - self.baseline) / 64 };
+        }
+        self.touched
+    }
+}
+
+/// Outcome of one control step of a fader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Motor drive
+    pub drive: i16,
+    /// The knob was touched (`true`) or let go
+    pub touch: Option<bool>,
+    /// Position to report, the user moved the fader
+    pub position: Option<u16>,
+}
+
+/// One fader: smoothed wiper position, motor target, touch and what was
+/// last reported
+pub struct Fader {
+    servo: Servo,
+    position: u16,
+    target: Option<u16>,
+    /// Start of the current move
+    moving_since: u32,
+    touched: bool,
+    /// Position last reported or reached by the motor
+    reported: Option<u16>,
+}
+
+impl Fader {
+    pub const fn new() -> Self {
+        Self { servo: Servo::new(), position: 0, target: None, moving_since: 0, touched: false, reported: None }
+    }
+
+    /// A fader was fitted: smoothing starts from where its knob is.
+    pub fn start(&mut self, sample: u16) {
+        self.position = sample;
+    }
+
+    /// The host sent a value; move there unless the user holds the knob.
+    pub fn move_to(&mut self, now: u32, position: u16) {
+        if self.touched {
+            return;
+        }
+        if self.target.is_none() {
+            self.moving_since = now;
+            self.servo.reset();
+        }
+        self.target = Some(position.min(FULL_SCALE));
+    }
+
+    /// One control step from a wiper sample and the touch state: the motor
+    /// drive, a touch change and the position to report.
+    pub fn step(&mut self, now: u32, sample: u16, touched: bool) -> Step {
+        self.position = ((self.position as u32 * 3 + sample as u32) / 4) as u16;
+        let touch = (touched != core::mem::replace(&mut self.touched, touched)).then_some(touched);
+        let (drive, position) = self.control(now, self.position, touched);
+        Step { drive, touch, position }
+    }
+
+    fn control(&mut self, now: u32, position: u16, touched: bool) -> (i16, Option<u16>) {
+        if touched {
+            self.target = None;
+        }
+        if let Some(target) = self.target {
+            let drive = match now.wrapping_sub(self.moving_since) < STALL_MS {
+                true => self.servo.step(target, position),
+                false => None,
+            };
+            if let Some(drive) = drive {
+                return (drive, None);
+            }
+            // Arrived (or gave up): where the motor left the knob is known
+            self.target = None;
+            self.reported = Some(position);
+            return (0, None);
+        }
+        let hysteresis = if touched { TOUCHED_HYSTERESIS } else { IDLE_HYSTERESIS };
+        let moved = match self.reported {
+            Some(reported) => position.abs_diff(reported) > hysteresis || (touched && at_end(position) && !at_end(reported)),
+            None => true,
+        };
+        if !moved {
+            return (0, None);
+        }
+        let position = match position {
+            p if p <= TOUCHED_HYSTERESIS => 0,
+            p if p >= FULL_SCALE - TOUCHED_HYSTERESIS => FULL_SCALE,
+            p => p,
+        };
+        self.reported = Some(position);
+        (0, Some(position))
+    }
+}
+
+/// Ends of the travel are always reported, and as the end itself, so a
+/// quick move reaches 0 and full scale exactly
+fn at_end(position: u16) -> bool {
+    position <= TOUCHED_HYSTERESIS || position >= FULL_SCALE - TOUCHED_HYSTERESIS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fader knob on a motor: mass, sliding friction that holds it still
+    /// against weak drive, and a few counts of wiper noise
+    struct Sim {
+        position: f32,
+        speed: f32,
+        noise: u32,
+    }
+
+    impl Sim {
+        const FRICTION: f32 = 0.8;
+
+        fn new(position: u16) -> Self {
+            Self { position: position as f32, speed: 0.0, noise: 1 }
+        }
+
+        /// Wiper reading
+        fn sample(&mut self) -> u16 {
+            self.noise = self.noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = (self.noise >> 16) as i32 % 41 - 20;
+            (self.position as i32 + noise).clamp(0, FULL_SCALE as i32) as u16
+        }
+
+        /// One millisecond with the motor at `drive`
+        fn run(&mut self, drive: i16) {
+            let force = drive as f32 * 0.004;
+            if self.speed == 0.0 && force.abs() < Self::FRICTION {
+                return;
+            }
+            self.speed += force - Self::FRICTION * self.speed.signum() - self.speed * 0.05;
+            if self.speed.abs() < 0.5 && force.abs() < Self::FRICTION {
+                self.speed = 0.0;
+            }
+            self.position = (self.position + self.speed).clamp(0.0, FULL_SCALE as f32);
+            if self.position == 0.0 || self.position == FULL_SCALE as f32 {
+                self.speed = 0.0;
+            }
+        }
+    }
+
+    /// Run `ms` control steps untouched, returning the reports
+    fn run(fader: &mut Fader, sim: &mut Sim, now: &mut u32, ms: u32) -> Vec<u16> {
+        let mut reports = Vec::new();
+        for _ in 0..ms {
+            let step = fader.step(*now, sim.sample(), false);
+            sim.run(step.drive);
+            reports.extend(step.position);
+            *now += 1;
+        }
+        reports
+    }
+
+    #[test]
+    fn settles_on_the_target() {
+        for (start, target) in [(0, FULL_SCALE), (16000, 200), (8000, 8100), (3000, 12000), (9000, 9030)] {
+            let mut sim = Sim::new(start);
+            let mut fader = Fader::new();
+            fader.start(start);
+            let mut now = 0;
+            // The first position is reported, then the idle noise is not
+            assert_eq!(run(&mut fader, &mut sim, &mut now, 50).len(), 1);
+
+            fader.move_to(now, target);
+            let moved_at = now;
+            let mut settled_at = None;
+            for _ in 0..STALL_MS {
+                let step = fader.step(now, sim.sample(), false);
+                sim.run(step.drive);
+                assert_eq!(step.position, None, "{start} -> {target} echoed at {now}");
+                if fader.target.is_none() && settled_at.is_none() {
+                    settled_at = Some(now - moved_at);
+                }
+                now += 1;
+            }
+            let settled_at = settled_at.unwrap_or_else(|| panic!("{start} -> {target} did not settle"));
+            assert!(settled_at < STALL_MS / 2, "{start} -> {target} took {settled_at} ms");
+            assert!((sim.position - target as f32).abs() <= 2.0 * DEADBAND as f32, "{start} -> {target}");
+            // Where the motor stopped is not sent back either
+            assert!(run(&mut fader, &mut sim, &mut now, 500).is_empty(), "{start} -> {target}");
+        }
+    }
+
+    #[test]
+    fn touch_stops_the_motor() {
+        let mut fader = Fader::new();
+        fader.start(0);
+        assert_eq!(fader.step(0, 0, false), Step { drive: 0, touch: None, position: Some(0) });
+        fader.move_to(1, FULL_SCALE);
+        assert!(fader.step(2, 0, false).drive > 0);
+
+        // Touch ends the move, movement is reported with a small hysteresis
+        let step = fader.step(3, 400, true);
+        assert_eq!((step.drive, step.touch, step.position), (0, Some(true), Some(100)));
+        fader.move_to(4, 0);
+        assert_eq!(fader.step(5, 100, true), Step { drive: 0, touch: None, position: None });
+        assert_eq!(fader.step(6, 400, true).position, Some(175));
+        assert_eq!(fader.step(7, 175, false).touch, Some(false));
+
+        // A value from the host moves the fader again once it is let go
+        fader.move_to(8, FULL_SCALE);
+        assert!(fader.step(9, 175, false).drive > 0);
+    }
+
+    #[test]
+    fn reports_the_ends_while_touched() {
+        let mut fader = Fader::new();
+        fader.start(40);
+        fader.step(0, 40, true);
+        let step = (1..20).map(|now| fader.step(now, 0, true)).filter_map(|step| step.position).last();
+        assert_eq!(step, Some(0));
+    }
+
+    #[test]
+    fn gives_up_on_a_blocked_knob() {
+        let mut fader = Fader::new();
+        fader.start(5000);
+        fader.step(0, 5000, false);
+        fader.move_to(1, 9000);
+        let mut now = 1;
+        while fader.step(now, 5000, false).drive != 0 {
+            now += 1;
+            assert!(now < 2 * STALL_MS);
+        }
+        assert!(now >= STALL_MS);
+        // Stopped where it was held, nothing to report
+        assert_eq!(fader.step(now + 1, 5000, false).position, None);
+    }
+
+    #[test]
+    fn senses_touch() {
+        let mut sense = TouchSense::new();
+        for _ in 0..100 {
+            assert!(!sense.update(100));
+        }
+        assert!(sense.update(130));
+        assert!(sense.update(115));
+        assert!(!sense.update(104));
+        // Slow drift moves the baseline instead of counting as touch
+        for reading in (100..200).flat_map(|r| [r; 8]) {
+            assert!(!sense.update(reading));
+        }
+        assert!(sense.update(260));
+    }
+}
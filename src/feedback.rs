@@ -0,0 +1,266 @@
+//! LED Feedback
+//!
+//! Incoming MIDI drives LEDs. The feedback table binds an LED to the MIDI
+//! address it shows: a note or controller on a channel, or whatever a
+//! control's mappings send, 14-bit controllers and NRPN/RPN parameters
+//! included. The latest value received on that address becomes the LED
+//! level, so mute, solo and record states in the host show on the hardware.
+
+use core::fmt;
+
+use crate::config::{ConfigError, Reader, Writer};
+use crate::hires::{Param, ParamChange};
+use crate::input::ControlId;
+use crate::led::{ids, LedId, MAX_LED};
+use crate::mapping::{MappingTable, DEFAULT_CHANNEL};
+use crate::midi::MidiMessage;
+
+pub const MAX_FEEDBACK: usize = 32;
+
+/// Note, controller or parameter on a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    Note { channel: u8, note: u8 },
+    Cc { channel: u8, control: u8 },
+    /// Controller pair 0-31 by its MSB controller
+    Cc14 { channel: u8, control: u8 },
+    Nrpn { channel: u8, param: u16 },
+    Rpn { channel: u8, param: u16 },
+}
+
+impl Address {
+    /// Address and value of a note or controller message; note off is 0
+    pub fn of_message(message: &MidiMessage) -> Option<(Address, u8)> {
+        match *message {
+            MidiMessage::NoteOn { channel, note, velocity } => Some((Address::Note { channel, note }, velocity)),
+            MidiMessage::NoteOff { channel, note, .. } => Some((Address::Note { channel, note }, 0)),
+            MidiMessage::ControlChange { channel, control, value } => Some((Address::Cc { channel, control }, value)),
+            _ => None,
+        }
+    }
+
+    /// Address and 14-bit value of a parameter update from `hires::Decoder`;
+    /// plain controllers are left to `of_message`
+    pub fn of_change(change: &ParamChange) -> Option<(Address, u16)> {
+        let channel = change.channel;
+        let address = match change.param {
+            Param::Cc14(control) => Address::Cc14 { channel, control },
+            Param::Nrpn(param) => Address::Nrpn { channel, param },
+            Param::Rpn(param) => Address::Rpn { channel, param },
+            Param::Cc(_) => return None,
+        };
+        Some((address, change.value))
+    }
+}
+
+/// What an LED shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// Channel 0-15 or `DEFAULT_CHANNEL`
+    Note { channel: u8, note: u8 },
+    Cc { channel: u8, control: u8 },
+    /// Every note and controller the control's mappings send
+    Control(ControlId),
+}
+
+impl Source {
+    fn matches(&self, address: Address, mappings: &MappingTable, default_channel: u8) -> bool {
+        let resolve = |channel| if channel == DEFAULT_CHANNEL { default_channel } else { channel };
+        match *self {
+            Source::Note { channel, note } => address == Address::Note { channel: resolve(channel), note },
+            Source::Cc { channel, control } => address == Address::Cc { channel: resolve(channel), control },
+            Source::Control(control) => mappings
+                .iter()
+                .any(|m| m.control == control && m.address(default_channel) == Some(address)),
+        }
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        let (kind, channel, number) = match *self {
+            Source::Note { channel, note } => (0, channel, note),
+            Source::Cc { channel, control } => (1, channel, control),
+            Source::Control(control) => (2, 0, control),
+        };
+        w.u8(kind);
+        w.u8(channel);
+        w.u8(number);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, ConfigError> {
+        let kind = r.u8()?;
+        let channel = r.u8()?;
+        let number = r.u8()?;
+        if channel > 15 && channel != DEFAULT_CHANNEL {
+            return Err(ConfigError::Invalid);
+        }
+        match kind {
+            0 if number < 128 => Ok(Source::Note { channel, note: number }),
+            1 if number < 128 => Ok(Source::Cc { channel, control: number }),
+            2 => Ok(Source::Control(number)),
+            _ => Err(ConfigError::Invalid),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, channel, number) = match *self {
+            Source::Note { channel, note } => ("note", channel, note),
+            Source::Cc { channel, control } => ("cc", channel, control),
+            Source::Control(control) => return write!(f, "control {}", control),
+        };
+        write!(f, "{} {}", name, number)?;
+        match channel {
+            DEFAULT_CHANNEL => f.write_str(" ch -"),
+            ch => write!(f, " ch {}", ch + 1),
+        }
+    }
+}
+
+/// One LED and its source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Feedback {
+    pub led: LedId,
+    pub source: Source,
+}
+
+impl fmt::Display for Feedback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>3}  {}", self.led, self.source)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedbackError {
+    /// No room for another LED
+    Full,
+}
+
+/// LED sources, at most one per LED
+#[derive(Clone)]
+pub struct FeedbackTable {
+    entries: [Feedback; MAX_FEEDBACK],
+    count: usize,
+}
+
+impl FeedbackTable {
+    pub const fn new() -> Self {
+        const EMPTY: Feedback = Feedback { led: 0, source: Source::Control(0) };
+        Self { entries: [EMPTY; MAX_FEEDBACK], count: 0 }
+    }
+
+    /// Factory feedback: the green LED shows SW1's note, the red one SW2's
+    /// sustain pedal
+    pub fn factory() -> Self {
+        let mut table = Self::new();
+        let _ = table.set(Feedback { led: ids::GREEN, source: Source::Control(0) });
+        let _ = table.set(Feedback { led: ids::RED, source: Source::Control(1) });
+        table
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Feedback> {
+        self.entries[..self.count].iter()
+    }
+
+    /// Whether incoming MIDI drives an LED
+    pub fn drives(&self, led: LedId) -> bool {
+        self.iter().any(|entry| entry.led == led)
+    }
+
+    /// Add an entry, replacing the one of the same LED.
+    pub fn set(&mut self, feedback: Feedback) -> Result<(), FeedbackError> {
+        if let Some(entry) = self.entries[..self.count].iter_mut().find(|e| e.led == feedback.led) {
+            *entry = feedback;
+            return Ok(());
+        }
+        if self.count == MAX_FEEDBACK {
+            return Err(FeedbackError::Full);
+        }
+        self.entries[self.count] = feedback;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Remove the entry of an LED, returns whether there was one.
+    pub fn remove(&mut self, led: LedId) -> bool {
+        let Some(index) = self.iter().position(|e| e.led == led) else {
+            return false;
+        };
+        self.entries.copy_within(index + 1..self.count, index);
+        self.count -= 1;
+        true
+    }
+
+    /// Set the level of every LED whose address the message carries, or
+    /// the parameter update `change` the decoder made of it; 14-bit values
+    /// show by their upper 7 bits.
+    pub fn process(
+        &self,
+        mappings: &MappingTable,
+        default_channel: u8,
+        message: &MidiMessage,
+        change: Option<&ParamChange>,
+        set: &mut dyn FnMut(LedId, u8),
+    ) {
+        let plain = Address::of_message(message);
+        let hires = change.and_then(Address::of_change).map(|(address, value)| (address, (value >> 7) as u8));
+        for (address, value) in plain.into_iter().chain(hires) {
+            for entry in self.iter() {
+                if entry.source.matches(address, mappings, default_channel) {
+                    set(entry.led, value);
+                }
+            }
+        }
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        w.u8(self.count as u8);
+        for entry in self.iter() {
+            w.u8(entry.led);
+            entry.source.encode(w);
+        }
+    }
+
+    pub fn decode(&mut self, r: &mut Reader) -> Result<(), ConfigError> {
+        let count = r.u8()? as usize;
+        if count > MAX_FEEDBACK {
+            return Err(ConfigError::Invalid);
+        }
+        for i in 0..count {
+            let led = r.u8()?;
+            // One entry per LED, as `set` keeps it
+            if led > MAX_LED || self.entries[..i].iter().any(|e| e.led == led) {
+                return Err(ConfigError::Invalid);
+            }
+            self.entries[i] = Feedback { led, source: Source::decode(r)? };
+        }
+        self.count = count;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<FeedbackTable, ConfigError> {
+        let mut table = FeedbackTable::new();
+        table.decode(&mut Reader::new(bytes))?;
+        Ok(table)
+    }
+
+    #[test]
+    fn decodes_one_entry_per_led() {
+        let table = decode(&[2, ids::GREEN, 2, 0, 0, MAX_LED, 1, 3, 64]).unwrap();
+        let entries: Vec<_> = table.iter().copied().collect();
+        assert_eq!(
+            entries,
+            [
+                Feedback { led: ids::GREEN, source: Source::Control(0) },
+                Feedback { led: MAX_LED, source: Source::Cc { channel: 3, control: 64 } },
+            ]
+        );
+        assert_eq!(decode(&[2, ids::GREEN, 2, 0, 0, ids::GREEN, 1, 3, 64]).err(), Some(ConfigError::Invalid));
+        assert_eq!(decode(&[1, MAX_LED + 1, 2, 0, 0]).err(), Some(ConfigError::Invalid));
+    }
+}
@@ -0,0 +1,55 @@
+//! Flash Storage
+//!
+//! Reserved flash region holding the saved configuration. The region sits at
+//! the top of flash and is excluded from the FLASH region in memory.x.
+
+use crate::config::{Storage, StorageError};
+
+/// Start of the configuration region (must match memory.x)
+pub const CONFIG_BASE: usize = 0x0003_E000;
+/// Size of the configuration region
+pub const CONFIG_SIZE: usize = 8 * 1024;
+/// Erase block size of the TM4C123 flash
+const BLOCK_SIZE: usize = 1024;
+
+extern "C" {
+    /// Erase one 1 KB flash block, returns 0 on success
+    fn FlashErase(ui32Address: u32) -> i32;
+
+    /// Program words into flash, returns 0 on success
+    fn FlashProgram(pui32Data: *mut u32, ui32Address: u32, ui32Count: u32) -> i32;
+}
+
+/// Configuration region accessed through driverlib/flash.c
+pub struct FlashStorage;
+
+impl Storage for FlashStorage {
+    fn capacity(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn erase(&mut self) -> Result<(), StorageError> {
+        for block in (CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE).step_by(BLOCK_SIZE) {
+            if unsafe { FlashErase(block as u32) } != 0 {
+                return Err(StorageError::Erase);
+            }
+        }
+        Ok(())
+    }
+
+    fn program(&mut self, offset: usize, word: u32) -> Result<(), StorageError> {
+        if !offset.is_multiple_of(4) || offset + 4 > CONFIG_SIZE {
+            return Err(StorageError::Program);
+        }
+        let mut data = word;
+        match unsafe { FlashProgram(&mut data, (CONFIG_BASE + offset) as u32, 4) } {
+            0 => Ok(()),
+            _ => Err(StorageError::Program),
+        }
+    }
+
+    fn contents(&self) -> &[u8] {
+        // The region is memory mapped and never overlaps code (see memory.x)
+        unsafe { core::slice::from_raw_parts(CONFIG_BASE as *const u8, CONFIG_SIZE) }
+    }
+}
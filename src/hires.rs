@@ -0,0 +1,345 @@
+//! High-Resolution Parameters
+//!
+//! 14-bit controller pairs (CC 0-31 with their LSB at CC 32-63) and NRPN/RPN
+//! sequences, in both directions. `send_cc14` and `send_parameter` produce the
+//! controller messages for a 14-bit value; `Decoder` reassembles incoming
+//! controller streams into one `ParamChange` per parameter update.
+
+use crate::midi::MidiMessage;
+
+/// Data entry MSB; its LSB is CC 38
+pub const DATA_ENTRY: u8 = 6;
+pub const DATA_INCREMENT: u8 = 96;
+pub const DATA_DECREMENT: u8 = 97;
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+
+/// RPN 127/127, deselects the current parameter
+pub const RPN_NULL: u16 = 0x3FFF;
+
+/// Offset of the LSB controller of a 14-bit pair
+const LSB_OFFSET: u8 = 32;
+
+const MAX_VALUE: u16 = 0x3FFF;
+
+/// How 14-bit values are sent and expected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    /// Send the LSB before the MSB (some devices latch on the LSB)
+    pub lsb_first: bool,
+    /// Follow every NRPN/RPN update with RPN null
+    pub null_terminate: bool,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { lsb_first: false, null_terminate: false }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parameter number space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    Nrpn,
+    Rpn,
+}
+
+fn cc(channel: u8, control: u8, value: u16) -> MidiMessage {
+    MidiMessage::ControlChange { channel, control, value: (value & 0x7F) as u8 }
+}
+
+/// MSB and LSB controllers of a pair, in the configured order.
+fn pair(channel: u8, msb: u8, lsb: u8, value: u16, options: &Options, out: &mut dyn FnMut(MidiMessage)) {
+    let (first, second) = (cc(channel, msb, value >> 7), cc(channel, lsb, value));
+    if options.lsb_first {
+        out(second);
+        out(first);
+    } else {
+        out(first);
+        out(second);
+    }
+}
+
+/// 14-bit value on controller `control` (0-31) and `control + 32`.
+pub fn send_cc14(channel: u8, control: u8, value: u16, options: &Options, out: &mut dyn FnMut(MidiMessage)) {
+    pair(channel, control, control + LSB_OFFSET, value, options, out);
+}
+
+/// Parameter number followed by the data entry pair, optionally closed with
+/// RPN null. The parameter number is always sent MSB first.
+pub fn send_parameter(
+    channel: u8,
+    kind: ParamKind,
+    param: u16,
+    value: u16,
+    options: &Options,
+    out: &mut dyn FnMut(MidiMessage),
+) {
+    let (msb, lsb) = match kind {
+        ParamKind::Nrpn => (NRPN_MSB, NRPN_LSB),
+        ParamKind::Rpn => (RPN_MSB, RPN_LSB),
+    };
+    out(cc(channel, msb, param >> 7));
+    out(cc(channel, lsb, param));
+    pair(channel, DATA_ENTRY, DATA_ENTRY + LSB_OFFSET, value, options, out);
+    if options.null_terminate {
+        out(cc(channel, RPN_MSB, RPN_NULL >> 7));
+        out(cc(channel, RPN_LSB, RPN_NULL));
+    }
+}
+
+// ============================================================================
+// Decoder
+// ============================================================================
+
+/// A parameter as seen by the decoder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Param {
+    /// 7-bit controller (64-95, 102-119), value 0-127
+    Cc(u8),
+    /// 14-bit controller pair 0-31, value 0-16383
+    Cc14(u8),
+    Nrpn(u16),
+    Rpn(u16),
+}
+
+/// One reassembled parameter update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamChange {
+    pub channel: u8,
+    pub param: Param,
+    pub value: u16,
+}
+
+#[derive(Clone, Copy)]
+struct ChannelState {
+    msb: [u8; 32],
+    lsb: [u8; 32],
+    /// Pairs for which an LSB has been received; a pair whose LSB was never
+    /// seen is treated as a 7-bit controller and reported on every MSB
+    lsb_seen: u32,
+    /// Selected parameter and its number space
+    selected: Option<ParamKind>,
+    param_msb: u8,
+    param_lsb: u8,
+}
+
+impl ChannelState {
+    const fn new() -> Self {
+        Self { msb: [0; 32], lsb: [0; 32], lsb_seen: 0, selected: None, param_msb: 0, param_lsb: 0 }
+    }
+
+    fn value(&self, index: usize) -> u16 {
+        (self.msb[index] as u16) << 7 | self.lsb[index] as u16
+    }
+}
+
+/// Reassembles 14-bit controller pairs and NRPN/RPN sequences per channel
+pub struct Decoder {
+    channels: [ChannelState; 16],
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self { channels: [ChannelState::new(); 16] }
+    }
+
+    pub fn reset(&mut self) {
+        self.channels = [ChannelState::new(); 16];
+    }
+
+    /// Feed a received message. Controllers that belong to a pair or a
+    /// parameter sequence report a change once the update is complete; other
+    /// controllers are reported right away. Returns `None` for anything else.
+    pub fn feed(&mut self, message: &MidiMessage, options: &Options) -> Option<ParamChange> {
+        let MidiMessage::ControlChange { channel, control, value } = *message else {
+            return None;
+        };
+        let state = &mut self.channels[channel as usize & 0x0F];
+        let param = match control {
+            0..=31 => {
+                let index = control as usize;
+                state.msb[index] = value;
+                let has_lsb = state.lsb_seen & (1 << index) != 0;
+                // MSB first: the LSB completes the update, LSB first: the MSB does
+                if has_lsb && !options.lsb_first {
+                    return None;
+                }
+                Self::pair_param(state, control)?
+            }
+            32..=63 => {
+                let index = (control - LSB_OFFSET) as usize;
+                state.lsb[index] = value;
+                state.lsb_seen |= 1 << index;
+                if options.lsb_first {
+                    return None;
+                }
+                Self::pair_param(state, control - LSB_OFFSET)?
+            }
+            NRPN_MSB | NRPN_LSB | RPN_MSB | RPN_LSB => {
+                let kind = if control >= RPN_LSB { ParamKind::Rpn } else { ParamKind::Nrpn };
+                if state.selected != Some(kind) {
+                    state.selected = Some(kind);
+                    state.param_msb = 0x7F;
+                    state.param_lsb = 0x7F;
+                }
+                if control & 1 != 0 {
+                    state.param_msb = value;
+                } else {
+                    state.param_lsb = value;
+                }
+                if kind == ParamKind::Rpn && state.param_msb == 0x7F && state.param_lsb == 0x7F {
+                    state.selected = None;
+                }
+                // New parameter, data entry starts over; it may come without
+                // an LSB this time
+                state.msb[DATA_ENTRY as usize] = 0;
+                state.lsb[DATA_ENTRY as usize] = 0;
+                state.lsb_seen &= !(1 << DATA_ENTRY);
+                return None;
+            }
+            DATA_INCREMENT | DATA_DECREMENT => {
+                let index = DATA_ENTRY as usize;
+                let current = state.value(index);
+                let next = if control == DATA_INCREMENT {
+                    (current + 1).min(MAX_VALUE)
+                } else {
+                    current.saturating_sub(1)
+                };
+                state.msb[index] = (next >> 7) as u8;
+                state.lsb[index] = (next & 0x7F) as u8;
+                Self::pair_param(state, DATA_ENTRY)?
+            }
+            // Channel mode messages are not parameters
+            120..=127 => return None,
+            _ => {
+                return Some(ParamChange { channel, param: Param::Cc(control), value: value as u16 });
+            }
+        };
+        let index = match param {
+            Param::Cc14(control) => control as usize,
+            _ => DATA_ENTRY as usize,
+        };
+        Some(ParamChange { channel, param, value: state.value(index) })
+    }
+
+    /// Parameter a completed pair belongs to; data entry goes to the selected
+    /// NRPN/RPN and is dropped when none is selected.
+    fn pair_param(state: &ChannelState, control: u8) -> Option<Param> {
+        if control != DATA_ENTRY {
+            return Some(Param::Cc14(control));
+        }
+        let number = (state.param_msb as u16) << 7 | state.param_lsb as u16;
+        match state.selected? {
+            ParamKind::Nrpn => Some(Param::Nrpn(number)),
+            ParamKind::Rpn => Some(Param::Rpn(number)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB_FIRST: Options = Options { lsb_first: true, null_terminate: false };
+
+    fn sent(send: impl FnOnce(&mut dyn FnMut(MidiMessage))) -> Vec<(u8, u8)> {
+        let mut sent = Vec::new();
+        send(&mut |m| match m {
+            MidiMessage::ControlChange { control, value, .. } => sent.push((control, value)),
+            other => panic!("{other:?}"),
+        });
+        sent
+    }
+
+    fn feed(decoder: &mut Decoder, options: &Options, controls: &[(u8, u8)]) -> Vec<(Param, u16)> {
+        let mut changes = Vec::new();
+        for &(control, value) in controls {
+            let message = MidiMessage::ControlChange { channel: 3, control, value };
+            if let Some(change) = decoder.feed(&message, options) {
+                assert_eq!(change.channel, 3);
+                changes.push((change.param, change.value));
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn sends_pairs() {
+        assert_eq!(sent(|out| send_cc14(0, 7, 0x1234, &Options::new(), out)), [(7, 0x24), (39, 0x34)]);
+        assert_eq!(sent(|out| send_cc14(0, 7, 0x1234, &LSB_FIRST, out)), [(39, 0x34), (7, 0x24)]);
+    }
+
+    #[test]
+    fn sends_parameters() {
+        let options = Options { lsb_first: true, null_terminate: true };
+        assert_eq!(
+            sent(|out| send_parameter(0, ParamKind::Nrpn, 0x0123, 0x1234, &Options::new(), out)),
+            [(NRPN_MSB, 0x02), (NRPN_LSB, 0x23), (DATA_ENTRY, 0x24), (38, 0x34)]
+        );
+        assert_eq!(
+            sent(|out| send_parameter(0, ParamKind::Rpn, 0x0123, 0x1234, &options, out)),
+            [(RPN_MSB, 0x02), (RPN_LSB, 0x23), (38, 0x34), (DATA_ENTRY, 0x24), (RPN_MSB, 0x7F), (RPN_LSB, 0x7F)]
+        );
+    }
+
+    #[test]
+    fn reassembles_pairs() {
+        let mut decoder = Decoder::new();
+        let options = Options::new();
+        // Without an LSB the MSB is a 7-bit controller, once there is one
+        // the LSB completes the value
+        assert_eq!(feed(&mut decoder, &options, &[(7, 0x24)]), [(Param::Cc14(7), 0x24 << 7)]);
+        assert_eq!(feed(&mut decoder, &options, &[(39, 0x34)]), [(Param::Cc14(7), 0x1234)]);
+        assert_eq!(feed(&mut decoder, &options, &[(7, 0x25), (39, 0x00)]), [(Param::Cc14(7), 0x25 << 7)]);
+        assert_eq!(feed(&mut decoder, &options, &[(64, 127), (121, 0)]), [(Param::Cc(64), 127)]);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(feed(&mut decoder, &LSB_FIRST, &[(39, 0x34), (7, 0x24)]), [(Param::Cc14(7), 0x1234)]);
+    }
+
+    #[test]
+    fn reassembles_parameters() {
+        let mut decoder = Decoder::new();
+        let options = Options { lsb_first: false, null_terminate: true };
+        let update = sent(|out| send_parameter(0, ParamKind::Nrpn, 0x0123, 0x1234, &options, out));
+        assert_eq!(
+            feed(&mut decoder, &Options::new(), &update),
+            [(Param::Nrpn(0x0123), 0x24 << 7), (Param::Nrpn(0x0123), 0x1234)]
+        );
+        // RPN null deselects, data entry goes nowhere
+        assert!(feed(&mut decoder, &Options::new(), &[(DATA_ENTRY, 5)]).is_empty());
+
+        // A 7-bit update after a 14-bit one
+        let update = [(NRPN_MSB, 0x02), (NRPN_LSB, 0x23), (DATA_ENTRY, 10)];
+        assert_eq!(feed(&mut decoder, &Options::new(), &update), [(Param::Nrpn(0x0123), 10 << 7)]);
+
+        let mut decoder = Decoder::new();
+        let update = sent(|out| send_parameter(0, ParamKind::Rpn, 2, 0x1234, &LSB_FIRST, out));
+        assert_eq!(feed(&mut decoder, &LSB_FIRST, &update), [(Param::Rpn(2), 0x1234)]);
+    }
+
+    #[test]
+    fn steps_data_entry() {
+        let mut decoder = Decoder::new();
+        let options = Options::new();
+        feed(&mut decoder, &options, &[(RPN_MSB, 0), (RPN_LSB, 0), (DATA_ENTRY, 2)]);
+        let steps = [(DATA_INCREMENT, 0), (DATA_DECREMENT, 0), (DATA_DECREMENT, 0)];
+        assert_eq!(
+            feed(&mut decoder, &options, &steps),
+            [(Param::Rpn(0), 257), (Param::Rpn(0), 256), (Param::Rpn(0), 255)]
+        );
+        feed(&mut decoder, &options, &[(DATA_ENTRY, 127), (38, 127)]);
+        assert_eq!(feed(&mut decoder, &options, &[(DATA_INCREMENT, 0)]), [(Param::Rpn(0), MAX_VALUE)]);
+        feed(&mut decoder, &options, &[(DATA_ENTRY, 0), (38, 0)]);
+        assert_eq!(feed(&mut decoder, &options, &[(DATA_DECREMENT, 0)]), [(Param::Rpn(0), 0)]);
+    }
+}
@@ -0,0 +1,402 @@
+//! IMU Driver
+//!
+//! An MPU6050, MPU9150 or LSM303DLHC on I2C3 (PD0 SCL, PD1 SDA), read
+//! through the TivaWare sensor library at 100 Hz. The readings go through
+//! the library's complementary DCM filter for the orientation, and `motion`
+//! turns that into control input. On the LaunchPad R9 and R10 tie PD0 and
+//! PD1 to PB6 and PB7 (the shift register port) and have to be removed.
+//!
+//! The sensor library runs the bus from the I2C interrupt and reports the
+//! end of each transfer through a callback; `poll` starts the next step of
+//! a sequence once the previous one finished, so nothing here waits for
+//! the bus.
+
+use core::ptr::{self, addr_of_mut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use tm4c123x::{GPIO_PORTD, SYSCTL};
+
+use crate::clock;
+use crate::input::{InputEvent, InputSource};
+use crate::motion::{Calibration, Calibrator, Motion, Options, Reading, Sensor};
+use crate::sensorlib::{self as sl, tCompDCM, tI2CMInstance, tLSM303DLHCAccel, tLSM303DLHCMag, tMPU6050, tMPU9150};
+use crate::usb_device;
+
+const I2C3_BASE: u32 = 0x4002_3000;
+
+/// PD0 SCL, PD1 SDA
+const PINS: u32 = 0x03;
+const SDA: u32 = 0x02;
+
+/// Sensor addresses: MPU with AD0 low, LSM303DLHC accelerometer and
+/// magnetometer
+const MPU_ADDRESS: u32 = 0x68;
+const ACCEL_ADDRESS: u32 = 0x19;
+const MAG_ADDRESS: u32 = 0x1E;
+
+/// Sample period
+const SAMPLE_MS: u32 = 10;
+/// A transfer that has not finished by then failed
+const TIMEOUT_MS: u32 = 100;
+/// Pause before talking to a sensor that did not answer
+const RETRY_MS: u32 = 1000;
+
+/// Callback status while a transfer is running
+const PENDING: u32 = u32::MAX;
+
+const RADIANS_TO_CENTIDEGREES: f32 = 18000.0 / core::f32::consts::PI;
+const MS2_TO_MILLI_G: f32 = 1000.0 / 9.80665;
+
+// Driver state shared with the I2C interrupt. Register writes point into
+// the constant sequences below, the library does not copy them.
+static mut I2C: tI2CMInstance = tI2CMInstance::new();
+static mut MPU6050: tMPU6050 = tMPU6050::new();
+static mut MPU9150: tMPU9150 = tMPU9150::new();
+static mut ACCEL: tLSM303DLHCAccel = tLSM303DLHCAccel::new();
+static mut MAG: tLSM303DLHCMag = tLSM303DLHCMag::new();
+static mut DCM: tCompDCM = tCompDCM::new();
+static STATUS: AtomicU32 = AtomicU32::new(0);
+
+/// Driver of one sensor chip
+#[derive(Clone, Copy)]
+enum Device {
+    Mpu6050,
+    Mpu9150,
+    Accel,
+    Mag,
+}
+
+/// One transfer through the sensor library
+#[derive(Clone, Copy)]
+enum Op {
+    /// Reset the chip and the driver
+    Init(Device),
+    /// Write registers from the first one given
+    Write(Device, u8, &'static [u8]),
+    /// Read the measurements into the driver
+    Read(Device),
+}
+
+/// After the reset: 44 Hz low pass filter, then wake up on the X gyroscope
+/// clock
+const MPU6050_INIT: &[Op] =
+    &[Op::Init(Device::Mpu6050), Op::Write(Device::Mpu6050, 0x1A, &[0x03]), Op::Write(Device::Mpu6050, 0x6B, &[0x01])];
+const MPU9150_INIT: &[Op] =
+    &[Op::Init(Device::Mpu9150), Op::Write(Device::Mpu9150, 0x1A, &[0x03]), Op::Write(Device::Mpu9150, 0x6B, &[0x01])];
+/// Accelerometer at 100 Hz on all axes, magnetometer at 75 Hz measuring
+/// continuously
+const LSM303DLHC_INIT: &[Op] = &[
+    Op::Init(Device::Accel),
+    Op::Write(Device::Accel, 0x20, &[0x57]),
+    Op::Init(Device::Mag),
+    Op::Write(Device::Mag, 0x00, &[0x18]),
+    Op::Write(Device::Mag, 0x02, &[0x00]),
+];
+
+/// Init and read sequences of a sensor
+fn sequences(sensor: Sensor) -> (&'static [Op], &'static [Op]) {
+    match sensor {
+        Sensor::None => (&[], &[]),
+        Sensor::Mpu6050 => (MPU6050_INIT, &[Op::Read(Device::Mpu6050)]),
+        Sensor::Mpu9150 => (MPU9150_INIT, &[Op::Read(Device::Mpu9150)]),
+        Sensor::Lsm303dlhc => (LSM303DLHC_INIT, &[Op::Read(Device::Accel), Op::Read(Device::Mag)]),
+    }
+}
+
+/// Filter weights of the accelerometer, gyroscope and magnetometer; a
+/// missing sensor has none
+fn weights(sensor: Sensor) -> (f32, f32, f32) {
+    match sensor {
+        Sensor::Mpu6050 => (0.2, 0.6, 0.0),
+        Sensor::Lsm303dlhc => (0.2, 0.0, 0.2),
+        _ => (0.2, 0.6, 0.2),
+    }
+}
+
+unsafe extern "C" fn done(_data: *mut core::ffi::c_void, status: u32) {
+    STATUS.store(status, Ordering::Release);
+}
+
+unsafe extern "C" fn interrupt() {
+    sl::I2CMIntHandler(addr_of_mut!(I2C));
+}
+
+/// Start a transfer; false if the driver refused it.
+fn start(op: Op) -> bool {
+    let callback: sl::tSensorCallback = Some(done);
+    let data = ptr::null_mut();
+    STATUS.store(PENDING, Ordering::Relaxed);
+    let started = unsafe {
+        let i2c = addr_of_mut!(I2C);
+        match op {
+            Op::Init(Device::Mpu6050) => sl::MPU6050Init(addr_of_mut!(MPU6050), i2c, MPU_ADDRESS, callback, data),
+            Op::Init(Device::Mpu9150) => sl::MPU9150Init(addr_of_mut!(MPU9150), i2c, MPU_ADDRESS, callback, data),
+            Op::Init(Device::Accel) => sl::LSM303DLHCAccelInit(addr_of_mut!(ACCEL), i2c, ACCEL_ADDRESS, callback, data),
+            Op::Init(Device::Mag) => sl::LSM303DLHCMagInit(addr_of_mut!(MAG), i2c, MAG_ADDRESS, callback, data),
+            Op::Write(device, register, bytes) => {
+                let (register, bytes, count) = (register as u32, bytes.as_ptr(), bytes.len() as u32);
+                match device {
+                    Device::Mpu6050 => sl::MPU6050Write(addr_of_mut!(MPU6050), register, bytes, count, callback, data),
+                    Device::Mpu9150 => sl::MPU9150Write(addr_of_mut!(MPU9150), register, bytes, count, callback, data),
+                    Device::Accel => {
+                        sl::LSM303DLHCAccelWrite(addr_of_mut!(ACCEL), register, bytes, count, callback, data)
+                    }
+                    Device::Mag => sl::LSM303DLHCMagWrite(addr_of_mut!(MAG), register, bytes, count, callback, data),
+                }
+            }
+            Op::Read(Device::Mpu6050) => sl::MPU6050DataRead(addr_of_mut!(MPU6050), callback, data),
+            Op::Read(Device::Mpu9150) => sl::MPU9150DataRead(addr_of_mut!(MPU9150), callback, data),
+            Op::Read(Device::Accel) => sl::LSM303DLHCAccelDataRead(addr_of_mut!(ACCEL), callback, data),
+            Op::Read(Device::Mag) => sl::LSM303DLHCMagDataRead(addr_of_mut!(MAG), callback, data),
+        }
+    };
+    started != 0
+}
+
+/// Measurements of the last read, in the library's units (m/s², rad/s,
+/// tesla); a sensor without a gyroscope reads no rotation, one without a
+/// magnetometer a fixed field along X so the filter keeps its heading
+fn measurements(sensor: Sensor) -> [[f32; 3]; 3] {
+    let mut m = [[0.0; 3], [0.0; 3], [1.0, 0.0, 0.0]];
+    unsafe {
+        let [accel, gyro, mag] = &mut m;
+        let [ax, ay, az] = accel.each_mut();
+        let [gx, gy, gz] = gyro.each_mut();
+        let [mx, my, mz] = mag.each_mut();
+        match sensor {
+            Sensor::None => {}
+            Sensor::Mpu6050 => {
+                sl::MPU6050DataAccelGetFloat(addr_of_mut!(MPU6050), ax, ay, az);
+                sl::MPU6050DataGyroGetFloat(addr_of_mut!(MPU6050), gx, gy, gz);
+            }
+            Sensor::Mpu9150 => {
+                sl::MPU9150DataAccelGetFloat(addr_of_mut!(MPU9150), ax, ay, az);
+                sl::MPU9150DataGyroGetFloat(addr_of_mut!(MPU9150), gx, gy, gz);
+                sl::MPU9150DataMagnetoGetFloat(addr_of_mut!(MPU9150), mx, my, mz);
+            }
+            Sensor::Lsm303dlhc => {
+                sl::LSM303DLHCAccelDataAccelGetFloat(addr_of_mut!(ACCEL), ax, ay, az);
+                sl::LSM303DLHCMagDataMagnetoGetFloat(addr_of_mut!(MAG), mx, my, mz);
+            }
+        }
+    }
+    m
+}
+
+pub struct Imu {
+    options: Options,
+    /// Sequence being run and the next step of it
+    ops: &'static [Op],
+    index: usize,
+    /// Start of the transfer in progress
+    busy_since: Option<u32>,
+    /// Init sequence done, the sensor is read from now on
+    running: bool,
+    /// The filter has its first sample
+    filtering: bool,
+    next_read: u32,
+    /// No transfers before this time after an error
+    retry_at: Option<u32>,
+    reading: Option<Reading>,
+    motion: Motion,
+    calibrator: Option<Calibrator>,
+    calibrated: Option<Calibration>,
+}
+
+impl Imu {
+    /// Set up I2C3 for the sensor library; no sensor until `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let portd = unsafe { &*GPIO_PORTD::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 3)) });
+        while sysctl.prgpio.read().bits() & (1 << 3) == 0 {}
+        sysctl.rcgci2c.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 3)) });
+        while sysctl.pri2c.read().bits() & (1 << 3) == 0 {}
+
+        // I2C3 is alternate function 3 on PD0 and PD1, SDA open drain
+        portd.afsel.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+        portd.odr.modify(|r, w| unsafe { w.bits(r.bits() | SDA) });
+        portd.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0xFF) | 0x33) });
+        portd.den.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+
+        unsafe {
+            usb_device::IntRegister(usb_device::ints::INT_I2C3, interrupt);
+            sl::I2CMInit(
+                addr_of_mut!(I2C),
+                I2C3_BASE,
+                usb_device::ints::INT_I2C3,
+                0xFF,
+                0xFF,
+                usb_device::SysCtlClockGet(),
+            );
+        }
+
+        Self {
+            options: Options::new(),
+            ops: &[],
+            index: 0,
+            busy_since: None,
+            running: false,
+            filtering: false,
+            next_read: 0,
+            retry_at: None,
+            reading: None,
+            motion: Motion::new(),
+            calibrator: None,
+            calibrated: None,
+        }
+    }
+
+    /// Follow the settings; a new sensor is initialised once the bus is
+    /// free.
+    pub fn configure(&mut self, options: &Options) {
+        let changed = options.sensor != self.options.sensor;
+        if changed && self.busy_since.is_some() {
+            return;
+        }
+        self.options = *options;
+        if changed {
+            self.restart();
+            self.retry_at = None;
+        }
+    }
+
+    /// Sensor answering and being read
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Latest sample
+    pub fn reading(&self) -> Option<Reading> {
+        self.reading
+    }
+
+    /// Average the next samples into a new calibration; the sensor has to
+    /// lie level and still meanwhile.
+    pub fn calibrate(&mut self) {
+        self.calibrator = Some(Calibrator::new());
+    }
+
+    pub fn calibrating(&self) -> bool {
+        self.calibrator.is_some()
+    }
+
+    /// A finished calibration, once
+    pub fn take_calibration(&mut self) -> Option<Calibration> {
+        self.calibrated.take()
+    }
+
+    /// Start over with the init sequence.
+    fn restart(&mut self) {
+        self.ops = sequences(self.options.sensor).0;
+        self.index = 0;
+        self.running = false;
+        self.filtering = false;
+        self.reading = None;
+        self.motion.reset();
+    }
+
+    /// Give up on the sensor for a while.
+    fn fail(&mut self, now: u32) {
+        self.busy_since = None;
+        self.restart();
+        self.retry_at = Some(now.wrapping_add(RETRY_MS));
+    }
+
+    /// Fold the measurements just read into the filter and the reading.
+    fn sample(&mut self) -> Reading {
+        let sensor = self.options.sensor;
+        let [accel, raw, mag] = measurements(sensor);
+        let mut gyro = raw;
+        for (rate, bias) in gyro.iter_mut().zip(self.options.calibration.gyro) {
+            *rate -= bias as f32 / RADIANS_TO_CENTIDEGREES;
+        }
+        let (mut roll, mut pitch, mut yaw) = (0.0, 0.0, 0.0);
+        unsafe {
+            let dcm = addr_of_mut!(DCM);
+            if !self.filtering {
+                let (a, g, m) = weights(sensor);
+                sl::CompDCMInit(dcm, SAMPLE_MS as f32 / 1000.0, a, g, m);
+            }
+            sl::CompDCMMagnetoUpdate(dcm, mag[0], mag[1], mag[2]);
+            sl::CompDCMAccelUpdate(dcm, accel[0], accel[1], accel[2]);
+            sl::CompDCMGyroUpdate(dcm, gyro[0], gyro[1], gyro[2]);
+            if self.filtering {
+                sl::CompDCMUpdate(dcm);
+            } else {
+                sl::CompDCMStart(dcm);
+                self.filtering = true;
+            }
+            sl::CompDCMComputeEulers(dcm, &mut roll, &mut pitch, &mut yaw);
+        }
+        let angle = |radians: f32| (radians * RADIANS_TO_CENTIDEGREES) as i32;
+        Reading {
+            roll: angle(roll),
+            pitch: angle(pitch),
+            yaw: angle(yaw),
+            accel: accel.map(|a| (a * MS2_TO_MILLI_G) as i32),
+            // Before the calibration, which is measured from these
+            gyro: raw.map(angle),
+        }
+    }
+}
+
+impl InputSource for Imu {
+    /// Move the current sequence on by one transfer; report a sample when
+    /// a read is complete.
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        if self.options.sensor == Sensor::None {
+            return;
+        }
+        if let Some(retry_at) = self.retry_at {
+            if !clock::reached(now, retry_at) {
+                return;
+            }
+            self.retry_at = None;
+        }
+        if let Some(since) = self.busy_since {
+            match STATUS.load(Ordering::Acquire) {
+                PENDING if now.wrapping_sub(since) < TIMEOUT_MS => return,
+                sl::I2CM_STATUS_SUCCESS => {
+                    self.busy_since = None;
+                    self.index += 1;
+                }
+                _ => return self.fail(now),
+            }
+        }
+        if let Some(&op) = self.ops.get(self.index) {
+            if !start(op) {
+                return self.fail(now);
+            }
+            self.busy_since = Some(now);
+            return;
+        }
+
+        // A sequence finished: a read once running, before that the init
+        if self.index > 0 && self.running {
+            let reading = self.sample();
+            self.reading = Some(reading);
+            if let Some(calibrator) = &mut self.calibrator {
+                if let Some(calibration) = calibrator.push(&reading) {
+                    self.calibrated = Some(calibration);
+                    self.calibrator = None;
+                }
+            }
+            self.motion.update(now, &reading, &self.options, emit);
+        } else if self.index > 0 {
+            self.running = true;
+            self.next_read = now;
+        }
+        self.index = 0;
+        self.ops = &[];
+        if self.running && clock::reached(now, self.next_read) {
+            // Catch up on a late sample, but never with a burst
+            self.next_read = match clock::reached(now, self.next_read.wrapping_add(SAMPLE_MS)) {
+                true => now.wrapping_add(SAMPLE_MS),
+                false => self.next_read.wrapping_add(SAMPLE_MS),
+            };
+            self.ops = sequences(self.options.sensor).1;
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Physical Inputs
+//!
+//! Input sources (buttons, pots, encoders, pads...) report what happened to a
+//! control as an `InputEvent`; the mapping engine turns events into MIDI.
+//! Controls are identified by a number; every source owns a fixed range of
+//! ids (see `ids`).
+
+/// Full scale of absolute values and velocities (14 bit)
+pub const FULL_SCALE: u16 = 16383;
+
+pub type ControlId = u8;
+
+/// Control id ranges of the input sources
+pub mod ids {
+    /// LaunchPad SW1 and SW2
+    pub const ONBOARD: u8 = 0;
+    /// Motorised faders
+    pub const FADER: u8 = 8;
+    /// Touch on the knobs of motorised faders
+    pub const FADER_TOUCH: u8 = 12;
+    /// 74HC165 shift register inputs, one id per input
+    pub const SHIFT: u8 = 16;
+    /// IMU pitch, roll, yaw and shake (see `motion::axis`)
+    pub const MOTION: u8 = 80;
+    /// Capacitive touch pads, one id per pad
+    pub const TOUCH: u8 = 84;
+    /// Capacitive ribbon position, pressure and touch (see
+    /// `capsense::ribbon`)
+    pub const RIBBON: u8 = 92;
+    /// Velocity sensitive drum pads, one id per pad
+    pub const DRUM: u8 = 96;
+    /// Expression jacks, one id per jack (see `pedal`)
+    pub const PEDAL: u8 = 100;
+    /// Sustain jacks, one id per jack
+    pub const SUSTAIN: u8 = 102;
+}
+
+/// Most 74HC165 inputs, eight per chip
+pub const MAX_SHIFT_INPUTS: usize = 64;
+
+/// What happened to a control
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    /// Button or pad pressed, with velocity if the control senses it
+    Press(Option<u16>),
+    Release,
+    /// Absolute position (pot, fader, pedal), `0..=FULL_SCALE`
+    Absolute(u16),
+    /// Relative movement in detents (encoder)
+    Relative(i16),
+    /// Pressure while held, `0..=FULL_SCALE`
+    Pressure(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub control: ControlId,
+    pub input: Input,
+}
+
+/// Something that produces input events when polled from the main loop
+pub trait InputSource {
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent));
+}
@@ -0,0 +1,398 @@
+//! Minimal JSON
+//!
+//! Just enough JSON for MIDI-CI property exchange. Documents are written with
+//! `core::fmt` into a `Window`, which keeps only a byte range of the output so
+//! a long document can be produced chunk by chunk by rendering it again.
+//! The parser walks objects and arrays and hands out values as borrowed
+//! slices; nested containers come back unparsed. Strings are not unescaped
+//! and numbers are integers only. `ArrayReader` parses an array of objects
+//! that arrives in pieces.
+
+use core::fmt;
+
+/// `fmt::Write` sink that keeps the bytes `skip..skip + buf.len()` of
+/// everything written and counts the rest.
+pub struct Window<'a> {
+    buf: &'a mut [u8],
+    skip: usize,
+    total: usize,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(buf: &'a mut [u8], skip: usize) -> Self {
+        Self { buf, skip, total: 0 }
+    }
+
+    /// Bytes written so far, kept or not
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Bytes stored in the buffer
+    pub fn len(&self) -> usize {
+        self.total.saturating_sub(self.skip).min(self.buf.len())
+    }
+}
+
+impl fmt::Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if let Some(slot) = self.total.checked_sub(self.skip).and_then(|i| self.buf.get_mut(i)) {
+                *slot = byte;
+            }
+            self.total += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Displays a string as a quoted JSON string
+pub struct Str<'a>(pub &'a str);
+
+impl fmt::Display for Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => fmt::Write::write_char(f, c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+// ============================================================================
+// Parser
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Number(i32),
+    /// Contents between the quotes, escapes left in place
+    Str(&'a str),
+    /// The whole array text including brackets
+    Array(&'a str),
+    /// The whole object text including braces, for `object`
+    Object(&'a str),
+}
+
+pub type Error = &'static str;
+
+const SYNTAX: Error = "invalid JSON";
+
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> Option<u8> {
+        let bytes = self.text.as_bytes();
+        while self.pos < bytes.len() && bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        if self.peek() != Some(byte) {
+            return Err(SYNTAX);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str) -> Result<(), Error> {
+        if !self.text[self.pos..].starts_with(word) {
+            return Err(SYNTAX);
+        }
+        self.pos += word.len();
+        Ok(())
+    }
+
+    /// String at the cursor, without the quotes
+    fn string(&mut self) -> Result<&'a str, Error> {
+        self.expect(b'"')?;
+        let bytes = self.text.as_bytes();
+        let start = self.pos;
+        while let Some(&byte) = bytes.get(self.pos) {
+            self.pos += 1;
+            match byte {
+                b'"' => return Ok(&self.text[start..self.pos - 1]),
+                b'\\' => self.pos += 1,
+                _ => {}
+            }
+        }
+        Err(SYNTAX)
+    }
+
+    /// Skip an array or object, keeping track of strings
+    fn container(&mut self) -> Result<&'a str, Error> {
+        let bytes = self.text.as_bytes();
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(&byte) = bytes.get(self.pos) {
+            match byte {
+                b'"' => {
+                    self.string()?;
+                    continue;
+                }
+                b'[' | b'{' => depth += 1,
+                b']' | b'}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return Ok(&self.text[start..self.pos]);
+                    }
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Err(SYNTAX)
+    }
+
+    fn number(&mut self) -> Result<i32, Error> {
+        let bytes = self.text.as_bytes();
+        let negative = bytes[self.pos] == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let start = self.pos;
+        let mut value: i32 = 0;
+        while let Some(&byte @ b'0'..=b'9') = bytes.get(self.pos) {
+            value = value.checked_mul(10).and_then(|v| v.checked_add((byte - b'0') as i32)).ok_or(SYNTAX)?;
+            self.pos += 1;
+        }
+        if self.pos == start || matches!(bytes.get(self.pos), Some(b'.' | b'e' | b'E')) {
+            return Err(SYNTAX);
+        }
+        Ok(if negative { -value } else { value })
+    }
+
+    fn value(&mut self) -> Result<Value<'a>, Error> {
+        Ok(match self.peek().ok_or(SYNTAX)? {
+            b'"' => Value::Str(self.string()?),
+            b'[' => Value::Array(self.container()?),
+            b'{' => Value::Object(self.container()?),
+            b't' => self.literal("true").map(|_| Value::Bool(true))?,
+            b'f' => self.literal("false").map(|_| Value::Bool(false))?,
+            b'n' => self.literal("null").map(|_| Value::Null)?,
+            b'-' | b'0'..=b'9' => Value::Number(self.number()?),
+            _ => return Err(SYNTAX),
+        })
+    }
+
+    /// Comma separated items up to `close`, the opening bracket already taken
+    fn items(&mut self, close: u8, item: &mut dyn FnMut(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        if self.peek() == Some(close) {
+            self.pos += 1;
+        } else {
+            loop {
+                item(self)?;
+                match self.peek() {
+                    Some(b',') => self.pos += 1,
+                    Some(byte) if byte == close => {
+                        self.pos += 1;
+                        break;
+                    }
+                    _ => return Err(SYNTAX),
+                }
+            }
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(SYNTAX),
+        }
+    }
+}
+
+/// Call `member` with every key and value of an object.
+pub fn object<'a>(text: &'a str, member: &mut dyn FnMut(&'a str, Value<'a>) -> Result<(), Error>) -> Result<(), Error> {
+    let mut cursor = Cursor { text, pos: 0 };
+    cursor.expect(b'{')?;
+    cursor.items(b'}', &mut |cursor| {
+        let key = cursor.string()?;
+        cursor.expect(b':')?;
+        member(key, cursor.value()?)
+    })
+}
+
+/// Call `element` with every value of an array.
+pub fn array<'a>(text: &'a str, element: &mut dyn FnMut(Value<'a>) -> Result<(), Error>) -> Result<(), Error> {
+    let mut cursor = Cursor { text, pos: 0 };
+    cursor.expect(b'[')?;
+    cursor.items(b']', &mut |cursor| element(cursor.value()?))
+}
+
+/// Array of objects or arrays parsed as it arrives: each `feed` hands out the
+/// elements that are complete and says how much of the text it used; the
+/// caller keeps the rest and passes it again with the next piece appended.
+pub struct ArrayReader {
+    state: ReaderState,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReaderState {
+    Open,
+    First,
+    Next,
+    Closed,
+}
+
+impl ArrayReader {
+    pub const fn new() -> Self {
+        Self { state: ReaderState::Open }
+    }
+
+    /// The closing bracket was seen
+    pub fn finished(&self) -> bool {
+        self.state == ReaderState::Closed
+    }
+
+    /// Call `element` with every complete element at the start of `text`,
+    /// returns the number of bytes consumed.
+    pub fn feed<'a>(&mut self, text: &'a str, element: &mut dyn FnMut(Value<'a>) -> Result<(), Error>) -> Result<usize, Error> {
+        let mut cursor = Cursor { text, pos: 0 };
+        loop {
+            let start = cursor.pos;
+            let Some(byte) = cursor.peek() else {
+                return Ok(cursor.pos);
+            };
+            match self.state {
+                ReaderState::Open => {
+                    cursor.expect(b'[')?;
+                    self.state = ReaderState::First;
+                }
+                ReaderState::Closed => return Err(SYNTAX),
+                _ if byte == b']' => {
+                    cursor.pos += 1;
+                    self.state = ReaderState::Closed;
+                }
+                state => {
+                    if state == ReaderState::Next {
+                        cursor.expect(b',')?;
+                    }
+                    if !matches!(cursor.peek(), Some(b'{' | b'[')) {
+                        // Scalar, or the text ends after the comma
+                        return match cursor.peek() {
+                            None => Ok(start),
+                            Some(_) => Err(SYNTAX),
+                        };
+                    }
+                    // Containers only fail to parse when they are cut off
+                    let Ok(value) = cursor.container() else {
+                        return Ok(start);
+                    };
+                    let value = match value.as_bytes()[0] {
+                        b'{' => Value::Object(value),
+                        _ => Value::Array(value),
+                    };
+                    element(value)?;
+                    self.state = ReaderState::Next;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn keeps_a_window_of_the_output() {
+        let mut buf = [0u8; 4];
+        let mut window = Window::new(&mut buf, 3);
+        write!(window, "{}{}", Str("a\"b"), 12).unwrap();
+        assert_eq!((window.total(), window.len()), (8, 4));
+        assert_eq!(&buf, b"\"b\"1");
+
+        let mut window = Window::new(&mut buf, 10);
+        window.write_str("short").unwrap();
+        assert_eq!((window.total(), window.len()), (5, 0));
+
+        let mut buf = [0u8; 16];
+        let mut window = Window::new(&mut buf, 0);
+        write!(window, "{}", Str("\\\n")).unwrap();
+        let len = window.len();
+        assert_eq!(&buf[..len], b"\"\\\\\\u000a\"");
+    }
+
+    #[test]
+    fn parses_objects_and_arrays() {
+        let text = r#" { "n": -12, "s": "a\"b", "t": true, "f": false, "z": null, "a": [1, {"x": "]"}], "o": {} } "#;
+        let mut members = Vec::new();
+        object(text, &mut |key, value| {
+            members.push((key, value));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            members,
+            [
+                ("n", Value::Number(-12)),
+                ("s", Value::Str("a\\\"b")),
+                ("t", Value::Bool(true)),
+                ("f", Value::Bool(false)),
+                ("z", Value::Null),
+                ("a", Value::Array(r#"[1, {"x": "]"}]"#)),
+                ("o", Value::Object("{}")),
+            ]
+        );
+
+        let mut sum = 0;
+        array("[1,2, 3]", &mut |value| {
+            sum += match value {
+                Value::Number(n) => n,
+                _ => return Err("not a number"),
+            };
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sum, 6);
+        let mut numbers_only = |value| matches!(value, Value::Number(_)).then_some(()).ok_or("no");
+        assert_eq!(array("[1,\"x\"]", &mut numbers_only), Err("no"));
+
+        let mut ignore = |_: &str, _: Value| Ok(());
+        for bad in ["", "[]", "{", r#"{"a":1,}"#, r#"{"a":1.5}"#, r#"{"a":1e3}"#, r#"{"a":99999999999}"#, "{} x"] {
+            assert_eq!(object(bad, &mut ignore), Err(SYNTAX), "{bad}");
+        }
+    }
+
+    #[test]
+    fn reads_an_array_in_pieces() {
+        let text = r#"[ {"a":1}, ["}"], {"b":{"c":2}} ]"#;
+        for size in 1..text.len() {
+            let mut reader = ArrayReader::new();
+            let mut elements = Vec::new();
+            let mut kept = String::new();
+            for piece in text.as_bytes().chunks(size) {
+                kept.push_str(core::str::from_utf8(piece).unwrap());
+                let used = reader
+                    .feed(&kept, &mut |value| {
+                        elements.push(format!("{value:?}"));
+                        Ok(())
+                    })
+                    .unwrap();
+                kept.drain(..used);
+            }
+            assert!(reader.finished(), "pieces of {size}");
+            assert!(kept.trim().is_empty());
+            assert_eq!(elements, [r#"Object("{\"a\":1}")"#, r#"Array("[\"}\"]")"#, r#"Object("{\"b\":{\"c\":2}}")"#]);
+        }
+
+        let mut reader = ArrayReader::new();
+        assert_eq!(reader.feed("[1]", &mut |_| Ok(())), Err(SYNTAX));
+        let mut reader = ArrayReader::new();
+        assert_eq!(reader.feed("[] []", &mut |_| Ok(())), Err(SYNTAX));
+        let mut reader = ArrayReader::new();
+        assert_eq!(reader.feed("{}", &mut |_| Ok(())), Err(SYNTAX));
+    }
+}
@@ -0,0 +1,158 @@
+//! Pad Keymaps
+//!
+//! Controls mapped as pads (`Action::Pad`) play the note of their place in
+//! a grid of `columns` pads per row, pad 0 bottom left. The grid is
+//! isomorphic: a chord or scale shape plays the same anywhere on it.
+//!
+//! - Chromatic: every pad a semitone up, rows carry on where the row below
+//!   ended.
+//! - In key: only the notes of the key, rows a fourth apart (the scale
+//!   degrees below a fourth), so the pads never play a wrong note.
+//! - Fourths: every pad a semitone up, rows five semitones apart, like a
+//!   bass guitar; notes outside a key are left to the quantisation of
+//!   `arp` when one is set.
+//!
+//! Pad 0 is the root of the key in `octave`. Buttons mapped to
+//! `Action::Keymap` shift the octave, transpose the key a semitone at a
+//! time and step through scales and layouts; the result is part of the
+//! configuration and kept by `save`.
+
+use core::fmt;
+
+use crate::scale::{Key, Scale};
+
+/// Widest row
+pub const MAX_COLUMNS: u8 = 16;
+/// Highest octave of pad 0, C of octave 9 is note 108
+pub const MAX_OCTAVE: u8 = 9;
+
+/// How notes are laid out on the pads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Chromatic = 0,
+    InKey = 1,
+    Fourths = 2,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 3] = [Layout::Chromatic, Layout::InKey, Layout::Fourths];
+
+    pub fn from_u8(value: u8) -> Option<Layout> {
+        Layout::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Layout::Chromatic => "chromatic",
+            Layout::InKey => "in key",
+            Layout::Fourths => "fourths",
+        })
+    }
+}
+
+/// What a keymap button does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyOp {
+    OctaveDown = 0,
+    OctaveUp = 1,
+    /// Key a semitone down or up, into the next octave past the ends
+    TransposeDown = 2,
+    TransposeUp = 3,
+    NextScale = 4,
+    NextLayout = 5,
+}
+
+impl KeyOp {
+    pub const ALL: [KeyOp; 6] = [
+        KeyOp::OctaveDown,
+        KeyOp::OctaveUp,
+        KeyOp::TransposeDown,
+        KeyOp::TransposeUp,
+        KeyOp::NextScale,
+        KeyOp::NextLayout,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<KeyOp> {
+        KeyOp::ALL.get(value as usize).copied()
+    }
+
+    /// Name used by the shell
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyOp::OctaveDown => "octdown",
+            KeyOp::OctaveUp => "octup",
+            KeyOp::TransposeDown => "down",
+            KeyOp::TransposeUp => "up",
+            KeyOp::NextScale => "scale",
+            KeyOp::NextLayout => "layout",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<KeyOp> {
+        KeyOp::ALL.iter().copied().find(|op| op.name() == name)
+    }
+}
+
+/// Settings of the pad keymap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub layout: Layout,
+    /// Pads per row
+    pub columns: u8,
+    /// Octave of pad 0
+    pub octave: u8,
+    /// Key of the pads and of note quantisation
+    pub key: Key,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { layout: Layout::Chromatic, columns: 8, octave: 3, key: Key::new() }
+    }
+
+    /// Note of pad `pad`, `None` past the top of the MIDI range
+    pub fn note(&self, pad: u8) -> Option<u8> {
+        let columns = self.columns.clamp(1, MAX_COLUMNS) as u32;
+        let (row, column) = (pad as u32 / columns, pad as u32 % columns);
+        let scale = self.key.scale;
+        let offset = match self.layout {
+            Layout::Chromatic => pad as u32,
+            Layout::InKey => scale.degree(row * scale.degrees_below(5) + column),
+            Layout::Fourths => row * 5 + column,
+        };
+        let note = 12 * self.octave as u32 + self.key.root as u32 % 12 + offset;
+        u8::try_from(note).ok().filter(|&note| note < 128)
+    }
+
+    /// Carry out a keymap button press.
+    pub fn apply(&mut self, op: KeyOp) {
+        let next = |all: &[u8], value: u8| all[(all.iter().position(|&v| v == value).unwrap_or(0) + 1) % all.len()];
+        match op {
+            KeyOp::OctaveDown => self.octave = self.octave.saturating_sub(1),
+            KeyOp::OctaveUp => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+            KeyOp::TransposeDown if self.key.root > 0 => self.key.root -= 1,
+            KeyOp::TransposeDown if self.octave > 0 => {
+                self.octave -= 1;
+                self.key.root = 11;
+            }
+            KeyOp::TransposeUp if self.key.root < 11 => self.key.root += 1,
+            KeyOp::TransposeUp if self.octave < MAX_OCTAVE => {
+                self.octave += 1;
+                self.key.root = 0;
+            }
+            KeyOp::TransposeDown | KeyOp::TransposeUp => {}
+            KeyOp::NextScale => {
+                let scales = Scale::ALL.map(|scale| scale as u8);
+                self.key.scale = Scale::from_u8(next(&scales, self.key.scale as u8)).unwrap_or(Scale::Chromatic);
+            }
+            KeyOp::NextLayout => {
+                let layouts = Layout::ALL.map(|layout| layout as u8);
+                self.layout = Layout::from_u8(next(&layouts, self.layout as u8)).unwrap_or(Layout::Chromatic);
+            }
+        }
+    }
+}
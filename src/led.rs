@@ -0,0 +1,96 @@
+//! LED Outputs
+//!
+//! LEDs are numbered like controls; every output owns a fixed range of ids
+//! (see `ids`). An LED is set to a level from 0 to 127, normally the value of
+//! the MIDI message that drives it: outputs that can only switch light up for
+//! anything above 0, others may turn the level into brightness or colour.
+//! Dimmable outputs also follow the global `Options`.
+
+use core::fmt;
+
+pub type LedId = u8;
+
+/// Highest level
+pub const FULL: u8 = 127;
+
+/// LED id ranges of the outputs
+pub mod ids {
+    /// LaunchPad RGB LED: red, green, blue
+    pub const ONBOARD: u8 = 0;
+    pub const RED: u8 = ONBOARD;
+    pub const GREEN: u8 = ONBOARD + 1;
+    pub const BLUE: u8 = ONBOARD + 2;
+    /// Addressable LED strip, one id per pixel
+    pub const STRIP: u8 = 16;
+    /// 74HC595 shift register outputs, one id per output
+    pub const SHIFT: u8 = 96;
+}
+
+/// Longest addressable LED strip
+pub const MAX_PIXELS: usize = 64;
+
+/// Most 74HC595 outputs, eight per chip
+pub const MAX_SHIFT_OUTPUTS: usize = 64;
+
+/// Highest LED id, the last shift register output
+pub const MAX_LED: LedId = ids::SHIFT + MAX_SHIFT_OUTPUTS as u8 - 1;
+
+/// Something LEDs are attached to
+pub trait LedOutput {
+    /// Set the level of one LED; ids of other outputs are ignored.
+    fn set(&mut self, led: LedId, level: u8);
+}
+
+/// 8-bit per channel colour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color { r: 0, g: 0, b: 0 };
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.r, self.g, self.b)
+    }
+}
+
+/// Animation of outputs that can dim
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Animation {
+    /// Levels as set
+    Steady = 0,
+    /// Brightness slowly rises and falls
+    Breathe = 1,
+    /// Dimmed, flashing on every beat of incoming MIDI clock
+    Beat = 2,
+    /// Dimmed, flashing on every incoming message
+    Flash = 3,
+}
+
+impl Animation {
+    pub const ALL: [Animation; 4] = [Animation::Steady, Animation::Breathe, Animation::Beat, Animation::Flash];
+
+    pub fn from_u8(value: u8) -> Option<Animation> {
+        Animation::ALL.get(value as usize).copied()
+    }
+}
+
+/// Settings of dimmable outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    /// Global brightness, 0-255
+    pub brightness: u8,
+    pub animation: Animation,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { brightness: 255, animation: Animation::Steady }
+    }
+}
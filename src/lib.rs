@@ -0,0 +1,39 @@
+//! Hardware independent part of the controller firmware: the shell, the
+//! configuration, MIDI parsing and routing, the mapping engine and the
+//! note, sequencer and MIDI-CI logic. Nothing here touches a register, so
+//! it builds for the host too, where `cargo test` runs its unit tests.
+
+#![cfg_attr(not(test), no_std)]
+// State is built with `const fn new()` for statics; `len()` counts bytes written
+#![allow(clippy::new_without_default, clippy::len_without_is_empty)]
+
+pub mod arp;
+pub mod capsense;
+pub mod ci;
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod curve;
+pub mod display;
+pub mod drum;
+pub mod fader;
+pub mod feedback;
+pub mod hires;
+pub mod input;
+pub mod json;
+pub mod keymap;
+pub mod led;
+pub mod macros;
+pub mod mapping;
+pub mod mcu;
+pub mod midi;
+pub mod monitor;
+pub mod motion;
+pub mod mpe;
+pub mod palette;
+pub mod pedal;
+pub mod ring;
+pub mod router;
+pub mod scale;
+pub mod sequencer;
+pub mod ump;
@@ -0,0 +1,344 @@
+//! Macros
+//!
+//! A macro is a list of MIDI messages with waits between them that one
+//! press of a button mapped to `Action::Macro` sends: a mixer scene, the
+//! SysEx that sets up a synth. A macro is kept as the bytes of its
+//! messages, each with its status byte, and `WAIT` followed by a time in
+//! milliseconds as two 7-bit bytes, LSB first. Macros are part of the
+//! configuration and edited with `macro` in the shell, a few bytes a line,
+//! so a macro may end in an unfinished message for a while; that one is
+//! never sent.
+//!
+//! `Player` sends one macro at a time from the main loop, a message per poll
+//! while the output queues have room for it, and waits without blocking.
+//! Starting another macro, or pressing the button of the one playing again,
+//! stops it between two messages; a SysEx message always goes out whole.
+
+use crate::config::{ConfigError, Reader, Writer};
+use crate::midi::{self, MidiMessage, Parser};
+
+pub const MAX_MACROS: usize = 8;
+/// Bytes of a macro, waits included
+pub const MACRO_LEN: usize = 64;
+/// Marks a wait; an undefined real-time status byte, never sent
+pub const WAIT: u8 = 0xFD;
+/// Longest single wait, 16.383 s
+pub const MAX_WAIT: u16 = 0x3FFF;
+/// Most `SysEx` fragments one step sends
+pub const MAX_FRAGMENTS: usize = MACRO_LEN.div_ceil(3);
+
+/// One step of a macro
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step<'a> {
+    /// Bytes of a message, from its status byte on
+    Message(&'a [u8]),
+    /// Milliseconds to wait
+    Wait(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Macro {
+    pub len: u8,
+    pub bytes: [u8; MACRO_LEN],
+}
+
+impl Macro {
+    pub const EMPTY: Macro = Macro { len: 0, bytes: [0; MACRO_LEN] };
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Append bytes, `false` if they do not fit.
+    pub fn push(&mut self, bytes: &[u8]) -> bool {
+        let len = self.len as usize;
+        let Some(dst) = self.bytes.get_mut(len..len + bytes.len()) else {
+            return false;
+        };
+        dst.copy_from_slice(bytes);
+        self.len += bytes.len() as u8;
+        true
+    }
+
+    /// Append a wait, split into several past `MAX_WAIT`.
+    pub fn push_wait(&mut self, mut ms: u32) -> bool {
+        while ms > 0 {
+            let wait = ms.min(MAX_WAIT as u32) as u16;
+            if !self.push(&[WAIT, (wait & 0x7F) as u8, (wait >> 7) as u8]) {
+                return false;
+            }
+            ms -= wait as u32;
+        }
+        true
+    }
+
+    /// Step at byte `offset` and the offset of the next one; `None` at the
+    /// end or where the bytes are not a complete message.
+    pub fn step(&self, offset: usize) -> Option<(Step<'_>, usize)> {
+        let bytes = self.as_slice();
+        let status = *bytes.get(offset)?;
+        let len = match status {
+            WAIT => 3,
+            0xF0 => bytes[offset..].iter().position(|&b| b == 0xF7)? + 1,
+            0xF4 | 0xF5 | 0xF7 | 0xF9 => return None,
+            0x80.. => 1 + Parser::data_len(status),
+            _ => return None,
+        };
+        let step = bytes.get(offset..offset + len)?;
+        let data = if status == 0xF0 { &step[1..len - 1] } else { &step[1..] };
+        if data.iter().any(|&b| b >= 0x80) {
+            return None;
+        }
+        Some(match status {
+            WAIT => (Step::Wait(step[1] as u16 | (step[2] as u16) << 7), offset + len),
+            _ => (Step::Message(step), offset + len),
+        })
+    }
+
+    /// All steps, up to the first invalid byte
+    pub fn steps(&self) -> impl Iterator<Item = Step<'_>> {
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let (step, next) = self.step(offset)?;
+            offset = next;
+            Some(step)
+        })
+    }
+
+    /// Bytes of the complete steps at the start
+    pub fn complete(&self) -> usize {
+        let mut offset = 0;
+        while let Some((_, next)) = self.step(offset) {
+            offset = next;
+        }
+        offset
+    }
+
+    /// Whether the bytes are complete messages and waits, but for an
+    /// unfinished last message
+    pub fn is_valid(&self) -> bool {
+        match self.as_slice()[self.complete()..] {
+            [] => true,
+            [status, ref data @ ..] => {
+                status >= 0x80 && !matches!(status, 0xF4 | 0xF5 | 0xF7 | 0xF9 | WAIT) && data.iter().all(|&b| b < 0x80)
+            }
+        }
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        w.u8(self.len);
+        w.bytes(self.as_slice());
+    }
+
+    pub fn decode(r: &mut Reader) -> Result<Self, ConfigError> {
+        let len = r.u8()? as usize;
+        if len > MACRO_LEN {
+            return Err(ConfigError::Invalid);
+        }
+        let mut m = Macro::EMPTY;
+        m.bytes[..len].copy_from_slice(r.bytes(len)?);
+        m.len = len as u8;
+        if !m.is_valid() {
+            return Err(ConfigError::Invalid);
+        }
+        Ok(m)
+    }
+}
+
+/// Sends the macro playing
+pub struct Player {
+    /// Macro playing, the offset of its next step and when that is due
+    playing: Option<(u8, usize, u32)>,
+}
+
+impl Player {
+    pub const fn new() -> Self {
+        Self { playing: None }
+    }
+
+    /// Index of the macro playing
+    pub fn playing(&self) -> Option<u8> {
+        self.playing.map(|(index, ..)| index)
+    }
+
+    /// Start a macro from the top, stopping the one playing.
+    pub fn start(&mut self, now: u32, index: u8) {
+        self.playing = Some((index, 0, now));
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = None;
+    }
+
+    /// A macro button press: start the macro, or stop it if it is playing.
+    pub fn trigger(&mut self, now: u32, index: u8) {
+        match self.playing() == Some(index) {
+            true => self.stop(),
+            false => self.start(now, index),
+        }
+    }
+
+    /// Take the next step if it is due: send a message, as up to
+    /// `MAX_FRAGMENTS` messages for SysEx, or start a wait.
+    pub fn poll(&mut self, now: u32, macros: &[Macro; MAX_MACROS], out: &mut dyn FnMut(MidiMessage)) {
+        let Some((index, offset, due)) = self.playing.as_mut() else {
+            return;
+        };
+        if (now.wrapping_sub(*due) as i32) < 0 {
+            return;
+        }
+        let Some((step, next)) = macros.get(*index as usize).and_then(|m| m.step(*offset)) else {
+            self.playing = None;
+            return;
+        };
+        *offset = next;
+        match step {
+            Step::Wait(ms) => *due = now.wrapping_add(ms as u32),
+            Step::Message(bytes @ [0xF0, ..]) => midi::sysex_fragments(bytes, out),
+            Step::Message(bytes) => {
+                let data = |i: usize| bytes.get(i).copied().unwrap_or(0);
+                if let Some(message) = MidiMessage::from_bytes(bytes[0], data(1), data(2)) {
+                    out(message);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSEX: [u8; 10] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0xF7];
+
+    fn of(bytes: &[u8]) -> Macro {
+        let mut m = Macro::EMPTY;
+        assert!(m.push(bytes));
+        m
+    }
+
+    fn poll(player: &mut Player, now: u32, macros: &[Macro; MAX_MACROS]) -> Vec<MidiMessage> {
+        let mut sent = Vec::new();
+        player.poll(now, macros, &mut |message| sent.push(message));
+        sent
+    }
+
+    fn note(velocity: u8) -> MidiMessage {
+        match velocity {
+            0 => MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 },
+            _ => MidiMessage::NoteOn { channel: 0, note: 60, velocity },
+        }
+    }
+
+    #[test]
+    fn reads_steps() {
+        let mut m = of(&[0x90, 0x3C, 0x64]);
+        assert!(m.push_wait(1000));
+        assert!(m.push(&SYSEX));
+        assert!(m.push(&[0xC0, 0x05]));
+        let steps: Vec<_> = m.steps().collect();
+        assert_eq!(
+            steps,
+            [
+                Step::Message(&[0x90, 0x3C, 0x64]),
+                Step::Wait(1000),
+                Step::Message(&SYSEX),
+                Step::Message(&[0xC0, 0x05])
+            ]
+        );
+        assert_eq!(m.as_slice()[3..6], [WAIT, 0x68, 0x07]);
+        assert_eq!((m.complete(), m.is_valid()), (m.len as usize, true));
+    }
+
+    #[test]
+    fn splits_long_waits() {
+        let mut m = Macro::EMPTY;
+        assert!(m.push_wait(40_000));
+        let steps: Vec<_> = m.steps().collect();
+        assert_eq!(steps, [Step::Wait(MAX_WAIT), Step::Wait(MAX_WAIT), Step::Wait(40_000 - 2 * MAX_WAIT)]);
+        assert!(m.push_wait(0));
+        assert_eq!(m.len, 9);
+
+        let mut full = of(&[0; MACRO_LEN - 2]);
+        assert!(!full.push_wait(1));
+    }
+
+    #[test]
+    fn allows_only_an_unfinished_last_message() {
+        // Still being typed in
+        for bytes in [&[0x90, 0x3C, 0x64, 0xB0, 0x07][..], &[0xF0, 0x01, 0x02], &[0x90]] {
+            let m = of(bytes);
+            assert!(m.is_valid(), "{bytes:02X?}");
+            assert!(m.complete() < bytes.len());
+        }
+        // Stray data, undefined or lone end of SysEx, a cut off wait, a
+        // status byte inside SysEx
+        for bytes in [
+            &[0x90, 0x3C, 0x64, 0x3C][..],
+            &[0xF4],
+            &[0xF5],
+            &[0xF7],
+            &[0xF9],
+            &[WAIT, 0x01],
+            &[0xF0, 0x01, 0x90, 0xF7],
+        ] {
+            assert!(!of(bytes).is_valid(), "{bytes:02X?}");
+        }
+    }
+
+    #[test]
+    fn waits_without_blocking() {
+        let mut macros = [Macro::EMPTY; MAX_MACROS];
+        macros[0] = of(&[0x90, 0x3C, 0x64]);
+        macros[0].push_wait(100);
+        macros[0].push(&[0x80, 0x3C, 0x00]);
+        let mut player = Player::new();
+        let start = u32::MAX - 10;
+
+        player.trigger(start, 0);
+        assert_eq!(poll(&mut player, start, &macros), [note(100)]);
+        assert!(poll(&mut player, start + 1, &macros).is_empty());
+        // Across the wrap of the clock
+        for now in (start + 2..=u32::MAX).chain(0..90) {
+            assert!(poll(&mut player, now, &macros).is_empty(), "{now}");
+        }
+        assert_eq!(poll(&mut player, 90, &macros), [note(0)]);
+        assert_eq!(player.playing(), Some(0));
+        assert!(poll(&mut player, 91, &macros).is_empty());
+        assert_eq!(player.playing(), None);
+    }
+
+    #[test]
+    fn sends_sysex_whole() {
+        let mut macros = [Macro::EMPTY; MAX_MACROS];
+        macros[1] = of(&SYSEX);
+        let mut player = Player::new();
+        player.trigger(0, 1);
+        let mut fragments = Vec::new();
+        midi::sysex_fragments(&SYSEX, &mut |message| fragments.push(message));
+        assert_eq!(poll(&mut player, 0, &macros), fragments);
+    }
+
+    #[test]
+    fn stops_and_preempts() {
+        let mut macros = [Macro::EMPTY; MAX_MACROS];
+        macros[0] = of(&[0x90, 0x3C, 0x64, 0x90, 0x3C, 0x65]);
+        macros[1] = of(&[0xC0, 0x05]);
+        let mut player = Player::new();
+
+        // Pressing the button of the macro playing stops it
+        player.trigger(0, 0);
+        assert_eq!(poll(&mut player, 0, &macros), [note(100)]);
+        player.trigger(1, 0);
+        assert_eq!(player.playing(), None);
+        assert!(poll(&mut player, 1, &macros).is_empty());
+
+        // Another macro takes over between two messages
+        player.trigger(2, 0);
+        assert_eq!(poll(&mut player, 2, &macros), [note(100)]);
+        player.trigger(3, 1);
+        assert_eq!(poll(&mut player, 3, &macros), [MidiMessage::ProgramChange { channel: 0, program: 5 }]);
+        assert!(poll(&mut player, 4, &macros).is_empty());
+        assert_eq!(player.playing(), None);
+    }
+}
@@ -16,6 +16,8 @@ unsafe fn HardFault(_ef: &cortex_m_rt::ExceptionFrame) -> ! {
     sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 5)) });
     while sysctl.prgpio.read().bits() & (1 << 5) == 0 {}
     
+    // PF1 is on PWM1 once the RGB LED is set up, take it back as GPIO
+    portf.afsel.modify(|r, w| unsafe { w.bits(r.bits() & !0x02) });
     portf.dir.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
     portf.den.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
     
@@ -36,6 +38,8 @@ fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
     sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 5)) });
     while sysctl.prgpio.read().bits() & (1 << 5) == 0 {}
     
+    // PF1 is on PWM1 once the RGB LED is set up, take it back as GPIO
+    portf.afsel.modify(|r, w| unsafe { w.bits(r.bits() & !0x02) });
     portf.dir.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
     portf.den.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
     
@@ -50,27 +54,45 @@ fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
 // Load modules AFTER panic handler is set up
 mod usb_device;
 mod usb_descriptors;
+mod clock;
+mod cdc;
+mod flash;
+mod app;
+mod buttons;
+mod rgb;
+mod dma;
+mod strip;
+mod shift;
+mod oled;
+mod motor;
+mod sensorlib;
+mod imu;
+mod touch;
+mod drumpads;
+mod pedals;
+mod usb_midi;
+
+// Hardware independent modules, see lib.rs
+use tiva_controller::{
+    arp, capsense, ci, cli, commands, config, display, drum, fader, hires, input, led, macros, mapping, mcu,
+    midi, monitor, motion, mpe, palette, pedal, ring, router, sequencer, ump,
+};
 
 use cortex_m_rt::exception;
 
-/// USB Device Example
-/// Makes the TM4C123 enumerate as a USB CDC serial port when connected to a computer.
+/// Makes the TM4C123 enumerate as a composite USB device when connected to a computer:
+/// a CDC serial port serving the configuration shell and a USB-MIDI interface.
 #[entry]
 fn main() -> ! {
     let sysctl = unsafe { &*SYSCTL::ptr() };
     let portf = unsafe { &*GPIO_PORTF::ptr() };
 
-    // Enable GPIO Port F
+    // Enable GPIO Port F, PF1 (red LED) blinks if USB setup fails
     sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 5)) });
     while sysctl.prgpio.read().bits() & (1 << 5) == 0 {}
+    portf.dir.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
+    portf.den.modify(|r, w| unsafe { w.bits(r.bits() | 0x02) });
 
-    // Configure PF2 (blue LED) as output
-    portf.dir.modify(|r, w| unsafe { w.bits(r.bits() | 0x04) });
-    portf.den.modify(|r, w| unsafe { w.bits(r.bits() | 0x04) });
-    
-    // Initialize LED to OFF
-    portf.data.modify(|r, w| unsafe { w.bits(r.bits() & !0x04) });
-    
     unsafe {
         usb_device::FPULazyStackingEnable();
         usb_device::SysCtlClockSet(
@@ -80,7 +102,7 @@ fn main() -> ! {
             usb_device::sysctl_clock::SYSCTL_XTAL_16MHZ
         );
         let sys_clock = usb_device::SysCtlClockGet();
-        usb_device::SysTickPeriodSet(sys_clock / 100);
+        usb_device::SysTickPeriodSet(sys_clock / clock::TICK_HZ);
         usb_device::SysTickIntEnable();
         usb_device::SysTickEnable();
     }
@@ -107,10 +129,17 @@ fn main() -> ! {
     let string_descriptors_ptr = usb_descriptors::get_string_descriptors();
     
     static mut CDC_DEVICE: Option<usb_device::tUSBDCDCDevice> = None;
+    static mut COMPOSITE_ENTRIES: [usb_device::tCompositeEntry; 2] = [
+        usb_device::tCompositeEntry { psDevInfo: ptr::null(), pvInstance: ptr::null_mut(), ui32DeviceWorkspace: 0 },
+        usb_device::tCompositeEntry { psDevInfo: ptr::null(), pvInstance: ptr::null_mut(), ui32DeviceWorkspace: 0 },
+    ];
+    static mut COMPOSITE_DEVICE: Option<usb_device::tUSBDCompositeDevice> = None;
+    // Merged configuration descriptor of all functions
+    static mut COMPOSITE_WORKSPACE: [u8; 256] = [0; 256];
     unsafe {
         CDC_DEVICE = Some(usb_device::tUSBDCDCDevice {
             ui16VID: usb_device::usb_ids::USB_VID_TI_1CBE,
-            ui16PID: usb_device::usb_ids::USB_PID_SERIAL,
+            ui16PID: usb_device::usb_ids::USB_PID_COMP_MIDI_SERIAL,
             ui16MaxPowermA: 0,
             ui8PwrAttributes: usb_device::usb_conf::USB_CONF_ATTR_SELF_PWR,
             pfnControlCallback: Some(usb_descriptors::control_handler),
@@ -136,9 +165,29 @@ fn main() -> ! {
         );
         
         let cdc_device_ptr = CDC_DEVICE.as_mut().unwrap();
-        let instance = usb_device::USBDCDCInit(0, cdc_device_ptr);
+        let cdc_instance = usb_device::USBDCDCCompositeInit(0, cdc_device_ptr, &mut COMPOSITE_ENTRIES[0]);
+        COMPOSITE_ENTRIES[1] = usb_midi::composite_entry();
+
+        COMPOSITE_DEVICE = Some(usb_device::tUSBDCompositeDevice {
+            ui16VID: usb_device::usb_ids::USB_VID_TI_1CBE,
+            ui16PID: usb_device::usb_ids::USB_PID_COMP_MIDI_SERIAL,
+            ui16MaxPowermA: 0,
+            ui8PwrAttributes: usb_device::usb_conf::USB_CONF_ATTR_SELF_PWR,
+            pfnCallback: None,
+            ppui8StringDescriptors: string_descriptors_ptr,
+            ui32NumStringDescriptors: 6,
+            ui32NumDevices: COMPOSITE_ENTRIES.len() as u32,
+            psDevices: COMPOSITE_ENTRIES.as_mut_ptr(),
+            sPrivateData: core::mem::zeroed(),
+        });
+        let composite = usb_device::USBDCompositeInit(
+            0,
+            COMPOSITE_DEVICE.as_mut().unwrap(),
+            COMPOSITE_WORKSPACE.len() as u32,
+            COMPOSITE_WORKSPACE.as_mut_ptr(),
+        );
         
-        if instance.is_null() {
+        if cdc_instance.is_null() || composite.is_null() {
             loop {
                 portf.data.modify(|r, w| w.bits(r.bits() ^ 0x02));
                 for _ in 0..50_000 {
@@ -147,27 +196,32 @@ fn main() -> ! {
             }
         }
         
+        cdc::init(cdc_instance);
         cortex_m::interrupt::enable();
     }
-    
-    for _ in 0..1_000_000 {
-        cortex_m::asm::nop();
-    }
+
+    let mut app = app::App::new();
+    let mut shell: cli::Shell<app::App, 80> = cli::Shell::new(commands::Commands::<app::App>::TABLE);
+    let mut was_connected = false;
 
     loop {
-        portf.data.modify(|r, w| unsafe { w.bits(r.bits() | 0x04) });
-        for _ in 0..5_000_000 {
-            cortex_m::asm::nop();
+        // Greet every new terminal session with a prompt
+        let connected = cdc::is_connected();
+        if connected && !was_connected {
+            shell.prompt(&mut cdc::Writer);
         }
-        portf.data.modify(|r, w| unsafe { w.bits(r.bits() & !0x04) });
-        for _ in 0..5_000_000 {
-            cortex_m::asm::nop();
+        was_connected = connected;
+
+        while let Some(byte) = cdc::read_byte() {
+            shell.input(byte, &mut app, &mut cdc::Writer);
         }
+        app.poll();
+        cdc::flush();
     }
 }
 
 #[exception]
 #[allow(non_snake_case)]
 fn SysTick() {
-    // Acknowledge SysTick interrupt
+    clock::tick();
 }
@@ -0,0 +1,1481 @@
+//! Control Mapping
+//!
+//! Declarative mapping from physical controls to MIDI. The mapping table is
+//! plain data kept in the configuration; `MappingEngine` holds the runtime
+//! state (toggle states, encoder positions, last sent values) and turns input
+//! events into messages.
+//!
+//! A control value goes through these steps: input (press, position,
+//! movement) to a 14-bit value, optional inversion, the value curve, scaling
+//! into `min..=max` of the target, and finally the message(s) of the action.
+//!
+//! Mappings belong to a bank (or to every bank) and to the base or the shift
+//! layer, so the same controls can address different targets. A control
+//! uses its mappings of the active bank and layer; where it has none there,
+//! it falls back to its mappings for every bank, then to the base layer.
+//! Each mapping keeps its own runtime state, so a bank comes back with the
+//! values it had.
+
+use core::fmt;
+
+use crate::config::{ConfigError, Reader, Writer};
+use crate::curve::{self, Curve, USER_CURVES};
+use crate::feedback::Address;
+use crate::hires::{self, ParamChange, ParamKind};
+use crate::input::{self, ControlId, Input, InputEvent, FULL_SCALE};
+use crate::keymap::{self, KeyOp};
+use crate::macros::MAX_MACROS;
+use crate::midi::{self, MidiMessage};
+use crate::mpe;
+
+pub const MAX_MAPPINGS: usize = 64;
+pub const MAX_TEMPLATES: usize = 8;
+pub const TEMPLATE_LEN: usize = 16;
+/// Banks of mappings, numbered from 1; bank 0 of a mapping means every bank
+pub const MAX_BANKS: u8 = 4;
+
+/// `Action::Bank` parameters besides a bank number
+pub const NEXT_BANK: u8 = 0;
+pub const PREVIOUS_BANK: u8 = 127;
+
+/// Channel value meaning "follow the global channel setting"
+pub const DEFAULT_CHANNEL: u8 = 0xFF;
+
+/// Encoder step per detent, in 14-bit units (128 detents end to end)
+const ENCODER_STEP: i32 = 128;
+
+/// Distance in 14-bit units at which a control counts as having caught up
+/// with the remote value, about 3 % of the travel
+const PICKUP_WINDOW: u16 = 512;
+
+/// SysEx template placeholders
+pub mod placeholder {
+    /// Low 7 bits of the value
+    pub const VALUE: u8 = 0x80;
+    /// Bits 7..13 of the value
+    pub const VALUE_MSB: u8 = 0x81;
+    /// MIDI channel, 0-based
+    pub const CHANNEL: u8 = 0x82;
+}
+
+/// What a control sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Note on while active, velocity from the value
+    Note(u8),
+    Cc(u8),
+    /// 14-bit CC pair on controller n (0-31) and n + 32
+    Cc14(u8),
+    Nrpn(u16),
+    Rpn(u16),
+    /// Program number from the value
+    Program,
+    PitchBend,
+    /// Fill in and send a SysEx template
+    SysEx(u8),
+    /// Note on its own MPE member channel, pressure follows the control
+    MpeNote(u8),
+    /// Pitch bend of MPE note n, on its member channel while it sounds
+    MpePitch(u8),
+    /// Timbre (CC 74) of MPE note n, on its member channel while it sounds
+    MpeTimbre(u8),
+    /// Note of pad n of the keymap, velocity from the value
+    Pad(u8),
+    /// Keymap button, changes the notes of the pads on press
+    Keymap(KeyOp),
+    /// Bank button: selects a bank, `NEXT_BANK` or `PREVIOUS_BANK` on press
+    Bank(u8),
+    /// Shift button: the shift layer is active while held (or toggled on)
+    Shift,
+    /// Start macro n on press, or stop it while it plays
+    Macro(u8),
+}
+
+impl Action {
+    /// Largest value the target accepts
+    pub fn limit(&self) -> u16 {
+        match self {
+            Action::Cc14(_)
+            | Action::Nrpn(_)
+            | Action::Rpn(_)
+            | Action::PitchBend
+            | Action::MpePitch(_)
+            | Action::SysEx(_) => FULL_SCALE,
+            _ => 127,
+        }
+    }
+
+    /// Default `max` of a new mapping
+    pub fn default_max(&self) -> u16 {
+        match self {
+            Action::SysEx(_) => 127,
+            _ => self.limit(),
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Action::None => 0,
+            Action::Note(_) => 1,
+            Action::Cc(_) => 2,
+            Action::Cc14(_) => 3,
+            Action::Nrpn(_) => 4,
+            Action::Rpn(_) => 5,
+            Action::Program => 6,
+            Action::PitchBend => 7,
+            Action::SysEx(_) => 8,
+            Action::MpeNote(_) => 9,
+            Action::Pad(_) => 10,
+            Action::Keymap(_) => 11,
+            Action::Bank(_) => 12,
+            Action::Shift => 13,
+            Action::Macro(_) => 14,
+            Action::MpePitch(_) => 15,
+            Action::MpeTimbre(_) => 16,
+        }
+    }
+
+    /// Name used by the shell and in JSON, indexed by `kind`
+    const NAMES: [&'static str; 17] = [
+        "none", "note", "cc", "cc14", "nrpn", "rpn", "program", "bend", "sysex", "mpe", "pad", "keymap", "bank",
+        "shift", "macro", "mpepitch", "mpetimbre",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES[self.kind() as usize]
+    }
+
+    /// Action from its name and parameter, `None` if either is invalid
+    pub fn from_name(name: &str, param: u16) -> Option<Action> {
+        let kind = Self::NAMES.iter().position(|&n| n == name)?;
+        Self::from_parts(kind as u8, param)
+    }
+
+    /// Note, controller, parameter, template, pad, keymap operation, bank or
+    /// macro number; 0 if the action has none
+    pub fn param(&self) -> u16 {
+        match *self {
+            Action::Note(n)
+            | Action::Cc(n)
+            | Action::Cc14(n)
+            | Action::SysEx(n)
+            | Action::MpeNote(n)
+            | Action::MpePitch(n)
+            | Action::MpeTimbre(n)
+            | Action::Pad(n)
+            | Action::Bank(n)
+            | Action::Macro(n) => n as u16,
+            Action::Nrpn(n) | Action::Rpn(n) => n,
+            Action::Keymap(op) => op as u16,
+            _ => 0,
+        }
+    }
+
+    fn from_parts(kind: u8, param: u16) -> Option<Action> {
+        let byte = (param < 128).then_some(param as u8);
+        Some(match kind {
+            0 => Action::None,
+            1 => Action::Note(byte?),
+            2 => Action::Cc(byte?),
+            3 => Action::Cc14(byte.filter(|&n| n < 32)?),
+            4 if param <= FULL_SCALE => Action::Nrpn(param),
+            5 if param <= FULL_SCALE => Action::Rpn(param),
+            6 => Action::Program,
+            7 => Action::PitchBend,
+            8 => Action::SysEx(byte.filter(|&n| (n as usize) < MAX_TEMPLATES)?),
+            9 => Action::MpeNote(byte?),
+            10 => Action::Pad(byte?),
+            11 => Action::Keymap(KeyOp::from_u8(byte?)?),
+            12 => Action::Bank(byte.filter(|&n| n <= MAX_BANKS || n == PREVIOUS_BANK)?),
+            13 => Action::Shift,
+            14 => Action::Macro(byte.filter(|&n| (n as usize) < MAX_MACROS)?),
+            15 => Action::MpePitch(byte?),
+            16 => Action::MpeTimbre(byte?),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::None => f.write_str("none"),
+            Action::Note(n) => write!(f, "note {}", n),
+            Action::Cc(n) => write!(f, "cc {}", n),
+            Action::Cc14(n) => write!(f, "cc14 {}", n),
+            Action::Nrpn(n) => write!(f, "nrpn {}", n),
+            Action::Rpn(n) => write!(f, "rpn {}", n),
+            Action::Program => f.write_str("program"),
+            Action::PitchBend => f.write_str("bend"),
+            Action::SysEx(n) => write!(f, "sysex {}", n),
+            Action::MpeNote(n) => write!(f, "mpe {}", n),
+            Action::MpePitch(n) => write!(f, "mpepitch {}", n),
+            Action::MpeTimbre(n) => write!(f, "mpetimbre {}", n),
+            Action::Pad(n) => write!(f, "pad {}", n),
+            Action::Keymap(op) => write!(f, "keymap {}", op.name()),
+            Action::Bank(NEXT_BANK) => f.write_str("bank next"),
+            Action::Bank(PREVIOUS_BANK) => f.write_str("bank prev"),
+            Action::Bank(n) => write!(f, "bank {}", n),
+            Action::Shift => f.write_str("shift"),
+            Action::Macro(n) => write!(f, "macro {}", n),
+        }
+    }
+}
+
+/// What an absolute control does when the remote value moved away from it,
+/// e.g. after a preset change in the DAW
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Takeover {
+    /// Send the control's value straight away
+    Jump = 0,
+    /// Send nothing until the control reaches or passes the remote value
+    Pickup = 1,
+    /// Move the remote value in proportion, meeting the control at the end
+    /// it is turned towards
+    Scale = 2,
+}
+
+impl Takeover {
+    pub const ALL: [Takeover; 3] = [Takeover::Jump, Takeover::Pickup, Takeover::Scale];
+
+    pub fn from_u8(value: u8) -> Option<Takeover> {
+        Takeover::ALL.get(value as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Takeover::Jump => "jump",
+            Takeover::Pickup => "pickup",
+            Takeover::Scale => "scale",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Takeover> {
+        Takeover::ALL.iter().copied().find(|t| t.name() == name)
+    }
+}
+
+/// One control to action binding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub control: ControlId,
+    pub action: Action,
+    /// 0-15 or `DEFAULT_CHANNEL`
+    pub channel: u8,
+    /// Output range in target units; `min > max` is allowed
+    pub min: u16,
+    pub max: u16,
+    pub invert: bool,
+    /// Buttons: press toggles between on and off instead of on while held
+    pub toggle: bool,
+    pub curve: Curve,
+    /// Absolute controls on a controller or pitch bend
+    pub takeover: Takeover,
+    /// 1 to `MAX_BANKS`, or 0 for every bank
+    pub bank: u8,
+    /// Used while the shift layer is active
+    pub shift: bool,
+}
+
+impl Mapping {
+    pub fn new(control: ControlId, action: Action) -> Self {
+        Self {
+            control,
+            action,
+            channel: DEFAULT_CHANNEL,
+            min: 0,
+            max: action.default_max(),
+            invert: false,
+            toggle: false,
+            curve: Curve::Linear,
+            takeover: Takeover::Jump,
+            bank: 0,
+            shift: false,
+        }
+    }
+
+    /// Note, controller or parameter the mapping sends
+    pub fn address(&self, default_channel: u8) -> Option<Address> {
+        let channel = match self.channel {
+            DEFAULT_CHANNEL => default_channel,
+            ch => ch,
+        };
+        match self.action {
+            Action::Note(note) | Action::MpeNote(note) => Some(Address::Note { channel, note }),
+            Action::Cc(control) => Some(Address::Cc { channel, control }),
+            Action::Cc14(control) => Some(Address::Cc14 { channel, control }),
+            Action::Nrpn(param) => Some(Address::Nrpn { channel, param }),
+            Action::Rpn(param) => Some(Address::Rpn { channel, param }),
+            _ => None,
+        }
+    }
+
+    /// Output value for a 14-bit input value, as `MappingEngine` sends it
+    fn output(&self, raw: u16, curves: &[curve::Table; USER_CURVES]) -> u16 {
+        let value = if self.invert { FULL_SCALE - raw } else { raw };
+        self.scale(self.curve.apply(value, curves))
+    }
+
+    /// Control position at which the mapping would send what `message`
+    /// carries: the inverse of `output` for the value of a controller or
+    /// pitch bend on the mapping's channel. 14-bit controllers and NRPN/RPN
+    /// parameters take their value from `change`, the decoded message.
+    pub fn position(
+        &self,
+        default_channel: u8,
+        message: &MidiMessage,
+        change: Option<&ParamChange>,
+        curves: &[curve::Table; USER_CURVES],
+    ) -> Option<u16> {
+        let channel = match self.channel {
+            DEFAULT_CHANNEL => default_channel,
+            ch => ch,
+        };
+        let address = self.address(default_channel);
+        let value = match (self.action, *message) {
+            (Action::Cc(_), _) => {
+                let (_, value) = Address::of_message(message).filter(|&(a, _)| Some(a) == address)?;
+                value as u16
+            }
+            (Action::Cc14(_) | Action::Nrpn(_) | Action::Rpn(_), _) => {
+                let (_, value) = change.and_then(Address::of_change).filter(|&(a, _)| Some(a) == address)?;
+                value
+            }
+            (Action::PitchBend, MidiMessage::PitchBend { channel: ch, value }) if ch == channel => value,
+            _ => return None,
+        };
+        // `output` is monotonic, find the closest position by bisection
+        let rising = self.output(FULL_SCALE, curves) >= self.output(0, curves);
+        let (mut low, mut high) = (0, FULL_SCALE);
+        while low < high {
+            let mid = (low + high) / 2;
+            let output = self.output(mid, curves);
+            if (rising && output < value) || (!rising && output > value) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Some(low)
+    }
+
+    /// Scale a 14-bit value into `min..=max`.
+    fn scale(&self, value: u16) -> u16 {
+        let (min, max) = (self.min as i32, self.max as i32);
+        let span = max - min;
+        let half = if span < 0 { -(FULL_SCALE as i32 / 2) } else { FULL_SCALE as i32 / 2 };
+        (min + (value as i32 * span + half) / FULL_SCALE as i32) as u16
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.control);
+        w.u8(self.action.kind());
+        w.u16(self.action.param());
+        w.u8(self.channel);
+        w.u16(self.min);
+        w.u16(self.max);
+        w.u8(
+            self.invert as u8
+                | (self.toggle as u8) << 1
+                | (self.takeover as u8) << 2
+                | self.bank << 4
+                | (self.shift as u8) << 7,
+        );
+        w.u8(self.curve as u8);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, ConfigError> {
+        let control = r.u8()?;
+        let kind = r.u8()?;
+        let action = Action::from_parts(kind, r.u16()?).ok_or(ConfigError::Invalid)?;
+        let channel = r.u8()?;
+        let min = r.u16()?;
+        let max = r.u16()?;
+        let flags = r.u8()?;
+        let curve = Curve::from_u8(r.u8()?).ok_or(ConfigError::Invalid)?;
+        let takeover = Takeover::from_u8(flags >> 2 & 3).ok_or(ConfigError::Invalid)?;
+        let bank = flags >> 4 & 7;
+        let invalid_range = min > action.limit() || max > action.limit();
+        if (channel > 15 && channel != DEFAULT_CHANNEL) || invalid_range || bank > MAX_BANKS {
+            return Err(ConfigError::Invalid);
+        }
+        Ok(Self {
+            control,
+            action,
+            channel,
+            min,
+            max,
+            invert: flags & 1 != 0,
+            toggle: flags & 2 != 0,
+            curve,
+            takeover,
+            bank,
+            shift: flags & 0x80 != 0,
+        })
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>3}  {}", self.control, self.action)?;
+        match self.channel {
+            DEFAULT_CHANNEL => f.write_str(" ch -")?,
+            ch => write!(f, " ch {}", ch + 1)?,
+        }
+        write!(f, " {}..{} curve {}", self.min, self.max, self.curve.name())?;
+        if self.invert {
+            f.write_str(" invert")?;
+        }
+        if self.toggle {
+            f.write_str(" toggle")?;
+        }
+        if self.takeover != Takeover::Jump {
+            write!(f, " takeover {}", self.takeover.name())?;
+        }
+        if self.bank != 0 {
+            write!(f, " bank {}", self.bank)?;
+        }
+        if self.shift {
+            f.write_str(" shift")?;
+        }
+        Ok(())
+    }
+}
+
+/// SysEx message with placeholders, see `placeholder`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SysExTemplate {
+    pub len: u8,
+    pub bytes: [u8; TEMPLATE_LEN],
+}
+
+impl SysExTemplate {
+    pub const EMPTY: SysExTemplate = SysExTemplate { len: 0, bytes: [0; TEMPLATE_LEN] };
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MappingError {
+    /// No room for another mapping
+    Full,
+}
+
+/// All mappings, SysEx templates and user curves
+#[derive(Clone)]
+pub struct MappingTable {
+    entries: [Mapping; MAX_MAPPINGS],
+    count: usize,
+    pub templates: [SysExTemplate; MAX_TEMPLATES],
+    pub curves: [curve::Table; USER_CURVES],
+}
+
+impl MappingTable {
+    pub const fn new() -> Self {
+        const EMPTY: Mapping = Mapping {
+            control: 0,
+            action: Action::None,
+            channel: DEFAULT_CHANNEL,
+            min: 0,
+            max: 0,
+            invert: false,
+            toggle: false,
+            curve: Curve::Linear,
+            takeover: Takeover::Jump,
+            bank: 0,
+            shift: false,
+        };
+        Self {
+            entries: [EMPTY; MAX_MAPPINGS],
+            count: 0,
+            templates: [SysExTemplate::EMPTY; MAX_TEMPLATES],
+            curves: [curve::Table::LINEAR; USER_CURVES],
+        }
+    }
+
+    /// Factory mappings: SW1 plays middle C, SW2 toggles the sustain pedal
+    pub fn factory() -> Self {
+        let mut table = Self::new();
+        let _ = table.add(Mapping::new(0, Action::Note(60)));
+        let _ = table.add(Mapping { toggle: true, ..Mapping::new(1, Action::Cc(64)) });
+        // Expression (CC 11), foot controller (CC 4), sustain and sostenuto
+        let _ = table.add(Mapping::new(input::ids::PEDAL, Action::Cc(11)));
+        let _ = table.add(Mapping::new(input::ids::PEDAL + 1, Action::Cc(4)));
+        let _ = table.add(Mapping::new(input::ids::SUSTAIN, Action::Cc(64)));
+        let _ = table.add(Mapping::new(input::ids::SUSTAIN + 1, Action::Cc(66)));
+        table
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.entries[..self.count].iter()
+    }
+
+    pub fn add(&mut self, mapping: Mapping) -> Result<(), MappingError> {
+        if self.count == MAX_MAPPINGS {
+            return Err(MappingError::Full);
+        }
+        self.entries[self.count] = mapping;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    /// Remove every mapping of a control, returns how many were removed.
+    pub fn remove(&mut self, control: ControlId) -> usize {
+        self.retain(|mapping| mapping.control != control)
+    }
+
+    /// Keep only the mappings `keep` accepts, returns how many were removed.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Mapping) -> bool) -> usize {
+        let before = self.count;
+        let mut kept = 0;
+        for i in 0..self.count {
+            if keep(&self.entries[i]) {
+                self.entries[kept] = self.entries[i];
+                kept += 1;
+            }
+        }
+        self.count = kept;
+        before - kept
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        w.u8(self.count as u8);
+        for mapping in self.iter() {
+            mapping.encode(w);
+        }
+    }
+
+    pub fn decode(&mut self, r: &mut Reader) -> Result<(), ConfigError> {
+        let count = r.u8()? as usize;
+        if count > MAX_MAPPINGS {
+            return Err(ConfigError::Invalid);
+        }
+        for i in 0..count {
+            self.entries[i] = Mapping::decode(r)?;
+        }
+        self.count = count;
+        Ok(())
+    }
+
+    pub fn encode_templates(&self, w: &mut Writer) {
+        for template in &self.templates {
+            w.u8(template.len);
+            w.bytes(template.as_slice());
+        }
+    }
+
+    pub fn decode_templates(&mut self, r: &mut Reader) -> Result<(), ConfigError> {
+        for template in self.templates.iter_mut() {
+            let len = r.u8()? as usize;
+            if len > TEMPLATE_LEN {
+                return Err(ConfigError::Invalid);
+            }
+            template.bytes[..len].copy_from_slice(r.bytes(len)?);
+            template.len = len as u8;
+        }
+        Ok(())
+    }
+
+    pub fn encode_curves(&self, w: &mut Writer) {
+        for table in &self.curves {
+            table.encode(w);
+        }
+    }
+
+    pub fn decode_curves(&mut self, r: &mut Reader) -> Result<(), ConfigError> {
+        for table in self.curves.iter_mut() {
+            *table = curve::Table::decode(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for MappingTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Engine
+// ============================================================================
+
+/// What pressure on a held note sends, outside MPE zones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Aftertouch {
+    Off = 0,
+    /// Polyphonic key pressure on the note
+    Poly = 1,
+    /// Channel pressure
+    Channel = 2,
+}
+
+impl Aftertouch {
+    pub const ALL: [Aftertouch; 3] = [Aftertouch::Off, Aftertouch::Poly, Aftertouch::Channel];
+
+    pub fn from_u8(value: u8) -> Option<Aftertouch> {
+        Aftertouch::ALL.get(value as usize).copied()
+    }
+}
+
+/// Runtime state of one mapping
+#[derive(Clone, Copy)]
+struct Slot {
+    /// Toggle state, or whether a note is sounding
+    on: bool,
+    /// Encoder position
+    position: u16,
+    /// Last value sent, `None` if nothing was sent yet
+    last: Option<u16>,
+    /// Note sounding, pads keep theirs across keymap changes
+    note: Option<u8>,
+    /// Last input of an absolute control
+    physical: Option<u16>,
+    /// Remote value as a control position while the control has not taken
+    /// it over, see `Takeover`
+    remote: Option<u16>,
+    /// Position of the last value an absolute control sent, the one to take
+    /// over again after the control moved on in another bank or layer
+    sent: Option<u16>,
+}
+
+const IDLE: Slot =
+    Slot { on: false, position: 0, last: None, note: None, physical: None, remote: None, sent: None };
+
+/// Bank settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Banks {
+    /// Banks that `NEXT_BANK` and `PREVIOUS_BANK` step through
+    pub count: u8,
+    /// Controller on the global channel that reports a bank change (value
+    /// bank - 1) and selects a bank when the host sends it
+    pub cc: Option<u8>,
+}
+
+impl Banks {
+    pub const fn new() -> Self {
+        Self { count: MAX_BANKS, cc: None }
+    }
+}
+
+/// Settings the engine needs from the configuration besides the table
+pub struct Defaults {
+    pub channel: u8,
+    /// Velocity for notes triggered by controls without velocity
+    pub velocity: u8,
+    pub hires: hires::Options,
+    pub mpe: mpe::Layout,
+    pub aftertouch: Aftertouch,
+    pub keymap: keymap::Options,
+    pub banks: Banks,
+}
+
+/// How well a mapping fits the active bank and layer, `None` if it is not
+/// used there at all. A control uses its mappings of the best fit.
+fn fit(mapping: &Mapping, bank: u8, shift: bool) -> Option<u8> {
+    if (mapping.shift && !shift) || (mapping.bank != 0 && mapping.bank != bank) {
+        return None;
+    }
+    Some((mapping.shift as u8) << 1 | (mapping.bank != 0) as u8)
+}
+
+pub struct MappingEngine {
+    slots: [Slot; MAX_MAPPINGS],
+    pub mpe: mpe::Sender,
+    /// Active bank, 1 to `MAX_BANKS`
+    bank: u8,
+    shift: bool,
+}
+
+impl MappingEngine {
+    pub const fn new() -> Self {
+        Self { slots: [IDLE; MAX_MAPPINGS], mpe: mpe::Sender::new(), bank: 1, shift: false }
+    }
+
+    /// Forget all runtime state, e.g. after the table changed. The bank
+    /// stays selected.
+    pub fn reset(&mut self) {
+        self.slots = [IDLE; MAX_MAPPINGS];
+        self.mpe = mpe::Sender::new();
+        self.shift = false;
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Whether the shift layer is active
+    pub fn shift(&self) -> bool {
+        self.shift
+    }
+
+    /// Best fit of the mappings of `control`
+    fn layer(&self, table: &MappingTable, control: ControlId) -> Option<u8> {
+        let mappings = table.iter().filter(|m| m.control == control);
+        mappings.filter_map(|m| fit(m, self.bank, self.shift)).max()
+    }
+
+    /// Mappings `control` uses in the active bank and layer
+    pub fn active<'a>(&self, table: &'a MappingTable, control: ControlId) -> impl Iterator<Item = &'a Mapping> {
+        let (layer, bank, shift) = (self.layer(table, control), self.bank, self.shift);
+        table.iter().filter(move |m| m.control == control && layer.is_some() && fit(m, bank, shift) == layer)
+    }
+
+    /// Position the active mapping of an absolute control last sent or
+    /// has to take over, for motorised faders after a bank change
+    pub fn recall(&self, table: &MappingTable, control: ControlId) -> Option<u16> {
+        let layer = self.layer(table, control)?;
+        let mut slots = table.iter().zip(&self.slots);
+        slots.find_map(|(m, slot)| match m.control == control && fit(m, self.bank, self.shift) == Some(layer) {
+            true => slot.remote.or(slot.sent),
+            false => None,
+        })
+    }
+
+    /// A motorised fader was moved to `position` for the host: the active
+    /// mappings of `control` sit on the remote value, nothing to take over.
+    pub fn moved(&mut self, table: &MappingTable, control: ControlId, position: u16) {
+        let (layer, bank, shift) = (self.layer(table, control), self.bank, self.shift);
+        for (mapping, slot) in table.iter().zip(self.slots.iter_mut()) {
+            if mapping.control == control && layer.is_some() && fit(mapping, bank, shift) == layer {
+                slot.physical = Some(position);
+                slot.sent = Some(position);
+                slot.remote = None;
+            }
+        }
+    }
+
+    /// Select a bank from a bank button: a bank number, `NEXT_BANK` or
+    /// `PREVIOUS_BANK`. The bank controller tells the host.
+    fn select(&mut self, banks: &Banks, channel: u8, param: u8, out: &mut dyn FnMut(MidiMessage)) {
+        let count = banks.count.clamp(1, MAX_BANKS);
+        let bank = match param {
+            NEXT_BANK => self.bank % count + 1,
+            PREVIOUS_BANK => (self.bank + count - 2) % count + 1,
+            bank => bank,
+        };
+        if bank == self.bank {
+            return;
+        }
+        self.bank = bank;
+        if let Some(control) = banks.cc {
+            out(MidiMessage::ControlChange { channel, control, value: bank - 1 });
+        }
+    }
+
+    /// Run an input event through every mapping of its control.
+    pub fn process(
+        &mut self,
+        table: &MappingTable,
+        defaults: &Defaults,
+        event: InputEvent,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        self.mpe.configure(&defaults.mpe);
+        let (layer, bank, shift) = (self.layer(table, event.control), self.bank, self.shift);
+        for (index, mapping) in table.iter().enumerate() {
+            if mapping.control != event.control {
+                continue;
+            }
+            let active = layer.is_some() && fit(mapping, bank, shift) == layer;
+            match (mapping.action, event.input) {
+                (Action::Bank(param), Input::Press(_)) if active => {
+                    self.select(&defaults.banks, defaults.channel, param, out);
+                    continue;
+                }
+                (Action::Shift, Input::Press(_)) if active => {
+                    self.shift = !(mapping.toggle && self.shift);
+                    continue;
+                }
+                (Action::Shift, Input::Release) if active && !mapping.toggle => {
+                    self.shift = false;
+                    continue;
+                }
+                (Action::Bank(_) | Action::Shift, _) => continue,
+                _ => {}
+            }
+            let slot = &mut self.slots[index];
+            if !active {
+                match event.input {
+                    // Notes started before a bank or layer change still end
+                    Input::Release | Input::Pressure(_) if slot.on && !mapping.toggle => {}
+                    // The control moves on without the mapping, which has
+                    // to take over its own value again
+                    Input::Absolute(value) => {
+                        let value = value.min(FULL_SCALE);
+                        slot.physical = Some(value);
+                        if mapping.takeover != Takeover::Jump && slot.remote.is_none() {
+                            slot.remote = slot.sent.filter(|sent| sent.abs_diff(value) > PICKUP_WINDOW);
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                }
+            }
+            match mapping.action {
+                Action::MpeNote(_) | Action::MpePitch(_) | Action::MpeTimbre(_) if self.mpe.is_active() => {
+                    Self::apply_mpe(slot, &mut self.mpe, mapping, &table.curves, defaults, event.input, out)
+                }
+                _ => Self::apply(slot, mapping, table, defaults, event.input, out),
+            }
+        }
+    }
+
+    /// Whether a control of the active bank and layer is still to take
+    /// over the remote value
+    pub fn waiting(&self, table: &MappingTable) -> bool {
+        table.iter().zip(&self.slots).any(|(mapping, slot)| {
+            slot.remote.is_some() && fit(mapping, self.bank, self.shift) == self.layer(table, mapping.control)
+        })
+    }
+
+    /// Take over state reported by the host: a note, controller or
+    /// parameter on the address of a toggle mapping sets its toggle state,
+    /// so the next press turns it the other way. Values of absolute controls
+    /// with a takeover mode become the remote value the control has to catch
+    /// up with, in whichever bank they are. The bank controller selects a
+    /// bank. `change` is what `hires::Decoder` made of the message.
+    pub fn follow(
+        &mut self,
+        table: &MappingTable,
+        default_channel: u8,
+        banks: &Banks,
+        message: &MidiMessage,
+        change: Option<&ParamChange>,
+    ) {
+        if let MidiMessage::ControlChange { channel, control, value } = *message {
+            if Some(control) == banks.cc && channel == default_channel && value < banks.count.clamp(1, MAX_BANKS) {
+                self.bank = value + 1;
+            }
+        }
+        for (index, mapping) in table.iter().enumerate() {
+            if mapping.toggle || mapping.takeover == Takeover::Jump {
+                continue;
+            }
+            if let Some(position) = mapping.position(default_channel, message, change, &table.curves) {
+                let slot = &mut self.slots[index];
+                let near = slot.physical.is_some_and(|physical| physical.abs_diff(position) <= PICKUP_WINDOW);
+                slot.remote = if near { None } else { Some(position) };
+            }
+        }
+        let plain = Address::of_message(message).map(|(address, value)| (address, value as u16));
+        let hires = change.and_then(Address::of_change);
+        for (address, value) in plain.into_iter().chain(hires) {
+            Self::follow_toggles(&mut self.slots, table, default_channel, address, value);
+        }
+    }
+
+    /// Set the toggle state of the toggle mappings on `address`.
+    fn follow_toggles(slots: &mut [Slot], table: &MappingTable, default_channel: u8, address: Address, value: u16) {
+        for (index, mapping) in table.iter().enumerate() {
+            if !mapping.toggle || mapping.address(default_channel) != Some(address) {
+                continue;
+            }
+            let slot = &mut slots[index];
+            match mapping.action {
+                Action::Note(_) => slot.on = (value > 0) != mapping.invert,
+                Action::Cc(_) | Action::Cc14(_) | Action::Nrpn(_) | Action::Rpn(_) => {
+                    let (on, off) = (mapping.output(FULL_SCALE, &table.curves), mapping.output(0, &table.curves));
+                    slot.on = value.abs_diff(on) < value.abs_diff(off);
+                    slot.last = Some(value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// MPE notes: velocity from the press, then pressure while held. Pitch
+    /// and timbre controls bend or colour the note while it sounds.
+    fn apply_mpe(
+        slot: &mut Slot,
+        sender: &mut mpe::Sender,
+        mapping: &Mapping,
+        curves: &[curve::Table; USER_CURVES],
+        defaults: &Defaults,
+        input: Input,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        let note = match mapping.action {
+            Action::MpeNote(note) => note,
+            Action::MpePitch(note) | Action::MpeTimbre(note) => {
+                let raw = match input {
+                    Input::Press(velocity) => velocity.unwrap_or(FULL_SCALE),
+                    Input::Release => 0,
+                    Input::Absolute(value) | Input::Pressure(value) => value.min(FULL_SCALE),
+                    Input::Relative(detents) => {
+                        let position = slot.position as i32 + detents as i32 * ENCODER_STEP;
+                        slot.position = position.clamp(0, FULL_SCALE as i32) as u16;
+                        slot.position
+                    }
+                };
+                let value = mapping.output(raw, curves);
+                match mapping.action {
+                    Action::MpePitch(_) => sender.bend(note, value, out),
+                    _ => sender.timbre(note, value as u8, out),
+                }
+                return;
+            }
+            _ => return,
+        };
+        match input {
+            Input::Press(velocity) => {
+                let velocity = match velocity {
+                    Some(v) => mapping.scale(mapping.curve.apply(v, curves)).clamp(1, 127) as u8,
+                    None => defaults.velocity,
+                };
+                slot.on = sender.note_on(note, velocity, out);
+            }
+            Input::Release if slot.on => {
+                slot.on = false;
+                sender.note_off(note, 0, out);
+            }
+            Input::Pressure(value) if slot.on => {
+                sender.pressure(note, (mapping.curve.apply(value, curves) >> 7) as u8, out);
+            }
+            _ => {}
+        }
+    }
+
+    fn apply(
+        slot: &mut Slot,
+        mapping: &Mapping,
+        table: &MappingTable,
+        defaults: &Defaults,
+        input: Input,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        let channel = match mapping.channel {
+            DEFAULT_CHANNEL => defaults.channel,
+            ch => ch,
+        };
+
+        // Input to 14-bit value; `active` is the button state before inversion
+        let (raw, active) = match input {
+            Input::Press(velocity) => {
+                let value = velocity.unwrap_or(FULL_SCALE);
+                if mapping.toggle {
+                    slot.on = !slot.on;
+                    (if slot.on { value } else { 0 }, slot.on)
+                } else {
+                    (value, true)
+                }
+            }
+            Input::Release if mapping.toggle => return,
+            Input::Release => (0, false),
+            Input::Absolute(value) => match Self::takeover(slot, mapping.takeover, value.min(FULL_SCALE)) {
+                Some(value) => {
+                    slot.sent = Some(value);
+                    (value, value > 0)
+                }
+                None => return,
+            },
+            Input::Relative(detents) => {
+                let position = slot.position as i32 + detents as i32 * ENCODER_STEP;
+                slot.position = position.clamp(0, FULL_SCALE as i32) as u16;
+                (slot.position, slot.position > 0)
+            }
+            Input::Pressure(value) => {
+                Self::aftertouch(slot, mapping, &table.curves, defaults.aftertouch, channel, value, out);
+                return;
+            }
+        };
+        let scaled = mapping.output(raw, &table.curves);
+
+        // Without an MPE zone an MPE note is an ordinary note. A pad plays
+        // the note the keymap gives it now and releases the one it started.
+        if let Action::Note(_) | Action::MpeNote(_) | Action::Pad(_) = mapping.action {
+            let note = match mapping.action {
+                Action::Pad(pad) => defaults.keymap.note(pad),
+                action => Some(action.param() as u8),
+            };
+            let on = active != mapping.invert;
+            if on {
+                let velocity = match input {
+                    Input::Press(None) => defaults.velocity,
+                    _ => scaled.clamp(1, 127) as u8,
+                };
+                if let Some(note) = slot.note.or(note).filter(|_| slot.on && !mapping.toggle) {
+                    out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+                }
+                if let Some(note) = note {
+                    out(MidiMessage::NoteOn { channel, note, velocity });
+                }
+                slot.note = note;
+                slot.last = None;
+            } else if slot.on || mapping.toggle {
+                if let Some(note) = slot.note.take().or(note) {
+                    out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+                }
+            }
+            if !mapping.toggle {
+                slot.on = on;
+            }
+            return;
+        }
+
+        // Program changes on buttons fire on press only
+        if mapping.action == Action::Program && matches!(input, Input::Press(_)) {
+            slot.last = None;
+        }
+        if slot.last == Some(scaled) || (mapping.action == Action::Program && matches!(input, Input::Release)) {
+            return;
+        }
+        slot.last = Some(scaled);
+
+        match mapping.action {
+            Action::None
+            | Action::Note(_)
+            | Action::MpeNote(_)
+            | Action::Pad(_)
+            | Action::Keymap(_)
+            | Action::Bank(_)
+            | Action::Shift
+            | Action::Macro(_) => {}
+            Action::Cc(control) => out(MidiMessage::ControlChange { channel, control, value: scaled as u8 }),
+            Action::Cc14(control) => hires::send_cc14(channel, control, scaled, &defaults.hires, out),
+            Action::Nrpn(param) => {
+                hires::send_parameter(channel, ParamKind::Nrpn, param, scaled, &defaults.hires, out)
+            }
+            Action::Rpn(param) => hires::send_parameter(channel, ParamKind::Rpn, param, scaled, &defaults.hires, out),
+            Action::Program => out(MidiMessage::ProgramChange { channel, program: scaled as u8 }),
+            // Without an MPE zone these bend and colour the mapping's channel
+            Action::PitchBend | Action::MpePitch(_) => out(MidiMessage::PitchBend { channel, value: scaled }),
+            Action::MpeTimbre(_) => {
+                out(MidiMessage::ControlChange { channel, control: mpe::TIMBRE_CC, value: scaled as u8 })
+            }
+            Action::SysEx(index) => {
+                let template = &table.templates[index as usize];
+                Self::sysex(template, channel, scaled, out);
+            }
+        }
+    }
+
+    /// Soft takeover of an absolute control: the position to send, `None`
+    /// while the control has yet to catch up with the remote value.
+    fn takeover(slot: &mut Slot, mode: Takeover, value: u16) -> Option<u16> {
+        let previous = slot.physical.replace(value);
+        let Some(remote) = slot.remote.filter(|_| mode != Takeover::Jump) else {
+            return Some(value);
+        };
+        let passed = previous.is_some_and(|previous| (previous.min(value)..=previous.max(value)).contains(&remote));
+        match (mode, previous) {
+            (Takeover::Pickup, _) if passed || remote.abs_diff(value) <= PICKUP_WINDOW => {
+                slot.remote = None;
+                Some(value)
+            }
+            (Takeover::Scale, Some(previous)) if previous != value => {
+                // Cover the rest of the way to the end turned towards in
+                // the same proportion as the control; the two meet there
+                let (remote, previous, value) = (remote as i32, previous as i32, value as i32);
+                let full = FULL_SCALE as i32;
+                let moved = if value > previous {
+                    remote + (value - previous) * (full - remote) / (full - previous)
+                } else {
+                    remote - (previous - value) * remote / previous
+                } as u16;
+                slot.remote = Some(moved).filter(|&moved| moved != value as u16);
+                Some(moved)
+            }
+            _ => None,
+        }
+    }
+
+    /// Pressure on a sounding note; only sent when it changes.
+    fn aftertouch(
+        slot: &mut Slot,
+        mapping: &Mapping,
+        curves: &[curve::Table; USER_CURVES],
+        mode: Aftertouch,
+        channel: u8,
+        value: u16,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        let note = match mapping.action {
+            Action::Note(note) | Action::MpeNote(note) => note,
+            Action::Pad(_) => match slot.note {
+                Some(note) => note,
+                None => return,
+            },
+            _ => return,
+        };
+        let pressure = (mapping.curve.apply(value, curves) >> 7) as u8;
+        if !slot.on || slot.last == Some(pressure as u16) {
+            return;
+        }
+        slot.last = Some(pressure as u16);
+        match mode {
+            Aftertouch::Off => {}
+            Aftertouch::Poly => out(MidiMessage::PolyPressure { channel, note, pressure }),
+            Aftertouch::Channel => out(MidiMessage::ChannelPressure { channel, pressure }),
+        }
+    }
+
+    fn sysex(template: &SysExTemplate, channel: u8, value: u16, out: &mut dyn FnMut(MidiMessage)) {
+        let mut bytes = [0u8; TEMPLATE_LEN];
+        for (dst, &src) in bytes.iter_mut().zip(template.as_slice()) {
+            *dst = match src {
+                placeholder::VALUE => (value & 0x7F) as u8,
+                placeholder::VALUE_MSB => (value >> 7) as u8 & 0x7F,
+                placeholder::CHANNEL => channel,
+                byte => byte,
+            };
+        }
+        midi::sysex_fragments(&bytes[..template.len as usize], out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feedback::{Feedback, FeedbackTable, Source};
+
+    fn settings(mpe: mpe::Layout) -> Defaults {
+        Defaults {
+            channel: 0,
+            velocity: 100,
+            hires: hires::Options::new(),
+            mpe,
+            aftertouch: Aftertouch::Off,
+            keymap: keymap::Options::new(),
+            banks: Banks::new(),
+        }
+    }
+
+    fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange { channel, control, value }
+    }
+
+    fn run(
+        engine: &mut MappingEngine,
+        table: &MappingTable,
+        defaults: &Defaults,
+        control: u8,
+        input: Input,
+    ) -> Vec<MidiMessage> {
+        let mut sent = Vec::new();
+        engine.process(table, defaults, InputEvent { control, input }, &mut |m| sent.push(m));
+        sent
+    }
+
+    #[test]
+    fn bends_mpe_notes() {
+        let mut table = MappingTable::new();
+        table.add(Mapping::new(1, Action::MpeNote(60))).unwrap();
+        table.add(Mapping::new(2, Action::MpePitch(60))).unwrap();
+        table.add(Mapping::new(3, Action::MpeTimbre(60))).unwrap();
+        let mut layout = mpe::Layout::new();
+        layout.set_members(mpe::Zone::Lower, 2);
+        let defaults = settings(layout);
+        let mut engine = MappingEngine::new();
+
+        // Nothing to bend before the note sounds
+        assert!(run(&mut engine, &table, &defaults, 2, Input::Absolute(9000)).is_empty());
+        let sent = run(&mut engine, &table, &defaults, 1, Input::Press(None));
+        assert_eq!(sent.last(), Some(&MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }));
+        assert_eq!(
+            run(&mut engine, &table, &defaults, 2, Input::Absolute(FULL_SCALE)),
+            [MidiMessage::PitchBend { channel: 1, value: FULL_SCALE }]
+        );
+        assert_eq!(
+            run(&mut engine, &table, &defaults, 3, Input::Absolute(FULL_SCALE)),
+            [MidiMessage::ControlChange { channel: 1, control: mpe::TIMBRE_CC, value: 127 }]
+        );
+        run(&mut engine, &table, &defaults, 1, Input::Release);
+        assert!(run(&mut engine, &table, &defaults, 3, Input::Absolute(0)).is_empty());
+
+        // Without a zone they go to the mapping's channel
+        let defaults = settings(mpe::Layout::new());
+        assert_eq!(
+            run(&mut engine, &table, &defaults, 2, Input::Absolute(8192)),
+            [MidiMessage::PitchBend { channel: 0, value: 8192 }]
+        );
+        assert_eq!(
+            run(&mut engine, &table, &defaults, 3, Input::Absolute(0)),
+            [MidiMessage::ControlChange { channel: 0, control: mpe::TIMBRE_CC, value: 0 }]
+        );
+    }
+
+    #[test]
+    fn follows_hires_parameters() {
+        let mut table = MappingTable::new();
+        let mut fader = Mapping::new(1, Action::Cc14(7));
+        fader.takeover = Takeover::Pickup;
+        table.add(fader).unwrap();
+        let mut button = Mapping::new(2, Action::Nrpn(0x0123));
+        button.toggle = true;
+        table.add(button).unwrap();
+        let mut feedback = FeedbackTable::new();
+        feedback.set(Feedback { led: 5, source: Source::Control(1) }).unwrap();
+        let (defaults, banks) = (settings(mpe::Layout::new()), Banks::new());
+        let mut engine = MappingEngine::new();
+        let mut decoder = hires::Decoder::new();
+        let mut receive = |engine: &mut MappingEngine, control, value| {
+            let message = MidiMessage::ControlChange { channel: 0, control, value };
+            let change = decoder.feed(&message, &defaults.hires);
+            engine.follow(&table, 0, &banks, &message, change.as_ref());
+            let mut levels = Vec::new();
+            feedback.process(&table, 0, &message, change.as_ref(), &mut |led, level| levels.push((led, level)));
+            (change, levels)
+        };
+
+        // The fader takes over all 14 bits, the LED shows the upper 7
+        receive(&mut engine, 7, 64);
+        let (change, levels) = receive(&mut engine, 39, 5);
+        let lsb = MidiMessage::ControlChange { channel: 0, control: 39, value: 5 };
+        assert_eq!(table.iter().next().unwrap().position(0, &lsb, change.as_ref(), &table.curves), Some(64 << 7 | 5));
+        assert_eq!(levels, [(5, 64)]);
+        assert!(engine.waiting(&table));
+
+        // A parameter update turns the toggle on, the next press turns it off
+        let update = [(hires::NRPN_MSB, 0x02), (hires::NRPN_LSB, 0x23), (hires::DATA_ENTRY, 127), (38, 127)];
+        for (control, value) in update {
+            receive(&mut engine, control, value);
+        }
+        let sent = run(&mut engine, &table, &defaults, 2, Input::Press(None));
+        assert_eq!(
+            sent[sent.len() - 2..],
+            [
+                MidiMessage::ControlChange { channel: 0, control: hires::DATA_ENTRY, value: 0 },
+                MidiMessage::ControlChange { channel: 0, control: 38, value: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn motorised_faders_take_over() {
+        let mut table = MappingTable::new();
+        let mut fader = Mapping::new(1, Action::Cc(7));
+        fader.takeover = Takeover::Pickup;
+        table.add(fader).unwrap();
+        let (defaults, banks) = (settings(mpe::Layout::new()), Banks::new());
+        let mut engine = MappingEngine::new();
+        run(&mut engine, &table, &defaults, 1, Input::Absolute(0));
+
+        let message = MidiMessage::ControlChange { channel: 0, control: 7, value: 100 };
+        engine.follow(&table, 0, &banks, &message, None);
+        assert!(engine.waiting(&table));
+        let position = table.iter().next().unwrap().position(0, &message, None, &table.curves).unwrap();
+        engine.moved(&table, 1, position);
+        assert!(!engine.waiting(&table));
+        assert_eq!(engine.recall(&table, 1), Some(position));
+
+        // The knob is where the host left it, the next move goes out
+        assert_eq!(
+            run(&mut engine, &table, &defaults, 1, Input::Absolute(position + 200)),
+            [MidiMessage::ControlChange { channel: 0, control: 7, value: 101 }]
+        );
+    }
+
+    fn mapping(control: ControlId, action: Action, bank: u8) -> Mapping {
+        Mapping { bank, ..Mapping::new(control, action) }
+    }
+
+    #[test]
+    fn selects_banks() {
+        let mut table = MappingTable::new();
+        table.add(Mapping::new(1, Action::Bank(NEXT_BANK))).unwrap();
+        table.add(Mapping::new(2, Action::Bank(PREVIOUS_BANK))).unwrap();
+        table.add(Mapping::new(3, Action::Bank(2))).unwrap();
+        table.add(mapping(5, Action::Cc(10), 0)).unwrap();
+        table.add(mapping(5, Action::Cc(11), 2)).unwrap();
+        let mut defaults = settings(mpe::Layout::new());
+        defaults.banks = Banks { count: 2, cc: Some(20) };
+        let mut engine = MappingEngine::new();
+        let fader = |engine: &mut MappingEngine| run(engine, &table, &defaults, 5, Input::Absolute(FULL_SCALE));
+
+        // Mappings of every bank unless the bank has its own
+        assert_eq!(fader(&mut engine), [cc(0, 10, 127)]);
+        assert_eq!(run(&mut engine, &table, &defaults, 1, Input::Press(None)), [cc(0, 20, 1)]);
+        assert_eq!(engine.bank(), 2);
+        assert_eq!(fader(&mut engine), [cc(0, 11, 127)]);
+        // Next and previous wrap around the banks in use
+        assert_eq!(run(&mut engine, &table, &defaults, 1, Input::Press(None)), [cc(0, 20, 0)]);
+        assert_eq!(run(&mut engine, &table, &defaults, 2, Input::Press(None)), [cc(0, 20, 1)]);
+        assert_eq!(run(&mut engine, &table, &defaults, 2, Input::Press(None)), [cc(0, 20, 0)]);
+        assert_eq!(run(&mut engine, &table, &defaults, 3, Input::Press(None)), [cc(0, 20, 1)]);
+        assert!(run(&mut engine, &table, &defaults, 3, Input::Press(None)).is_empty());
+
+        // The host selects banks in use on the global channel only
+        for (message, bank) in [(cc(0, 20, 0), 1), (cc(1, 20, 1), 1), (cc(0, 20, 2), 1), (cc(0, 20, 1), 2)] {
+            engine.follow(&table, 0, &defaults.banks, &message, None);
+            assert_eq!(engine.bank(), bank, "{message:?}");
+        }
+    }
+
+    #[test]
+    fn shifts_layers() {
+        let mut table = MappingTable::new();
+        table.add(Mapping::new(1, Action::Shift)).unwrap();
+        table.add(Mapping { toggle: true, ..Mapping::new(2, Action::Shift) }).unwrap();
+        table.add(Mapping::new(5, Action::Cc(10))).unwrap();
+        table.add(Mapping { shift: true, ..Mapping::new(5, Action::Cc(11)) }).unwrap();
+        table.add(mapping(5, Action::Cc(12), 2)).unwrap();
+        let defaults = settings(mpe::Layout::new());
+        let mut engine = MappingEngine::new();
+        // Unchanged values are not sent again, the top and the bottom tell
+        // the mappings apart
+        let fader = |engine: &mut MappingEngine, control, top: bool| {
+            let sent = run(engine, &table, &defaults, 5, Input::Absolute(top as u16 * FULL_SCALE));
+            assert_eq!(sent, [cc(0, control, top as u8 * 127)]);
+        };
+
+        // Held, the shift layer wins over the bank
+        run(&mut engine, &table, &defaults, 1, Input::Press(None));
+        fader(&mut engine, 11, true);
+        run(&mut engine, &table, &defaults, 1, Input::Release);
+        fader(&mut engine, 10, true);
+        engine.follow(&table, 0, &Banks { count: 2, cc: Some(20) }, &cc(0, 20, 1), None);
+        fader(&mut engine, 12, true);
+
+        // Toggled, it stays until the next press
+        run(&mut engine, &table, &defaults, 2, Input::Press(None));
+        run(&mut engine, &table, &defaults, 2, Input::Release);
+        fader(&mut engine, 11, false);
+        run(&mut engine, &table, &defaults, 2, Input::Press(None));
+        fader(&mut engine, 12, false);
+    }
+
+    #[test]
+    fn takes_over_per_bank() {
+        let mut table = MappingTable::new();
+        table.add(Mapping { takeover: Takeover::Pickup, ..mapping(5, Action::Cc(10), 1) }).unwrap();
+        table.add(Mapping { takeover: Takeover::Pickup, ..mapping(5, Action::Cc(11), 2) }).unwrap();
+        let mut defaults = settings(mpe::Layout::new());
+        defaults.banks = Banks { count: 2, cc: Some(20) };
+        let mut engine = MappingEngine::new();
+        let fader = |engine: &mut MappingEngine, position| {
+            run(engine, &table, &defaults, 5, Input::Absolute(position))
+        };
+
+        assert_eq!(fader(&mut engine, 4000), [cc(0, 10, 31)]);
+        engine.follow(&table, 0, &defaults.banks, &cc(0, 20, 1), None);
+        assert_eq!(fader(&mut engine, 12000), [cc(0, 11, 93)]);
+        // Back in bank 1 the fader has to find the value it left there
+        engine.follow(&table, 0, &defaults.banks, &cc(0, 20, 0), None);
+        assert!(engine.waiting(&table));
+        assert_eq!(engine.recall(&table, 5), Some(4000));
+        assert!(fader(&mut engine, 8000).is_empty());
+        assert_eq!(fader(&mut engine, 3900), [cc(0, 10, 30)]);
+        assert!(!engine.waiting(&table));
+    }
+
+    /// A 14-bit fader with takeover `mode` and what it sends for a position,
+    /// `None` for nothing
+    struct Fader {
+        table: MappingTable,
+        engine: MappingEngine,
+        defaults: Defaults,
+    }
+
+    impl Fader {
+        fn new(mode: Takeover) -> Self {
+            let mut table = MappingTable::new();
+            table.add(Mapping { takeover: mode, ..Mapping::new(5, Action::Cc14(7)) }).unwrap();
+            Self { table, engine: MappingEngine::new(), defaults: settings(mpe::Layout::new()) }
+        }
+
+        fn move_to(&mut self, position: u16) -> Option<u16> {
+            match run(&mut self.engine, &self.table, &self.defaults, 5, Input::Absolute(position))[..] {
+                [] => None,
+                [MidiMessage::ControlChange { value: msb, .. }, MidiMessage::ControlChange { value: lsb, .. }] => {
+                    Some((msb as u16) << 7 | lsb as u16)
+                }
+                ref other => panic!("{other:?}"),
+            }
+        }
+
+        fn remote(&mut self, value: u16) {
+            let change = ParamChange { channel: 0, param: hires::Param::Cc14(7), value };
+            let lsb = cc(0, 39, value as u8 & 0x7F);
+            self.engine.follow(&self.table, 0, &self.defaults.banks, &lsb, Some(&change));
+        }
+    }
+
+    #[test]
+    fn picks_up() {
+        let mut fader = Fader::new(Takeover::Pickup);
+        assert_eq!(fader.move_to(2000), Some(2000));
+        fader.remote(10000);
+        assert!(fader.engine.waiting(&fader.table));
+        // Nothing until the fader comes within reach, then it tracks
+        assert_eq!(fader.move_to(5000), None);
+        assert_eq!(fader.move_to(10000 - PICKUP_WINDOW - 1), None);
+        assert_eq!(fader.move_to(10000 - PICKUP_WINDOW), Some(10000 - PICKUP_WINDOW));
+        assert!(!fader.engine.waiting(&fader.table));
+        assert_eq!(fader.move_to(9700), Some(9700));
+
+        // Or until it passes the remote value
+        fader.remote(4000);
+        assert_eq!(fader.move_to(6000), None);
+        assert_eq!(fader.move_to(2000), Some(2000));
+        assert_eq!(fader.move_to(2100), Some(2100));
+
+        // A remote value next to the fader needs no pickup
+        fader.remote(2100 + PICKUP_WINDOW);
+        assert!(!fader.engine.waiting(&fader.table));
+    }
+
+    #[test]
+    fn scales_to_the_remote_value() {
+        let mut fader = Fader::new(Takeover::Scale);
+        assert_eq!(fader.move_to(2000), Some(2000));
+        fader.remote(10000);
+        // A quarter of the way up takes the remote value a quarter of the
+        // rest of its way
+        let quarter = 2000 + (FULL_SCALE - 2000) / 4;
+        assert_eq!(fader.move_to(quarter), Some(10000 + (FULL_SCALE - 10000) / 4));
+        assert!(fader.engine.waiting(&fader.table));
+        assert_eq!(fader.move_to(FULL_SCALE), Some(FULL_SCALE));
+        assert!(!fader.engine.waiting(&fader.table));
+        assert_eq!(fader.move_to(15000), Some(15000));
+
+        // Down it closes in from the other side
+        fader.remote(3000);
+        assert_eq!(fader.move_to(7500), Some(1500));
+        assert_eq!(fader.move_to(0), Some(0));
+        assert_eq!(fader.move_to(100), Some(100));
+    }
+
+    #[test]
+    fn jumps() {
+        let mut fader = Fader::new(Takeover::Jump);
+        assert_eq!(fader.move_to(2000), Some(2000));
+        fader.remote(10000);
+        assert!(!fader.engine.waiting(&fader.table));
+        assert_eq!(fader.move_to(2100), Some(2100));
+    }
+
+    #[test]
+    fn rearms_in_other_banks() {
+        let mut fader = Fader::new(Takeover::Pickup);
+        fader.table.clear();
+        fader.table.add(Mapping { takeover: Takeover::Pickup, ..mapping(5, Action::Cc14(7), 1) }).unwrap();
+        fader.table.add(mapping(5, Action::Cc14(8), 2)).unwrap();
+        fader.defaults.banks = Banks { count: 2, cc: Some(20) };
+        let select = |fader: &mut Fader, bank: u8| {
+            fader.engine.follow(&fader.table, 0, &fader.defaults.banks, &cc(0, 20, bank - 1), None);
+        };
+
+        assert_eq!(fader.move_to(4000), Some(4000));
+        select(&mut fader, 2);
+        // Moves close to what bank 1 sent leave nothing to take over
+        fader.move_to(4000 + PICKUP_WINDOW);
+        select(&mut fader, 1);
+        assert!(!fader.engine.waiting(&fader.table));
+        select(&mut fader, 2);
+        fader.move_to(12000);
+        select(&mut fader, 1);
+        assert!(fader.engine.waiting(&fader.table));
+        assert_eq!(fader.move_to(8000), None);
+        assert_eq!(fader.move_to(3000), Some(3000));
+    }
+}
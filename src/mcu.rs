@@ -0,0 +1,755 @@
+//! Control Surface Protocols
+//!
+//! Mackie Control Universal and HUI emulation. With a protocol selected the
+//! controls no longer go through the mapping engine: they send what a DAW
+//! expects from a control surface, and what the DAW sends back moves the
+//! motorised faders, lights the button LEDs, draws the V-Pot rings on the
+//! LED strip and fills the scribble strips and the timecode display on the
+//! OLED.
+//!
+//! The layout is the same for both protocols:
+//! - fader `n` is channel strip `bank + n` and its knob the strip's fader
+//!   touch; SW1 and SW2 step `bank` through the eight strips by the number
+//!   of faders and ask the DAW for the next bank past either end
+//! - relative movement from a control turns V-Pot `control % 8`
+//! - shift register input `k` (and the LED of the same number) is, in rows
+//!   of eight strips, record arm, solo, mute, select and V-Pot push for
+//!   `k < 40`, then the transport and assignment buttons of `BUTTONS`;
+//!   the RGB LED also shows play (green) and record (red)
+//! - the ring of V-Pot `n` takes pixels `8n` to `8n + 7` of the LED strip
+//!
+//! Everything here works on messages and plain state, the hardware is
+//! driven from the `Update`s it hands out.
+
+use core::fmt::{self, Write};
+
+use crate::display::{Canvas, WIDTH};
+use crate::fader::MAX_FADERS;
+use crate::input::{self, Input, InputEvent};
+use crate::led::{self, LedId};
+use crate::midi::MidiMessage;
+
+/// Channel strips of one unit
+pub const STRIPS: usize = 8;
+
+/// Characters per strip and row of the scribble strips
+const NAME_LEN: usize = 7;
+const SCRIBBLE_LEN: usize = 2 * STRIPS * NAME_LEN;
+/// Digits of the timecode display
+const TIMECODE_LEN: usize = 10;
+
+/// LED strip pixels per V-Pot ring, and their colour (full amber)
+const RING_PIXELS: u8 = 8;
+const RING_LEVEL: u8 = 0x72;
+/// LEDs of a ring on a real surface, without the centre one
+const RING_LEDS: u8 = 11;
+
+const BLINK_MS: u32 = 250;
+
+/// Shift register inputs that are strip buttons, eight per row
+const STRIP_BUTTONS: usize = 5 * STRIPS;
+
+/// MCU note numbers
+mod note {
+    pub const BANK_LEFT: u8 = 0x2E;
+    pub const BANK_RIGHT: u8 = 0x2F;
+    pub const PLAY: u8 = 0x5E;
+    pub const RECORD: u8 = 0x5F;
+    pub const FADER_TOUCH: u8 = 0x68;
+}
+
+/// HUI switch ports of the strip button rows and fader touch; zones 0-7
+/// are the strips
+const HUI_ROWS: [u8; 5] = [7, 3, 2, 1, 5];
+const HUI_TOUCH: u8 = 0;
+/// HUI zone and ports of the bank buttons
+const HUI_BANK: (u8, u8, u8) = (0x0A, 1, 3);
+
+/// Shift register inputs from 40: MCU note and HUI zone and port
+const BUTTONS: [(u8, Option<(u8, u8)>); 24] = [
+    (0x5B, Some((0x0E, 1))), // rewind
+    (0x5C, Some((0x0E, 2))), // fast forward
+    (0x5D, Some((0x0E, 3))), // stop
+    (note::PLAY, Some((0x0E, 4))),
+    (note::RECORD, Some((0x0E, 5))),
+    (0x56, None), // cycle
+    (note::BANK_LEFT, Some((0x0A, 1))),
+    (note::BANK_RIGHT, Some((0x0A, 3))),
+    (0x30, Some((0x0A, 0))), // channel left
+    (0x31, Some((0x0A, 2))), // channel right
+    (0x32, None),            // flip
+    (0x28, None),            // track
+    (0x29, None),            // send
+    (0x2A, None),            // pan
+    (0x2B, None),            // plug-in
+    (0x2C, None),            // eq
+    (0x2D, None),            // instrument
+    (0x46, None),            // shift
+    (0x47, None),            // option
+    (0x48, None),            // control
+    (0x49, None),            // alt
+    (0x50, None),            // save
+    (0x51, None),            // undo
+    (0x54, None),            // marker
+];
+
+/// SysEx headers: Mackie manufacturer id and device type
+const MCU_HEADER: [u8; 5] = [0xF0, 0x00, 0x00, 0x66, 0x14];
+const HUI_HEADER: [u8; 6] = [0xF0, 0x00, 0x00, 0x66, 0x05, 0x00];
+/// Sent in the MCU handshake, 7 ASCII characters
+const SERIAL: &[u8; 7] = b"TIVA001";
+const CHALLENGE: [u8; 4] = [0x01, 0x02, 0x03, 0x04];
+const VERSION: &[u8] = b"V1.00";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Protocol {
+    /// Controls go through the mapping engine
+    Off = 0,
+    Mcu = 1,
+    Hui = 2,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 3] = [Protocol::Off, Protocol::Mcu, Protocol::Hui];
+
+    pub fn from_u8(value: u8) -> Option<Protocol> {
+        Protocol::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Off => "off",
+            Protocol::Mcu => "MCU",
+            Protocol::Hui => "HUI",
+        })
+    }
+}
+
+/// What the surface wants done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Update {
+    /// Message to the DAW
+    Send(MidiMessage),
+    Led(LedId, u8),
+    /// Move motorised fader `index`
+    Fader(usize, u16),
+}
+
+/// State of the emulated surface
+pub struct Surface {
+    protocol: Protocol,
+    /// Motorised faders fitted
+    faders: u8,
+    /// Strip of fader 0
+    bank: u8,
+    /// Fader positions the DAW sent, by strip
+    positions: [u16; STRIPS],
+    /// Button LEDs blinking, by input
+    blinking: u64,
+    blink_on: bool,
+    blink_at: u32,
+    scribble: [u8; SCRIBBLE_LEN],
+    /// Characters, bit 7 for the dot after one; the first is the leftmost
+    timecode: [u8; TIMECODE_LEN],
+    assignment: [u8; 2],
+    /// HUI: zone selected for LEDs, fader MSBs waiting for their LSB
+    zone: u8,
+    msb: [u8; STRIPS],
+    /// The screen needs drawing
+    dirty: bool,
+}
+
+impl Surface {
+    pub const fn new() -> Self {
+        Self {
+            protocol: Protocol::Off,
+            faders: 0,
+            bank: 0,
+            positions: [0; STRIPS],
+            blinking: 0,
+            blink_on: false,
+            blink_at: 0,
+            scribble: [b' '; SCRIBBLE_LEN],
+            timecode: [b' '; TIMECODE_LEN],
+            assignment: [b' '; 2],
+            zone: 0,
+            msb: [0; STRIPS],
+            dirty: true,
+        }
+    }
+
+    /// Follow the settings; another protocol starts from a blank surface.
+    pub fn configure(&mut self, protocol: Protocol, faders: u8) {
+        if protocol != self.protocol {
+            *self = Self::new();
+            self.protocol = protocol;
+        }
+        self.faders = faders.min(MAX_FADERS as u8);
+        self.bank -= self.bank % self.width();
+    }
+
+    /// Strip of fader 0
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Strips covered by one bank step
+    fn width(&self) -> u8 {
+        self.faders.max(1)
+    }
+
+    // ------------------------------------------------------------------------
+    // Controls to the DAW
+    // ------------------------------------------------------------------------
+
+    /// Turn a control event into surface messages; emits `Send` and
+    /// `Fader` only.
+    pub fn input(&mut self, event: InputEvent, out: &mut dyn FnMut(Update)) {
+        let control = event.control;
+        let pressed = matches!(event.input, Input::Press(_));
+        let fader = control.wrapping_sub(input::ids::FADER) as usize;
+        let touch = control.wrapping_sub(input::ids::FADER_TOUCH) as usize;
+        let button = control.wrapping_sub(input::ids::SHIFT) as usize;
+        match event.input {
+            Input::Relative(detents) => self.vpot(control % STRIPS as u8, detents, out),
+            Input::Absolute(position) if fader < MAX_FADERS => {
+                let strip = self.bank + fader as u8;
+                if let Some(stored) = self.positions.get_mut(strip as usize) {
+                    *stored = position;
+                    self.fader(strip, position, out);
+                }
+            }
+            Input::Press(_) | Input::Release => match control {
+                input::ids::ONBOARD if pressed => self.step_bank(false, out),
+                c if c == input::ids::ONBOARD + 1 && pressed => self.step_bank(true, out),
+                _ if touch < MAX_FADERS && ((self.bank as usize) + touch) < STRIPS => {
+                    let strip = self.bank + touch as u8;
+                    match self.protocol {
+                        Protocol::Hui => self.switch(strip, HUI_TOUCH, pressed, out),
+                        _ => self.note(note::FADER_TOUCH + strip, pressed, out),
+                    }
+                }
+                _ if button < input::MAX_SHIFT_INPUTS => self.button(button, pressed, out),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn fader(&self, strip: u8, position: u16, out: &mut dyn FnMut(Update)) {
+        match self.protocol {
+            Protocol::Hui => {
+                out(Update::Send(cc(strip, (position >> 7) as u8)));
+                out(Update::Send(cc(0x20 + strip, (position & 0x7F) as u8)));
+            }
+            _ => out(Update::Send(MidiMessage::PitchBend { channel: strip, value: position })),
+        }
+    }
+
+    fn vpot(&self, strip: u8, detents: i16, out: &mut dyn FnMut(Update)) {
+        let steps = detents.unsigned_abs().min(0x3F) as u8;
+        if steps == 0 {
+            return;
+        }
+        // MCU: bit 6 turns left; HUI: bit 6 turns right
+        out(Update::Send(match (self.protocol, detents > 0) {
+            (Protocol::Hui, true) => cc(0x40 + strip, 0x40 | steps),
+            (Protocol::Hui, false) => cc(0x40 + strip, steps),
+            (_, true) => cc(0x10 + strip, steps),
+            (_, false) => cc(0x10 + strip, 0x40 | steps),
+        }));
+    }
+
+    fn button(&self, button: usize, pressed: bool, out: &mut dyn FnMut(Update)) {
+        match self.protocol {
+            Protocol::Hui => {
+                if let Some((zone, port)) = hui_switch(button) {
+                    self.switch(zone, port, pressed, out);
+                }
+            }
+            _ => {
+                if let Some(note) = mcu_note(button) {
+                    self.note(note, pressed, out);
+                }
+            }
+        }
+    }
+
+    fn note(&self, note: u8, pressed: bool, out: &mut dyn FnMut(Update)) {
+        let velocity = if pressed { 0x7F } else { 0 };
+        out(Update::Send(MidiMessage::NoteOn { channel: 0, note, velocity }));
+    }
+
+    fn switch(&self, zone: u8, port: u8, pressed: bool, out: &mut dyn FnMut(Update)) {
+        out(Update::Send(cc(0x0F, zone)));
+        out(Update::Send(cc(0x2F, if pressed { 0x40 | port } else { port })));
+    }
+
+    /// Next or previous strips on the faders; past the last or first
+    /// strip the DAW changes banks and the faders wrap around.
+    fn step_bank(&mut self, up: bool, out: &mut dyn FnMut(Update)) {
+        let width = self.width();
+        let last = (STRIPS as u8 / width - 1) * width;
+        self.bank = match (up, self.bank) {
+            (true, bank) if bank < last => bank + width,
+            (false, bank) if bank >= width => bank - width,
+            (up, _) => {
+                let (note, port) = match up {
+                    true => (note::BANK_RIGHT, HUI_BANK.2),
+                    false => (note::BANK_LEFT, HUI_BANK.1),
+                };
+                for pressed in [true, false] {
+                    match self.protocol {
+                        Protocol::Hui => self.switch(HUI_BANK.0, port, pressed, out),
+                        _ => self.note(note, pressed, out),
+                    }
+                }
+                if up { 0 } else { last }
+            }
+        };
+        for index in 0..self.faders {
+            out(Update::Fader(index as usize, self.positions[(self.bank + index) as usize]));
+        }
+        self.dirty = true;
+    }
+
+    // ------------------------------------------------------------------------
+    // DAW to the surface
+    // ------------------------------------------------------------------------
+
+    /// Take a message from the DAW.
+    pub fn follow(&mut self, message: &MidiMessage, out: &mut dyn FnMut(Update)) {
+        match (self.protocol, *message) {
+            (Protocol::Mcu, MidiMessage::PitchBend { channel, value }) => self.position(channel, value, out),
+            (Protocol::Mcu, MidiMessage::NoteOn { channel: 0, note, velocity }) => {
+                if let Some(button) = (0..input::MAX_SHIFT_INPUTS).find(|&b| mcu_note(b) == Some(note)) {
+                    // Velocity 1 blinks
+                    self.led(button, velocity > 1, velocity == 1, out);
+                }
+            }
+            (Protocol::Mcu, MidiMessage::ControlChange { channel: 0, control, value }) => match control {
+                0x30..=0x37 => ring(control - 0x30, value, out),
+                0x40..=0x49 => {
+                    self.timecode[(0x49 - control) as usize] = segment_char(value);
+                    self.dirty = true;
+                }
+                0x4A | 0x4B => {
+                    self.assignment[(0x4B - control) as usize] = segment_char(value);
+                    self.dirty = true;
+                }
+                _ => {}
+            },
+            // Ping, the DAW takes the surface offline without an answer
+            (Protocol::Hui, MidiMessage::NoteOn { channel: 0, note: 0, velocity: 0 }) => {
+                out(Update::Send(MidiMessage::NoteOn { channel: 0, note: 0, velocity: 0x7F }));
+            }
+            (Protocol::Hui, MidiMessage::ControlChange { channel: 0, control, value }) => match control {
+                0x00..=0x07 => self.msb[control as usize] = value,
+                0x20..=0x27 => {
+                    let strip = control - 0x20;
+                    let value = (self.msb[strip as usize] as u16) << 7 | value as u16;
+                    self.position(strip, value, out);
+                }
+                0x0C => self.zone = value,
+                0x2C => {
+                    let (zone, port) = (self.zone, value & 0x0F);
+                    if let Some(button) = (0..input::MAX_SHIFT_INPUTS).find(|&b| hui_switch(b) == Some((zone, port))) {
+                        self.led(button, value & 0x40 != 0, false, out);
+                    }
+                }
+                // Ring position 1-11 with the centre in bit 6, as MCU single
+                // dot mode
+                0x10..=0x17 => ring(control - 0x10, value & 0x4F, out),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Store a fader position from the DAW, move the fader showing it.
+    fn position(&mut self, strip: u8, value: u16, out: &mut dyn FnMut(Update)) {
+        let Some(stored) = self.positions.get_mut(strip as usize) else {
+            return;
+        };
+        *stored = value;
+        let index = strip.wrapping_sub(self.bank);
+        if index < self.faders {
+            out(Update::Fader(index as usize, value));
+        }
+    }
+
+    fn led(&mut self, button: usize, on: bool, blink: bool, out: &mut dyn FnMut(Update)) {
+        let bit = 1 << button;
+        self.blinking = if blink { self.blinking | bit } else { self.blinking & !bit };
+        show(button, on || (blink && self.blink_on), out);
+    }
+
+    /// Take a complete SysEx message; false if it is not for the surface.
+    pub fn sysex(&mut self, message: &[u8], out: &mut dyn FnMut(&[u8])) -> bool {
+        let Some(body) = message.strip_suffix(&[0xF7]) else {
+            return false;
+        };
+        match self.protocol {
+            Protocol::Mcu => match body.strip_prefix(&MCU_HEADER) {
+                Some([0x00, ..]) => {
+                    let mut reply = [0; MCU_HEADER.len() + 13];
+                    reply[..5].copy_from_slice(&MCU_HEADER);
+                    reply[5] = 0x01;
+                    reply[6..13].copy_from_slice(SERIAL);
+                    reply[13..17].copy_from_slice(&CHALLENGE);
+                    reply[17] = 0xF7;
+                    out(&reply);
+                }
+                Some([0x02, ..]) => {
+                    let mut reply = [0; MCU_HEADER.len() + 9];
+                    reply[..5].copy_from_slice(&MCU_HEADER);
+                    reply[5] = 0x03;
+                    reply[6..13].copy_from_slice(SERIAL);
+                    reply[13] = 0xF7;
+                    out(&reply);
+                }
+                Some([0x13, ..]) => {
+                    let mut reply = [0; MCU_HEADER.len() + 7];
+                    reply[..5].copy_from_slice(&MCU_HEADER);
+                    reply[5] = 0x14;
+                    reply[6..11].copy_from_slice(VERSION);
+                    reply[11] = 0xF7;
+                    out(&reply);
+                }
+                Some([0x12, offset, text @ ..]) => self.write_scribble(*offset as usize, text),
+                Some(_) => {}
+                None => return false,
+            },
+            Protocol::Hui => match body.strip_prefix(&HUI_HEADER) {
+                // Four characters of a strip name
+                Some([0x10, strip, text @ ..]) if (*strip as usize) < STRIPS => {
+                    let offset = *strip as usize * NAME_LEN;
+                    self.write_scribble(offset, &[b' '; NAME_LEN]);
+                    self.write_scribble(offset, &text[..text.len().min(4)]);
+                }
+                // Timecode digits, last digit first, 0x10 for a dot
+                Some([0x11, digits @ ..]) => {
+                    for (i, &digit) in digits.iter().take(TIMECODE_LEN).enumerate() {
+                        let dot = if digit & 0x10 != 0 { 0x80 } else { 0 };
+                        self.timecode[TIMECODE_LEN - 1 - i] = (b'0' + (digit & 0x0F).min(9)) | dot;
+                    }
+                    self.dirty = true;
+                }
+                Some(_) => {}
+                None => return false,
+            },
+            Protocol::Off => return false,
+        }
+        true
+    }
+
+    fn write_scribble(&mut self, offset: usize, text: &[u8]) {
+        for (slot, &c) in self.scribble.iter_mut().skip(offset).zip(text) {
+            *slot = c;
+        }
+        self.dirty = true;
+    }
+
+    /// Blink the LEDs that the DAW asked to blink.
+    pub fn poll(&mut self, now: u32, out: &mut dyn FnMut(Update)) {
+        if now.wrapping_sub(self.blink_at) < BLINK_MS {
+            return;
+        }
+        self.blink_at = now;
+        self.blink_on = !self.blink_on;
+        for button in (0..input::MAX_SHIFT_INPUTS).filter(|b| self.blinking & (1 << b) != 0) {
+            show(button, self.blink_on, out);
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Screen
+    // ------------------------------------------------------------------------
+
+    /// Something on the screen changed since the last `render`
+    pub fn changed(&self) -> bool {
+        self.dirty
+    }
+
+    /// Draw assignment and timecode on top, below them the scribble strips,
+    /// three strips to a line pair, the ones on the faders underlined.
+    pub fn render(&mut self, canvas: &mut Canvas) {
+        canvas.clear();
+        let mut text = canvas.text(0, 0, 1);
+        for &c in self.assignment.iter().chain(b" ").chain(self.timecode.iter()) {
+            let _ = text.write_char(printable(c & 0x7F));
+            if c & 0x80 != 0 {
+                let _ = text.write_char('.');
+            }
+        }
+        canvas.fill(0, 10, WIDTH, 1, true);
+
+        for strip in 0..STRIPS {
+            let x = (strip % 3) * NAME_LEN * 6;
+            let y = 14 + (strip / 3) * 16;
+            for row in 0..2 {
+                let name = &self.scribble[row * STRIPS * NAME_LEN + strip * NAME_LEN..][..NAME_LEN];
+                let mut text = canvas.text(x, y + row * 8, 1);
+                for &c in name {
+                    let _ = text.write_char(printable(c));
+                }
+            }
+            if (strip as u8).wrapping_sub(self.bank) < self.faders {
+                canvas.fill(x, y + 15, NAME_LEN * 6 - 1, 1, true);
+            }
+        }
+        self.dirty = false;
+    }
+}
+
+fn cc(control: u8, value: u8) -> MidiMessage {
+    MidiMessage::ControlChange { channel: 0, control, value }
+}
+
+/// MCU note of shift register input `button`
+fn mcu_note(button: usize) -> Option<u8> {
+    match button {
+        b if b < STRIP_BUTTONS => Some(b as u8),
+        b => BUTTONS.get(b - STRIP_BUTTONS).map(|&(note, _)| note),
+    }
+}
+
+/// HUI zone and port of shift register input `button`
+fn hui_switch(button: usize) -> Option<(u8, u8)> {
+    match button {
+        b if b < STRIP_BUTTONS => Some(((b % STRIPS) as u8, HUI_ROWS[b / STRIPS])),
+        b => BUTTONS.get(b - STRIP_BUTTONS).and_then(|&(_, switch)| switch),
+    }
+}
+
+/// Light the LED of a button, and the RGB LED for play and record.
+fn show(button: usize, on: bool, out: &mut dyn FnMut(Update)) {
+    let level = if on { led::FULL } else { 0 };
+    out(Update::Led(led::ids::SHIFT + button as u8, level));
+    match mcu_note(button) {
+        Some(note::PLAY) => out(Update::Led(led::ids::GREEN, level)),
+        Some(note::RECORD) => out(Update::Led(led::ids::RED, level)),
+        _ => {}
+    }
+}
+
+/// Draw the ring of V-Pot `strip` on the LED strip.
+fn ring(strip: u8, value: u8, out: &mut dyn FnMut(Update)) {
+    let leds = ring_leds(value);
+    for pixel in 0..RING_PIXELS {
+        // Pixel lit if any of the ring LEDs that fall on it is
+        let on = (0..RING_LEDS).any(|i| i * RING_PIXELS / RING_LEDS == pixel && leds & (1 << i) != 0);
+        let level = if on { RING_LEVEL } else { 0 };
+        out(Update::Led(led::ids::STRIP + strip * RING_PIXELS + pixel, level));
+    }
+}
+
+/// LEDs of an MCU V-Pot ring, left to right, for a ring controller value:
+/// position 1-11 in the low nibble (0 is off), mode in bits 4-5 (dot,
+/// boost/cut, wrap, spread)
+pub fn ring_leds(value: u8) -> u16 {
+    const CENTRE: u8 = RING_LEDS / 2;
+    let position = value & 0x0F;
+    if position == 0 {
+        return 0;
+    }
+    let p = position.min(RING_LEDS) - 1;
+    let span = |from: u8, to: u8| ((1u16 << (to + 1)) - 1) & !((1u16 << from) - 1);
+    match (value >> 4) & 3 {
+        0 => 1 << p,
+        1 => span(p.min(CENTRE), p.max(CENTRE)),
+        2 => span(0, p),
+        _ => span(CENTRE.saturating_sub(p), (CENTRE + p).min(RING_LEDS - 1)),
+    }
+}
+
+/// ASCII of an MCU 7-segment character, bit 6 is the dot after it
+fn segment_char(value: u8) -> u8 {
+    let c = value & 0x3F;
+    let c = if c < 0x20 { c + 0x40 } else { c };
+    c | if value & 0x40 != 0 { 0x80 } else { 0 }
+}
+
+fn printable(c: u8) -> char {
+    match c {
+        0x20..=0x7E => c as char,
+        _ => ' ',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn surface(protocol: Protocol, faders: u8) -> Surface {
+        let mut surface = Surface::new();
+        surface.configure(protocol, faders);
+        surface
+    }
+
+    fn input(surface: &mut Surface, control: u8, input: Input) -> Vec<Update> {
+        let mut updates = Vec::new();
+        surface.input(InputEvent { control, input }, &mut |update| updates.push(update));
+        updates
+    }
+
+    fn follow(surface: &mut Surface, message: MidiMessage) -> Vec<Update> {
+        let mut updates = Vec::new();
+        surface.follow(&message, &mut |update| updates.push(update));
+        updates
+    }
+
+    fn sysex(surface: &mut Surface, message: &[u8]) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        assert!(surface.sysex(message, &mut |reply| replies.push(reply.to_vec())));
+        replies
+    }
+
+    fn send(control: u8, value: u8) -> Update {
+        Update::Send(cc(control, value))
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Update {
+        Update::Send(MidiMessage::NoteOn { channel: 0, note, velocity })
+    }
+
+    #[test]
+    fn answers_the_handshake() {
+        let mut mcu = surface(Protocol::Mcu, 2);
+        for (query, reply, len) in [(0x00, 0x01, 18), (0x02, 0x03, 14), (0x13, 0x14, 12)] {
+            let replies = sysex(&mut mcu, &[&MCU_HEADER[..], &[query, 0xF7]].concat());
+            assert_eq!(replies.len(), 1);
+            let message = &replies[0];
+            assert_eq!(message.len(), len);
+            assert_eq!((&message[..5], message[5], message[len - 1]), (&MCU_HEADER[..], reply, 0xF7));
+            assert!(message[1..len - 1].iter().all(|&b| b < 0x80));
+        }
+        // Not for the surface
+        assert!(!mcu.sysex(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], &mut |_| panic!()));
+        assert!(!surface(Protocol::Hui, 2).sysex(&[&MCU_HEADER[..], &[0x00, 0xF7]].concat(), &mut |_| panic!()));
+    }
+
+    #[test]
+    fn sends_faders() {
+        let mut mcu = surface(Protocol::Mcu, 2);
+        let bend = |channel, value| Update::Send(MidiMessage::PitchBend { channel, value });
+        assert_eq!(input(&mut mcu, input::ids::FADER + 1, Input::Absolute(0x2345)), [bend(1, 0x2345)]);
+        assert_eq!(follow(&mut mcu, MidiMessage::PitchBend { channel: 0, value: 0x1000 }), [Update::Fader(0, 0x1000)]);
+        // Strip not on a fader
+        assert!(follow(&mut mcu, MidiMessage::PitchBend { channel: 5, value: 0x1000 }).is_empty());
+        assert_eq!(input(&mut mcu, input::ids::FADER_TOUCH, Input::Press(None)), [note_on(note::FADER_TOUCH, 0x7F)]);
+
+        let mut hui = surface(Protocol::Hui, 2);
+        assert_eq!(input(&mut hui, input::ids::FADER + 1, Input::Absolute(0x2345)), [send(1, 0x46), send(0x21, 0x45)]);
+        assert!(follow(&mut hui, cc(0x00, 0x46)).is_empty());
+        assert_eq!(follow(&mut hui, cc(0x20, 0x45)), [Update::Fader(0, 0x2345)]);
+        assert_eq!(input(&mut hui, input::ids::FADER_TOUCH, Input::Press(None)), [send(0x0F, 0), send(0x2F, 0x40)]);
+    }
+
+    #[test]
+    fn sends_buttons() {
+        let mut mcu = surface(Protocol::Mcu, 2);
+        // Mute of strip 3, then play
+        let mute = input::ids::SHIFT + 2 * STRIPS as u8 + 3;
+        let play = input::ids::SHIFT + STRIP_BUTTONS as u8 + 3;
+        assert_eq!(input(&mut mcu, mute, Input::Press(None)), [note_on(0x13, 0x7F)]);
+        assert_eq!(input(&mut mcu, mute, Input::Release), [note_on(0x13, 0)]);
+        assert_eq!(input(&mut mcu, play, Input::Press(None)), [note_on(note::PLAY, 0x7F)]);
+
+        // The same buttons are zone and port pairs in HUI
+        let mut hui = surface(Protocol::Hui, 2);
+        assert_eq!(input(&mut hui, mute, Input::Press(None)), [send(0x0F, 3), send(0x2F, 0x42)]);
+        assert_eq!(input(&mut hui, mute, Input::Release), [send(0x0F, 3), send(0x2F, 0x02)]);
+        assert_eq!(input(&mut hui, play, Input::Release), [send(0x0F, 0x0E), send(0x2F, 0x04)]);
+        // No HUI switch for cycle
+        assert!(input(&mut hui, play + 2, Input::Press(None)).is_empty());
+    }
+
+    #[test]
+    fn lights_buttons() {
+        let play = STRIP_BUTTONS as u8 + 3;
+        let mut mcu = surface(Protocol::Mcu, 2);
+        let on = [Update::Led(led::ids::SHIFT + play, led::FULL), Update::Led(led::ids::GREEN, led::FULL)];
+        assert_eq!(follow(&mut mcu, MidiMessage::NoteOn { channel: 0, note: note::PLAY, velocity: 0x7F }), on);
+
+        let mut hui = surface(Protocol::Hui, 2);
+        assert!(follow(&mut hui, cc(0x0C, 0x0E)).is_empty());
+        assert_eq!(follow(&mut hui, cc(0x2C, 0x44)), on);
+        assert_eq!(follow(&mut hui, MidiMessage::NoteOn { channel: 0, note: 0, velocity: 0 }), [note_on(0, 0x7F)]);
+    }
+
+    #[test]
+    fn turns_vpots_opposite_ways() {
+        let mut mcu = surface(Protocol::Mcu, 2);
+        assert_eq!(input(&mut mcu, 10, Input::Relative(3)), [send(0x12, 3)]);
+        assert_eq!(input(&mut mcu, 10, Input::Relative(-3)), [send(0x12, 0x43)]);
+        assert_eq!(input(&mut mcu, 10, Input::Relative(-100)), [send(0x12, 0x7F)]);
+        assert!(input(&mut mcu, 10, Input::Relative(0)).is_empty());
+
+        let mut hui = surface(Protocol::Hui, 2);
+        assert_eq!(input(&mut hui, 10, Input::Relative(3)), [send(0x42, 0x43)]);
+        assert_eq!(input(&mut hui, 10, Input::Relative(-3)), [send(0x42, 3)]);
+    }
+
+    #[test]
+    fn draws_rings() {
+        // Dot, boost/cut, wrap and spread
+        assert_eq!(ring_leds(0x00), 0);
+        assert_eq!(ring_leds(0x01), 0b000_0000_0001);
+        assert_eq!(ring_leds(0x0B), 0b100_0000_0000);
+        assert_eq!(ring_leds(0x12), 0b000_0011_1110);
+        assert_eq!(ring_leds(0x19), 0b001_1110_0000);
+        assert_eq!(ring_leds(0x24), 0b000_0000_1111);
+        assert_eq!(ring_leds(0x31), 0b000_0010_0000);
+        assert_eq!(ring_leds(0x33), 0b000_1111_1000);
+        assert_eq!(ring_leds(0x3F), 0b111_1111_1111);
+
+        let mut mcu = surface(Protocol::Mcu, 2);
+        let updates = follow(&mut mcu, cc(0x32, 0x0B));
+        let lit: Vec<_> = updates.iter().filter(|u| !matches!(u, Update::Led(_, 0))).collect();
+        assert_eq!(updates.len(), RING_PIXELS as usize);
+        assert_eq!(lit, [&Update::Led(led::ids::STRIP + 2 * RING_PIXELS + 7, RING_LEVEL)]);
+    }
+
+    #[test]
+    fn shows_digits_in_order() {
+        let mut mcu = surface(Protocol::Mcu, 2);
+        // Rightmost timecode digit first, its dot in bit 6
+        for (control, value) in [(0x40, 0x30), (0x41, 0x71), (0x49, 0x39), (0x4B, 0x10), (0x4A, 0x41)] {
+            follow(&mut mcu, cc(control, value));
+        }
+        assert_eq!(mcu.timecode, [b'9', b' ', b' ', b' ', b' ', b' ', b' ', b' ', b'1' | 0x80, b'0']);
+        assert_eq!(mcu.assignment, [b'P', b'A' | 0x80]);
+
+        let mut hui = surface(Protocol::Hui, 2);
+        sysex(&mut hui, &[&HUI_HEADER[..], &[0x11, 0x00, 0x11, 0x02, 0xF7]].concat());
+        assert_eq!(hui.timecode[TIMECODE_LEN - 3..], [b'2', b'1' | 0x80, b'0']);
+    }
+
+    #[test]
+    fn steps_banks() {
+        for (faders, last) in [(1, 7), (2, 6)] {
+            let mut mcu = surface(Protocol::Mcu, faders);
+            follow(&mut mcu, MidiMessage::PitchBend { channel: last, value: 0x1234 });
+            let left = input(&mut mcu, input::ids::ONBOARD, Input::Press(None));
+            assert_eq!(mcu.bank(), last);
+            assert_eq!(left[..2], [note_on(note::BANK_LEFT, 0x7F), note_on(note::BANK_LEFT, 0)]);
+            assert_eq!(left[2], Update::Fader(0, 0x1234));
+            assert_eq!(left.len(), 2 + faders as usize);
+
+            let right = input(&mut mcu, input::ids::ONBOARD + 1, Input::Press(None));
+            assert_eq!(mcu.bank(), 0);
+            assert_eq!(right[..2], [note_on(note::BANK_RIGHT, 0x7F), note_on(note::BANK_RIGHT, 0)]);
+            let right = input(&mut mcu, input::ids::ONBOARD + 1, Input::Press(None));
+            assert_eq!((mcu.bank(), right.len()), (faders, faders as usize));
+            assert!(input(&mut mcu, input::ids::ONBOARD + 1, Input::Release).is_empty());
+        }
+
+        let mut hui = surface(Protocol::Hui, 2);
+        let left = input(&mut hui, input::ids::ONBOARD, Input::Press(None));
+        assert_eq!(left[..4], [send(0x0F, 0x0A), send(0x2F, 0x41), send(0x0F, 0x0A), send(0x2F, 0x01)]);
+    }
+}
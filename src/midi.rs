@@ -0,0 +1,511 @@
+//! MIDI 1.0 Messages
+//!
+//! Message model shared by the router and everything that produces or
+//! consumes MIDI, plus a byte stream parser and encoder. Channels are stored
+//! 0-based and shown 1-based.
+
+use core::fmt;
+
+/// One MIDI 1.0 message. SysEx travels in fragments of up to three raw bytes
+/// (including the F0/F7 framing), the same way USB-MIDI carries it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// 14-bit value, 8192 is centre
+    PitchBend { channel: u8, value: u16 },
+    SysEx { data: [u8; 3], len: u8 },
+    TimecodeQuarterFrame(u8),
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+/// Message categories, used for filtering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Note = 0,
+    PolyPressure = 1,
+    ControlChange = 2,
+    ProgramChange = 3,
+    ChannelPressure = 4,
+    PitchBend = 5,
+    SysEx = 6,
+    /// Clock and transport real-time messages
+    Clock = 7,
+    /// System common messages and the remaining real-time messages
+    System = 8,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 9] = [
+        Kind::Note,
+        Kind::PolyPressure,
+        Kind::ControlChange,
+        Kind::ProgramChange,
+        Kind::ChannelPressure,
+        Kind::PitchBend,
+        Kind::SysEx,
+        Kind::Clock,
+        Kind::System,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Note => "note",
+            Kind::PolyPressure => "poly",
+            Kind::ControlChange => "cc",
+            Kind::ProgramChange => "program",
+            Kind::ChannelPressure => "pressure",
+            Kind::PitchBend => "bend",
+            Kind::SysEx => "sysex",
+            Kind::Clock => "clock",
+            Kind::System => "system",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::ALL.iter().copied().find(|k| k.name() == name)
+    }
+
+    pub fn bit(self) -> u16 {
+        1 << self as u8
+    }
+}
+
+impl MidiMessage {
+    /// Channel of a channel voice message
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            MidiMessage::NoteOff { .. } | MidiMessage::NoteOn { .. } => Kind::Note,
+            MidiMessage::PolyPressure { .. } => Kind::PolyPressure,
+            MidiMessage::ControlChange { .. } => Kind::ControlChange,
+            MidiMessage::ProgramChange { .. } => Kind::ProgramChange,
+            MidiMessage::ChannelPressure { .. } => Kind::ChannelPressure,
+            MidiMessage::PitchBend { .. } => Kind::PitchBend,
+            MidiMessage::SysEx { .. } => Kind::SysEx,
+            MidiMessage::Clock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop => {
+                Kind::Clock
+            }
+            _ => Kind::System,
+        }
+    }
+
+    /// Serialize to MIDI bytes, returns the number of bytes used.
+    pub fn encode(&self, out: &mut [u8; 3]) -> usize {
+        let mut put = |bytes: &[u8]| {
+            out[..bytes.len()].copy_from_slice(bytes);
+            bytes.len()
+        };
+        match *self {
+            MidiMessage::NoteOff { channel, note, velocity } => put(&[0x80 | channel, note, velocity]),
+            MidiMessage::NoteOn { channel, note, velocity } => put(&[0x90 | channel, note, velocity]),
+            MidiMessage::PolyPressure { channel, note, pressure } => put(&[0xA0 | channel, note, pressure]),
+            MidiMessage::ControlChange { channel, control, value } => put(&[0xB0 | channel, control, value]),
+            MidiMessage::ProgramChange { channel, program } => put(&[0xC0 | channel, program]),
+            MidiMessage::ChannelPressure { channel, pressure } => put(&[0xD0 | channel, pressure]),
+            MidiMessage::PitchBend { channel, value } => {
+                put(&[0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8 & 0x7F])
+            }
+            MidiMessage::SysEx { data, len } => put(&data[..len as usize]),
+            MidiMessage::TimecodeQuarterFrame(value) => put(&[0xF1, value]),
+            MidiMessage::SongPosition(beats) => put(&[0xF2, (beats & 0x7F) as u8, (beats >> 7) as u8 & 0x7F]),
+            MidiMessage::SongSelect(song) => put(&[0xF3, song]),
+            MidiMessage::TuneRequest => put(&[0xF6]),
+            MidiMessage::Clock => put(&[0xF8]),
+            MidiMessage::Start => put(&[0xFA]),
+            MidiMessage::Continue => put(&[0xFB]),
+            MidiMessage::Stop => put(&[0xFC]),
+            MidiMessage::ActiveSensing => put(&[0xFE]),
+            MidiMessage::SystemReset => put(&[0xFF]),
+        }
+    }
+}
+
+/// Split a complete SysEx message (F0 ... F7) into `SysEx` fragments.
+pub fn sysex_fragments(bytes: &[u8], out: &mut dyn FnMut(MidiMessage)) {
+    for chunk in bytes.chunks(3) {
+        let mut data = [0; 3];
+        data[..chunk.len()].copy_from_slice(chunk);
+        out(MidiMessage::SysEx { data, len: chunk.len() as u8 });
+    }
+}
+
+impl MidiMessage {
+    /// Message from a status byte and its data bytes, as carried in USB-MIDI
+    /// and UMP packets. SysEx is not handled here.
+    pub fn from_bytes(status: u8, data1: u8, data2: u8) -> Option<MidiMessage> {
+        let data = [data1 & 0x7F, data2 & 0x7F];
+        Some(match status {
+            0x80..=0xEF | 0xF1..=0xF3 => Parser::build(status, data),
+            0xF6 => MidiMessage::TuneRequest,
+            0xF8 => MidiMessage::Clock,
+            0xFA => MidiMessage::Start,
+            0xFB => MidiMessage::Continue,
+            0xFC => MidiMessage::Stop,
+            0xFE => MidiMessage::ActiveSensing,
+            0xFF => MidiMessage::SystemReset,
+            _ => return None,
+        })
+    }
+}
+
+// ============================================================================
+// USB-MIDI 1.0 Event Packets
+// ============================================================================
+
+/// Four-byte USB-MIDI 1.0 event packet for a message on virtual cable `cable`.
+pub fn to_usb_packet(cable: u8, message: &MidiMessage) -> [u8; 4] {
+    let mut bytes = [0u8; 3];
+    let len = message.encode(&mut bytes);
+    // Code index number: how to read the three MIDI bytes
+    let cin = match *message {
+        MidiMessage::SysEx { data, len } => match (len, data[len as usize - 1]) {
+            (_, 0xF7) => 0x4 + len,
+            (1, _) if data[0] != 0xF0 => 0xF,
+            _ => 0x4,
+        },
+        _ if bytes[0] < 0xF0 => bytes[0] >> 4,
+        _ => match len {
+            3 => 0x3,
+            2 => 0x2,
+            // Tune request is system common, real-time bytes are single bytes
+            _ if bytes[0] == 0xF6 => 0x5,
+            _ => 0xF,
+        },
+    };
+    [cable << 4 | cin, bytes[0], bytes[1], bytes[2]]
+}
+
+/// Message carried by a USB-MIDI 1.0 event packet, ignoring the cable number.
+pub fn from_usb_packet(packet: [u8; 4]) -> Option<MidiMessage> {
+    let [header, b0, b1, b2] = packet;
+    let sysex = |len: usize| {
+        let mut data = [0u8; 3];
+        data[..len].copy_from_slice(&[b0, b1, b2][..len]);
+        Some(MidiMessage::SysEx { data, len: len as u8 })
+    };
+    match header & 0x0F {
+        0x4 => sysex(3),
+        0x5 if b0 == 0xF7 => sysex(1),
+        0x6 => sysex(2),
+        0x7 => sysex(3),
+        // Reserved code index numbers
+        0x0 | 0x1 => None,
+        _ => MidiMessage::from_bytes(b0, b1, b2),
+    }
+}
+
+/// Note number with its name, e.g. `60 (C4)`
+pub struct NoteName(pub u8);
+
+impl fmt::Display for NoteName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+        let octave = (self.0 / 12) as i8 - 1;
+        write!(f, "{} ({}{})", self.0, NAMES[(self.0 % 12) as usize], octave)
+    }
+}
+
+impl fmt::Display for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MidiMessage::NoteOff { channel, note, velocity } => {
+                write!(f, "note-off  ch{:<2} {} vel {}", channel + 1, NoteName(note), velocity)
+            }
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                write!(f, "note-on   ch{:<2} {} vel {}", channel + 1, NoteName(note), velocity)
+            }
+            MidiMessage::PolyPressure { channel, note, pressure } => {
+                write!(f, "poly-at   ch{:<2} {} {}", channel + 1, NoteName(note), pressure)
+            }
+            MidiMessage::ControlChange { channel, control, value } => {
+                write!(f, "cc        ch{:<2} #{} = {}", channel + 1, control, value)
+            }
+            MidiMessage::ProgramChange { channel, program } => {
+                write!(f, "program   ch{:<2} {}", channel + 1, program)
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                write!(f, "chan-at   ch{:<2} {}", channel + 1, pressure)
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                write!(f, "bend      ch{:<2} {:+}", channel + 1, value as i32 - 8192)
+            }
+            MidiMessage::SysEx { data, len } => {
+                f.write_str("sysex    ")?;
+                for byte in &data[..len as usize] {
+                    write!(f, " {:02X}", byte)?;
+                }
+                Ok(())
+            }
+            MidiMessage::TimecodeQuarterFrame(value) => {
+                write!(f, "mtc       piece {} value {}", value >> 4, value & 0x0F)
+            }
+            MidiMessage::SongPosition(beats) => write!(f, "song-pos  {}", beats),
+            MidiMessage::SongSelect(song) => write!(f, "song-sel  {}", song),
+            MidiMessage::TuneRequest => f.write_str("tune-request"),
+            MidiMessage::Clock => f.write_str("clock"),
+            MidiMessage::Start => f.write_str("start"),
+            MidiMessage::Continue => f.write_str("continue"),
+            MidiMessage::Stop => f.write_str("stop"),
+            MidiMessage::ActiveSensing => f.write_str("active-sensing"),
+            MidiMessage::SystemReset => f.write_str("reset"),
+        }
+    }
+}
+
+// ============================================================================
+// Parser
+// ============================================================================
+
+/// Byte stream to message parser with running status. Real-time bytes may be
+/// interleaved anywhere, including inside SysEx.
+pub struct Parser {
+    status: u8,
+    data: [u8; 2],
+    count: usize,
+    sysex: [u8; 3],
+    sysex_len: usize,
+    in_sysex: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self { status: 0, data: [0; 2], count: 0, sysex: [0; 3], sysex_len: 0, in_sysex: false }
+    }
+
+    /// Number of data bytes that follow a status byte
+    pub fn data_len(status: u8) -> usize {
+        match status {
+            0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+            0x80..=0xEF | 0xF2 => 2,
+            _ => 0,
+        }
+    }
+
+    fn sysex_push(&mut self, byte: u8) -> Option<MidiMessage> {
+        self.sysex[self.sysex_len] = byte;
+        self.sysex_len += 1;
+        if self.sysex_len == 3 || byte == 0xF7 {
+            let len = core::mem::take(&mut self.sysex_len);
+            return Some(MidiMessage::SysEx { data: self.sysex, len: len as u8 });
+        }
+        None
+    }
+
+    /// Feed one byte, returns a message once one is complete.
+    pub fn feed(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            0xF8 => Some(MidiMessage::Clock),
+            0xFA => Some(MidiMessage::Start),
+            0xFB => Some(MidiMessage::Continue),
+            0xFC => Some(MidiMessage::Stop),
+            0xFE => Some(MidiMessage::ActiveSensing),
+            0xFF => Some(MidiMessage::SystemReset),
+            0xF9 | 0xFD => None,
+            0xF0 => {
+                self.status = 0;
+                self.in_sysex = true;
+                self.sysex_len = 0;
+                self.sysex_push(byte)
+            }
+            0xF7 => {
+                if !self.in_sysex {
+                    return None;
+                }
+                self.in_sysex = false;
+                self.sysex_push(byte)
+            }
+            0x80..=0xF6 => {
+                // Any other status byte aborts an unterminated SysEx
+                self.in_sysex = false;
+                self.sysex_len = 0;
+                self.count = 0;
+                self.status = byte;
+                match byte {
+                    0xF6 => {
+                        self.status = 0;
+                        Some(MidiMessage::TuneRequest)
+                    }
+                    0xF4 | 0xF5 => {
+                        self.status = 0;
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            _ => {
+                if self.in_sysex {
+                    return self.sysex_push(byte);
+                }
+                if self.status == 0 {
+                    return None;
+                }
+                self.data[self.count] = byte;
+                self.count += 1;
+                if self.count < Self::data_len(self.status) {
+                    return None;
+                }
+                self.count = 0;
+                let message = Self::build(self.status, self.data);
+                // System common messages do not set running status
+                if self.status >= 0xF0 {
+                    self.status = 0;
+                }
+                Some(message)
+            }
+        }
+    }
+
+    fn build(status: u8, data: [u8; 2]) -> MidiMessage {
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff { channel, note: data[0], velocity: data[1] },
+            0x90 => MidiMessage::NoteOn { channel, note: data[0], velocity: data[1] },
+            0xA0 => MidiMessage::PolyPressure { channel, note: data[0], pressure: data[1] },
+            0xB0 => MidiMessage::ControlChange { channel, control: data[0], value: data[1] },
+            0xC0 => MidiMessage::ProgramChange { channel, program: data[0] },
+            0xD0 => MidiMessage::ChannelPressure { channel, pressure: data[0] },
+            0xE0 => MidiMessage::PitchBend { channel, value: data[0] as u16 | (data[1] as u16) << 7 },
+            _ => match status {
+                0xF1 => MidiMessage::TimecodeQuarterFrame(data[0]),
+                0xF2 => MidiMessage::SongPosition(data[0] as u16 | (data[1] as u16) << 7),
+                _ => MidiMessage::SongSelect(data[0]),
+            },
+        }
+    }
+}
+
+// ============================================================================
+// SysEx reassembly
+// ============================================================================
+
+/// Collects `SysEx` fragments into complete messages of up to `N` bytes.
+/// Longer messages are dropped.
+pub struct SysExBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    /// Inside a message that did not fit
+    overflow: bool,
+}
+
+impl<const N: usize> SysExBuffer<N> {
+    pub const fn new() -> Self {
+        Self { bytes: [0; N], len: 0, overflow: false }
+    }
+
+    /// Forget a partial message.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// Add the bytes of one fragment, returns the message (F0 ... F7) once
+    /// it is complete.
+    pub fn push(&mut self, fragment: &[u8]) -> Option<&[u8]> {
+        let mut complete = None;
+        for &byte in fragment {
+            if byte == 0xF0 {
+                self.clear();
+            } else if self.len == 0 {
+                // Not inside a message
+                continue;
+            }
+            if self.len < N {
+                self.bytes[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            if byte == 0xF7 {
+                if !self.overflow {
+                    complete = Some(self.len);
+                }
+                self.clear();
+            }
+        }
+        complete.map(|len| &self.bytes[..len])
+    }
+}
+
+// ============================================================================
+// Tempo
+// ============================================================================
+
+/// MIDI clocks per quarter note
+pub const CLOCKS_PER_BEAT: u8 = 24;
+
+/// Tempo of incoming MIDI clock, measured over one beat
+pub struct Tempo {
+    /// Clocks since `beat_at`
+    clocks: u8,
+    beat_at: Option<u32>,
+    clock_at: u32,
+    /// Beats per minute times 10
+    bpm10: Option<u16>,
+}
+
+impl Tempo {
+    /// Clock pause after which the tempo is unknown
+    const TIMEOUT_MS: u32 = 1000;
+
+    pub const fn new() -> Self {
+        Self { clocks: 0, beat_at: None, clock_at: 0, bpm10: None }
+    }
+
+    pub fn feed(&mut self, now: u32, message: &MidiMessage) {
+        match message {
+            MidiMessage::Clock => {}
+            MidiMessage::Start => {
+                self.beat_at = None;
+                return;
+            }
+            _ => return,
+        }
+        if now.wrapping_sub(self.clock_at) > Self::TIMEOUT_MS {
+            self.beat_at = None;
+        }
+        self.clock_at = now;
+        let Some(beat_at) = self.beat_at else {
+            self.beat_at = Some(now);
+            self.clocks = 0;
+            return;
+        };
+        self.clocks += 1;
+        if self.clocks == CLOCKS_PER_BEAT {
+            let beat = now.wrapping_sub(beat_at).max(1);
+            self.bpm10 = Some((600_000 / beat).min(u16::MAX as u32) as u16);
+            self.beat_at = Some(now);
+            self.clocks = 0;
+        }
+    }
+
+    /// Beats per minute times 10, while clock keeps coming
+    pub fn bpm10(&self, now: u32) -> Option<u16> {
+        self.bpm10.filter(|_| now.wrapping_sub(self.clock_at) <= Self::TIMEOUT_MS)
+    }
+}
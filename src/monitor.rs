@@ -0,0 +1,167 @@
+//! MIDI Monitor
+//!
+//! Prints the messages passing through the router on the CDC port. The router
+//! hands every delivery to `record`, which only keeps what passes the filter;
+//! the main loop calls `drain` to format queued messages, limited both to a
+//! number of lines per second and to the space left in the CDC transmit
+//! queue, so monitoring can never saturate the USB link or stall MIDI
+//! processing. Messages that do not fit are counted and reported as dropped.
+
+use core::fmt::Write;
+
+use crate::midi::Kind;
+use crate::ring::Queue;
+use crate::router::{Port, Routed};
+
+/// Longest line `drain` can produce
+pub const LINE_MAX: usize = 80;
+
+/// Pending lines held back by rate limiting
+const QUEUE_DEPTH: usize = 32;
+
+/// Which messages to show. A message passes if its source or destination is
+/// in `ports`, its channel (if it has one) is in `channels` and its kind is in
+/// `kinds`.
+#[derive(Clone, Copy)]
+pub struct Filter {
+    pub ports: u8,
+    pub channels: u16,
+    pub kinds: u16,
+}
+
+impl Filter {
+    /// Everything except clock, which would flood the output
+    pub const fn new() -> Self {
+        Self { ports: 0xFF, channels: 0xFFFF, kinds: !(1 << Kind::Clock as u8) }
+    }
+
+    pub fn matches(&self, routed: &Routed) -> bool {
+        let port_ok = self.ports & (routed.source.bit() | routed.dest.bit()) != 0;
+        let channel_ok = match routed.message.channel() {
+            Some(channel) => self.channels & (1 << channel) != 0,
+            None => true,
+        };
+        port_ok && channel_ok && self.kinds & routed.message.kind().bit() != 0
+    }
+}
+
+pub struct Monitor {
+    pub enabled: bool,
+    pub filter: Filter,
+    /// Maximum lines per second
+    pub rate: u16,
+    queue: Queue<Routed, QUEUE_DEPTH>,
+    dropped: u32,
+    tokens: u32,
+    last_refill: u32,
+}
+
+impl Monitor {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            filter: Filter::new(),
+            rate: 50,
+            queue: Queue::new(),
+            dropped: 0,
+            tokens: 0,
+            last_refill: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool, now: u32) {
+        self.enabled = enabled;
+        self.queue.clear();
+        self.dropped = 0;
+        self.tokens = self.burst();
+        self.last_refill = now;
+    }
+
+    /// Lines that may be printed back to back after a quiet period
+    fn burst(&self) -> u32 {
+        (self.rate as u32 / 4).max(4)
+    }
+
+    /// Take note of a routed message.
+    pub fn record(&mut self, routed: &Routed) {
+        if !self.enabled || !self.filter.matches(routed) {
+            return;
+        }
+        if !self.queue.push(*routed) {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Print queued messages while rate and `room` (free bytes in the output)
+    /// allow.
+    pub fn drain(&mut self, now: u32, mut room: usize, out: &mut dyn Write) {
+        if !self.enabled {
+            return;
+        }
+
+        // Token bucket refill
+        let rate = self.rate.max(1) as u32;
+        let elapsed = now.wrapping_sub(self.last_refill).min(60_000);
+        let earned = elapsed * rate / 1000;
+        if earned > 0 {
+            self.tokens = (self.tokens + earned).min(self.burst());
+            self.last_refill = if self.tokens == self.burst() {
+                now
+            } else {
+                self.last_refill.wrapping_add(earned * 1000 / rate)
+            };
+        }
+
+        while self.tokens > 0 && room >= LINE_MAX {
+            if self.dropped > 0 {
+                let _ = write!(out, "  ... {} dropped\r\n", self.dropped);
+                self.dropped = 0;
+            } else if let Some(routed) = self.queue.pop() {
+                Self::print(&routed, out);
+            } else {
+                break;
+            }
+            self.tokens -= 1;
+            room -= LINE_MAX;
+        }
+    }
+
+    fn print(routed: &Routed, out: &mut dyn Write) {
+        let _ = write!(
+            out,
+            "{:>6}.{:03} {:>5}>{:<5} {}\r\n",
+            routed.time / 1000,
+            routed.time % 1000,
+            routed.source.name(),
+            routed.dest.name(),
+            routed.message
+        );
+    }
+
+    /// Print the current settings.
+    pub fn describe(&self, out: &mut dyn Write) {
+        let _ = write!(out, "monitor  {}\r\nports   ", if self.enabled { "on" } else { "off" });
+        for port in Port::ALL {
+            if self.filter.ports & port.bit() != 0 {
+                let _ = write!(out, " {}", port.name());
+            }
+        }
+        let _ = out.write_str("\r\nchannels");
+        if self.filter.channels == 0xFFFF {
+            let _ = out.write_str(" all");
+        } else {
+            for channel in 0..16 {
+                if self.filter.channels & (1 << channel) != 0 {
+                    let _ = write!(out, " {}", channel + 1);
+                }
+            }
+        }
+        let _ = out.write_str("\r\ntypes   ");
+        for kind in Kind::ALL {
+            if self.filter.kinds & kind.bit() != 0 {
+                let _ = write!(out, " {}", kind.name());
+            }
+        }
+        let _ = write!(out, "\r\nrate     {} lines/s\r\n", self.rate);
+    }
+}
@@ -0,0 +1,328 @@
+//! Motion Controls
+//!
+//! Turns the orientation and acceleration of an IMU into control input:
+//! tilt forward and back (pitch), tilt sideways (roll), heading (yaw) and
+//! shaking are controls `input::ids::MOTION` and up, mapped like any other.
+//! Pitch and roll cover `-range..=range` degrees around the calibrated rest
+//! position, yaw the full turn; a shake is a press with a velocity from how
+//! hard it was, released once the sensor is still again.
+//!
+//! Angles are in hundredths of a degree, acceleration in thousandths of g
+//! and rotation rates in hundredths of a degree per second, so this part
+//! runs on integers and is tested on the host. Reading the sensor is
+//! in `imu`.
+
+use core::fmt;
+
+use crate::input::{self, Input, InputEvent, FULL_SCALE};
+
+/// Controls of the motion source, offsets from `input::ids::MOTION`
+pub mod axis {
+    pub const PITCH: u8 = 0;
+    pub const ROLL: u8 = 1;
+    pub const YAW: u8 = 2;
+    pub const SHAKE: u8 = 3;
+}
+
+/// Controls of the motion source
+pub const CONTROLS: usize = 4;
+
+/// Largest tilt range in degrees
+pub const MAX_RANGE: u8 = 90;
+
+/// Change of an axis before it is reported again, about 0.2% of its span
+const HYSTERESIS: u16 = 32;
+/// Acceleration beyond gravity that starts a shake, and the one that gives
+/// full velocity
+const SHAKE_THRESHOLD: i32 = 1000;
+const SHAKE_FULL: i32 = 3000;
+/// A shake ends after this long below half the threshold
+const SHAKE_QUIET_MS: u32 = 150;
+
+/// Samples averaged by a calibration
+const CALIBRATION_SAMPLES: u32 = 100;
+
+/// IMU on I2C3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Sensor {
+    None = 0,
+    /// Accelerometer and gyroscope; yaw drifts
+    Mpu6050 = 1,
+    /// Accelerometer, gyroscope and magnetometer
+    Mpu9150 = 2,
+    /// Accelerometer and magnetometer, slower to follow
+    Lsm303dlhc = 3,
+}
+
+impl Sensor {
+    pub const ALL: [Sensor; 4] = [Sensor::None, Sensor::Mpu6050, Sensor::Mpu9150, Sensor::Lsm303dlhc];
+
+    pub fn from_u8(value: u8) -> Option<Sensor> {
+        Sensor::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sensor::None => "none",
+            Sensor::Mpu6050 => "MPU6050",
+            Sensor::Mpu9150 => "MPU9150",
+            Sensor::Lsm303dlhc => "LSM303DLHC",
+        })
+    }
+}
+
+/// Rest position and gyroscope offsets, measured with the sensor level and
+/// still
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// Angles read at rest, hundredths of a degree
+    pub pitch: i16,
+    pub roll: i16,
+    /// Rotation rates read at rest, hundredths of a degree per second
+    pub gyro: [i16; 3],
+}
+
+impl Calibration {
+    pub const fn new() -> Self {
+        Self { pitch: 0, roll: 0, gyro: [0; 3] }
+    }
+}
+
+/// Settings of the motion source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub sensor: Sensor,
+    /// Tilt in degrees either way that covers the full value range
+    pub range: u8,
+    pub calibration: Calibration,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { sensor: Sensor::None, range: 45, calibration: Calibration::new() }
+    }
+}
+
+/// One sample of the sensor
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Reading {
+    /// Orientation, hundredths of a degree
+    pub roll: i32,
+    pub pitch: i32,
+    pub yaw: i32,
+    /// Acceleration, thousandths of g
+    pub accel: [i32; 3],
+    /// Rotation rates before the calibration is applied, hundredths of a
+    /// degree per second
+    pub gyro: [i32; 3],
+}
+
+/// Reported state of the motion controls
+pub struct Motion {
+    reported: [Option<u16>; 3],
+    /// Time of the last strong movement while a shake is held
+    shaking: Option<u32>,
+}
+
+impl Motion {
+    pub const fn new() -> Self {
+        Self { reported: [None; 3], shaking: None }
+    }
+
+    /// Report everything again, e.g. after the sensor restarted.
+    pub fn reset(&mut self) {
+        self.reported = [None; 3];
+    }
+
+    /// Turn a sample into events for the axes that moved and the shake.
+    pub fn update(&mut self, now: u32, reading: &Reading, options: &Options, emit: &mut dyn FnMut(InputEvent)) {
+        let range = options.range.clamp(1, MAX_RANGE) as i32 * 100;
+        let tilt = |angle: i32, rest: i16| {
+            let angle = (angle - rest as i32).clamp(-range, range);
+            ((angle + range) * FULL_SCALE as i32 / (2 * range)) as u16
+        };
+        let heading = (reading.yaw + 18000).rem_euclid(36000) * FULL_SCALE as i32 / 35999;
+        let values = [
+            (axis::PITCH, tilt(reading.pitch, options.calibration.pitch)),
+            (axis::ROLL, tilt(reading.roll, options.calibration.roll)),
+            (axis::YAW, heading as u16),
+        ];
+        for (axis, value) in values {
+            let reported = &mut self.reported[axis as usize];
+            // Ends of the range are always reported, like a fader's
+            let moved = match *reported {
+                Some(last) => {
+                    value.abs_diff(last) > HYSTERESIS || ((value == 0 || value == FULL_SCALE) && value != last)
+                }
+                None => true,
+            };
+            if moved {
+                *reported = Some(value);
+                emit(InputEvent { control: input::ids::MOTION + axis, input: Input::Absolute(value) });
+            }
+        }
+
+        let [x, y, z] = reading.accel.map(|a| a as i64);
+        let excess = (x * x + y * y + z * z).isqrt() as i32 - 1000;
+        let control = input::ids::MOTION + axis::SHAKE;
+        match self.shaking {
+            None if excess > SHAKE_THRESHOLD => {
+                let velocity = (excess - SHAKE_THRESHOLD) * FULL_SCALE as i32 / (SHAKE_FULL - SHAKE_THRESHOLD);
+                self.shaking = Some(now);
+                emit(InputEvent { control, input: Input::Press(Some(velocity.clamp(1, FULL_SCALE as i32) as u16)) });
+            }
+            Some(_) if excess.abs() > SHAKE_THRESHOLD / 2 => self.shaking = Some(now),
+            Some(since) if now.wrapping_sub(since) >= SHAKE_QUIET_MS => {
+                self.shaking = None;
+                emit(InputEvent { control, input: Input::Release });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Averages samples taken at rest into a `Calibration`
+pub struct Calibrator {
+    sums: [i32; 5],
+    samples: u32,
+}
+
+impl Calibrator {
+    pub const fn new() -> Self {
+        Self { sums: [0; 5], samples: 0 }
+    }
+
+    /// Add a sample; the calibration once enough were taken.
+    pub fn push(&mut self, reading: &Reading) -> Option<Calibration> {
+        let values = [reading.pitch, reading.roll, reading.gyro[0], reading.gyro[1], reading.gyro[2]];
+        for (sum, value) in self.sums.iter_mut().zip(values) {
+            *sum += value;
+        }
+        self.samples += 1;
+        if self.samples < CALIBRATION_SAMPLES {
+            return None;
+        }
+        let [pitch, roll, gx, gy, gz] = self.sums.map(|sum| (sum / CALIBRATION_SAMPLES as i32) as i16);
+        *self = Self::new();
+        Some(Calibration { pitch, roll, gyro: [gx, gy, gz] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Level and still
+    const REST: Reading = Reading { roll: 0, pitch: 0, yaw: 0, accel: [0, 0, 1000], gyro: [0; 3] };
+
+    fn update(motion: &mut Motion, now: u32, reading: Reading, options: &Options) -> Vec<(u8, Input)> {
+        let mut events = Vec::new();
+        motion.update(now, &reading, options, &mut |event| {
+            events.push((event.control - input::ids::MOTION, event.input))
+        });
+        events
+    }
+
+    fn pitch(motion: &mut Motion, angle: i32, options: &Options) -> Option<u16> {
+        let events = update(motion, 0, Reading { pitch: angle, ..REST }, options);
+        events.into_iter().find_map(|event| match event {
+            (axis::PITCH, Input::Absolute(value)) => Some(value),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn tilts_within_the_range() {
+        let mut options = Options::new();
+        options.range = 30;
+        options.calibration.pitch = 1000;
+        let mut motion = Motion::new();
+        let center = FULL_SCALE / 2;
+        assert_eq!(
+            update(&mut motion, 0, Reading { pitch: 1000, roll: -3000, ..REST }, &options),
+            [
+                (axis::PITCH, Input::Absolute(center)),
+                (axis::ROLL, Input::Absolute(0)),
+                (axis::YAW, Input::Absolute(center)),
+            ]
+        );
+        assert_eq!(pitch(&mut motion, 4000, &options), Some(FULL_SCALE));
+        // Clamped past the range
+        assert_eq!(pitch(&mut motion, 9000, &options), None);
+        assert_eq!(pitch(&mut motion, -9000, &options), Some(0));
+    }
+
+    #[test]
+    fn wraps_the_heading() {
+        let options = Options::new();
+        let mut motion = Motion::new();
+        let yaw = |motion: &mut Motion, yaw| match update(motion, 0, Reading { yaw, ..REST }, &options).last() {
+            Some(&(axis::YAW, Input::Absolute(value))) => Some(value),
+            _ => None,
+        };
+        assert_eq!(yaw(&mut motion, -18000), Some(0));
+        assert_eq!(yaw(&mut motion, 17999), Some(FULL_SCALE));
+        // Half a turn further is the other end again
+        assert_eq!(yaw(&mut motion, 18000), Some(0));
+        assert_eq!(yaw(&mut motion, 18000 + 36000), None);
+        assert_eq!(yaw(&mut motion, 9000 - 36000), Some(FULL_SCALE * 3 / 4));
+    }
+
+    #[test]
+    fn reports_past_the_hysteresis_and_at_the_ends() {
+        let options = Options::new();
+        let mut motion = Motion::new();
+        // 45 degrees either way, a step is about 0.55 hundredths of a degree
+        assert_eq!(pitch(&mut motion, 0, &options), Some(FULL_SCALE / 2));
+        assert_eq!(pitch(&mut motion, 15, &options), None);
+        assert_eq!(pitch(&mut motion, 20, &options), Some(FULL_SCALE / 2 + 36));
+        assert_eq!(pitch(&mut motion, 4490, &options), Some(FULL_SCALE - 19));
+        assert_eq!(pitch(&mut motion, 4500, &options), Some(FULL_SCALE));
+        assert_eq!(pitch(&mut motion, -4490, &options), Some(18));
+        assert_eq!(pitch(&mut motion, -4500, &options), Some(0));
+
+        motion.reset();
+        assert_eq!(update(&mut motion, 0, REST, &options).len(), 3);
+    }
+
+    #[test]
+    fn shakes() {
+        let options = Options::new();
+        let mut motion = Motion::new();
+        let shake = |motion: &mut Motion, now, accel| {
+            let events = update(motion, now, Reading { accel, ..REST }, &options);
+            events.into_iter().filter(|&(axis, _)| axis == axis::SHAKE).map(|(_, input)| input).collect::<Vec<_>>()
+        };
+        assert!(shake(&mut motion, 0, [0, 0, 1000]).is_empty());
+        assert!(shake(&mut motion, 0, [0, 0, 2000]).is_empty());
+        assert_eq!(shake(&mut motion, 10, [0, 0, 1000 + 2000]), [Input::Press(Some(FULL_SCALE / 2))]);
+        // Still moving, then quiet
+        assert!(shake(&mut motion, 100, [0, 0, 1000 + 600]).is_empty());
+        assert!(shake(&mut motion, 100 + SHAKE_QUIET_MS - 1, [0, 0, 1000]).is_empty());
+        assert_eq!(shake(&mut motion, 100 + SHAKE_QUIET_MS, [0, 0, 1000]), [Input::Release]);
+
+        // As hard as it gets, and just past the threshold
+        assert_eq!(shake(&mut motion, 1000, [0, 0, 9000]), [Input::Press(Some(FULL_SCALE))]);
+        shake(&mut motion, 2000, [0, 0, 1000]);
+        assert_eq!(shake(&mut motion, 3000, [0, 0, 2001]), [Input::Press(Some(8))]);
+    }
+
+    #[test]
+    fn averages_a_calibration() {
+        let mut calibrator = Calibrator::new();
+        let reading = |i: u32| {
+            let wobble = if i.is_multiple_of(2) { -1 } else { 1 };
+            Reading { pitch: 100 + wobble, roll: -50, gyro: [10 + 10 * wobble, -7, 0], ..REST }
+        };
+        for i in 0..CALIBRATION_SAMPLES - 1 {
+            assert_eq!(calibrator.push(&reading(i)), None);
+        }
+        let calibration = calibrator.push(&reading(CALIBRATION_SAMPLES - 1));
+        assert_eq!(calibration, Some(Calibration { pitch: 100, roll: -50, gyro: [10, -7, 0] }));
+        // Starts over
+        assert_eq!(calibrator.push(&reading(0)), None);
+    }
+}
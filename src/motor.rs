@@ -0,0 +1,210 @@
+//! Motor Fader Driver
+//!
+//! Up to two motorised faders: the wiper of fader 0 on PE3 (AIN0) and of
+//! fader 1 on PE2 (AIN1), read by ADC0 sequencer 3; each motor on an
+//! H-bridge driven by a PWM0 generator at 20 kHz, fader 0 on PE4/PE5
+//! (M0PWM4/5), fader 1 on PC4/PC5 (M0PWM6/7), the first pin of a pair
+//! pushing the knob up. Touch is sensed on the conductive knob, wired to
+//! PA6 (fader 0) or PA7 (fader 1) with a 1 MΩ pull-up: the pin is
+//! discharged and the time until it reads high again grows with the
+//! capacitance of a finger.
+//!
+//! Fader positions are controls `input::ids::FADER` and up, touch is a
+//! press and release of controls `input::ids::FADER_TOUCH` and up. The
+//! control loop itself is in `fader`.
+
+use tm4c123x::{ADC0, GPIO_PORTA, GPIO_PORTC, GPIO_PORTE, PWM0, SYSCTL};
+
+use crate::fader::{Fader, TouchSense, FULL_DRIVE, MAX_FADERS};
+use crate::input::{self, Input, InputEvent, InputSource, FULL_SCALE};
+use crate::usb_device;
+
+/// Motor PWM, above hearing
+const PWM_HZ: u32 = 20_000;
+
+/// ADC inputs of the wipers
+const WIPERS: [u32; MAX_FADERS] = [0, 1];
+/// PE2 and PE3
+const WIPER_PINS: u32 = 0x0C;
+/// PE4 and PE5, PC4 and PC5
+const MOTOR_PINS: u32 = 0x30;
+/// PA6 and PA7
+const TOUCH_PINS: [u32; MAX_FADERS] = [0x40, 0x80];
+
+/// Longest touch measurement in loop passes, a shorted or open knob
+/// must not stall the main loop
+const TOUCH_LIMIT: u32 = 2000;
+
+pub struct Motors {
+    faders: [Fader; MAX_FADERS],
+    touch: [TouchSense; MAX_FADERS],
+    count: usize,
+    stepped_at: u32,
+    /// PWM period in system clocks
+    period: u32,
+}
+
+impl Motors {
+    /// Set up the ADC, the motor PWM and the touch pins; no faders until
+    /// `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let porta = unsafe { &*GPIO_PORTA::ptr() };
+        let portc = unsafe { &*GPIO_PORTC::ptr() };
+        let porte = unsafe { &*GPIO_PORTE::ptr() };
+        let adc = unsafe { &*ADC0::ptr() };
+        let pwm = unsafe { &*PWM0::ptr() };
+
+        // Ports A, C and E
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | 0x15) });
+        while sysctl.prgpio.read().bits() & 0x15 != 0x15 {}
+        sysctl.rcgcadc.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.pradc.read().bits() & 1 == 0 {}
+        sysctl.rcgcpwm.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.prpwm.read().bits() & 1 == 0 {}
+
+        // Wipers are analog inputs
+        porte.afsel.modify(|r, w| unsafe { w.bits(r.bits() | WIPER_PINS) });
+        porte.den.modify(|r, w| unsafe { w.bits(r.bits() & !WIPER_PINS) });
+        porte.amsel.modify(|r, w| unsafe { w.bits(r.bits() | WIPER_PINS) });
+
+        // PWM0 is alternate function 4 on PE4/PE5 and PC4/PC5
+        porte.afsel.modify(|r, w| unsafe { w.bits(r.bits() | MOTOR_PINS) });
+        porte.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0x00FF_0000) | 0x0044_0000) });
+        porte.den.modify(|r, w| unsafe { w.bits(r.bits() | MOTOR_PINS) });
+        portc.afsel.modify(|r, w| unsafe { w.bits(r.bits() | MOTOR_PINS) });
+        portc.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0x00FF_0000) | 0x0044_0000) });
+        portc.den.modify(|r, w| unsafe { w.bits(r.bits() | MOTOR_PINS) });
+
+        // Touch pins are plain GPIO, discharged between measurements
+        let touch_pins = TOUCH_PINS.iter().fold(0, |pins, pin| pins | pin);
+        porta.data.modify(|r, w| unsafe { w.bits(r.bits() & !touch_pins) });
+        porta.dir.modify(|r, w| unsafe { w.bits(r.bits() | touch_pins) });
+        porta.den.modify(|r, w| unsafe { w.bits(r.bits() | touch_pins) });
+
+        let period = unsafe { usb_device::SysCtlClockGet() } / PWM_HZ;
+        unsafe {
+            // Sequencer 3 takes one sample on request
+            adc.actss.modify(|r, w| w.bits(r.bits() & !(1 << 3)));
+            adc.emux.modify(|r, w| w.bits(r.bits() & !0xF000));
+            adc.ssctl3.write(|w| w.bits(0x6));
+            adc.actss.modify(|r, w| w.bits(r.bits() | (1 << 3)));
+
+            // Count down; outputs go high at load and low on their compare,
+            // both outputs of a generator share compare A
+            pwm._2_ctl.write(|w| w.bits(0));
+            pwm._3_ctl.write(|w| w.bits(0));
+            pwm._2_gena.write(|w| w.bits(0x8C));
+            pwm._2_genb.write(|w| w.bits(0x8C));
+            pwm._3_gena.write(|w| w.bits(0x8C));
+            pwm._3_genb.write(|w| w.bits(0x8C));
+            pwm._2_load.write(|w| w.bits(period - 1));
+            pwm._3_load.write(|w| w.bits(period - 1));
+            pwm.enable.modify(|r, w| w.bits(r.bits() & !0xF0));
+            pwm._2_ctl.write(|w| w.bits(1));
+            pwm._3_ctl.write(|w| w.bits(1));
+        }
+
+        Self {
+            faders: [const { Fader::new() }; MAX_FADERS],
+            touch: [const { TouchSense::new() }; MAX_FADERS],
+            count: 0,
+            stepped_at: 0,
+            period,
+        }
+    }
+
+    /// Follow the settings: faders fitted.
+    pub fn configure(&mut self, count: u8) {
+        let count = (count as usize).min(MAX_FADERS);
+        for index in count..self.count {
+            self.drive(index, 0);
+        }
+        for index in self.count..count {
+            self.faders[index].start(Self::sample(index));
+        }
+        self.count = count;
+    }
+
+    /// Move fader `index` to a value from the host.
+    pub fn move_to(&mut self, now: u32, index: usize, position: u16) {
+        if let Some(fader) = self.faders[..self.count].get_mut(index) {
+            fader.move_to(now, position);
+        }
+    }
+
+    /// Wiper position, `0..=FULL_SCALE`
+    fn sample(index: usize) -> u16 {
+        let adc = unsafe { &*ADC0::ptr() };
+        unsafe {
+            adc.ssmux3.write(|w| w.bits(WIPERS[index]));
+            adc.pssi.write(|w| w.bits(1 << 3));
+        }
+        while adc.ris.read().bits() & (1 << 3) == 0 {}
+        let value = adc.ssfifo3.read().bits() & 0xFFF;
+        unsafe { adc.isc.write(|w| w.bits(1 << 3)) };
+        (value * FULL_SCALE as u32 / 0xFFF) as u16
+    }
+
+    /// Charge time of a touch pin in loop passes
+    fn charge_time(index: usize) -> u32 {
+        let porta = unsafe { &*GPIO_PORTA::ptr() };
+        let pin = TOUCH_PINS[index];
+        // Interrupts would stretch the count like a finger does
+        let time = cortex_m::interrupt::free(|_| {
+            porta.dir.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+            let mut time = 0;
+            while porta.data.read().bits() & pin == 0 && time < TOUCH_LIMIT {
+                time += 1;
+            }
+            time
+        });
+        porta.dir.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+        time
+    }
+
+    /// Set motor `index` to `drive` per mille, positive up.
+    fn drive(&self, index: usize, drive: i16) {
+        let pwm = unsafe { &*PWM0::ptr() };
+        let duty = self.period * drive.unsigned_abs() as u32 / FULL_DRIVE as u32;
+        let compare = (self.period - 1).saturating_sub(duty.max(1));
+        unsafe {
+            match index {
+                0 => pwm._2_cmpa.write(|w| w.bits(compare)),
+                _ => pwm._3_cmpa.write(|w| w.bits(compare)),
+            }
+            // Only one side of the bridge runs, a stopped motor has neither
+            let up = 1 << (4 + 2 * index);
+            let enabled = match drive {
+                0 => 0,
+                d if d > 0 => up,
+                _ => up << 1,
+            };
+            pwm.enable.modify(|r, w| w.bits((r.bits() & !(up | up << 1)) | enabled));
+        }
+    }
+}
+
+impl InputSource for Motors {
+    /// One control step per millisecond.
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        if now == self.stepped_at {
+            return;
+        }
+        self.stepped_at = now;
+        for index in 0..self.count {
+            let sample = Self::sample(index);
+            let touched = self.touch[index].update(Self::charge_time(index));
+            let step = self.faders[index].step(now, sample, touched);
+            self.drive(index, step.drive);
+
+            if let Some(touched) = step.touch {
+                let input = if touched { Input::Press(None) } else { Input::Release };
+                emit(InputEvent { control: input::ids::FADER_TOUCH + index as u8, input });
+            }
+            if let Some(position) = step.position {
+                emit(InputEvent { control: input::ids::FADER + index as u8, input: Input::Absolute(position) });
+            }
+        }
+    }
+}
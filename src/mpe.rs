@@ -0,0 +1,616 @@
+//! MIDI Polyphonic Expression
+//!
+//! MPE zones as defined by the MPE specification (RP-053): the lower zone is
+//! managed on channel 1 with member channels counting up from 2, the upper
+//! zone on channel 16 with members counting down from 15. Zones are announced
+//! with the MIDI Configuration Message (RPN 6 on the manager channel).
+//!
+//! `Allocator` hands out member channels to notes; `Sender` uses it to play
+//! notes with per-note pitch bend, pressure and timbre (CC 74); `Receiver`
+//! interprets incoming traffic according to the zones configured, or those
+//! the host announced since. None of this touches hardware.
+
+use core::cmp::Reverse;
+
+use crate::hires::{self, Decoder, Param, ParamKind};
+use crate::midi::MidiMessage;
+
+/// RPN of the MIDI Configuration Message
+pub const MCM_RPN: u16 = 6;
+/// RPN of the pitch bend sensitivity
+pub const BEND_RANGE_RPN: u16 = 0;
+/// Member channel pitch bend range implied by the MCM
+pub const DEFAULT_BEND_RANGE: u8 = 48;
+/// Timbre controller
+pub const TIMBRE_CC: u8 = 74;
+
+const CHANNELS: usize = 16;
+/// Most member channels a zone can have
+pub const MAX_MEMBERS: u8 = 15;
+
+const BEND_CENTER: u16 = 8192;
+const TIMBRE_CENTER: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Lower,
+    Upper,
+}
+
+impl Zone {
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Lower => "lower",
+            Zone::Upper => "upper",
+        }
+    }
+
+    /// Manager channel, 0-based
+    pub fn manager(self) -> u8 {
+        match self {
+            Zone::Lower => 0,
+            Zone::Upper => 15,
+        }
+    }
+
+    /// The `index`th member channel, 0-based
+    fn member(self, index: u8) -> u8 {
+        match self {
+            Zone::Lower => 1 + index,
+            Zone::Upper => 14 - index,
+        }
+    }
+}
+
+/// What to do when a note arrives and every member channel is busy
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Steal {
+    /// Take the channel of the note that started first
+    Oldest = 0,
+    /// Take the channel of the lowest note
+    Lowest = 1,
+    /// Take the channel of the highest note
+    Highest = 2,
+    /// Drop the new note
+    Never = 3,
+}
+
+impl Steal {
+    pub const ALL: [Steal; 4] = [Steal::Oldest, Steal::Lowest, Steal::Highest, Steal::Never];
+
+    pub fn from_u8(value: u8) -> Option<Steal> {
+        Steal::ALL.get(value as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Steal::Oldest => "oldest",
+            Steal::Lowest => "lowest",
+            Steal::Highest => "highest",
+            Steal::Never => "never",
+        }
+    }
+}
+
+/// Zone configuration. Member counts of 0 disable a zone; both zones together
+/// use at most 14 member channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub lower: u8,
+    pub upper: u8,
+    /// Member channel pitch bend range in semitones
+    pub bend_range: u8,
+    pub steal: Steal,
+}
+
+impl Layout {
+    /// MPE off
+    pub const fn new() -> Self {
+        Self { lower: 0, upper: 0, bend_range: DEFAULT_BEND_RANGE, steal: Steal::Oldest }
+    }
+
+    /// Member count of a zone
+    pub fn members(&self, zone: Zone) -> u8 {
+        match zone {
+            Zone::Lower => self.lower,
+            Zone::Upper => self.upper,
+        }
+    }
+
+    /// Change one zone; like an MCM, the other zone shrinks to make room.
+    pub fn set_members(&mut self, zone: Zone, members: u8) {
+        let members = members.min(MAX_MEMBERS);
+        match zone {
+            Zone::Lower => {
+                self.lower = members;
+                self.upper = self.upper.min(14u8.saturating_sub(members));
+            }
+            Zone::Upper => {
+                self.upper = members;
+                self.lower = self.lower.min(14u8.saturating_sub(members));
+            }
+        }
+    }
+
+    /// Zone the controller plays into: the lower zone if it is enabled
+    pub fn play_zone(&self) -> Option<Zone> {
+        if self.lower > 0 {
+            Some(Zone::Lower)
+        } else if self.upper > 0 {
+            Some(Zone::Upper)
+        } else {
+            None
+        }
+    }
+
+    /// Zone a channel belongs to and whether it is the manager channel
+    pub fn zone_of(&self, channel: u8) -> Option<(Zone, bool)> {
+        for zone in [Zone::Lower, Zone::Upper] {
+            let members = self.members(zone);
+            if members == 0 {
+                continue;
+            }
+            if channel == zone.manager() {
+                return Some((zone, true));
+            }
+            if (0..members).any(|i| zone.member(i) == channel) {
+                return Some((zone, false));
+            }
+        }
+        None
+    }
+
+    /// MCMs for both zones plus the pitch bend range if it is not the default.
+    pub fn announce(&self, out: &mut dyn FnMut(MidiMessage)) {
+        let options = hires::Options::new();
+        for zone in [Zone::Lower, Zone::Upper] {
+            let members = self.members(zone);
+            hires::send_parameter(zone.manager(), ParamKind::Rpn, MCM_RPN, (members as u16) << 7, &options, out);
+            if members > 0 && self.bend_range != DEFAULT_BEND_RANGE {
+                let range = (self.bend_range as u16) << 7;
+                hires::send_parameter(zone.member(0), ParamKind::Rpn, BEND_RANGE_RPN, range, &options, out);
+            }
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Channel Allocation
+// ============================================================================
+
+#[derive(Clone, Copy)]
+struct Voice {
+    note: Option<u8>,
+    /// Allocation counter value at note on or release
+    stamp: u32,
+}
+
+/// Result of allocating a channel for a note
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Allocation {
+    /// Channel was free
+    Free(u8),
+    /// Channel was taken from `note`, which must be ended first
+    Stolen { channel: u8, note: u8 },
+    /// All channels busy and stealing is off
+    Full,
+}
+
+/// Assigns each sounding note its own member channel of a zone. Free
+/// channels are reused least recently released first so release tails are
+/// not cut off.
+pub struct Allocator {
+    zone: Zone,
+    members: u8,
+    voices: [Voice; MAX_MEMBERS as usize],
+    counter: u32,
+}
+
+impl Allocator {
+    pub const fn new(zone: Zone, members: u8) -> Self {
+        Self { zone, members, voices: [Voice { note: None, stamp: 0 }; MAX_MEMBERS as usize], counter: 0 }
+    }
+
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
+
+    fn voices(&self) -> &[Voice] {
+        &self.voices[..self.members as usize]
+    }
+
+    fn stamp(&mut self) -> u32 {
+        self.counter = self.counter.wrapping_add(1);
+        self.counter
+    }
+
+    /// Channel sounding `note`
+    pub fn channel_of(&self, note: u8) -> Option<u8> {
+        let index = self.voices().iter().position(|v| v.note == Some(note))?;
+        Some(self.zone.member(index as u8))
+    }
+
+    /// Number of sounding notes
+    pub fn active(&self) -> usize {
+        self.voices().iter().filter(|v| v.note.is_some()).count()
+    }
+
+    pub fn allocate(&mut self, note: u8, steal: Steal) -> Allocation {
+        if self.members == 0 {
+            return Allocation::Full;
+        }
+        let voices = self.voices();
+        // A retriggered note takes over its own channel
+        let same = voices.iter().position(|v| v.note == Some(note));
+        let free = voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.note.is_none())
+            .max_by_key(|&(i, v)| (self.counter.wrapping_sub(v.stamp), Reverse(i)))
+            .map(|(i, _)| i);
+        let busy = voices.iter().enumerate().filter_map(|(i, v)| Some((i, v.note?, v.stamp)));
+        let victim = match steal {
+            Steal::Oldest => busy.max_by_key(|&(i, _, stamp)| (self.counter.wrapping_sub(stamp), Reverse(i))),
+            Steal::Lowest => busy.min_by_key(|&(_, note, _)| note),
+            Steal::Highest => busy.max_by_key(|&(_, note, _)| note),
+            Steal::Never => None,
+        };
+        let (index, result) = match (same, free, victim) {
+            (Some(i), _, _) | (None, None, Some((i, _, _))) => {
+                let stolen = self.voices[i].note.unwrap_or(note);
+                (i, Allocation::Stolen { channel: self.zone.member(i as u8), note: stolen })
+            }
+            (None, Some(i), _) => (i, Allocation::Free(self.zone.member(i as u8))),
+            (None, None, None) => return Allocation::Full,
+        };
+        let stamp = self.stamp();
+        self.voices[index] = Voice { note: Some(note), stamp };
+        result
+    }
+
+    /// End a note, returns the channel it was on.
+    pub fn release(&mut self, note: u8) -> Option<u8> {
+        let index = self.voices().iter().position(|v| v.note == Some(note))?;
+        let stamp = self.stamp();
+        self.voices[index] = Voice { note: None, stamp };
+        Some(self.zone.member(index as u8))
+    }
+}
+
+// ============================================================================
+// Sending
+// ============================================================================
+
+/// Plays notes into the play zone of a layout
+pub struct Sender {
+    layout: Layout,
+    allocator: Option<Allocator>,
+}
+
+impl Sender {
+    pub const fn new() -> Self {
+        Self { layout: Layout::new(), allocator: None }
+    }
+
+    /// Follow a layout change; sounding notes are forgotten.
+    pub fn configure(&mut self, layout: &Layout) {
+        if self.layout != *layout {
+            self.layout = *layout;
+            self.allocator = layout.play_zone().map(|zone| Allocator::new(zone, layout.members(zone)));
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.allocator.is_some()
+    }
+
+    /// Start a note on its own channel. Per-note controllers are reset before
+    /// the note on so the note does not inherit the previous note's expression.
+    pub fn note_on(&mut self, note: u8, velocity: u8, out: &mut dyn FnMut(MidiMessage)) -> bool {
+        let Some(allocator) = self.allocator.as_mut() else {
+            return false;
+        };
+        let channel = match allocator.allocate(note, self.layout.steal) {
+            Allocation::Free(channel) => channel,
+            Allocation::Stolen { channel, note } => {
+                out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+                channel
+            }
+            Allocation::Full => return false,
+        };
+        out(MidiMessage::PitchBend { channel, value: BEND_CENTER });
+        out(MidiMessage::ControlChange { channel, control: TIMBRE_CC, value: TIMBRE_CENTER });
+        out(MidiMessage::ChannelPressure { channel, pressure: 0 });
+        out(MidiMessage::NoteOn { channel, note, velocity: velocity.max(1) });
+        true
+    }
+
+    pub fn note_off(&mut self, note: u8, velocity: u8, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some(channel) = self.allocator.as_mut().and_then(|a| a.release(note)) {
+            out(MidiMessage::NoteOff { channel, note, velocity });
+        }
+    }
+
+    fn channel_of(&self, note: u8) -> Option<u8> {
+        self.allocator.as_ref()?.channel_of(note)
+    }
+
+    /// Per-note pitch bend, 14-bit with 8192 as centre
+    pub fn bend(&self, note: u8, value: u16, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some(channel) = self.channel_of(note) {
+            out(MidiMessage::PitchBend { channel, value });
+        }
+    }
+
+    /// Per-note pressure
+    pub fn pressure(&self, note: u8, pressure: u8, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some(channel) = self.channel_of(note) {
+            out(MidiMessage::ChannelPressure { channel, pressure });
+        }
+    }
+
+    /// Per-note timbre (CC 74)
+    pub fn timbre(&self, note: u8, value: u8, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some(channel) = self.channel_of(note) {
+            out(MidiMessage::ControlChange { channel, control: TIMBRE_CC, value });
+        }
+    }
+
+    /// Sounding notes and the zone they play in
+    pub fn active(&self) -> Option<(Zone, usize)> {
+        self.allocator.as_ref().map(|a| (a.zone(), a.active()))
+    }
+}
+
+// ============================================================================
+// Receiving
+// ============================================================================
+
+/// Incoming MPE traffic, resolved to notes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MpeEvent {
+    /// Note on (`velocity > 0`) or off on a member channel
+    Note { zone: Zone, note: u8, velocity: u8 },
+    /// Pitch bend of the note on a member channel
+    Bend { zone: Zone, note: u8, value: u16 },
+    Pressure { zone: Zone, note: u8, value: u8 },
+    Timbre { zone: Zone, note: u8, value: u8 },
+    /// Message on a manager channel, applies to the whole zone
+    Zone { zone: Zone, message: MidiMessage },
+    /// An MCM or a member pitch bend range changed the layout
+    Layout(Layout),
+}
+
+/// Tracks the zone layout from received MCMs and maps member channel messages
+/// to the note playing on that channel
+pub struct Receiver {
+    layout: Layout,
+    /// Layout of the settings, followed until an MCM changes it
+    configured: Layout,
+    decoder: Decoder,
+    /// Last note started on each channel
+    notes: [Option<u8>; CHANNELS],
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self { layout: Layout::new(), configured: Layout::new(), decoder: Decoder::new(), notes: [None; CHANNELS] }
+    }
+
+    /// Follow a layout change in the settings, which replaces a received one.
+    pub fn configure(&mut self, layout: &Layout) {
+        if self.configured != *layout {
+            self.configured = *layout;
+            self.layout = *layout;
+            self.notes = [None; CHANNELS];
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Interpret a received message. Messages outside both zones give `None`.
+    pub fn feed(&mut self, message: &MidiMessage) -> Option<MpeEvent> {
+        let channel = message.channel()?;
+        if let Some(change) = self.decoder.feed(message, &hires::Options::new()) {
+            let mut layout = self.layout;
+            match (change.channel, change.param) {
+                (0, Param::Rpn(MCM_RPN)) => layout.set_members(Zone::Lower, (change.value >> 7) as u8),
+                (15, Param::Rpn(MCM_RPN)) => layout.set_members(Zone::Upper, (change.value >> 7) as u8),
+                (ch, Param::Rpn(BEND_RANGE_RPN)) if self.layout.zone_of(ch).is_some_and(|(_, m)| !m) => {
+                    layout.bend_range = (change.value >> 7) as u8;
+                }
+                _ => {}
+            }
+            if layout != self.layout {
+                self.layout = layout;
+                self.notes = [None; CHANNELS];
+                return Some(MpeEvent::Layout(layout));
+            }
+        }
+        let (zone, manager) = self.layout.zone_of(channel)?;
+        if manager {
+            return Some(MpeEvent::Zone { zone, message: *message });
+        }
+        let slot = &mut self.notes[channel as usize];
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                *slot = (velocity > 0).then_some(note);
+                Some(MpeEvent::Note { zone, note, velocity })
+            }
+            MidiMessage::NoteOff { note, .. } => {
+                if *slot == Some(note) {
+                    *slot = None;
+                }
+                Some(MpeEvent::Note { zone, note, velocity: 0 })
+            }
+            MidiMessage::PitchBend { value, .. } => Some(MpeEvent::Bend { zone, note: (*slot)?, value }),
+            MidiMessage::ChannelPressure { pressure, .. } => {
+                Some(MpeEvent::Pressure { zone, note: (*slot)?, value: pressure })
+            }
+            MidiMessage::ControlChange { control: TIMBRE_CC, value, .. } => {
+                Some(MpeEvent::Timbre { zone, note: (*slot)?, value })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_member_channels() {
+        let mut lower = Allocator::new(Zone::Lower, 3);
+        assert_eq!(lower.allocate(60, Steal::Oldest), Allocation::Free(1));
+        assert_eq!(lower.allocate(62, Steal::Oldest), Allocation::Free(2));
+        assert_eq!(lower.allocate(64, Steal::Oldest), Allocation::Free(3));
+        assert_eq!((lower.active(), lower.channel_of(62)), (3, Some(2)));
+
+        let mut upper = Allocator::new(Zone::Upper, 2);
+        assert_eq!(upper.allocate(60, Steal::Oldest), Allocation::Free(14));
+        assert_eq!(upper.allocate(61, Steal::Oldest), Allocation::Free(13));
+
+        let mut off = Allocator::new(Zone::Lower, 0);
+        assert_eq!(off.allocate(60, Steal::Oldest), Allocation::Full);
+    }
+
+    #[test]
+    fn steals_notes() {
+        let mut allocator = Allocator::new(Zone::Lower, 3);
+        for note in [60, 72, 48] {
+            allocator.allocate(note, Steal::Oldest);
+        }
+        assert_eq!(allocator.allocate(50, Steal::Never), Allocation::Full);
+        assert_eq!(allocator.allocate(50, Steal::Oldest), Allocation::Stolen { channel: 1, note: 60 });
+        assert_eq!(allocator.allocate(51, Steal::Highest), Allocation::Stolen { channel: 2, note: 72 });
+        assert_eq!(allocator.allocate(52, Steal::Lowest), Allocation::Stolen { channel: 3, note: 48 });
+        // Oldest is now the note stolen into channel 1
+        assert_eq!(allocator.allocate(53, Steal::Oldest), Allocation::Stolen { channel: 1, note: 50 });
+        assert_eq!(allocator.channel_of(50), None);
+        // A retriggered note keeps its channel even with stealing off
+        assert_eq!(allocator.allocate(51, Steal::Never), Allocation::Stolen { channel: 2, note: 51 });
+        assert_eq!(allocator.active(), 3);
+    }
+
+    #[test]
+    fn reuses_least_recently_released() {
+        let mut allocator = Allocator::new(Zone::Lower, 4);
+        for note in [60, 61, 62] {
+            allocator.allocate(note, Steal::Oldest);
+        }
+        assert_eq!(allocator.release(61), Some(2));
+        assert_eq!(allocator.release(60), Some(1));
+        assert_eq!(allocator.release(61), None);
+        // Channel 4 was never used, then 2 was released before 1
+        assert_eq!(allocator.allocate(70, Steal::Oldest), Allocation::Free(4));
+        assert_eq!(allocator.allocate(71, Steal::Oldest), Allocation::Free(2));
+        assert_eq!(allocator.allocate(72, Steal::Oldest), Allocation::Free(1));
+        assert_eq!(allocator.allocate(73, Steal::Never), Allocation::Full);
+    }
+
+    #[test]
+    fn lays_out_zones() {
+        let mut layout = Layout::new();
+        assert_eq!(layout.play_zone(), None);
+        layout.set_members(Zone::Lower, 5);
+        layout.set_members(Zone::Upper, 12);
+        assert_eq!((layout.lower, layout.upper), (2, 12));
+        layout.set_members(Zone::Lower, 20);
+        assert_eq!((layout.lower, layout.upper), (15, 0));
+        layout.set_members(Zone::Lower, 3);
+        layout.set_members(Zone::Upper, 2);
+        assert_eq!(layout.play_zone(), Some(Zone::Lower));
+        assert_eq!(layout.zone_of(0), Some((Zone::Lower, true)));
+        assert_eq!(layout.zone_of(3), Some((Zone::Lower, false)));
+        assert_eq!(layout.zone_of(4), None);
+        assert_eq!(layout.zone_of(13), Some((Zone::Upper, false)));
+        assert_eq!(layout.zone_of(15), Some((Zone::Upper, true)));
+    }
+
+    #[test]
+    fn sends_and_receives_notes() {
+        let mut layout = Layout::new();
+        layout.set_members(Zone::Lower, 2);
+        layout.bend_range = 24;
+        layout.steal = Steal::Oldest;
+        let mut sent = Vec::new();
+        layout.announce(&mut |m| sent.push(m));
+
+        let mut sender = Sender::new();
+        sender.configure(&layout);
+        let mut out = |m| sent.push(m);
+        for note in [60, 62, 64] {
+            assert!(sender.note_on(note, 100, &mut out));
+        }
+        sender.pressure(62, 50, &mut out);
+        sender.bend(64, 9000, &mut out);
+        sender.timbre(60, 10, &mut out);
+        sender.note_off(62, 0, &mut out);
+        assert_eq!(sender.active(), Some((Zone::Lower, 1)));
+
+        // The third note stole channel 1 from the first
+        let note_on = MidiMessage::NoteOn { channel: 1, note: 64, velocity: 100 };
+        let at = sent.iter().position(|m| *m == note_on).unwrap();
+        assert_eq!(sent[at - 4], MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 });
+
+        let mut receiver = Receiver::new();
+        let events: Vec<_> = sent.iter().filter_map(|m| receiver.feed(m)).collect();
+        let expected = Layout { lower: 2, upper: 0, bend_range: 24, steal: Steal::Oldest };
+        assert_eq!(*receiver.layout(), expected);
+        assert!(events.contains(&MpeEvent::Layout(expected)));
+        let lower = Zone::Lower;
+        for event in [
+            MpeEvent::Note { zone: lower, note: 62, velocity: 100 },
+            MpeEvent::Pressure { zone: lower, note: 62, value: 50 },
+            MpeEvent::Bend { zone: lower, note: 64, value: 9000 },
+            MpeEvent::Note { zone: lower, note: 62, velocity: 0 },
+        ] {
+            assert!(events.contains(&event), "{event:?} missing");
+        }
+        // Timbre for a note no longer sounding is not sent
+        assert!(!events.iter().any(|e| matches!(e, MpeEvent::Timbre { .. })));
+
+        let manager = MidiMessage::ControlChange { channel: 0, control: 64, value: 127 };
+        assert_eq!(receiver.feed(&manager), Some(MpeEvent::Zone { zone: lower, message: manager }));
+        assert_eq!(receiver.feed(&MidiMessage::PitchBend { channel: 9, value: 0 }), None);
+    }
+
+    #[test]
+    fn receives_in_the_configured_zones() {
+        let mut layout = Layout::new();
+        layout.set_members(Zone::Upper, 3);
+        let mut receiver = Receiver::new();
+        receiver.configure(&layout);
+        let on = |channel| MidiMessage::NoteOn { channel, note: 60, velocity: 90 };
+        let upper = Zone::Upper;
+        assert_eq!(receiver.feed(&on(13)), Some(MpeEvent::Note { zone: upper, note: 60, velocity: 90 }));
+        assert_eq!(receiver.feed(&on(11)), None);
+        let bend = MidiMessage::PitchBend { channel: 13, value: 100 };
+        assert_eq!(receiver.feed(&bend), Some(MpeEvent::Bend { zone: upper, note: 60, value: 100 }));
+
+        // An MCM from the host moves the zone until the settings change
+        let mut mcm = Vec::new();
+        let options = hires::Options::new();
+        hires::send_parameter(15, ParamKind::Rpn, MCM_RPN, 5 << 7, &options, &mut |m| mcm.push(m));
+        let events: Vec<_> = mcm.iter().filter_map(|m| receiver.feed(m)).collect();
+        assert!(events.contains(&MpeEvent::Layout(Layout { upper: 5, ..layout })));
+        assert!(receiver.feed(&on(11)).is_some());
+        assert_eq!(receiver.feed(&bend), None);
+        receiver.configure(&layout);
+        assert_eq!(receiver.layout().upper, 5);
+        layout.set_members(Zone::Lower, 1);
+        receiver.configure(&layout);
+        assert_eq!(*receiver.layout(), layout);
+        assert_eq!(receiver.feed(&on(11)), None);
+    }
+}
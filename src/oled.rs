@@ -0,0 +1,219 @@
+//! OLED Driver
+//!
+//! SSD1306 or SH1106 128x64 displays on I2C0 (PB2 SCL, PB3 SDA, address
+//! 0x3C). Both SPI ports the display could use are taken by the LED strip
+//! and the shift registers. Transfers never wait: every `poll` hands at most
+//! one byte to the I2C master and returns, so MIDI keeps flowing while a
+//! page goes out. A page is copied when its transfer starts, the canvas can
+//! be redrawn at any time.
+
+use tm4c123x::{GPIO_PORTB, I2C0, SYSCTL};
+
+use crate::clock;
+use crate::display::{Canvas, Controller, WIDTH};
+use crate::usb_device;
+
+const ADDRESS: u32 = 0x3C;
+const I2C_HZ: u32 = 400_000;
+
+/// PB2 SCL, PB3 SDA
+const PINS: u32 = 0x0C;
+const SDA: u32 = 0x08;
+
+/// Pause before talking to a display that did not answer
+const RETRY_MS: u32 = 1000;
+
+/// MCS bits, written
+const RUN: u32 = 0x01;
+const START: u32 = 0x02;
+const STOP: u32 = 0x04;
+/// MCS bits, read
+const BUSY: u32 = 0x01;
+const ERROR: u32 = 0x02;
+
+/// Control bytes: commands or display data follow
+const COMMANDS: u8 = 0x00;
+const DATA: u8 = 0x40;
+
+const SSD1306_INIT: &[u8] = &[
+    0xAE, // off
+    0xD5, 0x80, // clock
+    0xA8, 0x3F, // 64 rows
+    0xD3, 0x00, // no offset
+    0x40, // start line 0
+    0x8D, 0x14, // charge pump on
+    0x20, 0x02, // page addressing
+    0xA1, 0xC8, // flip to match the module
+    0xDA, 0x12, // COM pins
+    0x81, 0xCF, // contrast
+    0xD9, 0xF1, // precharge
+    0xDB, 0x40, // VCOMH
+    0xA4, 0xA6, // show RAM, not inverted
+    0xAF, // on
+];
+
+const SH1106_INIT: &[u8] = &[
+    0xAE, // off
+    0xD5, 0x80, // clock
+    0xA8, 0x3F, // 64 rows
+    0xD3, 0x00, // no offset
+    0x40, // start line 0
+    0xAD, 0x8B, // DC-DC on
+    0xA1, 0xC8, // flip to match the module
+    0xDA, 0x12, // COM pins
+    0x81, 0x80, // contrast
+    0xD9, 0x22, // precharge
+    0xDB, 0x35, // VCOMH
+    0xA4, 0xA6, // show RAM, not inverted
+    0xAF, // on
+];
+
+/// Transfer in progress
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Idle,
+    Init,
+    /// Select the page
+    Address(u8),
+    /// Page contents from `buf`
+    Data,
+}
+
+pub struct Oled {
+    controller: Controller,
+    step: Step,
+    /// Bytes of the current transfer already sent
+    pos: usize,
+    /// Page being sent
+    buf: [u8; WIDTH],
+    /// No transfers before this time after an error
+    retry_at: Option<u32>,
+}
+
+impl Oled {
+    /// Set up I2C0 as a 400 kHz master; no display until `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let portb = unsafe { &*GPIO_PORTB::ptr() };
+        let i2c = unsafe { &*I2C0::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        while sysctl.prgpio.read().bits() & (1 << 1) == 0 {}
+        sysctl.rcgci2c.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.pri2c.read().bits() & 1 == 0 {}
+
+        // I2C0 is alternate function 3 on PB2 and PB3, SDA open drain
+        portb.afsel.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+        portb.odr.modify(|r, w| unsafe { w.bits(r.bits() | SDA) });
+        portb.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0xFF00) | 0x3300) });
+        portb.den.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+
+        let clock = unsafe { usb_device::SysCtlClockGet() };
+        unsafe {
+            i2c.mcr.write(|w| w.bits(0x10));
+            i2c.mtpr.write(|w| w.bits(clock.div_ceil(20 * I2C_HZ) - 1));
+            i2c.msa.write(|w| w.bits(ADDRESS << 1));
+        }
+
+        Self { controller: Controller::None, step: Step::Idle, pos: 0, buf: [0; WIDTH], retry_at: None }
+    }
+
+    /// Follow the settings; a new controller is initialised and gets the
+    /// whole canvas.
+    pub fn configure(&mut self, controller: Controller, canvas: &mut Canvas) {
+        if controller == self.controller {
+            return;
+        }
+        self.controller = controller;
+        self.restart(canvas);
+    }
+
+    /// Move the transfer on by at most one byte.
+    pub fn poll(&mut self, now: u32, canvas: &mut Canvas) {
+        if self.controller == Controller::None {
+            return;
+        }
+        if let Some(retry_at) = self.retry_at {
+            if !clock::reached(now, retry_at) {
+                return;
+            }
+            self.retry_at = None;
+        }
+        let i2c = unsafe { &*I2C0::ptr() };
+        let status = i2c.mcs.read().bits();
+        if status & BUSY != 0 {
+            return;
+        }
+        if status & ERROR != 0 && self.pos > 0 {
+            unsafe { i2c.mcs.write(|w| w.bits(STOP)) };
+            self.restart(canvas);
+            self.retry_at = Some(now.wrapping_add(RETRY_MS));
+            return;
+        }
+        if self.step == Step::Idle {
+            let Some(page) = canvas.changed() else {
+                return;
+            };
+            self.buf = *canvas.page(page);
+            canvas.mark_sent(page);
+            self.step = Step::Address(page as u8);
+        }
+
+        let (byte, len) = self.byte(self.pos);
+        let mut command = RUN;
+        if self.pos == 0 {
+            command |= START;
+        }
+        if self.pos + 1 == len {
+            command |= STOP;
+        }
+        unsafe {
+            i2c.mdr.write(|w| w.bits(byte as u32));
+            i2c.mcs.write(|w| w.bits(command));
+        }
+        self.pos += 1;
+        if self.pos == len {
+            self.pos = 0;
+            self.step = match self.step {
+                Step::Address(_) => Step::Data,
+                _ => Step::Idle,
+            };
+        }
+    }
+
+    /// Byte `pos` of the current transfer and the transfer length
+    fn byte(&self, pos: usize) -> (u8, usize) {
+        let init = match self.controller {
+            Controller::Sh1106 => SH1106_INIT,
+            _ => SSD1306_INIT,
+        };
+        let column = match self.controller {
+            Controller::Sh1106 => 2,
+            _ => 0,
+        };
+        let (first, len) = match self.step {
+            Step::Init => (COMMANDS, init.len() + 1),
+            Step::Address(_) => (COMMANDS, 4),
+            Step::Data | Step::Idle => (DATA, WIDTH + 1),
+        };
+        let byte = match (self.step, pos) {
+            (_, 0) => first,
+            (Step::Init, pos) => init[pos - 1],
+            (Step::Address(page), 1) => 0xB0 | page,
+            (Step::Address(_), 2) => column & 0x0F,
+            (Step::Address(_), _) => 0x10 | (column >> 4),
+            (_, pos) => self.buf[pos - 1],
+        };
+        (byte, len)
+    }
+
+    /// Start over with the init sequence and a full frame.
+    fn restart(&mut self, canvas: &mut Canvas) {
+        self.step = match self.controller {
+            Controller::None => Step::Idle,
+            _ => Step::Init,
+        };
+        self.pos = 0;
+        canvas.invalidate();
+    }
+}
@@ -0,0 +1,48 @@
+//! Colour Palettes
+//!
+//! Colours for RGB pads set from MIDI. Like on a Launchpad, the velocity of
+//! a note picks the colour: the low four bits select one of 16 clip colours
+//! (close to the ones DAWs such as Live and Bitwig use), the high three bits
+//! the brightness, from dim (0-15) to full (112-127). Velocity 0 is off.
+
+use crate::led::Color;
+
+const fn rgb(hex: u32) -> Color {
+    Color { r: (hex >> 16) as u8, g: (hex >> 8) as u8, b: hex as u8 }
+}
+
+/// Clip colours, indexed by the low four bits of a velocity
+pub const CLIP: [Color; 16] = [
+    rgb(0xFF3636), // red
+    rgb(0xF66C03), // orange
+    rgb(0xFFA529), // amber
+    rgb(0xF7F47C), // yellow
+    rgb(0xBFFB00), // lime
+    rgb(0x1AFF2F), // green
+    rgb(0x25FFA8), // mint
+    rgb(0x5CFFE8), // cyan
+    rgb(0x8BC5FF), // sky
+    rgb(0x5480E4), // blue
+    rgb(0x92A7FF), // periwinkle
+    rgb(0xD86CE4), // violet
+    rgb(0xE553A0), // magenta
+    rgb(0xFF94A6), // pink
+    rgb(0xFFE0B0), // warm white
+    rgb(0xFFFFFF), // white
+];
+
+/// Colour for a note velocity or controller value
+pub fn velocity(value: u8) -> Color {
+    if value == 0 {
+        return Color::OFF;
+    }
+    let value = value.min(127);
+    let level = ((value >> 4) as u16 + 1) * 32 - 1;
+    scale(CLIP[(value & 0x0F) as usize], level as u8)
+}
+
+/// Colour dimmed to `level` / 255
+pub fn scale(color: Color, level: u8) -> Color {
+    let dim = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    Color { r: dim(color.r), g: dim(color.g), b: dim(color.b) }
+}
@@ -0,0 +1,383 @@
+//! Foot Controllers
+//!
+//! Hardware independent part of the pedal jacks. An expression jack takes
+//! an expression pedal (a pot between ring and sleeve, wiper on the tip)
+//! or a footswitch; in `Mode::Auto` it tells them apart by how the tip
+//! moves: a pedal passes through the middle of the range and stays there,
+//! a switch jumps from one end to the other between two samples. Pedals
+//! rarely use the whole track of their pot, so the range is learnt from the
+//! readings: heel and toe are the lowest and highest seen since the pedal
+//! was plugged in.
+//!
+//! Switches come normally open or normally closed. Nobody stands on a
+//! switch while the controller starts, so the level a switch has when it
+//! is first read is its released level.
+//!
+//! Expression jacks are controls `input::ids::PEDAL` and up, absolute for
+//! a pedal and pressed and released for a switch; sustain jacks are
+//! controls `input::ids::SUSTAIN` and up. Reading the jacks is in `pedals`.
+
+use core::fmt;
+
+use crate::input::{self, Input, InputEvent, FULL_SCALE};
+
+/// Expression jacks
+pub const JACKS: usize = 2;
+/// Sustain jacks
+pub const MAX_SUSTAIN: usize = 2;
+
+/// Highest reading, 12-bit ADC
+pub const FULL_LEVEL: u16 = 4095;
+
+/// Readings this close to either end are a switch position
+const RAIL: u16 = FULL_LEVEL / 16;
+/// Time in the middle of the range that makes a jack a pedal
+const PEDAL_MS: u32 = 20;
+/// Learnt range a pedal needs before it is reported
+const MIN_SPAN: u16 = FULL_LEVEL / 8;
+/// Change of a pedal before it is reported again
+const HYSTERESIS: u16 = 32;
+/// Time a switch must be stable before a change is reported
+const DEBOUNCE_MS: u32 = 10;
+
+/// What an expression jack is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    Off = 0,
+    /// Pedal or switch, whatever is plugged in
+    Auto = 1,
+    Expression = 2,
+    Switch = 3,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 4] = [Mode::Off, Mode::Auto, Mode::Expression, Mode::Switch];
+
+    pub fn from_u8(value: u8) -> Option<Mode> {
+        Mode::ALL.get(value as usize).copied()
+    }
+}
+
+/// What is plugged into an expression jack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Expression,
+    Switch,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Expression => "expression pedal",
+            Kind::Switch => "switch",
+        })
+    }
+}
+
+/// A footswitch with its polarity taken from the first reading
+pub struct Switch {
+    /// Level when released, once known
+    rest: Option<bool>,
+    pressed: bool,
+    last: bool,
+    changed_at: u32,
+}
+
+impl Switch {
+    pub const fn new() -> Self {
+        Self { rest: None, pressed: false, last: false, changed_at: 0 }
+    }
+
+    /// A switch resting at `level`, e.g. one found by the jump away from it.
+    const fn resting(level: bool, now: u32) -> Self {
+        Self { rest: Some(level), pressed: false, last: level, changed_at: now }
+    }
+
+    /// Whether normally closed, once known
+    pub fn normally_closed(&self) -> Option<bool> {
+        // Closed pulls the input low
+        self.rest.map(|level| !level)
+    }
+
+    /// Take the input level, return the new state when it changed.
+    pub fn update(&mut self, now: u32, level: bool) -> Option<bool> {
+        let rest = *self.rest.get_or_insert(level);
+        if level != self.last {
+            self.last = level;
+            self.changed_at = now;
+            return None;
+        }
+        let pressed = level != rest;
+        if pressed == self.pressed || now.wrapping_sub(self.changed_at) < DEBOUNCE_MS {
+            return None;
+        }
+        self.pressed = pressed;
+        Some(pressed)
+    }
+}
+
+/// An expression jack
+pub struct Jack {
+    kind: Option<Kind>,
+    /// Smoothed reading, times 4
+    level: u32,
+    last: Option<u16>,
+    /// Since when the reading is in the middle of the range
+    middle_since: Option<u32>,
+    /// Heel and toe as learnt
+    heel: u16,
+    toe: u16,
+    reported: Option<u16>,
+    switch: Switch,
+}
+
+impl Jack {
+    pub const fn new() -> Self {
+        Self {
+            kind: None,
+            level: 0,
+            last: None,
+            middle_since: None,
+            heel: 0,
+            toe: 0,
+            reported: None,
+            switch: Switch::new(),
+        }
+    }
+
+    /// What is plugged in, once known
+    pub fn kind(&self) -> Option<Kind> {
+        self.kind
+    }
+
+    /// Learnt heel and toe readings of a pedal
+    pub fn range(&self) -> (u16, u16) {
+        (self.heel, self.toe)
+    }
+
+    /// Take a reading, `0..=FULL_LEVEL`, and report control `control`.
+    pub fn update(&mut self, now: u32, reading: u16, mode: Mode, control: u8, emit: &mut dyn FnMut(InputEvent)) {
+        let last = self.last.replace(reading);
+        let kind = match mode {
+            Mode::Off => return,
+            Mode::Expression => Some(Kind::Expression),
+            Mode::Switch => Some(Kind::Switch),
+            Mode::Auto => self.detect(now, last, reading),
+        };
+        if kind != self.kind {
+            // A switch found by its jump rests where it came from
+            let rest = last.filter(|_| mode == Mode::Auto).map(|last| last > FULL_LEVEL / 2);
+            self.change(now, kind, reading, rest, control, emit);
+        }
+        match self.kind {
+            Some(Kind::Expression) => self.expression(reading, control, emit),
+            Some(Kind::Switch) => {
+                if let Some(pressed) = self.switch.update(now, reading > FULL_LEVEL / 2) {
+                    let input = if pressed { Input::Press(None) } else { Input::Release };
+                    emit(InputEvent { control, input });
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Pedal or switch by the way the reading moves
+    fn detect(&mut self, now: u32, last: Option<u16>, reading: u16) -> Option<Kind> {
+        let rail = |value: u16| match value {
+            v if v < RAIL => Some(false),
+            v if v > FULL_LEVEL - RAIL => Some(true),
+            _ => None,
+        };
+        match (last.and_then(rail), rail(reading)) {
+            (Some(from), Some(to)) if from != to => return Some(Kind::Switch),
+            (_, Some(_)) => self.middle_since = None,
+            (_, None) => {
+                let since = *self.middle_since.get_or_insert(now);
+                if now.wrapping_sub(since) >= PEDAL_MS {
+                    return Some(Kind::Expression);
+                }
+            }
+        }
+        self.kind
+    }
+
+    /// Something else is plugged in: a switch still held is released, a
+    /// pedal learns its range anew.
+    fn change(
+        &mut self,
+        now: u32,
+        kind: Option<Kind>,
+        reading: u16,
+        rest: Option<bool>,
+        control: u8,
+        emit: &mut dyn FnMut(InputEvent),
+    ) {
+        if self.kind == Some(Kind::Switch) && self.switch.pressed {
+            emit(InputEvent { control, input: Input::Release });
+        }
+        self.kind = kind;
+        self.reported = None;
+        self.level = reading as u32 * 4;
+        (self.heel, self.toe) = (reading, reading);
+        self.switch = match rest {
+            Some(level) => Switch::resting(level, now),
+            None => Switch::new(),
+        };
+        self.middle_since = None;
+    }
+
+    fn expression(&mut self, reading: u16, control: u8, emit: &mut dyn FnMut(InputEvent)) {
+        self.level = (self.level * 3 + reading as u32 * 4) / 4;
+        let level = (self.level / 4) as u16;
+        self.heel = self.heel.min(level);
+        self.toe = self.toe.max(level);
+        let span = self.toe - self.heel;
+        if span < MIN_SPAN {
+            return;
+        }
+        // A little of either end is dead, so heel and toe are always reached
+        let margin = span / 32;
+        let value = (level.saturating_sub(self.heel + margin) as u32 * FULL_SCALE as u32
+            / (span - 2 * margin) as u32)
+            .min(FULL_SCALE as u32) as u16;
+        let moved = match self.reported {
+            None => true,
+            Some(last) => value.abs_diff(last) > HYSTERESIS || ((value == 0 || value == FULL_SCALE) && value != last),
+        };
+        if moved {
+            self.reported = Some(value);
+            emit(InputEvent { control, input: Input::Absolute(value) });
+        }
+    }
+}
+
+/// Sustain jacks, switches only
+pub struct SustainJacks {
+    switches: [Switch; MAX_SUSTAIN],
+}
+
+impl SustainJacks {
+    pub const fn new() -> Self {
+        Self { switches: [const { Switch::new() }; MAX_SUSTAIN] }
+    }
+
+    /// The switches; polarity is known after the first update.
+    pub fn switches(&self) -> &[Switch] {
+        &self.switches
+    }
+
+    /// Take the input levels, high while open for a normally open switch.
+    pub fn update(&mut self, now: u32, levels: &[bool], emit: &mut dyn FnMut(InputEvent)) {
+        for (index, (switch, &level)) in self.switches.iter_mut().zip(levels).enumerate() {
+            if let Some(pressed) = switch.update(now, level) {
+                let input = if pressed { Input::Press(None) } else { Input::Release };
+                emit(InputEvent { control: input::ids::SUSTAIN + index as u8, input });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `reading` once per ms from `from` up to `to`, return the inputs
+    fn feed(jack: &mut Jack, mode: Mode, (from, to): (u32, u32), reading: u16) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        for now in from..to {
+            jack.update(now, reading, mode, 3, &mut |event| {
+                assert_eq!(event.control, 3);
+                inputs.push(event.input);
+            });
+        }
+        inputs
+    }
+
+    fn switch(switch: &mut Switch, (from, to): (u32, u32), level: bool) -> Vec<(u32, bool)> {
+        (from..to).filter_map(|now| switch.update(now, level).map(|pressed| (now, pressed))).collect()
+    }
+
+    #[test]
+    fn takes_switch_polarity_from_the_first_reading() {
+        let mut open = Switch::new();
+        assert!(switch(&mut open, (0, 50), true).is_empty());
+        assert_eq!(open.normally_closed(), Some(false));
+        assert_eq!(switch(&mut open, (50, 100), false), [(60, true)]);
+
+        let mut closed = Switch::new();
+        assert!(switch(&mut closed, (0, 50), false).is_empty());
+        assert_eq!(closed.normally_closed(), Some(true));
+        assert_eq!(switch(&mut closed, (50, 100), true), [(60, true)]);
+        assert_eq!(switch(&mut closed, (100, 150), false), [(110, false)]);
+    }
+
+    #[test]
+    fn debounces_switches() {
+        let mut open = Switch::new();
+        switch(&mut open, (0, 10), true);
+        // Bounces restart the wait
+        assert!(switch(&mut open, (10, 15), false).is_empty());
+        assert!(switch(&mut open, (15, 17), true).is_empty());
+        assert_eq!(switch(&mut open, (17, 40), false), [(27, true)]);
+    }
+
+    #[test]
+    fn detects_a_switch_by_its_jump() {
+        let mut jack = Jack::new();
+        assert!(feed(&mut jack, Mode::Auto, (0, 5), FULL_LEVEL).is_empty());
+        assert_eq!(jack.kind(), None);
+        // From one rail to the other between two readings, resting where
+        // it came from
+        assert_eq!(feed(&mut jack, Mode::Auto, (5, 30), 0), [Input::Press(None)]);
+        assert_eq!(jack.kind(), Some(Kind::Switch));
+        assert_eq!(jack.switch.normally_closed(), Some(false));
+        assert_eq!(feed(&mut jack, Mode::Auto, (30, 60), FULL_LEVEL), [Input::Release]);
+    }
+
+    #[test]
+    fn detects_a_pedal_in_the_middle() {
+        let mut jack = Jack::new();
+        // Passing through the middle is not enough
+        feed(&mut jack, Mode::Auto, (0, 10), 2000);
+        feed(&mut jack, Mode::Auto, (10, 20), 100);
+        feed(&mut jack, Mode::Auto, (20, 20 + PEDAL_MS), 2000);
+        assert_eq!(jack.kind(), None);
+        feed(&mut jack, Mode::Auto, (20 + PEDAL_MS, 21 + PEDAL_MS), 2000);
+        assert_eq!(jack.kind(), Some(Kind::Expression));
+    }
+
+    #[test]
+    fn releases_a_held_switch_when_a_pedal_shows_up() {
+        let mut jack = Jack::new();
+        feed(&mut jack, Mode::Auto, (0, 5), FULL_LEVEL);
+        assert_eq!(feed(&mut jack, Mode::Auto, (5, 30), 0), [Input::Press(None)]);
+        let inputs = feed(&mut jack, Mode::Auto, (30, 30 + PEDAL_MS + 1), 2000);
+        assert_eq!(inputs, [Input::Release]);
+        assert_eq!(jack.kind(), Some(Kind::Expression));
+    }
+
+    #[test]
+    fn learns_heel_and_toe() {
+        let mut jack = Jack::new();
+        let mode = Mode::Expression;
+        // Nothing until the pedal has moved across enough of its range
+        assert!(feed(&mut jack, mode, (0, 10), 1000).is_empty());
+        assert!(feed(&mut jack, mode, (10, 11), 1000 + MIN_SPAN).is_empty());
+        assert_eq!(feed(&mut jack, mode, (11, 100), 3000).last(), Some(&Input::Absolute(FULL_SCALE)));
+        // The smoothed reading settles a count short
+        assert_eq!(jack.range(), (1000, 2999));
+
+        let Some(&Input::Absolute(middle)) = feed(&mut jack, mode, (100, 200), 2000).last() else {
+            panic!("no value");
+        };
+        assert!(middle.abs_diff(FULL_SCALE / 2) < 100, "{middle}");
+        // Small changes are noise
+        assert!(feed(&mut jack, mode, (200, 300), 2003).is_empty());
+        assert!(!feed(&mut jack, mode, (300, 400), 2010).is_empty());
+
+        // Either end is reached before the pot's
+        assert_eq!(feed(&mut jack, mode, (400, 500), 2990).last(), Some(&Input::Absolute(FULL_SCALE)));
+        assert_eq!(feed(&mut jack, mode, (500, 600), 1010).last(), Some(&Input::Absolute(0)));
+    }
+}
@@ -0,0 +1,133 @@
+//! Pedal Jack Driver
+//!
+//! Two TRS expression jacks, tip on PE2 (AIN1, jack 1) and PE3 (AIN0,
+//! jack 2) with a 100 kΩ pull-up to 3.3 V, ring to 3.3 V through 470 Ω,
+//! sleeve to ground. They share the wiper inputs of the motorised faders:
+//! jack 1 with fader 1, jack 2 with fader 0. ADC0 sequencer 2 reads them on
+//! request, once per millisecond.
+//!
+//! Two sustain jacks on PA2 and PA3, tip to the pin with the internal
+//! pull-up, sleeve to ground; normally open and normally closed switches
+//! both work.
+//!
+//! Pedal and switch detection, range learning and polarity are in `pedal`.
+
+use tm4c123x::{ADC0, GPIO_PORTA, GPIO_PORTE, SYSCTL};
+
+use crate::input::{self, InputEvent, InputSource};
+use crate::pedal::{Jack, Mode, SustainJacks, JACKS, MAX_SUSTAIN};
+
+/// ADC inputs of the expression jacks
+const TIPS: [u32; JACKS] = [1, 0];
+/// PE2 and PE3
+const TIP_PINS: u32 = 0x0C;
+/// PA2 and PA3
+const SUSTAIN_PINS: [u32; MAX_SUSTAIN] = [0x04, 0x08];
+
+/// Sequencer 2, one step used
+const SEQUENCER: u32 = 2;
+
+pub struct Pedals {
+    jacks: [Jack; JACKS],
+    modes: [Mode; JACKS],
+    sustain: SustainJacks,
+    sustain_count: usize,
+    sampled_at: u32,
+}
+
+impl Pedals {
+    /// Set up the ADC sequencer and the pins; nothing is read until
+    /// `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let porta = unsafe { &*GPIO_PORTA::ptr() };
+        let porte = unsafe { &*GPIO_PORTE::ptr() };
+        let adc = unsafe { &*ADC0::ptr() };
+
+        // Ports A and E
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | 0x11) });
+        while sysctl.prgpio.read().bits() & 0x11 != 0x11 {}
+        sysctl.rcgcadc.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.pradc.read().bits() & 1 == 0 {}
+
+        // Tips are analog inputs, sustain pins inputs with pull-ups
+        porte.afsel.modify(|r, w| unsafe { w.bits(r.bits() | TIP_PINS) });
+        porte.den.modify(|r, w| unsafe { w.bits(r.bits() & !TIP_PINS) });
+        porte.amsel.modify(|r, w| unsafe { w.bits(r.bits() | TIP_PINS) });
+        let sustain_pins = SUSTAIN_PINS.iter().fold(0, |pins, pin| pins | pin);
+        porta.dir.modify(|r, w| unsafe { w.bits(r.bits() & !sustain_pins) });
+        porta.pur.modify(|r, w| unsafe { w.bits(r.bits() | sustain_pins) });
+        porta.den.modify(|r, w| unsafe { w.bits(r.bits() | sustain_pins) });
+
+        unsafe {
+            // Sequencer 2 takes one sample on request
+            adc.actss.modify(|r, w| w.bits(r.bits() & !(1 << SEQUENCER)));
+            adc.emux.modify(|r, w| w.bits(r.bits() & !0x0F00));
+            adc.ssctl2.write(|w| w.bits(0x6));
+            adc.actss.modify(|r, w| w.bits(r.bits() | (1 << SEQUENCER)));
+        }
+
+        Self {
+            jacks: [const { Jack::new() }; JACKS],
+            modes: [Mode::Off; JACKS],
+            sustain: SustainJacks::new(),
+            sustain_count: 0,
+            sampled_at: 0,
+        }
+    }
+
+    /// Follow the settings: use of the expression jacks and sustain jacks
+    /// fitted. A jack whose use changed starts over.
+    pub fn configure(&mut self, modes: &[Mode; JACKS], sustain: u8) {
+        for (jack, (mode, new)) in self.jacks.iter_mut().zip(self.modes.iter_mut().zip(modes)) {
+            if mode != new {
+                *jack = Jack::new();
+                *mode = *new;
+            }
+        }
+        self.sustain_count = (sustain as usize).min(MAX_SUSTAIN);
+    }
+
+    /// The expression jacks, with what is plugged in
+    pub fn jacks(&self) -> &[Jack] {
+        &self.jacks
+    }
+
+    /// Polarity of the sustain switches fitted, normally closed or not
+    pub fn polarity(&self) -> impl Iterator<Item = Option<bool>> + '_ {
+        self.sustain.switches()[..self.sustain_count].iter().map(|switch| switch.normally_closed())
+    }
+
+    /// Tip of expression jack `index`, `0..=FULL_LEVEL`
+    fn sample(index: usize) -> u16 {
+        let adc = unsafe { &*ADC0::ptr() };
+        unsafe {
+            adc.ssmux2.write(|w| w.bits(TIPS[index]));
+            adc.pssi.write(|w| w.bits(1 << SEQUENCER));
+        }
+        while adc.ris.read().bits() & (1 << SEQUENCER) == 0 {}
+        let value = adc.ssfifo2.read().bits() & 0xFFF;
+        unsafe { adc.isc.write(|w| w.bits(1 << SEQUENCER)) };
+        value as u16
+    }
+}
+
+impl InputSource for Pedals {
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        if now == self.sampled_at {
+            return;
+        }
+        self.sampled_at = now;
+        for (index, (jack, &mode)) in self.jacks.iter_mut().zip(&self.modes).enumerate() {
+            if mode != Mode::Off {
+                jack.update(now, Self::sample(index), mode, input::ids::PEDAL + index as u8, emit);
+            }
+        }
+        if self.sustain_count > 0 {
+            let porta = unsafe { &*GPIO_PORTA::ptr() };
+            let data = porta.data.read().bits();
+            let levels = SUSTAIN_PINS.map(|pin| data & pin != 0);
+            self.sustain.update(now, &levels[..self.sustain_count], emit);
+        }
+    }
+}
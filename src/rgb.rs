@@ -0,0 +1,179 @@
+//! On-board RGB LED
+//!
+//! The LaunchPad RGB LED dimmed by PWM module 1: PF1 (red) is M1PWM5 on
+//! generator 2, PF2 (blue) and PF3 (green) are M1PWM6 and M1PWM7 on
+//! generator 3. Each colour has an 8-bit level that goes through a gamma
+//! curve, so equal steps look equally bright, after the global brightness
+//! and the animation have scaled it.
+
+use tm4c123x::{GPIO_PORTF, PWM1, SYSCTL};
+
+use crate::led::{ids, Animation, Color, LedId, LedOutput, Options};
+use crate::midi::{MidiMessage, CLOCKS_PER_BEAT};
+use crate::usb_device;
+
+/// PWM frequency, well above visible flicker
+const PWM_HZ: u32 = 2000;
+
+/// PF1-PF3
+const PINS: u32 = 0x0E;
+
+/// Length of a breath in ms
+const BREATHE_MS: u32 = 3000;
+/// Decay of a beat or message flash in ms
+const FLASH_MS: u32 = 150;
+/// Intensity between flashes
+const FLASH_BASE: u32 = 64;
+/// Intensity at the bottom of a breath
+const BREATHE_FLOOR: u32 = 24;
+
+/// The LED and its animation state
+pub struct RgbLed {
+    color: Color,
+    options: Options,
+    /// Clocks since the last beat
+    clocks: u8,
+    /// Start of the running flash
+    flash_at: Option<u32>,
+    /// PWM period in system clocks
+    period: u32,
+}
+
+impl RgbLed {
+    /// Route PF1-PF3 to PWM module 1, all colours off.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let portf = unsafe { &*GPIO_PORTF::ptr() };
+        let pwm = unsafe { &*PWM1::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 5)) });
+        while sysctl.prgpio.read().bits() & (1 << 5) == 0 {}
+        sysctl.rcgcpwm.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        while sysctl.prpwm.read().bits() & (1 << 1) == 0 {}
+        // PWM clock = system clock
+        sysctl.rcc.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 20)) });
+
+        // Alternate function 5 on PF1-PF3
+        portf.afsel.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+        portf.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0xFFF0) | 0x5550) });
+        portf.den.modify(|r, w| unsafe { w.bits(r.bits() | PINS) });
+
+        let period = unsafe { usb_device::SysCtlClockGet() } / PWM_HZ;
+        unsafe {
+            // Count down; outputs go high at load and low on their compare
+            pwm._2_ctl.write(|w| w.bits(0));
+            pwm._3_ctl.write(|w| w.bits(0));
+            pwm._2_genb.write(|w| w.bits(0x80C));
+            pwm._3_gena.write(|w| w.bits(0x8C));
+            pwm._3_genb.write(|w| w.bits(0x80C));
+            pwm._2_load.write(|w| w.bits(period - 1));
+            pwm._3_load.write(|w| w.bits(period - 1));
+            pwm.enable.modify(|r, w| w.bits(r.bits() & !0xE0));
+            pwm._2_ctl.write(|w| w.bits(1));
+            pwm._3_ctl.write(|w| w.bits(1));
+        }
+
+        Self { color: Color::OFF, options: Options::new(), clocks: 0, flash_at: None, period }
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+    }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    pub fn configure(&mut self, options: &Options) {
+        self.options = *options;
+    }
+
+    /// Follow incoming MIDI: clock beats and messages start a flash.
+    pub fn midi(&mut self, now: u32, message: &MidiMessage) {
+        match *message {
+            MidiMessage::Start => self.clocks = 0,
+            MidiMessage::Clock => {
+                if self.clocks == 0 && self.options.animation == Animation::Beat {
+                    self.flash_at = Some(now);
+                }
+                self.clocks = (self.clocks + 1) % CLOCKS_PER_BEAT;
+            }
+            MidiMessage::ActiveSensing => {}
+            _ if self.options.animation == Animation::Flash => self.flash_at = Some(now),
+            _ => {}
+        }
+    }
+
+    /// Run the animation and update the outputs.
+    pub fn poll(&mut self, now: u32) {
+        let intensity = self.intensity(now);
+        let scale = |level: u8| {
+            let level = level as u32 * self.options.brightness as u32 / 255 * intensity / 255;
+            duty(self.period, level)
+        };
+        let Color { r, g, b } = self.color;
+        let pwm = unsafe { &*PWM1::ptr() };
+        let (red, green, blue) = (scale(r), scale(g), scale(b));
+        unsafe {
+            pwm._2_cmpb.write(|w| w.bits(self.compare(red)));
+            pwm._3_cmpa.write(|w| w.bits(self.compare(blue)));
+            pwm._3_cmpb.write(|w| w.bits(self.compare(green)));
+            // A compare cannot make an output stay low, so off is disabled
+            let enabled = [(red, 1 << 5), (blue, 1 << 6), (green, 1 << 7)]
+                .iter()
+                .filter(|(duty, _)| *duty > 0)
+                .fold(0, |bits, (_, bit)| bits | bit);
+            pwm.enable.modify(|r, w| w.bits((r.bits() & !0xE0) | enabled));
+        }
+    }
+
+    /// Animation level 0-255
+    fn intensity(&mut self, now: u32) -> u32 {
+        match self.options.animation {
+            Animation::Steady => 255,
+            Animation::Breathe => {
+                let phase = now % BREATHE_MS;
+                let rise = phase.min(BREATHE_MS - phase);
+                BREATHE_FLOOR + (255 - BREATHE_FLOOR) * rise / (BREATHE_MS / 2)
+            }
+            Animation::Beat | Animation::Flash => {
+                let Some(start) = self.flash_at else {
+                    return FLASH_BASE;
+                };
+                let elapsed = now.wrapping_sub(start);
+                if elapsed >= FLASH_MS {
+                    self.flash_at = None;
+                    return FLASH_BASE;
+                }
+                255 - (255 - FLASH_BASE) * elapsed / FLASH_MS
+            }
+        }
+    }
+
+    /// Compare value for a high time of `duty` clocks
+    fn compare(&self, duty: u32) -> u32 {
+        (self.period - 1).saturating_sub(duty)
+    }
+}
+
+/// High time in clocks of an 8-bit level, gamma corrected with
+/// 0.8 x^2 + 0.2 x^3 (close to gamma 2.2)
+fn duty(period: u32, level: u32) -> u32 {
+    let x = level.min(255) as u64;
+    let curve = 4 * x * x * 255 + x * x * x;
+    (period as u64 * curve / (5 * 255 * 255 * 255)) as u32
+}
+
+impl LedOutput for RgbLed {
+    /// Colours are LEDs `ids::RED`, `ids::GREEN` and `ids::BLUE`.
+    fn set(&mut self, led: LedId, level: u8) {
+        let level = level.min(127);
+        let level = level * 2 + level / 64;
+        match led {
+            ids::RED => self.color.r = level,
+            ids::GREEN => self.color.g = level,
+            ids::BLUE => self.color.b = level,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Ring Buffers
+//!
+//! `ByteRing` is a single-producer / single-consumer byte queue used to hand
+//! data between interrupt handlers and the main loop without disabling
+//! interrupts. `Queue` is a plain FIFO for main-loop only use.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fixed-size SPSC ring buffer. One slot is kept free to tell full from empty,
+/// so the usable capacity is `N - 1`.
+pub struct ByteRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize, // next slot to write (producer)
+    tail: AtomicUsize, // next slot to read (consumer)
+}
+
+// Safe because: exactly one context pushes and one context pops, and the
+// indices are published with release/acquire ordering.
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append a byte. Returns `false` if the buffer is full.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buf.get())[head] = byte };
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    /// Remove the oldest byte.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buf.get())[tail] };
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(byte)
+    }
+
+    /// Number of bytes currently queued.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + N - tail) % N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Free space in bytes.
+    pub fn free(&self) -> usize {
+        N - 1 - self.len()
+    }
+}
+
+/// Fixed-capacity FIFO of `Copy` items, for use from one context only
+pub struct Queue<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Self { items: [None; N], head: 0, len: 0 }
+    }
+
+    /// Append an item. Returns `false` if the queue is full.
+    pub fn push(&mut self, item: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    /// Remove the oldest item.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    /// Oldest item, without removing it.
+    pub fn peek(&self) -> Option<&T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.items[self.head].as_ref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
@@ -0,0 +1,130 @@
+//! MIDI Router
+//!
+//! Every MIDI message in the controller goes through here. Producers call
+//! `send` with the port the message came from; the routing table decides which
+//! destination ports get a copy, each destination has its own output queue
+//! that its transport drains with `pop`. Ports only receive messages once
+//! their transport has called `attach`. Deliveries are reported to the
+//! monitor. Replies to a request go straight back to the port it came from
+//! with `send_to`, whatever the routing table says.
+
+use crate::midi::MidiMessage;
+use crate::monitor::Monitor;
+use crate::ring::Queue;
+
+pub const PORTS: usize = 2;
+
+/// Message queue depth per destination port
+const OUTPUT_DEPTH: usize = 64;
+
+/// Endpoints of the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Port {
+    /// USB-MIDI to and from the host
+    Usb = 0,
+    /// The controller itself: physical controls as a source, feedback
+    /// handling and on-device engines as a destination
+    Local = 1,
+}
+
+impl Port {
+    pub const ALL: [Port; PORTS] = [Port::Usb, Port::Local];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Port::Usb => "usb",
+            Port::Local => "local",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Port> {
+        Port::ALL.iter().copied().find(|p| p.name() == name)
+    }
+
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// One delivery from a source to a destination
+#[derive(Debug, Clone, Copy)]
+pub struct Routed {
+    /// Milliseconds since boot
+    pub time: u32,
+    pub source: Port,
+    pub dest: Port,
+    pub message: MidiMessage,
+}
+
+pub struct Router {
+    /// Destination bit mask per source port
+    routes: [u8; PORTS],
+    /// Ports with a running transport
+    attached: u8,
+    /// Queued messages with the port they came from
+    outputs: [Queue<(Port, MidiMessage), OUTPUT_DEPTH>; PORTS],
+    /// Messages lost because an output queue was full
+    pub overflows: u32,
+    pub monitor: Monitor,
+}
+
+impl Router {
+    /// Controls go to the host, the host is delivered locally for
+    /// feedback.
+    pub const fn new() -> Self {
+        Self {
+            routes: [1 << Port::Local as u8, 1 << Port::Usb as u8],
+            attached: 0,
+            outputs: [Queue::new(), Queue::new()],
+            overflows: 0,
+            monitor: Monitor::new(),
+        }
+    }
+
+    pub fn route(&self, source: Port, dest: Port) -> bool {
+        self.routes[source as usize] & dest.bit() != 0
+    }
+
+    /// Start queueing messages for a port.
+    pub fn attach(&mut self, port: Port) {
+        self.attached |= port.bit();
+    }
+
+    /// Deliver a message from `source` to every routed destination.
+    pub fn send(&mut self, now: u32, source: Port, message: MidiMessage) {
+        for dest in Port::ALL {
+            if self.route(source, dest) {
+                self.send_to(now, source, dest, message);
+            }
+        }
+    }
+
+    /// Deliver a message from `source` to `dest` only, regardless of the
+    /// routing table.
+    pub fn send_to(&mut self, now: u32, source: Port, dest: Port, message: MidiMessage) {
+        if self.attached & dest.bit() == 0 {
+            return;
+        }
+        if !self.outputs[dest as usize].push((source, message)) {
+            self.overflows = self.overflows.wrapping_add(1);
+            return;
+        }
+        self.monitor.record(&Routed { time: now, source, dest, message });
+    }
+
+    /// Next message queued for a destination.
+    pub fn pop(&mut self, dest: Port) -> Option<MidiMessage> {
+        self.pop_with_source(dest).map(|(_, message)| message)
+    }
+
+    /// Next message queued for a destination and the port it came from.
+    pub fn pop_with_source(&mut self, dest: Port) -> Option<(Port, MidiMessage)> {
+        self.outputs[dest as usize].pop()
+    }
+
+    /// Free space in the output queue of a destination
+    pub fn free(&self, dest: Port) -> usize {
+        self.outputs[dest as usize].free()
+    }
+}
@@ -0,0 +1,154 @@
+//! Scales
+//!
+//! Scales as the set of pitch classes they use above their root, and keys
+//! as a scale on a root, for quantising played notes and laying out pads.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Scale {
+    /// Every note, no quantisation
+    Chromatic = 0,
+    Major = 1,
+    Minor = 2,
+    Dorian = 3,
+    Phrygian = 4,
+    Lydian = 5,
+    Mixolydian = 6,
+    Locrian = 7,
+    HarmonicMinor = 8,
+    MajorPentatonic = 9,
+    MinorPentatonic = 10,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 11] = [
+        Scale::Chromatic,
+        Scale::Major,
+        Scale::Minor,
+        Scale::Dorian,
+        Scale::Phrygian,
+        Scale::Lydian,
+        Scale::Mixolydian,
+        Scale::Locrian,
+        Scale::HarmonicMinor,
+        Scale::MajorPentatonic,
+        Scale::MinorPentatonic,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Scale> {
+        Scale::ALL.get(value as usize).copied()
+    }
+
+    /// Pitch classes above the root, bit n for n semitones
+    pub const fn mask(self) -> u16 {
+        match self {
+            Scale::Chromatic => 0xFFF,
+            Scale::Major => 0xAB5,
+            Scale::Minor => 0x5AD,
+            Scale::Dorian => 0x6AD,
+            Scale::Phrygian => 0x5AB,
+            Scale::Lydian => 0xAD5,
+            Scale::Mixolydian => 0x6B5,
+            Scale::Locrian => 0x56B,
+            Scale::HarmonicMinor => 0x9AD,
+            Scale::MajorPentatonic => 0x295,
+            Scale::MinorPentatonic => 0x4A9,
+        }
+    }
+
+    /// Whether `note` is in the scale on `root` (0 = C)
+    pub fn contains(self, note: u8, root: u8) -> bool {
+        let class = (note as u16 + 12 - (root % 12) as u16) % 12;
+        self.mask() & (1 << class) != 0
+    }
+
+    /// Semitones above the root of the scale degree `degree`, counting on
+    /// into the octaves above
+    pub fn degree(self, degree: u32) -> u32 {
+        let mask = self.mask();
+        let notes = mask.count_ones();
+        let mut index = degree % notes;
+        for class in 0..12 {
+            if mask & (1 << class) != 0 {
+                if index == 0 {
+                    return 12 * (degree / notes) + class;
+                }
+                index -= 1;
+            }
+        }
+        12 * (degree / notes)
+    }
+
+    /// Scale degrees below `semitones` above the root, e.g. 3 below a
+    /// fourth in a major scale
+    pub fn degrees_below(self, semitones: u32) -> u32 {
+        (self.mask() & ((1 << semitones.min(12)) - 1)).count_ones()
+    }
+
+    /// Nearest note of the scale on `root`, the lower one of two as near.
+    pub fn quantize(self, note: u8, root: u8) -> u8 {
+        for distance in 0..12u8 {
+            if let Some(below) = note.checked_sub(distance).filter(|&n| self.contains(n, root)) {
+                return below;
+            }
+            if let Some(above) = note.checked_add(distance).filter(|&n| n < 128 && self.contains(n, root)) {
+                return above;
+            }
+        }
+        note
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scale::Chromatic => "chromatic",
+            Scale::Major => "major",
+            Scale::Minor => "minor",
+            Scale::Dorian => "dorian",
+            Scale::Phrygian => "phrygian",
+            Scale::Lydian => "lydian",
+            Scale::Mixolydian => "mixolydian",
+            Scale::Locrian => "locrian",
+            Scale::HarmonicMinor => "harmonic minor",
+            Scale::MajorPentatonic => "major pentatonic",
+            Scale::MinorPentatonic => "minor pentatonic",
+        })
+    }
+}
+
+/// Name of the pitch class of `note`, for roots
+pub fn pitch_name(note: u8) -> &'static str {
+    const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    NAMES[(note % 12) as usize]
+}
+
+/// A scale on a root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub scale: Scale,
+    /// 0 = C
+    pub root: u8,
+}
+
+impl Key {
+    pub const fn new() -> Self {
+        Self { scale: Scale::Chromatic, root: 0 }
+    }
+
+    /// Nearest note in the key
+    pub fn quantize(&self, note: u8) -> u8 {
+        self.scale.quantize(note, self.root)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.scale {
+            Scale::Chromatic => f.write_str("chromatic"),
+            scale => write!(f, "{} {}", pitch_name(self.root), scale),
+        }
+    }
+}
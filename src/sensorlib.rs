@@ -0,0 +1,234 @@
+//! Sensor Library Bindings
+//!
+//! FFI bindings to the TivaWare sensor library: the interrupt driven I2C
+//! master driver, the MPU6050, MPU9150 and LSM303DLHC drivers and the
+//! complementary DCM filter. The C files are compiled by build.rs like the
+//! USB library.
+//!
+//! Driver instances are opaque here; their sizes and alignment match the C
+//! structures. Every driver call that starts a transfer returns 0 if the
+//! driver was busy and calls its callback with a status when the transfer
+//! is done (0 for success).
+
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use core::ffi::c_void;
+
+// ============================================================================
+// Type Definitions
+// ============================================================================
+
+/// I2C master driver state (tI2CMInstance from i2cm_drv.h)
+#[repr(C, align(4))]
+pub struct tI2CMInstance([u8; 292]);
+
+/// MPU6050 driver state (tMPU6050 from mpu6050.h)
+#[repr(C, align(4))]
+pub struct tMPU6050([u8; 56]);
+
+/// MPU9150 driver state, including its AK8975 (tMPU9150 from mpu9150.h)
+#[repr(C, align(4))]
+pub struct tMPU9150([u8; 108]);
+
+/// LSM303DLHC accelerometer driver state (tLSM303DLHCAccel)
+#[repr(C, align(4))]
+pub struct tLSM303DLHCAccel([u8; 44]);
+
+/// LSM303DLHC magnetometer driver state (tLSM303DLHCMag)
+#[repr(C, align(4))]
+pub struct tLSM303DLHCMag([u8; 44]);
+
+/// Complementary filter state (tCompDCM from comp_dcm.h)
+#[repr(C, align(4))]
+pub struct tCompDCM([u8; 88]);
+
+macro_rules! zeroed {
+    ($($name:ident),*) => {
+        $(impl $name {
+            pub const fn new() -> Self {
+                Self([0; core::mem::size_of::<Self>()])
+            }
+        })*
+    };
+}
+
+zeroed!(tI2CMInstance, tMPU6050, tMPU9150, tLSM303DLHCAccel, tLSM303DLHCMag, tCompDCM);
+
+/// Transfer done callback (tSensorCallback from i2cm_drv.h)
+pub type tSensorCallback = Option<unsafe extern "C" fn(*mut c_void, u32)>;
+
+/// Callback status of a successful transfer
+pub const I2CM_STATUS_SUCCESS: u32 = 0;
+
+// ============================================================================
+// I2C Master Driver
+// ============================================================================
+
+extern "C" {
+    /// Set up the I2C module at `ui32Base` as a 400 kHz master and enable
+    /// its interrupt; 0xFF for no uDMA channels
+    pub fn I2CMInit(
+        psInst: *mut tI2CMInstance,
+        ui32Base: u32,
+        ui32Int: u32,
+        ui8TxDMA: u32,
+        ui8RxDMA: u32,
+        ui32SysClock: u32,
+    );
+
+    /// Advance the transfer in progress, from the I2C interrupt
+    pub fn I2CMIntHandler(psInst: *mut tI2CMInstance);
+}
+
+// ============================================================================
+// Sensor Drivers
+// ============================================================================
+
+extern "C" {
+    pub fn MPU6050Init(
+        psInst: *mut tMPU6050,
+        psI2CInst: *mut tI2CMInstance,
+        ui8I2CAddr: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    /// Write `ui16Count` registers from `ui8Reg`; the data is not copied
+    pub fn MPU6050Write(
+        psInst: *mut tMPU6050,
+        ui8Reg: u32,
+        pui8Data: *const u8,
+        ui16Count: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    /// Read accelerometer and gyroscope into the instance
+    pub fn MPU6050DataRead(psInst: *mut tMPU6050, pfnCallback: tSensorCallback, pvCallbackData: *mut c_void) -> u32;
+
+    /// Last acceleration read, m/s²
+    pub fn MPU6050DataAccelGetFloat(psInst: *mut tMPU6050, pfAccelX: *mut f32, pfAccelY: *mut f32, pfAccelZ: *mut f32);
+
+    /// Last rotation rate read, rad/s
+    pub fn MPU6050DataGyroGetFloat(psInst: *mut tMPU6050, pfGyroX: *mut f32, pfGyroY: *mut f32, pfGyroZ: *mut f32);
+
+    pub fn MPU9150Init(
+        psInst: *mut tMPU9150,
+        psI2CInst: *mut tI2CMInstance,
+        ui8I2CAddr: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn MPU9150Write(
+        psInst: *mut tMPU9150,
+        ui8Reg: u32,
+        pui8Data: *const u8,
+        ui16Count: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    /// Read accelerometer, gyroscope and magnetometer into the instance
+    pub fn MPU9150DataRead(psInst: *mut tMPU9150, pfnCallback: tSensorCallback, pvCallbackData: *mut c_void) -> u32;
+
+    pub fn MPU9150DataAccelGetFloat(psInst: *mut tMPU9150, pfAccelX: *mut f32, pfAccelY: *mut f32, pfAccelZ: *mut f32);
+
+    pub fn MPU9150DataGyroGetFloat(psInst: *mut tMPU9150, pfGyroX: *mut f32, pfGyroY: *mut f32, pfGyroZ: *mut f32);
+
+    /// Last magnetic field read, tesla
+    pub fn MPU9150DataMagnetoGetFloat(
+        psInst: *mut tMPU9150,
+        pfMagnetoX: *mut f32,
+        pfMagnetoY: *mut f32,
+        pfMagnetoZ: *mut f32,
+    );
+
+    pub fn LSM303DLHCAccelInit(
+        psInst: *mut tLSM303DLHCAccel,
+        psI2CInst: *mut tI2CMInstance,
+        ui8I2CAddr: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCAccelWrite(
+        psInst: *mut tLSM303DLHCAccel,
+        ui8Reg: u32,
+        pui8Data: *const u8,
+        ui16Count: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCAccelDataRead(
+        psInst: *mut tLSM303DLHCAccel,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCAccelDataAccelGetFloat(
+        psInst: *mut tLSM303DLHCAccel,
+        pfAccelX: *mut f32,
+        pfAccelY: *mut f32,
+        pfAccelZ: *mut f32,
+    );
+
+    pub fn LSM303DLHCMagInit(
+        psInst: *mut tLSM303DLHCMag,
+        psI2CInst: *mut tI2CMInstance,
+        ui8I2CAddr: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCMagWrite(
+        psInst: *mut tLSM303DLHCMag,
+        ui8Reg: u32,
+        pui8Data: *const u8,
+        ui16Count: u32,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCMagDataRead(
+        psInst: *mut tLSM303DLHCMag,
+        pfnCallback: tSensorCallback,
+        pvCallbackData: *mut c_void,
+    ) -> u32;
+
+    pub fn LSM303DLHCMagDataMagnetoGetFloat(
+        psInst: *mut tLSM303DLHCMag,
+        pfMagnetoX: *mut f32,
+        pfMagnetoY: *mut f32,
+        pfMagnetoZ: *mut f32,
+    );
+}
+
+// ============================================================================
+// Complementary DCM Filter
+// ============================================================================
+
+extern "C" {
+    /// Reset the filter: sample period in seconds and the weights of the
+    /// accelerometer, gyroscope and magnetometer
+    pub fn CompDCMInit(psDCM: *mut tCompDCM, fDeltaT: f32, fScaleA: f32, fScaleG: f32, fScaleM: f32);
+
+    pub fn CompDCMAccelUpdate(psDCM: *mut tCompDCM, fAccelX: f32, fAccelY: f32, fAccelZ: f32);
+
+    pub fn CompDCMGyroUpdate(psDCM: *mut tCompDCM, fGyroX: f32, fGyroY: f32, fGyroZ: f32);
+
+    pub fn CompDCMMagnetoUpdate(psDCM: *mut tCompDCM, fMagnetoX: f32, fMagnetoY: f32, fMagnetoZ: f32);
+
+    /// Initial orientation from the first accelerometer and magnetometer
+    /// readings
+    pub fn CompDCMStart(psDCM: *mut tCompDCM);
+
+    /// Fold the latest readings into the orientation
+    pub fn CompDCMUpdate(psDCM: *mut tCompDCM);
+
+    /// Orientation as Euler angles, radians
+    pub fn CompDCMComputeEulers(psDCM: *mut tCompDCM, pfRoll: *mut f32, pfPitch: *mut f32, pfYaw: *mut f32);
+}
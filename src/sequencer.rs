@@ -0,0 +1,747 @@
+//! Step Sequencer
+//!
+//! A pattern of `MAX_TRACKS` tracks, each a row of up to `MAX_STEPS` steps
+//! with its own channel, length and rate, so tracks of different lengths
+//! drift against each other. A step has a note, a velocity (0 is a rest),
+//! a gate length, a probability and a ratchet count that plays it up to
+//! four times within the step. With `swing` above 50 % every second step
+//! of a track comes late, by a fraction of the step.
+//!
+//! The sequencer counts MIDI clocks (24 per beat) from its own clock at
+//! `bpm`, which it also sends so other gear follows, or from MIDI clock
+//! received from the host. Steps start on a clock; gates and
+//! ratchets are timed in milliseconds from the measured clock period, so
+//! the whole engine runs from `poll` and `midi` with the time passed in and
+//! can be tested against a virtual clock.
+//!
+//! Patterns live in the configuration and are stored with `save`; the
+//! button grid edits the one playing (see `grid`).
+
+use core::fmt;
+
+use crate::input;
+use crate::led;
+use crate::midi::{MidiMessage, CLOCKS_PER_BEAT};
+
+pub const MAX_TRACKS: usize = 4;
+pub const MAX_STEPS: usize = 64;
+/// Patterns kept in the configuration
+pub const MAX_PATTERNS: usize = 2;
+/// Most hits in one step
+pub const MAX_RATCHET: u8 = 4;
+/// Longest step in MIDI clocks, a whole note
+pub const MAX_RATE: u8 = 96;
+
+pub const MIN_BPM: u16 = 20;
+pub const MAX_BPM: u16 = 300;
+
+/// Swing in percent of a pair of steps taken by the first: 50 is straight,
+/// 66 a triplet feel
+pub const MIN_SWING: u8 = 50;
+pub const MAX_SWING: u8 = 75;
+
+/// Received clock period assumed before two clocks were seen, 120 BPM
+const DEFAULT_PERIOD_MS: u32 = 60_000 / (120 * CLOCKS_PER_BEAT as u32);
+
+/// One step of a track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub note: u8,
+    /// 0 is a rest
+    pub velocity: u8,
+    /// Note length in percent of the step, or of a ratchet hit
+    pub gate: u8,
+    /// Chance in percent that the step plays
+    pub probability: u8,
+    /// Hits in the step, 1 to `MAX_RATCHET`
+    pub ratchet: u8,
+}
+
+impl Step {
+    pub const fn rest(note: u8) -> Self {
+        Self { note, velocity: 0, gate: 50, probability: 100, ratchet: 1 }
+    }
+
+    pub fn is_rest(&self) -> bool {
+        self.velocity == 0
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.note < 128
+            && self.velocity < 128
+            && (1..=100).contains(&self.gate)
+            && self.probability <= 100
+            && (1..=MAX_RATCHET).contains(&self.ratchet)
+    }
+}
+
+/// A row of steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Track {
+    /// MIDI channel, 0-based
+    pub channel: u8,
+    /// Steps played before the track starts over
+    pub length: u8,
+    /// MIDI clocks per step, 6 for sixteenth notes
+    pub rate: u8,
+    pub muted: bool,
+    pub steps: [Step; MAX_STEPS],
+}
+
+impl Track {
+    /// Velocity of a step switched on from the grid
+    pub const GRID_VELOCITY: u8 = 100;
+
+    pub const fn new(channel: u8, note: u8) -> Self {
+        Self { channel, length: 16, rate: 6, muted: false, steps: [Step::rest(note); MAX_STEPS] }
+    }
+
+    /// Switch a step between a rest and a note, keeping its settings.
+    pub fn toggle(&mut self, step: usize) {
+        let step = &mut self.steps[step];
+        step.velocity = if step.is_rest() { Self::GRID_VELOCITY } else { 0 };
+    }
+}
+
+/// All tracks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pattern {
+    pub tracks: [Track; MAX_TRACKS],
+}
+
+impl Pattern {
+    /// Empty pattern with a drum kit on channel 10: bass drum, snare,
+    /// closed and open hi-hat.
+    pub const fn new() -> Self {
+        Self { tracks: [Track::new(9, 36), Track::new(9, 38), Track::new(9, 42), Track::new(9, 46)] }
+    }
+}
+
+/// Where the clock comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Own clock at `bpm`, sent out as MIDI clock
+    Internal = 0,
+    /// MIDI clock and transport from the host
+    External = 1,
+}
+
+impl ClockSource {
+    pub const ALL: [ClockSource; 2] = [ClockSource::Internal, ClockSource::External];
+
+    pub fn from_u8(value: u8) -> Option<ClockSource> {
+        ClockSource::ALL.get(value as usize).copied()
+    }
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ClockSource::Internal => "internal",
+            ClockSource::External => "external",
+        })
+    }
+}
+
+/// Settings of the sequencer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub source: ClockSource,
+    /// Tempo of the internal clock
+    pub bpm: u16,
+    /// Pattern played and edited
+    pub pattern: u8,
+    /// Shift register inputs and LEDs are the step grid
+    pub grid: bool,
+    /// `MIN_SWING` to `MAX_SWING`
+    pub swing: u8,
+}
+
+impl Options {
+    pub const fn new() -> Self {
+        Self { source: ClockSource::Internal, bpm: 120, pattern: 0, grid: false, swing: MIN_SWING }
+    }
+}
+
+/// Step length of `rate` clocks as a Display value
+pub struct Rate(pub u8);
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            3 => f.write_str("1/32"),
+            4 => f.write_str("1/16t"),
+            6 => f.write_str("1/16"),
+            8 => f.write_str("1/8t"),
+            12 => f.write_str("1/8"),
+            16 => f.write_str("1/4t"),
+            24 => f.write_str("1/4"),
+            48 => f.write_str("1/2"),
+            96 => f.write_str("1/1"),
+            clocks => write!(f, "{} clocks", clocks),
+        }
+    }
+}
+
+// ============================================================================
+// Engine
+// ============================================================================
+
+/// What a track is playing
+#[derive(Clone, Copy)]
+struct Voice {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+    /// First hit of the step, time between hits and note length
+    start: u32,
+    spacing: u32,
+    gate: u32,
+    hits: u8,
+    /// Hits played so far
+    played: u8,
+    /// Note sounding, with its channel and when it ends
+    sounding: Option<(u8, u8, u32)>,
+}
+
+impl Voice {
+    const IDLE: Voice =
+        Voice { channel: 0, note: 0, velocity: 0, start: 0, spacing: 0, gate: 0, hits: 0, played: 0, sounding: None };
+
+    /// Send the hits and note-offs that are due.
+    fn poll(&mut self, now: u32, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some((channel, note, off_at)) = self.sounding {
+            if now.wrapping_sub(off_at) as i32 >= 0 {
+                self.sounding = None;
+                out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+            }
+        }
+        while self.played < self.hits {
+            let at = self.start.wrapping_add(self.spacing * self.played as u32);
+            if (now.wrapping_sub(at) as i32) < 0 {
+                break;
+            }
+            self.played += 1;
+            self.release(out);
+            out(MidiMessage::NoteOn { channel: self.channel, note: self.note, velocity: self.velocity });
+            self.sounding = Some((self.channel, self.note, at.wrapping_add(self.gate)));
+        }
+    }
+
+    fn release(&mut self, out: &mut dyn FnMut(MidiMessage)) {
+        if let Some((channel, note, _)) = self.sounding.take() {
+            out(MidiMessage::NoteOff { channel, note, velocity: 0 });
+        }
+    }
+}
+
+/// MIDI clock counted from `bpm` or from clock received, with its period
+/// for timing within a clock
+pub struct Clock {
+    /// Received clock: time of the last one
+    clock_at: Option<u32>,
+    /// Period in 1/16 ms
+    period16: u32,
+    /// Internal clock: time of the last poll and progress to the next
+    /// clock, in ms times BPM times 24
+    ticked_at: u32,
+    phase: u32,
+}
+
+impl Clock {
+    pub const fn new() -> Self {
+        Self { clock_at: None, period16: DEFAULT_PERIOD_MS * 16, ticked_at: 0, phase: 0 }
+    }
+
+    /// Start the internal clock over, the next clock a whole period away.
+    pub fn restart(&mut self, now: u32) {
+        self.ticked_at = now;
+        self.phase = 0;
+    }
+
+    /// Run the internal clock at `bpm`, return the clocks due since the
+    /// last call.
+    pub fn internal(&mut self, now: u32, bpm: u16) -> u32 {
+        // A clock is due every 60000 / (bpm * 24) ms
+        let bpm = bpm.clamp(MIN_BPM, MAX_BPM) as u32;
+        self.period16 = 60_000 * 16 / (bpm * CLOCKS_PER_BEAT as u32);
+        self.phase += now.wrapping_sub(self.ticked_at).min(1000) * bpm * CLOCKS_PER_BEAT as u32;
+        self.ticked_at = now;
+        let due = self.phase / 60_000;
+        self.phase %= 60_000;
+        due
+    }
+
+    /// A clock was received: measure the period.
+    pub fn received(&mut self, now: u32) {
+        if let Some(clock_at) = self.clock_at {
+            let interval = now.wrapping_sub(clock_at).min(1000) * 16;
+            self.period16 = (self.period16 * 3 + interval) / 4;
+        }
+        self.clock_at = Some(now);
+    }
+
+    /// Length of `clocks` clocks in ms
+    pub fn span(&self, clocks: u32) -> u32 {
+        clocks * self.period16 / 16
+    }
+}
+
+/// Transport asked for from outside the MIDI stream
+#[derive(Clone, Copy, PartialEq)]
+enum Request {
+    Start,
+    Stop,
+    Continue,
+}
+
+pub struct Sequencer {
+    playing: bool,
+    /// Clocks counted since the start
+    clocks: u32,
+    /// Next clock is the first after a start
+    fresh: bool,
+    request: Option<Request>,
+    clock: Clock,
+    voices: [Voice; MAX_TRACKS],
+    /// Step each track is on
+    position: [u8; MAX_TRACKS],
+    /// First step of the grid
+    page: u8,
+    rng: u32,
+}
+
+impl Sequencer {
+    pub const fn new() -> Self {
+        Self {
+            playing: false,
+            clocks: 0,
+            fresh: false,
+            request: None,
+            clock: Clock::new(),
+            voices: [Voice::IDLE; MAX_TRACKS],
+            position: [0; MAX_TRACKS],
+            page: 0,
+            rng: 0x2545_F491,
+        }
+    }
+
+    /// Mix something unpredictable into the probability dice.
+    pub fn seed(&mut self, entropy: u32) {
+        self.rng = (self.rng ^ entropy) | 1;
+    }
+
+    /// Playing, or asked to start
+    pub fn playing(&self) -> bool {
+        match self.request {
+            Some(Request::Stop) => false,
+            Some(_) => true,
+            None => self.playing,
+        }
+    }
+
+    /// Step each track is on while playing
+    pub fn position(&self) -> Option<[u8; MAX_TRACKS]> {
+        self.playing.then_some(self.position)
+    }
+
+    /// Start from the first step.
+    pub fn start(&mut self) {
+        self.request = Some(Request::Start);
+    }
+
+    /// Stop, ending all notes.
+    pub fn stop(&mut self) {
+        self.request = Some(Request::Stop);
+    }
+
+    /// Go on from where it stopped.
+    pub fn resume(&mut self) {
+        self.request = Some(Request::Continue);
+    }
+
+    /// First step shown on the grid
+    pub fn page(&self) -> u8 {
+        self.page
+    }
+
+    pub fn set_page(&mut self, page: u8) {
+        self.page = page.min((MAX_STEPS / grid::COLUMNS - 1) as u8);
+    }
+
+    /// Run the internal clock, carry out requests and play what is due.
+    pub fn poll(&mut self, now: u32, options: &Options, pattern: &Pattern, out: &mut dyn FnMut(MidiMessage)) {
+        let internal = options.source == ClockSource::Internal;
+        if let Some(request) = self.request.take() {
+            self.transport(now, request, out);
+            if internal {
+                out(match request {
+                    Request::Start => MidiMessage::Start,
+                    Request::Stop => MidiMessage::Stop,
+                    Request::Continue => MidiMessage::Continue,
+                });
+            }
+            self.clock.restart(now);
+        }
+        if internal && self.playing {
+            let due = self.clock.internal(now, options.bpm) + self.fresh as u32;
+            for _ in 0..due {
+                out(MidiMessage::Clock);
+                self.tick(now, options, pattern);
+            }
+        }
+        for voice in self.voices.iter_mut() {
+            voice.poll(now, out);
+        }
+    }
+
+    /// Follow clock and transport received, when it is the clock source.
+    pub fn midi(
+        &mut self,
+        now: u32,
+        message: &MidiMessage,
+        options: &Options,
+        pattern: &Pattern,
+        out: &mut dyn FnMut(MidiMessage),
+    ) {
+        if options.source != ClockSource::External {
+            return;
+        }
+        match message {
+            MidiMessage::Clock => {
+                self.clock.received(now);
+                if self.playing {
+                    self.tick(now, options, pattern);
+                    for voice in self.voices.iter_mut() {
+                        voice.poll(now, out);
+                    }
+                }
+            }
+            MidiMessage::Start => self.transport(now, Request::Start, out),
+            MidiMessage::Stop => self.transport(now, Request::Stop, out),
+            MidiMessage::Continue => self.transport(now, Request::Continue, out),
+            _ => {}
+        }
+    }
+
+    fn transport(&mut self, _now: u32, request: Request, out: &mut dyn FnMut(MidiMessage)) {
+        match request {
+            Request::Start => {
+                self.clocks = 0;
+                self.fresh = true;
+                self.playing = true;
+            }
+            Request::Continue => self.playing = true,
+            Request::Stop => {
+                self.playing = false;
+                for voice in self.voices.iter_mut() {
+                    voice.hits = 0;
+                    voice.release(out);
+                }
+            }
+        }
+    }
+
+    /// One MIDI clock: start the steps that fall on it.
+    fn tick(&mut self, now: u32, options: &Options, pattern: &Pattern) {
+        if self.fresh {
+            self.fresh = false;
+        } else {
+            self.clocks = self.clocks.wrapping_add(1);
+        }
+        for (index, track) in pattern.tracks.iter().enumerate() {
+            let rate = track.rate.max(1) as u32;
+            if !self.clocks.is_multiple_of(rate) {
+                continue;
+            }
+            let length = track.length.clamp(1, MAX_STEPS as u8) as u32;
+            let position = (self.clocks / rate % length) as usize;
+            self.position[index] = position as u8;
+            let step = track.steps[position];
+            if track.muted || step.is_rest() || !self.roll(step.probability) {
+                continue;
+            }
+            // A swung step starts late and ends on time, its ratchets closer
+            let duration = self.clock.span(rate);
+            let swing = options.swing.clamp(MIN_SWING, MAX_SWING) - MIN_SWING;
+            let delay = if position % 2 == 1 { duration * 2 * swing as u32 / 100 } else { 0 };
+            let duration = duration - delay;
+            let hits = step.ratchet.clamp(1, MAX_RATCHET);
+            let spacing = duration / hits as u32;
+            let voice = &mut self.voices[index];
+            *voice = Voice {
+                channel: track.channel,
+                note: step.note,
+                velocity: step.velocity,
+                start: now.wrapping_add(delay),
+                spacing,
+                gate: (spacing * step.gate as u32 / 100).max(1),
+                hits,
+                played: 0,
+                sounding: voice.sounding,
+            };
+        }
+    }
+
+    /// Whether a step with `probability` percent plays
+    fn roll(&mut self, probability: u8) -> bool {
+        if probability >= 100 {
+            return true;
+        }
+        // xorshift32
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x % 100 < probability as u32
+    }
+}
+
+/// The shift register inputs and LEDs as a step grid: one row of
+/// `COLUMNS` per track, showing the page of steps chosen with
+/// `Sequencer::set_page`. A button switches its step on or off; a lit LED
+/// is a step that plays. The playhead inverts the cell it is on, white on
+/// the LED strip where there is no note.
+pub mod grid {
+    use super::*;
+
+    pub const COLUMNS: usize = 16;
+    pub const CELLS: usize = COLUMNS * MAX_TRACKS;
+
+    /// Strip colours: bright green and white
+    const STEP_LEVEL: u8 = 0x75;
+    const PLAYHEAD_LEVEL: u8 = 0x7F;
+
+    /// Track and step of a grid button
+    pub fn cell(sequencer: &Sequencer, control: input::ControlId) -> Option<(usize, usize)> {
+        let index = control.checked_sub(input::ids::SHIFT)? as usize;
+        (index < CELLS).then(|| (index / COLUMNS, sequencer.page as usize * COLUMNS + index % COLUMNS))
+    }
+
+    /// Whether an LED belongs to the grid, on the strip or the shift
+    /// register outputs
+    pub fn is_grid_led(led: led::LedId) -> bool {
+        [led::ids::STRIP, led::ids::SHIFT].iter().any(|&first| led.wrapping_sub(first) < CELLS as u8)
+    }
+
+    /// Level of every cell
+    pub fn levels(sequencer: &Sequencer, pattern: &Pattern) -> [u8; CELLS] {
+        let mut levels = [0; CELLS];
+        let position = sequencer.position();
+        for (cell, level) in levels.iter_mut().enumerate() {
+            let (track, column) = (cell / COLUMNS, cell % COLUMNS);
+            let step = sequencer.page as usize * COLUMNS + column;
+            let track = &pattern.tracks[track];
+            let on = step < track.length as usize && !track.steps[step].is_rest();
+            let here = position.is_some_and(|position| position[cell / COLUMNS] as usize == step);
+            *level = match (on, here) {
+                (true, false) => STEP_LEVEL,
+                (false, true) => PLAYHEAD_LEVEL,
+                _ => 0,
+            };
+        }
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Vec<(u32, MidiMessage)>;
+
+    /// Poll every millisecond of `times`, as the main loop does
+    fn run(sequencer: &mut Sequencer, options: &Options, pattern: &Pattern, times: core::ops::Range<u32>) -> Log {
+        let mut log = Vec::new();
+        for now in times {
+            sequencer.poll(now, options, pattern, &mut |message| log.push((now, message)));
+        }
+        log
+    }
+
+    fn ons(log: &Log, key: u8) -> Vec<u32> {
+        let on = |&(now, message): &(u32, MidiMessage)| match message {
+            MidiMessage::NoteOn { note, .. } if note == key => Some(now),
+            _ => None,
+        };
+        log.iter().filter_map(on).collect()
+    }
+
+    fn offs(log: &Log, key: u8) -> Vec<u32> {
+        let off = |&(now, message): &(u32, MidiMessage)| match message {
+            MidiMessage::NoteOff { note, .. } if note == key => Some(now),
+            _ => None,
+        };
+        log.iter().filter_map(off).collect()
+    }
+
+    fn step(note: u8, gate: u8, probability: u8, ratchet: u8) -> Step {
+        Step { note, velocity: 100, gate, probability, ratchet }
+    }
+
+    fn played(sequencer: &mut Sequencer, options: &Options, pattern: &Pattern, times: core::ops::Range<u32>) -> Log {
+        sequencer.start();
+        run(sequencer, options, pattern, times)
+    }
+
+    #[test]
+    fn runs_the_internal_clock() {
+        // 120 BPM: a clock every 20.8 ms, a sixteenth every 125 ms
+        let mut pattern = Pattern::new();
+        for number in [0, 4, 8, 12] {
+            pattern.tracks[0].steps[number] = step(36, 50, 100, 1);
+        }
+        let log = played(&mut Sequencer::new(), &Options::new(), &pattern, 0..2001);
+        assert_eq!(log[0], (0, MidiMessage::Start));
+        let clocks: Vec<u32> = log.iter().filter(|(_, m)| *m == MidiMessage::Clock).map(|&(now, _)| now).collect();
+        assert_eq!(clocks.len(), 97);
+        assert_eq!(&clocks[..7], &[0, 21, 42, 63, 84, 105, 125]);
+        assert_eq!(ons(&log, 36), [0, 500, 1000, 1500, 2000]);
+    }
+
+    #[test]
+    fn times_gates() {
+        let mut pattern = Pattern::new();
+        pattern.tracks[0].steps[0] = step(36, 50, 100, 1);
+        pattern.tracks[1].steps[0] = step(38, 10, 100, 1);
+        pattern.tracks[2].steps[0] = step(42, 100, 100, 1);
+        pattern.tracks[2].steps[1] = step(42, 100, 100, 1);
+        let log = played(&mut Sequencer::new(), &Options::new(), &pattern, 0..300);
+
+        // Steps are 124 ms from the clock period, the gate a part of that
+        assert_eq!(offs(&log, 36), [62]);
+        assert_eq!(offs(&log, 38), [12]);
+        // A full gate ends on the next step, before that plays
+        assert_eq!(ons(&log, 42), [0, 125]);
+        assert_eq!(offs(&log, 42), [124, 249]);
+    }
+
+    #[test]
+    fn plays_ratchets() {
+        let mut pattern = Pattern::new();
+        pattern.tracks[2].steps[0] = step(42, 50, 100, 4);
+        pattern.tracks[3].steps[0] = step(46, 100, 100, 2);
+        let log = played(&mut Sequencer::new(), &Options::new(), &pattern, 0..200);
+        assert_eq!(ons(&log, 42), [0, 31, 62, 93]);
+        assert_eq!(offs(&log, 42), [15, 46, 77, 108]);
+        // Back to back hits end the one before
+        assert_eq!(ons(&log, 46), [0, 62]);
+        assert_eq!(offs(&log, 46), [62, 124]);
+        let at_62: Vec<MidiMessage> = log.iter().filter(|(now, _)| *now == 62).map(|&(_, m)| m).collect();
+        let off = at_62.iter().position(|m| matches!(m, MidiMessage::NoteOff { note: 46, .. }));
+        let on = at_62.iter().position(|m| matches!(m, MidiMessage::NoteOn { note: 46, .. }));
+        assert!(off < on);
+    }
+
+    #[test]
+    fn rolls_probability() {
+        let mut pattern = Pattern::new();
+        for track in pattern.tracks.iter_mut() {
+            track.length = 1;
+        }
+        pattern.tracks[0].steps[0] = step(36, 50, 100, 1);
+        pattern.tracks[1].steps[0] = step(38, 50, 0, 1);
+        pattern.tracks[2].steps[0] = step(42, 50, 50, 1);
+        pattern.tracks[3].steps[0] = step(46, 50, 10, 1);
+        let mut sequencer = Sequencer::new();
+        sequencer.seed(1234);
+        let log = played(&mut sequencer, &Options::new(), &pattern, 0..125_000);
+        assert_eq!(ons(&log, 36).len(), 1000);
+        assert!(ons(&log, 38).is_empty());
+        assert!((450..550).contains(&ons(&log, 42).len()));
+        assert!((70..130).contains(&ons(&log, 46).len()));
+        // Whatever plays is on a step
+        assert!(ons(&log, 42).iter().all(|now| ons(&log, 36).contains(now)));
+    }
+
+    #[test]
+    fn swings_every_second_step() {
+        let mut pattern = Pattern::new();
+        for number in 0..4 {
+            pattern.tracks[0].steps[number] = step(36, 50, 100, 1);
+        }
+        pattern.tracks[1].steps[1] = step(38, 50, 100, 2);
+        pattern.tracks[2].rate = 12;
+        pattern.tracks[2].steps[1] = step(42, 50, 100, 1);
+        let straight = played(&mut Sequencer::new(), &Options::new(), &pattern, 0..500);
+        assert_eq!(ons(&straight, 36), [0, 125, 250, 375]);
+
+        // 66 %: the first of a pair takes two thirds, the second is 39 ms late
+        let options = Options { swing: 66, ..Options::new() };
+        let log = played(&mut Sequencer::new(), &options, &pattern, 0..500);
+        assert_eq!(ons(&log, 36), [0, 164, 250, 414]);
+        assert_eq!(offs(&log, 36), [62, 206, 312, 456]);
+        // Gates and ratchets of a swung step fit what is left of it
+        assert_eq!(ons(&log, 38), [164, 206]);
+        assert_eq!(offs(&log, 38), [185, 227]);
+        // Every track swings its own steps
+        assert_eq!(ons(&log, 42), [250 + 79]);
+
+        // Most swing, and out of range values are held to it
+        let options = Options { swing: 90, ..Options::new() };
+        let log = played(&mut Sequencer::new(), &options, &pattern, 0..500);
+        assert_eq!(ons(&log, 36), [0, 187, 250, 437]);
+    }
+
+    #[test]
+    fn follows_the_external_clock() {
+        let options = Options { source: ClockSource::External, swing: 66, ..Options::new() };
+        let mut pattern = Pattern::new();
+        for number in [0, 1, 4] {
+            pattern.tracks[0].steps[number] = step(36, 50, 100, 1);
+        }
+        let mut sequencer = Sequencer::new();
+        let mut log = Vec::new();
+        sequencer.midi(0, &MidiMessage::Start, &options, &pattern, &mut |m| log.push((0, m)));
+        // 100 BPM: a clock every 25 ms, two beats
+        for clock in 0..48 {
+            let now = clock * 25;
+            sequencer.midi(now, &MidiMessage::Clock, &options, &pattern, &mut |m| log.push((now, m)));
+            log.extend(run(&mut sequencer, &options, &pattern, now..now + 25));
+        }
+        assert!(!log.iter().any(|(_, m)| matches!(m, MidiMessage::Clock | MidiMessage::Start)));
+        // The period is measured as clocks come in: by the second step it
+        // is still close to the 120 BPM assumed
+        let kicks = ons(&log, 36);
+        assert_eq!(kicks.len(), 3);
+        assert_eq!((kicks[0], kicks[2]), (0, 600));
+        assert!((150 + 40..150 + 50).contains(&kicks[1]), "{kicks:?}");
+        assert_eq!(sequencer.position().unwrap()[0], 7);
+
+        // Ignored when the internal clock runs
+        let mut log = Vec::new();
+        let internal = Options::new();
+        sequencer.midi(1200, &MidiMessage::Stop, &internal, &pattern, &mut |m| log.push(m));
+        assert!(sequencer.position().is_some() && log.is_empty());
+    }
+
+    #[test]
+    fn stops_and_continues() {
+        let mut pattern = Pattern::new();
+        pattern.tracks[0].steps[1] = step(36, 50, 100, 1);
+        pattern.tracks[2].rate = 12;
+        pattern.tracks[2].steps[0] = step(42, 100, 100, 1);
+        let options = Options { swing: 75, ..Options::new() };
+        let mut sequencer = Sequencer::new();
+        let log = played(&mut sequencer, &options, &pattern, 0..150);
+        assert_eq!(ons(&log, 42), [0]);
+        assert!(ons(&log, 36).is_empty());
+
+        // Stopping ends the note and drops the swung step still to come
+        sequencer.stop();
+        assert!(!sequencer.playing());
+        let log = run(&mut sequencer, &options, &pattern, 150..400);
+        let off = MidiMessage::NoteOff { channel: 9, note: 42, velocity: 0 };
+        assert_eq!(log, [(150, off), (150, MidiMessage::Stop)]);
+        assert!(sequencer.position().is_none());
+
+        sequencer.resume();
+        let log = run(&mut sequencer, &options, &pattern, 400..530);
+        // On from the step it stopped on, a clock after the next
+        assert_eq!(log[0], (400, MidiMessage::Continue));
+        assert_eq!(log.iter().find(|(_, m)| *m == MidiMessage::Clock), Some(&(421, MidiMessage::Clock)));
+        assert_eq!(sequencer.position().unwrap()[0], 2);
+    }
+}
@@ -0,0 +1,182 @@
+//! Shift Register Expansion
+//!
+//! Daisy-chained 74HC595 outputs and 74HC165 inputs on SSI2: PB4 clocks
+//! both chains, PB7 feeds the first 595, the last 165 in the chain drives
+//! PB6 and PB5 is the shared latch (595 RCLK, 165 SH/LD). A 1 kHz timer
+//! interrupt pulses the latch, which loads the 165 inputs and shows the
+//! outputs shifted on the previous tick, then clocks a byte per chip through
+//! both chains at once.
+//!
+//! Inputs are buttons to ground with pull-ups, controls `input::ids::SHIFT`
+//! and up; outputs are LEDs `led::ids::SHIFT` and up, on for any level
+//! above 0. Chip 0 is the one nearest the controller in both chains.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use tm4c123x::{GPIO_PORTB, SSI2, SYSCTL, TIMER1};
+
+use crate::input::{self, Input, InputEvent, InputSource, MAX_SHIFT_INPUTS};
+use crate::led::{self, LedId, LedOutput, MAX_SHIFT_OUTPUTS};
+use crate::usb_device;
+
+/// Chips per chain
+const MAX_CHIPS: usize = 8;
+
+/// Refresh rate of both chains
+const REFRESH_HZ: u32 = 1000;
+/// SSI clock, well within both chips at 3.3 V
+const SSI_HZ: u32 = 4_000_000;
+
+/// PB4 clock, PB6 data in, PB7 data out
+const SSI_PINS: u32 = 0xD0;
+const LATCH: u32 = 0x20;
+
+/// Time an input must be stable before a change is reported
+const DEBOUNCE_MS: u32 = 10;
+
+// Shared with the timer interrupt, one byte per chip
+static OUTPUTS: [AtomicU8; MAX_CHIPS] = [const { AtomicU8::new(0) }; MAX_CHIPS];
+static INPUTS: [AtomicU8; MAX_CHIPS] = [const { AtomicU8::new(0xFF) }; MAX_CHIPS];
+static OUTPUT_CHIPS: AtomicU8 = AtomicU8::new(0);
+static INPUT_CHIPS: AtomicU8 = AtomicU8::new(0);
+
+/// Both chains, as an input source and an LED output
+pub struct Expansion {
+    stable: u64,
+    last_raw: u64,
+    changed_at: u32,
+}
+
+impl Expansion {
+    /// Set up SSI2, the latch and the refresh timer; both chains start
+    /// empty.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let portb = unsafe { &*GPIO_PORTB::ptr() };
+        let ssi = unsafe { &*SSI2::ptr() };
+        let timer = unsafe { &*TIMER1::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        while sysctl.prgpio.read().bits() & (1 << 1) == 0 {}
+        sysctl.rcgcssi.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 2)) });
+        while sysctl.prssi.read().bits() & (1 << 2) == 0 {}
+        sysctl.rcgctimer.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        while sysctl.prtimer.read().bits() & (1 << 1) == 0 {}
+
+        // SSI2 is alternate function 2 on PB4, PB6 and PB7; latch idles high
+        portb.afsel.modify(|r, w| unsafe { w.bits(r.bits() | SSI_PINS) });
+        portb.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0xFF0F_0000) | 0x2202_0000) });
+        portb.data.modify(|r, w| unsafe { w.bits(r.bits() | LATCH) });
+        portb.dir.modify(|r, w| unsafe { w.bits(r.bits() | LATCH) });
+        portb.den.modify(|r, w| unsafe { w.bits(r.bits() | SSI_PINS | LATCH) });
+
+        let clock = unsafe { usb_device::SysCtlClockGet() };
+        unsafe {
+            // SPI mode 0, 8-bit frames
+            ssi.cr1.write(|w| w.bits(0));
+            ssi.cc.write(|w| w.bits(0));
+            ssi.cpsr.write(|w| w.bits((clock / SSI_HZ).next_multiple_of(2).max(2)));
+            ssi.cr0.write(|w| w.bits(0x7));
+            ssi.cr1.write(|w| w.bits(1 << 1));
+
+            // Periodic 32-bit timer A
+            timer.ctl.write(|w| w.bits(0));
+            timer.cfg.write(|w| w.bits(0));
+            timer.tamr.write(|w| w.bits(0x2));
+            timer.tailr.write(|w| w.bits(clock / REFRESH_HZ - 1));
+            timer.icr.write(|w| w.bits(1));
+            timer.imr.write(|w| w.bits(1));
+            usb_device::IntRegister(usb_device::ints::INT_TIMER1A, refresh);
+            usb_device::IntEnable(usb_device::ints::INT_TIMER1A);
+            timer.ctl.write(|w| w.bits(1));
+        }
+
+        Self { stable: 0, last_raw: 0, changed_at: 0 }
+    }
+
+    /// Follow the settings: chips in each chain.
+    pub fn configure(&mut self, outputs: u8, inputs: u8) {
+        OUTPUT_CHIPS.store(outputs.min(MAX_CHIPS as u8), Ordering::Relaxed);
+        INPUT_CHIPS.store(inputs.min(MAX_CHIPS as u8), Ordering::Relaxed);
+    }
+
+    /// Pressed inputs as a bit mask, bit 8 * chip + input
+    fn read() -> u64 {
+        let chips = INPUT_CHIPS.load(Ordering::Relaxed) as usize;
+        INPUTS[..chips]
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (chip, byte)| mask | ((!byte.load(Ordering::Relaxed) as u64) << (8 * chip)))
+    }
+}
+
+impl InputSource for Expansion {
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        let raw = Self::read();
+        if raw != self.last_raw {
+            self.last_raw = raw;
+            self.changed_at = now;
+            return;
+        }
+        if raw == self.stable || now.wrapping_sub(self.changed_at) < DEBOUNCE_MS {
+            return;
+        }
+        let changed = raw ^ self.stable;
+        self.stable = raw;
+        for index in (0..MAX_SHIFT_INPUTS).filter(|i| changed & (1 << i) != 0) {
+            let input = if raw & (1 << index) != 0 { Input::Press(None) } else { Input::Release };
+            emit(InputEvent { control: input::ids::SHIFT + index as u8, input });
+        }
+    }
+}
+
+impl LedOutput for Expansion {
+    fn set(&mut self, led: LedId, level: u8) {
+        let Some(index) = led.checked_sub(led::ids::SHIFT).map(usize::from) else {
+            return;
+        };
+        if index >= MAX_SHIFT_OUTPUTS {
+            return;
+        }
+        let bit = 1 << (index % 8);
+        if level > 0 {
+            OUTPUTS[index / 8].fetch_or(bit, Ordering::Relaxed);
+        } else {
+            OUTPUTS[index / 8].fetch_and(!bit, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Timer interrupt: latch, then exchange a byte per chip with both chains.
+unsafe extern "C" fn refresh() {
+    let portb = &*GPIO_PORTB::ptr();
+    let ssi = &*SSI2::ptr();
+    let timer = &*TIMER1::ptr();
+    timer.icr.write(|w| w.bits(1));
+
+    let outputs = OUTPUT_CHIPS.load(Ordering::Relaxed) as usize;
+    let inputs = INPUT_CHIPS.load(Ordering::Relaxed) as usize;
+    if outputs == 0 && inputs == 0 {
+        return;
+    }
+
+    // 165 loads while the latch is low, 595 outputs update on the rising edge
+    portb.data.modify(|r, w| w.bits(r.bits() & !LATCH));
+    cortex_m::asm::delay(8);
+    portb.data.modify(|r, w| w.bits(r.bits() | LATCH));
+
+    // The first byte out ends up in the last 595, padding goes first so
+    // it falls off the end of a shorter output chain
+    let bytes = outputs.max(inputs);
+    for (i, input) in INPUTS.iter().enumerate().take(bytes) {
+        let chip = bytes - 1 - i;
+        let out = if chip < outputs { OUTPUTS[chip].load(Ordering::Relaxed) } else { 0 };
+        ssi.dr.write(|w| w.bits(out as u32));
+        // RNE
+        while ssi.sr.read().bits() & (1 << 2) == 0 {}
+        let byte = ssi.dr.read().bits() as u8;
+        if i < inputs {
+            input.store(byte, Ordering::Relaxed);
+        }
+    }
+}
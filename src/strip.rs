@@ -0,0 +1,161 @@
+//! Addressable LED Strip
+//!
+//! WS2812/SK6812 pixels on PA5 (SSI0Tx). The SSI runs at three times the
+//! pixel bit rate and every pixel bit becomes three SSI bits, `110` for a 1
+//! and `100` for a 0, so the waveform comes out of the SSI without gaps while
+//! the uDMA feeds it from a buffer: the CPU only fills the buffer. Trailing
+//! zero bytes hold the line low long enough to latch the frame. PA5 needs
+//! its pull-down because the SSI leaves the pin floating between frames.
+//!
+//! Pixels are LEDs `ids::STRIP` and up. A level picks a palette colour, so a
+//! pad lights in the colour of the velocity the host sends.
+
+use tm4c123x::{GPIO_PORTA, SSI0, SYSCTL};
+
+use crate::clock;
+use crate::dma;
+use crate::led::{ids, Color, LedId, LedOutput, Options, MAX_PIXELS};
+use crate::palette;
+use crate::usb_device;
+
+/// SSI bit rate, three per 1.2 us pixel bit
+const BIT_HZ: u32 = 2_500_000;
+
+/// PA5
+const TX_PIN: u32 = 0x20;
+
+/// uDMA channel 11 is SSI0 TX with encoding 0
+const DMA_CHANNEL: u8 = 11;
+
+/// SSI bytes per pixel: 24 bits of GRB, three SSI bits each
+const PIXEL_BYTES: usize = 9;
+/// Low time after a frame, 96 bytes = 320 us, enough for WS2812B too
+const LATCH_BYTES: usize = 96;
+
+/// Shortest time between frames in ms
+const REFRESH_MS: u32 = 10;
+
+const BUFFER_LEN: usize = MAX_PIXELS * PIXEL_BYTES + LATCH_BYTES;
+
+/// Encoded frame, read by the uDMA
+static mut BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+
+/// Frame buffer of the strip
+pub struct Strip {
+    pixels: [Color; MAX_PIXELS],
+    /// Pixels attached
+    len: usize,
+    /// Length before the strip got shorter, until a frame darkened the rest
+    clear_to: usize,
+    brightness: u8,
+    /// The frame buffer changed since the last frame went out
+    dirty: bool,
+    shown_at: u32,
+}
+
+impl Strip {
+    /// Set up SSI0 and its uDMA channel; the strip stays dark until it has
+    /// a length.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        let porta = unsafe { &*GPIO_PORTA::ptr() };
+        let ssi = unsafe { &*SSI0::ptr() };
+
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.prgpio.read().bits() & 1 == 0 {}
+        sysctl.rcgcssi.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        while sysctl.prssi.read().bits() & 1 == 0 {}
+
+        // SSI0Tx is alternate function 2 on PA5
+        porta.afsel.modify(|r, w| unsafe { w.bits(r.bits() | TX_PIN) });
+        porta.pctl.modify(|r, w| unsafe { w.bits((r.bits() & !0x00F0_0000) | 0x0020_0000) });
+        porta.pdr.modify(|r, w| unsafe { w.bits(r.bits() | TX_PIN) });
+        porta.den.modify(|r, w| unsafe { w.bits(r.bits() | TX_PIN) });
+
+        // Even prescaler, serial clock rate 0
+        let divider = (unsafe { usb_device::SysCtlClockGet() } / BIT_HZ) & !1;
+        unsafe {
+            ssi.cr1.write(|w| w.bits(0));
+            ssi.cc.write(|w| w.bits(0));
+            ssi.cpsr.write(|w| w.bits(divider.max(2)));
+            // Freescale SPI with SPH = 1 sends back-to-back frames without
+            // gaps; 8-bit frames
+            ssi.cr0.write(|w| w.bits((1 << 7) | 0x7));
+            ssi.dmactl.write(|w| w.bits(1 << 1));
+            ssi.cr1.write(|w| w.bits(1 << 1));
+        }
+
+        dma::init();
+        dma::assign(DMA_CHANNEL, 0);
+
+        Self { pixels: [Color::OFF; MAX_PIXELS], len: 0, clear_to: 0, brightness: 255, dirty: false, shown_at: 0 }
+    }
+
+    /// Follow the settings: strip length and global brightness.
+    pub fn configure(&mut self, len: u8, options: &Options) {
+        let len = (len as usize).min(MAX_PIXELS);
+        if len < self.len {
+            self.pixels[len..self.len].fill(Color::OFF);
+            self.clear_to = self.clear_to.max(self.len);
+        }
+        if len != self.len || options.brightness != self.brightness {
+            self.dirty = true;
+        }
+        self.len = len;
+        self.brightness = options.brightness;
+    }
+
+    pub fn pixel(&self, index: usize) -> Color {
+        self.pixels.get(index).copied().unwrap_or(Color::OFF)
+    }
+
+    pub fn set_pixel(&mut self, index: usize, color: Color) {
+        if index < self.len && self.pixels[index] != color {
+            self.pixels[index] = color;
+            self.dirty = true;
+        }
+    }
+
+    /// Send the frame buffer when it changed and the last frame is out.
+    pub fn poll(&mut self, now: u32) {
+        // Pixels that fell off the end go dark with the next frame
+        let frame = self.len.max(self.clear_to);
+        if !self.dirty || frame == 0 || dma::busy(DMA_CHANNEL) {
+            return;
+        }
+        if !clock::reached(now, self.shown_at.wrapping_add(REFRESH_MS)) {
+            return;
+        }
+        let ssi = unsafe { &*SSI0::ptr() };
+        // The uDMA is idle, the buffer is ours
+        let buffer = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
+        let (pixels, _) = buffer.as_chunks_mut::<PIXEL_BYTES>();
+        for (pixel, bytes) in self.pixels[..frame].iter().zip(pixels) {
+            let Color { r, g, b } = palette::scale(*pixel, self.brightness);
+            let (channels, _) = bytes.as_chunks_mut::<3>();
+            for (channel, out) in [g, r, b].iter().zip(channels) {
+                *out = encode(*channel);
+            }
+        }
+        let end = frame * PIXEL_BYTES;
+        buffer[end..end + LATCH_BYTES].fill(0);
+        unsafe { dma::send(DMA_CHANNEL, &buffer[..end + LATCH_BYTES], core::ptr::addr_of!(ssi.dr) as *const u32) };
+        self.dirty = false;
+        self.clear_to = 0;
+        self.shown_at = now;
+    }
+}
+
+/// The 24 SSI bits of one colour byte, MSB first
+fn encode(byte: u8) -> [u8; 3] {
+    let bits = (0..8).rev().fold(0u32, |bits, i| (bits << 3) | if byte & (1 << i) != 0 { 0b110 } else { 0b100 });
+    [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8]
+}
+
+impl LedOutput for Strip {
+    fn set(&mut self, led: LedId, level: u8) {
+        if let Some(index) = led.checked_sub(ids::STRIP) {
+            self.set_pixel(index as usize, palette::velocity(level));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Touch Input Driver
+//!
+//! Capacitive touch pads and a ribbon strip on up to eight plain GPIO
+//! pins, each with a 1 MΩ pull-up to 3.3 V: PE0, PE1, PD2, PD3, PC6, PC7,
+//! PB0 and PB1, in that order. Drum pads (`drumpads`) take the first pins
+//! as analog inputs, the ribbon electrodes the pins after them, the pads
+//! the rest. Like the fader knobs, a pin is discharged and
+//! the time until it reads high again is counted; a finger adds
+//! capacitance and stretches it.
+//!
+//! One pin is measured per millisecond, so a slow or broken electrode
+//! never holds up the main loop for long; pads and ribbon are updated once
+//! all pins have a new reading. Filtering and touch detection are in
+//! `capsense`.
+
+use tm4c123x::{gpio_porta, GPIO_PORTB, GPIO_PORTC, GPIO_PORTD, GPIO_PORTE, SYSCTL};
+
+use crate::capsense::{Pads, Ribbon, MAX_CHANNELS};
+use crate::input::{InputEvent, InputSource};
+
+/// Electrode pins, port and pin mask
+const PINS: [(*const gpio_porta::RegisterBlock, u32); MAX_CHANNELS] = [
+    (GPIO_PORTE::ptr(), 0x01),
+    (GPIO_PORTE::ptr(), 0x02),
+    (GPIO_PORTD::ptr(), 0x04),
+    (GPIO_PORTD::ptr(), 0x08),
+    (GPIO_PORTC::ptr(), 0x40),
+    (GPIO_PORTC::ptr(), 0x80),
+    (GPIO_PORTB::ptr(), 0x01),
+    (GPIO_PORTB::ptr(), 0x02),
+];
+
+/// Longest measurement in loop passes, a shorted or open electrode must
+/// not stall the main loop
+const TOUCH_LIMIT: u32 = 2000;
+
+pub struct TouchInputs {
+    pads: Pads,
+    ribbon: Ribbon,
+    /// First pin not taken by drum pads, then electrodes of the ribbon,
+    /// then pads
+    first: usize,
+    ribbon_len: usize,
+    pad_count: usize,
+    readings: [u32; MAX_CHANNELS],
+    /// Pin measured next
+    next: usize,
+    measured_at: u32,
+}
+
+impl TouchInputs {
+    /// Clock the ports; no electrodes until `configure`.
+    pub fn new() -> Self {
+        let sysctl = unsafe { &*SYSCTL::ptr() };
+        // Ports B, C, D and E
+        sysctl.rcgcgpio.modify(|r, w| unsafe { w.bits(r.bits() | 0x1E) });
+        while sysctl.prgpio.read().bits() & 0x1E != 0x1E {}
+
+        Self {
+            pads: Pads::new(),
+            ribbon: Ribbon::new(),
+            first: 0,
+            ribbon_len: 0,
+            pad_count: 0,
+            readings: [0; MAX_CHANNELS],
+            next: 0,
+            measured_at: 0,
+        }
+    }
+
+    /// Follow the settings: pins taken by drum pads, ribbon electrodes and
+    /// pads, as many as there are pins left.
+    pub fn configure(&mut self, drums: u8, ribbon: u8, pads: u8) {
+        let first = (drums as usize).min(MAX_CHANNELS);
+        let ribbon_len = (ribbon as usize).min(MAX_CHANNELS - first);
+        let pad_count = (pads as usize).min(MAX_CHANNELS - first - ribbon_len);
+        if (first, ribbon_len, pad_count) == (self.first, self.ribbon_len, self.pad_count) {
+            return;
+        }
+        let used = first..first + ribbon_len + pad_count;
+        let was_used = self.first..self.first + self.ribbon_len + self.pad_count;
+        for (index, &(port, pin)) in PINS.iter().enumerate() {
+            let port = unsafe { &*port };
+            // Used pins are driven low between measurements, others left alone
+            if used.contains(&index) {
+                port.data.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+                port.dir.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+                port.den.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+            } else if was_used.contains(&index) {
+                port.dir.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+            }
+        }
+        // Electrodes moving between ribbon and pads start over
+        self.pads = Pads::new();
+        self.ribbon = Ribbon::new();
+        self.first = first;
+        self.ribbon_len = ribbon_len;
+        self.pad_count = pad_count;
+        self.next = 0;
+    }
+
+    /// Charge time of a pin in loop passes
+    fn charge_time(index: usize) -> u32 {
+        let (port, pin) = PINS[index];
+        let port = unsafe { &*port };
+        // Interrupts would stretch the count like a finger does
+        let time = cortex_m::interrupt::free(|_| {
+            port.dir.modify(|r, w| unsafe { w.bits(r.bits() & !pin) });
+            let mut time = 0;
+            while port.data.read().bits() & pin == 0 && time < TOUCH_LIMIT {
+                time += 1;
+            }
+            time
+        });
+        port.dir.modify(|r, w| unsafe { w.bits(r.bits() | pin) });
+        time
+    }
+}
+
+impl InputSource for TouchInputs {
+    fn poll(&mut self, now: u32, emit: &mut dyn FnMut(InputEvent)) {
+        let used = self.ribbon_len + self.pad_count;
+        if used == 0 || now == self.measured_at {
+            return;
+        }
+        self.measured_at = now;
+        self.readings[self.next] = Self::charge_time(self.first + self.next);
+        self.next += 1;
+        if self.next < used {
+            return;
+        }
+        self.next = 0;
+        let (ribbon, pads) = self.readings[..used].split_at(self.ribbon_len);
+        if !ribbon.is_empty() {
+            self.ribbon.update(now, ribbon, emit);
+        }
+        self.pads.update(now, pads, emit);
+    }
+}
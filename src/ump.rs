@@ -0,0 +1,1161 @@
+//! Universal MIDI Packets
+//!
+//! MIDI 2.0 packet model: 32/64/128-bit packets, MIDI 1.0 and MIDI 2.0
+//! channel voice messages, SysEx7/SysEx8 and UMP stream messages, plus the
+//! translation between UMP and the MIDI 1.0 messages the router carries.
+//!
+//! MIDI 1.0 messages translate to UMP losslessly: with the MIDI 1.0 protocol
+//! they become MIDI 1.0 channel voice packets, with the MIDI 2.0 protocol
+//! values are upscaled with the min-center-max scheme of the UMP
+//! specification, which downscales back to the original value.
+//!
+//! Nothing in here touches hardware.
+
+use crate::hires::{self, ParamKind};
+use crate::midi::MidiMessage;
+
+/// Message types (top nibble of the first word)
+pub mod mt {
+    pub const UTILITY: u8 = 0x0;
+    pub const SYSTEM: u8 = 0x1;
+    pub const MIDI1: u8 = 0x2;
+    pub const SYSEX7: u8 = 0x3;
+    pub const MIDI2: u8 = 0x4;
+    pub const SYSEX8: u8 = 0x5;
+    pub const STREAM: u8 = 0xF;
+}
+
+/// Packet length in 32-bit words for each message type
+pub fn packet_words(message_type: u8) -> usize {
+    match message_type & 0x0F {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// Protocol spoken on a UMP endpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Midi1,
+    Midi2,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Midi1 => "MIDI 1.0",
+            Protocol::Midi2 => "MIDI 2.0",
+        }
+    }
+
+    /// Value used in stream configuration messages, also the protocol type
+    /// in MIDI-CI protocol negotiation
+    pub fn stream_value(self) -> u8 {
+        match self {
+            Protocol::Midi1 => 0x01,
+            Protocol::Midi2 => 0x02,
+        }
+    }
+
+    pub fn from_stream_value(value: u8) -> Option<Protocol> {
+        match value {
+            0x01 => Some(Protocol::Midi1),
+            0x02 => Some(Protocol::Midi2),
+            _ => None,
+        }
+    }
+}
+
+/// One Universal MIDI Packet, 1 to 4 words
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ump {
+    words: [u32; 4],
+}
+
+impl Ump {
+    pub const fn new(words: [u32; 4]) -> Self {
+        Self { words }
+    }
+
+    /// Packet from its words; `words` must hold at least a whole packet.
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        let len = packet_words((*words.first()? >> 28) as u8);
+        let mut packet = [0; 4];
+        packet[..len].copy_from_slice(words.get(..len)?);
+        Some(Self { words: packet })
+    }
+
+    pub fn message_type(&self) -> u8 {
+        (self.words[0] >> 28) as u8
+    }
+
+    pub fn group(&self) -> u8 {
+        (self.words[0] >> 24) as u8 & 0x0F
+    }
+
+    pub fn words(&self) -> &[u32] {
+        &self.words[..packet_words(self.message_type())]
+    }
+
+    fn byte(&self, word: usize, index: usize) -> u8 {
+        (self.words[word] >> (24 - 8 * index)) as u8
+    }
+}
+
+fn word(b0: u8, b1: u8, b2: u8, b3: u8) -> u32 {
+    u32::from_be_bytes([b0, b1, b2, b3])
+}
+
+/// First byte of a packet: message type and group
+fn header(message_type: u8, group: u8) -> u8 {
+    message_type << 4 | group & 0x0F
+}
+
+// ============================================================================
+// Scaling
+// ============================================================================
+
+/// Min-center-max upscaling from the UMP specification.
+pub fn scale_up(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    let scale_bits = dst_bits - src_bits;
+    let shifted = value << scale_bits;
+    let center = 1 << (src_bits - 1);
+    if value <= center {
+        return shifted;
+    }
+    // Above the center the lower bits are filled by repeating the value
+    let repeat_bits = src_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    repeat = if scale_bits > repeat_bits {
+        repeat << (scale_bits - repeat_bits)
+    } else {
+        repeat >> (repeat_bits - scale_bits)
+    };
+    let mut result = shifted;
+    while repeat != 0 {
+        result |= repeat;
+        repeat >>= repeat_bits;
+    }
+    result
+}
+
+pub fn scale_down(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    value >> (src_bits - dst_bits)
+}
+
+// ============================================================================
+// MIDI 2.0 Channel Voice
+// ============================================================================
+
+/// MIDI 2.0 channel voice message. Velocities are 16 bit, everything else
+/// 32 bit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Midi2Message {
+    NoteOff { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    NoteOn { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    PolyPressure { channel: u8, note: u8, value: u32 },
+    RegisteredPerNoteController { channel: u8, note: u8, index: u8, value: u32 },
+    AssignablePerNoteController { channel: u8, note: u8, index: u8, value: u32 },
+    PerNoteManagement { channel: u8, note: u8, flags: u8 },
+    ControlChange { channel: u8, index: u8, value: u32 },
+    Rpn { channel: u8, bank: u8, index: u8, value: u32 },
+    Nrpn { channel: u8, bank: u8, index: u8, value: u32 },
+    RelativeRpn { channel: u8, bank: u8, index: u8, value: i32 },
+    RelativeNrpn { channel: u8, bank: u8, index: u8, value: i32 },
+    ProgramChange { channel: u8, program: u8, bank: Option<(u8, u8)> },
+    ChannelPressure { channel: u8, value: u32 },
+    PitchBend { channel: u8, value: u32 },
+    PerNotePitchBend { channel: u8, note: u8, value: u32 },
+}
+
+impl Midi2Message {
+    fn decode(ump: &Ump) -> Option<Self> {
+        let status = ump.byte(0, 1);
+        let channel = status & 0x0F;
+        let (b2, b3) = (ump.byte(0, 2) & 0x7F, ump.byte(0, 3));
+        let data = ump.words[1];
+        let (high, low) = ((data >> 16) as u16, data as u16);
+        Some(match status >> 4 {
+            0x0 => Self::RegisteredPerNoteController { channel, note: b2, index: b3, value: data },
+            0x1 => Self::AssignablePerNoteController { channel, note: b2, index: b3, value: data },
+            0x2 => Self::Rpn { channel, bank: b2, index: b3 & 0x7F, value: data },
+            0x3 => Self::Nrpn { channel, bank: b2, index: b3 & 0x7F, value: data },
+            0x4 => Self::RelativeRpn { channel, bank: b2, index: b3 & 0x7F, value: data as i32 },
+            0x5 => Self::RelativeNrpn { channel, bank: b2, index: b3 & 0x7F, value: data as i32 },
+            0x6 => Self::PerNotePitchBend { channel, note: b2, value: data },
+            0x8 => Self::NoteOff { channel, note: b2, velocity: high, attribute_type: b3, attribute: low },
+            0x9 => Self::NoteOn { channel, note: b2, velocity: high, attribute_type: b3, attribute: low },
+            0xA => Self::PolyPressure { channel, note: b2, value: data },
+            0xB => Self::ControlChange { channel, index: b2, value: data },
+            0xC => Self::ProgramChange {
+                channel,
+                program: (data >> 24) as u8 & 0x7F,
+                bank: (b3 & 1 != 0).then_some(((data >> 8) as u8 & 0x7F, data as u8 & 0x7F)),
+            },
+            0xD => Self::ChannelPressure { channel, value: data },
+            0xE => Self::PitchBend { channel, value: data },
+            0xF => Self::PerNoteManagement { channel, note: b2, flags: b3 },
+            _ => return None,
+        })
+    }
+
+    fn encode(&self, group: u8) -> Ump {
+        let (opcode, channel, b2, b3, data) = match *self {
+            Self::RegisteredPerNoteController { channel, note, index, value } => (0x0, channel, note, index, value),
+            Self::AssignablePerNoteController { channel, note, index, value } => (0x1, channel, note, index, value),
+            Self::Rpn { channel, bank, index, value } => (0x2, channel, bank, index, value),
+            Self::Nrpn { channel, bank, index, value } => (0x3, channel, bank, index, value),
+            Self::RelativeRpn { channel, bank, index, value } => (0x4, channel, bank, index, value as u32),
+            Self::RelativeNrpn { channel, bank, index, value } => (0x5, channel, bank, index, value as u32),
+            Self::PerNotePitchBend { channel, note, value } => (0x6, channel, note, 0, value),
+            Self::NoteOff { channel, note, velocity, attribute_type, attribute } => {
+                (0x8, channel, note, attribute_type, (velocity as u32) << 16 | attribute as u32)
+            }
+            Self::NoteOn { channel, note, velocity, attribute_type, attribute } => {
+                (0x9, channel, note, attribute_type, (velocity as u32) << 16 | attribute as u32)
+            }
+            Self::PolyPressure { channel, note, value } => (0xA, channel, note, 0, value),
+            Self::ControlChange { channel, index, value } => (0xB, channel, index, 0, value),
+            Self::ProgramChange { channel, program, bank } => {
+                let (msb, lsb) = bank.unwrap_or((0, 0));
+                let data = (program as u32) << 24 | (msb as u32) << 8 | lsb as u32;
+                (0xC, channel, 0, bank.is_some() as u8, data)
+            }
+            Self::ChannelPressure { channel, value } => (0xD, channel, 0, 0, value),
+            Self::PitchBend { channel, value } => (0xE, channel, 0, 0, value),
+            Self::PerNoteManagement { channel, note, flags } => (0xF, channel, note, flags, 0),
+        };
+        let status = opcode << 4 | channel & 0x0F;
+        Ump::new([word(header(mt::MIDI2, group), status, b2 & 0x7F, b3), data, 0, 0])
+    }
+}
+
+// ============================================================================
+// Stream Messages
+// ============================================================================
+
+/// UMP stream message status values
+pub mod stream {
+    pub const ENDPOINT_DISCOVERY: u16 = 0x000;
+    pub const ENDPOINT_INFO: u16 = 0x001;
+    pub const DEVICE_IDENTITY: u16 = 0x002;
+    pub const ENDPOINT_NAME: u16 = 0x003;
+    pub const PRODUCT_INSTANCE_ID: u16 = 0x004;
+    pub const CONFIG_REQUEST: u16 = 0x005;
+    pub const CONFIG_NOTIFICATION: u16 = 0x006;
+    pub const FUNCTION_BLOCK_DISCOVERY: u16 = 0x010;
+    pub const FUNCTION_BLOCK_INFO: u16 = 0x011;
+    pub const FUNCTION_BLOCK_NAME: u16 = 0x012;
+    pub const START_OF_CLIP: u16 = 0x020;
+    pub const END_OF_CLIP: u16 = 0x021;
+
+    /// Endpoint discovery filter bits
+    pub const WANT_INFO: u8 = 0x01;
+    pub const WANT_DEVICE_IDENTITY: u8 = 0x02;
+    pub const WANT_NAME: u8 = 0x04;
+    pub const WANT_PRODUCT_INSTANCE_ID: u8 = 0x08;
+    pub const WANT_STREAM_CONFIG: u8 = 0x10;
+
+    /// Function block discovery filter bits
+    pub const WANT_BLOCK_INFO: u8 = 0x01;
+    pub const WANT_BLOCK_NAME: u8 = 0x02;
+}
+
+/// Position of a packet in a multi-packet message (SysEx, stream text)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Complete = 0,
+    Start = 1,
+    Continue = 2,
+    End = 3,
+}
+
+impl Part {
+    fn from_bits(bits: u8) -> Part {
+        match bits & 3 {
+            0 => Part::Complete,
+            1 => Part::Start,
+            2 => Part::Continue,
+            _ => Part::End,
+        }
+    }
+
+    /// Part of packet `index` of `count`
+    fn of(index: usize, count: usize) -> Part {
+        match (index == 0, index + 1 == count) {
+            (true, true) => Part::Complete,
+            (true, false) => Part::Start,
+            (false, false) => Part::Continue,
+            (false, true) => Part::End,
+        }
+    }
+}
+
+/// Function block, see `Stream::FunctionBlockInfo`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FunctionBlock {
+    pub active: bool,
+    pub number: u8,
+    /// 1 receiver, 2 sender, 3 both
+    pub ui_hint: u8,
+    /// 0 MIDI 2.0, 1 MIDI 1.0 unrestricted, 2 MIDI 1.0 at 31.25 kbit/s
+    pub midi1: u8,
+    /// 1 input, 2 output, 3 bidirectional
+    pub direction: u8,
+    pub first_group: u8,
+    pub groups: u8,
+    pub ci_version: u8,
+    pub sysex8_streams: u8,
+}
+
+/// UMP stream message (message type 0xF)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    EndpointDiscovery { version: u16, filter: u8 },
+    EndpointInfo { version: u16, static_blocks: bool, blocks: u8, midi2: bool, midi1: bool },
+    DeviceIdentity { manufacturer: [u8; 3], family: u16, model: u16, revision: [u8; 4] },
+    /// Endpoint name, product instance id and function block name text
+    /// fragments: status, part, block number (names only) and text bytes
+    Text { status: u16, part: Part, block: u8, text: [u8; 14], len: u8 },
+    ConfigRequest { protocol: u8 },
+    ConfigNotification { protocol: u8 },
+    FunctionBlockDiscovery { block: u8, filter: u8 },
+    FunctionBlockInfo(FunctionBlock),
+    StartOfClip,
+    EndOfClip,
+    Unknown { status: u16 },
+}
+
+/// UMP version reported in endpoint info (1.1)
+pub const UMP_VERSION: u16 = 0x0101;
+
+impl Stream {
+    fn decode(ump: &Ump) -> Self {
+        let w = &ump.words;
+        let part = Part::from_bits((w[0] >> 26) as u8);
+        let status = (w[0] >> 16) as u16 & 0x3FF;
+        let b = |word: usize, index: usize| ump.byte(word, index);
+        match status {
+            stream::ENDPOINT_DISCOVERY => Stream::EndpointDiscovery { version: w[0] as u16, filter: w[1] as u8 },
+            stream::ENDPOINT_INFO => Stream::EndpointInfo {
+                version: w[0] as u16,
+                static_blocks: w[1] & (1 << 31) != 0,
+                blocks: (w[1] >> 24) as u8 & 0x7F,
+                midi2: w[1] & (1 << 9) != 0,
+                midi1: w[1] & (1 << 8) != 0,
+            },
+            stream::DEVICE_IDENTITY => Stream::DeviceIdentity {
+                manufacturer: [b(1, 1), b(1, 2), b(1, 3)],
+                family: b(2, 0) as u16 | (b(2, 1) as u16) << 7,
+                model: b(2, 2) as u16 | (b(2, 3) as u16) << 7,
+                revision: w[3].to_be_bytes(),
+            },
+            stream::ENDPOINT_NAME | stream::PRODUCT_INSTANCE_ID | stream::FUNCTION_BLOCK_NAME => {
+                let mut bytes = [0u8; 16];
+                bytes[..4].copy_from_slice(&w[0].to_be_bytes());
+                bytes[4..8].copy_from_slice(&w[1].to_be_bytes());
+                bytes[8..12].copy_from_slice(&w[2].to_be_bytes());
+                bytes[12..].copy_from_slice(&w[3].to_be_bytes());
+                // Function block names carry the block number in the first byte
+                let (block, start) = if status == stream::FUNCTION_BLOCK_NAME { (bytes[2], 3) } else { (0, 2) };
+                let mut text = [0u8; 14];
+                let source = &bytes[start..];
+                let len = source.iter().position(|&c| c == 0).unwrap_or(source.len());
+                text[..len].copy_from_slice(&source[..len]);
+                Stream::Text { status, part, block, text, len: len as u8 }
+            }
+            stream::CONFIG_REQUEST => Stream::ConfigRequest { protocol: b(0, 2) },
+            stream::CONFIG_NOTIFICATION => Stream::ConfigNotification { protocol: b(0, 2) },
+            stream::FUNCTION_BLOCK_DISCOVERY => Stream::FunctionBlockDiscovery { block: b(0, 2), filter: b(0, 3) },
+            stream::FUNCTION_BLOCK_INFO => Stream::FunctionBlockInfo(FunctionBlock {
+                active: b(0, 2) & 0x80 != 0,
+                number: b(0, 2) & 0x7F,
+                ui_hint: (b(0, 3) >> 4) & 3,
+                midi1: (b(0, 3) >> 2) & 3,
+                direction: b(0, 3) & 3,
+                first_group: b(1, 0),
+                groups: b(1, 1),
+                ci_version: b(1, 2),
+                sysex8_streams: b(1, 3),
+            }),
+            stream::START_OF_CLIP => Stream::StartOfClip,
+            stream::END_OF_CLIP => Stream::EndOfClip,
+            status => Stream::Unknown { status },
+        }
+    }
+
+    fn encode(&self) -> Ump {
+        let first = |part: Part, status: u16, data: u16| {
+            (mt::STREAM as u32) << 28 | (part as u32) << 26 | (status as u32) << 16 | data as u32
+        };
+        let words = match *self {
+            Stream::EndpointDiscovery { version, filter } => {
+                [first(Part::Complete, stream::ENDPOINT_DISCOVERY, version), filter as u32, 0, 0]
+            }
+            Stream::EndpointInfo { version, static_blocks, blocks, midi2, midi1 } => [
+                first(Part::Complete, stream::ENDPOINT_INFO, version),
+                (static_blocks as u32) << 31 | (blocks as u32 & 0x7F) << 24 | (midi2 as u32) << 9 | (midi1 as u32) << 8,
+                0,
+                0,
+            ],
+            Stream::DeviceIdentity { manufacturer, family, model, revision } => [
+                first(Part::Complete, stream::DEVICE_IDENTITY, 0),
+                word(0, manufacturer[0], manufacturer[1], manufacturer[2]),
+                word(family as u8 & 0x7F, (family >> 7) as u8 & 0x7F, model as u8 & 0x7F, (model >> 7) as u8 & 0x7F),
+                u32::from_be_bytes(revision),
+            ],
+            Stream::Text { status, part, block, text, len } => {
+                let mut bytes = [0u8; 16];
+                let start = if status == stream::FUNCTION_BLOCK_NAME {
+                    bytes[2] = block;
+                    3
+                } else {
+                    2
+                };
+                let len = (len as usize).min(16 - start);
+                bytes[start..start + len].copy_from_slice(&text[..len]);
+                let mut words = [0u32; 4];
+                for (i, chunk) in bytes.chunks(4).enumerate() {
+                    words[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+                words[0] = first(part, status, words[0] as u16);
+                words
+            }
+            Stream::ConfigRequest { protocol } => {
+                [first(Part::Complete, stream::CONFIG_REQUEST, (protocol as u16) << 8), 0, 0, 0]
+            }
+            Stream::ConfigNotification { protocol } => {
+                [first(Part::Complete, stream::CONFIG_NOTIFICATION, (protocol as u16) << 8), 0, 0, 0]
+            }
+            Stream::FunctionBlockDiscovery { block, filter } => {
+                [first(Part::Complete, stream::FUNCTION_BLOCK_DISCOVERY, (block as u16) << 8 | filter as u16), 0, 0, 0]
+            }
+            Stream::FunctionBlockInfo(fb) => [
+                first(
+                    Part::Complete,
+                    stream::FUNCTION_BLOCK_INFO,
+                    ((fb.active as u16) << 7 | fb.number as u16 & 0x7F) << 8
+                        | ((fb.ui_hint & 3) << 4 | (fb.midi1 & 3) << 2 | fb.direction & 3) as u16,
+                ),
+                word(fb.first_group, fb.groups, fb.ci_version, fb.sysex8_streams),
+                0,
+                0,
+            ],
+            Stream::StartOfClip => [first(Part::Complete, stream::START_OF_CLIP, 0), 0, 0, 0],
+            Stream::EndOfClip => [first(Part::Complete, stream::END_OF_CLIP, 0), 0, 0, 0],
+            Stream::Unknown { status } => [first(Part::Complete, status, 0), 0, 0, 0],
+        };
+        Ump::new(words)
+    }
+
+    /// Split a text into stream text packets.
+    pub fn text(status: u16, block: u8, text: &[u8], out: &mut dyn FnMut(Ump)) {
+        let per_packet = if status == stream::FUNCTION_BLOCK_NAME { 13 } else { 14 };
+        let count = text.len().div_ceil(per_packet).max(1);
+        for index in 0..count {
+            let chunk = &text[(index * per_packet).min(text.len())..((index + 1) * per_packet).min(text.len())];
+            let mut bytes = [0u8; 14];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let part = Part::of(index, count);
+            out(Stream::Text { status, part, block, text: bytes, len: chunk.len() as u8 }.encode());
+        }
+    }
+}
+
+// ============================================================================
+// Decoding
+// ============================================================================
+
+/// Utility message (message type 0)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Utility {
+    Noop,
+    JrClock(u16),
+    JrTimestamp(u16),
+    DeltaClockstampTicks(u16),
+    DeltaClockstamp(u32),
+    Unknown(u8),
+}
+
+/// Decoded content of a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    Utility(Utility),
+    /// System common and real-time (message type 1)
+    System(MidiMessage),
+    /// MIDI 1.0 channel voice (message type 2)
+    Midi1(MidiMessage),
+    /// 7-bit SysEx without the F0/F7 framing, up to 6 bytes per packet
+    SysEx7 { part: Part, data: [u8; 6], len: u8 },
+    Midi2(Midi2Message),
+    /// 8-bit SysEx, up to 13 bytes per packet
+    SysEx8 { part: Part, stream: u8, data: [u8; 13], len: u8 },
+    Stream(Stream),
+    /// Reserved message type or malformed packet
+    Unknown,
+}
+
+impl Ump {
+    pub fn decode(&self) -> Message {
+        let status = self.byte(0, 1);
+        let (d1, d2) = (self.byte(0, 2) & 0x7F, self.byte(0, 3) & 0x7F);
+        match self.message_type() {
+            mt::UTILITY => Message::Utility(match (self.words[0] >> 20) & 0xF {
+                0 => Utility::Noop,
+                1 => Utility::JrClock(self.words[0] as u16),
+                2 => Utility::JrTimestamp(self.words[0] as u16),
+                3 => Utility::DeltaClockstampTicks(self.words[0] as u16),
+                4 => Utility::DeltaClockstamp(self.words[0] & 0xF_FFFF),
+                other => Utility::Unknown(other as u8),
+            }),
+            mt::SYSTEM | mt::MIDI1 => {
+                // Type 1 only carries system messages, type 2 only channel voice
+                let expected = (status < 0xF0) == (self.message_type() == mt::MIDI1);
+                match MidiMessage::from_bytes(status, d1, d2) {
+                    Some(message) if expected && message.channel().is_some() => Message::Midi1(message),
+                    Some(message) if expected => Message::System(message),
+                    _ => Message::Unknown,
+                }
+            }
+            mt::SYSEX7 => {
+                let len = ((self.words[0] >> 16) & 0xF).min(6) as usize;
+                let bytes = [d1, d2, self.byte(1, 0), self.byte(1, 1), self.byte(1, 2), self.byte(1, 3)];
+                let mut data = [0u8; 6];
+                for (dst, &src) in data.iter_mut().zip(&bytes[..len]) {
+                    *dst = src & 0x7F;
+                }
+                Message::SysEx7 { part: Part::from_bits((self.words[0] >> 20) as u8), data, len: len as u8 }
+            }
+            mt::MIDI2 => Midi2Message::decode(self).map_or(Message::Unknown, Message::Midi2),
+            mt::SYSEX8 => {
+                // The byte count includes the stream id
+                let len = ((self.words[0] >> 16) & 0xF).clamp(1, 14) as usize - 1;
+                let mut bytes = [0u8; 13];
+                bytes[0] = self.byte(0, 3);
+                for i in 0..12 {
+                    bytes[1 + i] = self.byte(1 + i / 4, i % 4);
+                }
+                let mut data = [0u8; 13];
+                data[..len].copy_from_slice(&bytes[..len]);
+                Message::SysEx8 {
+                    part: Part::from_bits((self.words[0] >> 20) as u8),
+                    stream: self.byte(0, 2),
+                    data,
+                    len: len as u8,
+                }
+            }
+            mt::STREAM => Message::Stream(Stream::decode(self)),
+            _ => Message::Unknown,
+        }
+    }
+}
+
+impl Message {
+    /// Packet for this message in `group` (ignored for stream messages)
+    pub fn encode(&self, group: u8) -> Ump {
+        match *self {
+            Message::Utility(utility) => {
+                let (status, data) = match utility {
+                    Utility::Noop => (0, 0),
+                    Utility::JrClock(t) => (1, t as u32),
+                    Utility::JrTimestamp(t) => (2, t as u32),
+                    Utility::DeltaClockstampTicks(t) => (3, t as u32),
+                    Utility::DeltaClockstamp(t) => (4, t & 0xF_FFFF),
+                    Utility::Unknown(status) => (status as u32, 0),
+                };
+                Ump::new([(status & 0xF) << 20 | data, 0, 0, 0])
+            }
+            Message::System(message) | Message::Midi1(message) => {
+                let mut bytes = [0u8; 3];
+                message.encode(&mut bytes);
+                let message_type = if bytes[0] >= 0xF0 { mt::SYSTEM } else { mt::MIDI1 };
+                Ump::new([word(header(message_type, group), bytes[0], bytes[1], bytes[2]), 0, 0, 0])
+            }
+            Message::SysEx7 { part, data, len } => {
+                let b0 = header(mt::SYSEX7, group);
+                let b1 = (part as u8) << 4 | len.min(6);
+                Ump::new([word(b0, b1, data[0], data[1]), word(data[2], data[3], data[4], data[5]), 0, 0])
+            }
+            Message::Midi2(message) => message.encode(group),
+            Message::SysEx8 { part, stream, data, len } => {
+                let b1 = (part as u8) << 4 | (len.min(13) + 1);
+                Ump::new([
+                    word(header(mt::SYSEX8, group), b1, stream, data[0]),
+                    word(data[1], data[2], data[3], data[4]),
+                    word(data[5], data[6], data[7], data[8]),
+                    word(data[9], data[10], data[11], data[12]),
+                ])
+            }
+            Message::Stream(stream) => stream.encode(),
+            Message::Unknown => Ump::new([0; 4]),
+        }
+    }
+}
+
+// ============================================================================
+// Translation
+// ============================================================================
+
+/// Per-channel state for turning controller sequences into MIDI 2.0
+/// messages
+#[derive(Clone, Copy)]
+struct ChannelState {
+    bank: Option<(u8, u8)>,
+    /// Selected parameter: number space, MSB, LSB
+    param: Option<(ParamKind, u8, u8)>,
+    data_msb: u8,
+}
+
+const IDLE: ChannelState = ChannelState { bank: None, param: None, data_msb: 0 };
+
+/// MIDI 1.0 messages to UMP
+pub struct ToUmp {
+    channels: [ChannelState; 16],
+    sysex: [u8; 6],
+    sysex_len: usize,
+    sysex_started: bool,
+}
+
+impl ToUmp {
+    pub const fn new() -> Self {
+        Self { channels: [IDLE; 16], sysex: [0; 6], sysex_len: 0, sysex_started: false }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn translate(&mut self, group: u8, message: &MidiMessage, protocol: Protocol, out: &mut dyn FnMut(Ump)) {
+        match *message {
+            MidiMessage::SysEx { data, len } => self.sysex(group, &data[..len as usize], out),
+            _ if message.channel().is_none() => out(Message::System(*message).encode(group)),
+            _ if protocol == Protocol::Midi1 => out(Message::Midi1(*message).encode(group)),
+            _ => {
+                if let Some(message) = self.upscale(message) {
+                    out(Message::Midi2(message).encode(group));
+                }
+            }
+        }
+    }
+
+    /// Collect SysEx fragments into SysEx7 packets. A full packet is only
+    /// sent once the next byte shows that the message continues.
+    fn sysex(&mut self, group: u8, bytes: &[u8], out: &mut dyn FnMut(Ump)) {
+        for &byte in bytes {
+            match byte {
+                0xF0 => {
+                    self.sysex_len = 0;
+                    self.sysex_started = false;
+                }
+                0xF7 => {
+                    let part = if self.sysex_started { Part::End } else { Part::Complete };
+                    self.sysex_packet(group, part, out);
+                    self.sysex_started = false;
+                }
+                _ => {
+                    if self.sysex_len == 6 {
+                        let part = if self.sysex_started { Part::Continue } else { Part::Start };
+                        self.sysex_packet(group, part, out);
+                        self.sysex_started = true;
+                    }
+                    self.sysex[self.sysex_len] = byte & 0x7F;
+                    self.sysex_len += 1;
+                }
+            }
+        }
+    }
+
+    fn sysex_packet(&mut self, group: u8, part: Part, out: &mut dyn FnMut(Ump)) {
+        let message = Message::SysEx7 { part, data: self.sysex, len: self.sysex_len as u8 };
+        out(message.encode(group));
+        self.sysex = [0; 6];
+        self.sysex_len = 0;
+    }
+
+    /// MIDI 2.0 equivalent of a channel voice message. Bank select and
+    /// parameter selection controllers only update state.
+    fn upscale(&mut self, message: &MidiMessage) -> Option<Midi2Message> {
+        let channel = message.channel()?;
+        let state = &mut self.channels[channel as usize];
+        let up7 = |v: u8| scale_up(v as u32, 7, 32);
+        Some(match *message {
+            MidiMessage::NoteOff { note, velocity, .. } => Midi2Message::NoteOff {
+                channel,
+                note,
+                velocity: scale_up(velocity as u32, 7, 16) as u16,
+                attribute_type: 0,
+                attribute: 0,
+            },
+            MidiMessage::NoteOn { note, velocity: 0, .. } => {
+                // Velocity 0 is a note off in MIDI 1.0 but not in MIDI 2.0
+                Midi2Message::NoteOff { channel, note, velocity: 0, attribute_type: 0, attribute: 0 }
+            }
+            MidiMessage::NoteOn { note, velocity, .. } => Midi2Message::NoteOn {
+                channel,
+                note,
+                velocity: scale_up(velocity as u32, 7, 16) as u16,
+                attribute_type: 0,
+                attribute: 0,
+            },
+            MidiMessage::PolyPressure { note, pressure, .. } => Midi2Message::PolyPressure { channel, note, value: up7(pressure) },
+            MidiMessage::ControlChange { control, value, .. } => match control {
+                0 => {
+                    state.bank = Some((value, state.bank.map_or(0, |b| b.1)));
+                    return None;
+                }
+                32 => {
+                    state.bank = Some((state.bank.map_or(0, |b| b.0), value));
+                    return None;
+                }
+                hires::NRPN_MSB | hires::RPN_MSB | hires::NRPN_LSB | hires::RPN_LSB => {
+                    let kind = if control >= hires::RPN_LSB { ParamKind::Rpn } else { ParamKind::Nrpn };
+                    let (mut msb, mut lsb) = match state.param {
+                        Some((k, msb, lsb)) if k == kind => (msb, lsb),
+                        _ => (0x7F, 0x7F),
+                    };
+                    if control & 1 != 0 {
+                        msb = value;
+                    } else {
+                        lsb = value;
+                    }
+                    state.param = Some((kind, msb, lsb));
+                    if kind == ParamKind::Rpn && msb == 0x7F && lsb == 0x7F {
+                        state.param = None;
+                    }
+                    return None;
+                }
+                hires::DATA_ENTRY | 38 => {
+                    let (kind, bank, index) = state.param?;
+                    let lsb = if control == hires::DATA_ENTRY {
+                        state.data_msb = value;
+                        0
+                    } else {
+                        value
+                    };
+                    let value = scale_up((state.data_msb as u32) << 7 | lsb as u32, 14, 32);
+                    match kind {
+                        ParamKind::Rpn => Midi2Message::Rpn { channel, bank, index, value },
+                        ParamKind::Nrpn => Midi2Message::Nrpn { channel, bank, index, value },
+                    }
+                }
+                _ => Midi2Message::ControlChange { channel, index: control, value: up7(value) },
+            },
+            MidiMessage::ProgramChange { program, .. } => Midi2Message::ProgramChange { channel, program, bank: state.bank },
+            MidiMessage::ChannelPressure { pressure, .. } => Midi2Message::ChannelPressure { channel, value: up7(pressure) },
+            MidiMessage::PitchBend { value, .. } => Midi2Message::PitchBend { channel, value: scale_up(value as u32, 14, 32) },
+            _ => return None,
+        })
+    }
+}
+
+/// UMP to MIDI 1.0 messages. SysEx7 payloads are regrouped into the
+/// three-byte fragments `MidiMessage::SysEx` uses.
+pub struct FromUmp {
+    carry: [u8; 3],
+    carry_len: usize,
+}
+
+impl FromUmp {
+    pub const fn new() -> Self {
+        Self { carry: [0; 3], carry_len: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.carry_len = 0;
+    }
+
+    /// Translate a packet. Messages without a MIDI 1.0 equivalent (per-note
+    /// controllers, SysEx8, utility and stream messages) produce nothing.
+    pub fn translate(&mut self, ump: &Ump, out: &mut dyn FnMut(MidiMessage)) {
+        match ump.decode() {
+            Message::System(message) | Message::Midi1(message) => out(message),
+            Message::SysEx7 { part, data, len } => {
+                if matches!(part, Part::Complete | Part::Start) {
+                    self.carry_len = 0;
+                    self.sysex_byte(0xF0, out);
+                }
+                for &byte in &data[..len as usize] {
+                    self.sysex_byte(byte, out);
+                }
+                if matches!(part, Part::Complete | Part::End) {
+                    self.sysex_byte(0xF7, out);
+                }
+            }
+            Message::Midi2(message) => Self::downscale(&message, out),
+            _ => {}
+        }
+    }
+
+    fn sysex_byte(&mut self, byte: u8, out: &mut dyn FnMut(MidiMessage)) {
+        self.carry[self.carry_len] = byte;
+        self.carry_len += 1;
+        if self.carry_len == 3 || byte == 0xF7 {
+            out(MidiMessage::SysEx { data: self.carry, len: self.carry_len as u8 });
+            self.carry = [0; 3];
+            self.carry_len = 0;
+        }
+    }
+
+    fn downscale(message: &Midi2Message, out: &mut dyn FnMut(MidiMessage)) {
+        let down7 = |v: u32| scale_down(v, 32, 7) as u8;
+        let parameter = |channel, kind, bank: u8, index: u8, value: u32, out: &mut dyn FnMut(MidiMessage)| {
+            let param = (bank as u16) << 7 | index as u16;
+            let value = scale_down(value, 32, 14) as u16;
+            hires::send_parameter(channel, kind, param, value, &hires::Options::new(), out);
+        };
+        match *message {
+            Midi2Message::NoteOff { channel, note, velocity, .. } => {
+                out(MidiMessage::NoteOff { channel, note, velocity: scale_down(velocity as u32, 16, 7) as u8 })
+            }
+            Midi2Message::NoteOn { channel, note, velocity, .. } => {
+                // A MIDI 2.0 note on with velocity 0 is still a note on
+                let velocity = (scale_down(velocity as u32, 16, 7) as u8).max(1);
+                out(MidiMessage::NoteOn { channel, note, velocity })
+            }
+            Midi2Message::PolyPressure { channel, note, value } => {
+                out(MidiMessage::PolyPressure { channel, note, pressure: down7(value) })
+            }
+            Midi2Message::ControlChange { channel, index, value } => {
+                out(MidiMessage::ControlChange { channel, control: index, value: down7(value) })
+            }
+            Midi2Message::Rpn { channel, bank, index, value } => parameter(channel, ParamKind::Rpn, bank, index, value, out),
+            Midi2Message::Nrpn { channel, bank, index, value } => {
+                parameter(channel, ParamKind::Nrpn, bank, index, value, out)
+            }
+            Midi2Message::ProgramChange { channel, program, bank } => {
+                if let Some((msb, lsb)) = bank {
+                    out(MidiMessage::ControlChange { channel, control: 0, value: msb });
+                    out(MidiMessage::ControlChange { channel, control: 32, value: lsb });
+                }
+                out(MidiMessage::ProgramChange { channel, program })
+            }
+            Midi2Message::ChannelPressure { channel, value } => {
+                out(MidiMessage::ChannelPressure { channel, pressure: down7(value) })
+            }
+            Midi2Message::PitchBend { channel, value } => {
+                out(MidiMessage::PitchBend { channel, value: scale_down(value, 32, 14) as u16 })
+            }
+            _ => {}
+        }
+    }
+}
+
+// ============================================================================
+// Endpoint
+// ============================================================================
+
+/// Identity and name the endpoint reports in stream messages
+pub struct EndpointInfo {
+    pub name: &'static str,
+    pub product_instance_id: &'static str,
+    pub manufacturer: [u8; 3],
+    pub family: u16,
+    pub model: u16,
+    pub revision: [u8; 4],
+    pub block_name: &'static str,
+    /// MIDI-CI version of the function block, 0 if MIDI-CI is not supported
+    pub ci_version: u8,
+}
+
+/// UMP endpoint with one static, bidirectional function block on group 1.
+/// Answers discovery and protocol negotiation from the host.
+pub struct Endpoint {
+    pub info: &'static EndpointInfo,
+    pub protocol: Protocol,
+}
+
+impl Endpoint {
+    pub const fn new(info: &'static EndpointInfo) -> Self {
+        Self { info, protocol: Protocol::Midi2 }
+    }
+
+    fn function_block(&self) -> FunctionBlock {
+        FunctionBlock {
+            active: true,
+            number: 0,
+            ui_hint: 3,
+            midi1: 0,
+            direction: 3,
+            first_group: 0,
+            groups: 1,
+            ci_version: self.info.ci_version,
+            sysex8_streams: 0,
+        }
+    }
+
+    /// Handle a stream message from the host, replies go to `out`.
+    pub fn handle(&mut self, message: &Stream, out: &mut dyn FnMut(Ump)) {
+        match *message {
+            Stream::EndpointDiscovery { filter, .. } => {
+                let info = self.info;
+                if filter & stream::WANT_INFO != 0 {
+                    let reply = Stream::EndpointInfo {
+                        version: UMP_VERSION,
+                        static_blocks: true,
+                        blocks: 1,
+                        midi2: true,
+                        midi1: true,
+                    };
+                    out(reply.encode());
+                }
+                if filter & stream::WANT_DEVICE_IDENTITY != 0 {
+                    let reply = Stream::DeviceIdentity {
+                        manufacturer: info.manufacturer,
+                        family: info.family,
+                        model: info.model,
+                        revision: info.revision,
+                    };
+                    out(reply.encode());
+                }
+                if filter & stream::WANT_NAME != 0 {
+                    Stream::text(stream::ENDPOINT_NAME, 0, info.name.as_bytes(), out);
+                }
+                if filter & stream::WANT_PRODUCT_INSTANCE_ID != 0 {
+                    Stream::text(stream::PRODUCT_INSTANCE_ID, 0, info.product_instance_id.as_bytes(), out);
+                }
+                if filter & stream::WANT_STREAM_CONFIG != 0 {
+                    out(Stream::ConfigNotification { protocol: self.protocol.stream_value() }.encode());
+                }
+            }
+            Stream::ConfigRequest { protocol } => {
+                // Unsupported requests (e.g. jitter reduction) keep the protocol
+                if let Some(protocol) = Protocol::from_stream_value(protocol) {
+                    self.protocol = protocol;
+                }
+                out(Stream::ConfigNotification { protocol: self.protocol.stream_value() }.encode());
+            }
+            Stream::FunctionBlockDiscovery { block, filter } if block == 0 || block == 0xFF => {
+                if filter & stream::WANT_BLOCK_INFO != 0 {
+                    out(Stream::FunctionBlockInfo(self.function_block()).encode());
+                }
+                if filter & stream::WANT_BLOCK_NAME != 0 {
+                    Stream::text(stream::FUNCTION_BLOCK_NAME, 0, self.info.block_name.as_bytes(), out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi;
+
+    static INFO: EndpointInfo = EndpointInfo {
+        name: "Tiva Controller",
+        product_instance_id: "0001",
+        manufacturer: [0x7D, 0, 0],
+        family: 0x0102,
+        model: 0x0304,
+        revision: [1, 2, 3, 4],
+        block_name: "Controls",
+        ci_version: 2,
+    };
+
+    fn to_ump(translator: &mut ToUmp, protocol: Protocol, messages: &[MidiMessage]) -> Vec<Ump> {
+        let mut packets = Vec::new();
+        for message in messages {
+            translator.translate(0, message, protocol, &mut |ump| packets.push(ump));
+        }
+        packets
+    }
+
+    fn from_ump(packets: &[Ump]) -> Vec<MidiMessage> {
+        let mut translator = FromUmp::new();
+        let mut messages = Vec::new();
+        for packet in packets {
+            translator.translate(packet, &mut |message| messages.push(message));
+        }
+        messages
+    }
+
+    fn round_trip(protocol: Protocol, messages: &[MidiMessage]) -> Vec<MidiMessage> {
+        from_ump(&to_ump(&mut ToUmp::new(), protocol, messages))
+    }
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange { channel: 2, control, value }
+    }
+
+    fn streams(endpoint: &mut Endpoint, message: Stream) -> Vec<Stream> {
+        let mut replies = Vec::new();
+        endpoint.handle(&message, &mut |ump| match ump.decode() {
+            Message::Stream(reply) => replies.push(reply),
+            other => panic!("{other:?}"),
+        });
+        replies
+    }
+
+    #[test]
+    fn scales_min_center_max() {
+        assert_eq!(scale_up(0, 7, 32), 0);
+        assert_eq!(scale_up(64, 7, 32), 0x8000_0000);
+        assert_eq!(scale_up(127, 7, 32), 0xFFFF_FFFF);
+        assert_eq!(scale_up(64, 7, 16), 0x8000);
+        assert_eq!(scale_up(127, 7, 16), 0xFFFF);
+        assert_eq!(scale_up(8192, 14, 32), 0x8000_0000);
+        assert_eq!(scale_up(16383, 14, 32), 0xFFFF_FFFF);
+        for value in 0..128 {
+            assert_eq!(scale_down(scale_up(value, 7, 32), 32, 7), value);
+            assert_eq!(scale_down(scale_up(value, 7, 16), 16, 7), value);
+        }
+        for value in 0..16384 {
+            assert_eq!(scale_down(scale_up(value, 14, 32), 32, 14), value);
+        }
+    }
+
+    #[test]
+    fn carries_midi1_unchanged() {
+        let messages = [
+            MidiMessage::NoteOn { channel: 2, note: 60, velocity: 100 },
+            MidiMessage::NoteOn { channel: 2, note: 60, velocity: 0 },
+            cc(7, 100),
+            MidiMessage::ProgramChange { channel: 2, program: 5 },
+            MidiMessage::PitchBend { channel: 2, value: 0x1234 },
+            MidiMessage::Clock,
+        ];
+        let packets = to_ump(&mut ToUmp::new(), Protocol::Midi1, &messages);
+        let types: Vec<_> = packets.iter().map(Ump::message_type).collect();
+        assert_eq!(types, [mt::MIDI1, mt::MIDI1, mt::MIDI1, mt::MIDI1, mt::MIDI1, mt::SYSTEM]);
+        assert_eq!(from_ump(&packets), messages);
+    }
+
+    #[test]
+    fn translates_to_midi2_losslessly() {
+        let note = |velocity| MidiMessage::NoteOn { channel: 2, note: 60, velocity };
+        let off = MidiMessage::NoteOff { channel: 2, note: 60, velocity: 0 };
+        for velocity in 1..128 {
+            assert_eq!(round_trip(Protocol::Midi2, &[note(velocity)]), [note(velocity)]);
+        }
+        // Velocity 0 is a note off in MIDI 1.0, not in MIDI 2.0
+        let packets = to_ump(&mut ToUmp::new(), Protocol::Midi2, &[note(0)]);
+        let expected = Midi2Message::NoteOff { channel: 2, note: 60, velocity: 0, attribute_type: 0, attribute: 0 };
+        assert_eq!(packets[0].decode(), Message::Midi2(expected));
+        assert_eq!(from_ump(&packets), [off]);
+
+        for value in [0, 1, 63, 64, 65, 127] {
+            assert_eq!(round_trip(Protocol::Midi2, &[cc(7, value)]), [cc(7, value)]);
+        }
+        for value in [0, 1, 0x2000, 0x3FFF] {
+            let bend = MidiMessage::PitchBend { channel: 2, value };
+            assert_eq!(round_trip(Protocol::Midi2, &[bend]), [bend]);
+        }
+    }
+
+    #[test]
+    fn folds_bank_select_into_program_change() {
+        let program = MidiMessage::ProgramChange { channel: 2, program: 5 };
+        let mut translator = ToUmp::new();
+        let packets = to_ump(&mut translator, Protocol::Midi2, &[cc(0, 1), cc(32, 2), program]);
+        assert_eq!(packets.len(), 1);
+        let expected = Midi2Message::ProgramChange { channel: 2, program: 5, bank: Some((1, 2)) };
+        assert_eq!(packets[0].decode(), Message::Midi2(expected));
+        assert_eq!(from_ump(&packets), [cc(0, 1), cc(32, 2), program]);
+
+        let packets = to_ump(&mut ToUmp::new(), Protocol::Midi2, &[program]);
+        assert_eq!(from_ump(&packets), [program]);
+    }
+
+    #[test]
+    fn translates_parameters() {
+        let update = [cc(hires::RPN_MSB, 0), cc(hires::RPN_LSB, 2), cc(hires::DATA_ENTRY, 0x24), cc(38, 0x34)];
+        let packets = to_ump(&mut ToUmp::new(), Protocol::Midi2, &update);
+        // The MSB alone and then the whole value
+        let rpn = |value| Message::Midi2(Midi2Message::Rpn { channel: 2, bank: 0, index: 2, value });
+        let decoded: Vec<_> = packets.iter().map(Ump::decode).collect();
+        assert_eq!(decoded, [rpn(scale_up(0x24 << 7, 14, 32)), rpn(scale_up(0x1234, 14, 32))]);
+        assert_eq!(from_ump(&packets[1..]), update);
+
+        // Data entry without a parameter goes nowhere, RPN null deselects
+        let mut translator = ToUmp::new();
+        assert!(to_ump(&mut translator, Protocol::Midi2, &[cc(hires::DATA_ENTRY, 1)]).is_empty());
+        let nrpn = [cc(hires::NRPN_MSB, 1), cc(hires::NRPN_LSB, 3), cc(hires::DATA_ENTRY, 1), cc(38, 0)];
+        let packets = to_ump(&mut translator, Protocol::Midi2, &nrpn);
+        assert_eq!(from_ump(&packets[1..]), nrpn);
+        let null = [cc(hires::RPN_MSB, 0x7F), cc(hires::RPN_LSB, 0x7F), cc(hires::DATA_ENTRY, 1)];
+        assert!(to_ump(&mut translator, Protocol::Midi2, &null).is_empty());
+    }
+
+    #[test]
+    fn splits_sysex_into_packets() {
+        for (len, parts) in [
+            (0, &[(Part::Complete, 0)][..]),
+            (6, &[(Part::Complete, 6)]),
+            (7, &[(Part::Start, 6), (Part::End, 1)]),
+            (12, &[(Part::Start, 6), (Part::End, 6)]),
+            (13, &[(Part::Start, 6), (Part::Continue, 6), (Part::End, 1)]),
+        ] {
+            let mut bytes = vec![0xF0];
+            bytes.extend(1..=len as u8);
+            bytes.push(0xF7);
+            let mut fragments = Vec::new();
+            midi::sysex_fragments(&bytes, &mut |message| fragments.push(message));
+
+            let packets = to_ump(&mut ToUmp::new(), Protocol::Midi2, &fragments);
+            let found: Vec<_> = packets
+                .iter()
+                .map(|ump| match ump.decode() {
+                    Message::SysEx7 { part, len, .. } => (part, len),
+                    other => panic!("{other:?}"),
+                })
+                .collect();
+            assert_eq!(found, parts, "{len} bytes");
+            assert_eq!(from_ump(&packets), fragments, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn answers_stream_discovery() {
+        let mut endpoint = Endpoint::new(&INFO);
+        let replies = streams(&mut endpoint, Stream::EndpointDiscovery { version: UMP_VERSION, filter: 0x1F });
+        assert_eq!(
+            replies[..2],
+            [
+                Stream::EndpointInfo { version: UMP_VERSION, static_blocks: true, blocks: 1, midi2: true, midi1: true },
+                Stream::DeviceIdentity {
+                    manufacturer: [0x7D, 0, 0],
+                    family: 0x0102,
+                    model: 0x0304,
+                    revision: [1, 2, 3, 4]
+                },
+            ]
+        );
+        // "Tiva Controller" takes two packets, the instance id one
+        let texts: Vec<_> = replies[2..5]
+            .iter()
+            .map(|reply| match *reply {
+                Stream::Text { status, part, text, len, .. } => (status, part, text[..len as usize].to_vec()),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                (stream::ENDPOINT_NAME, Part::Start, b"Tiva Controlle".to_vec()),
+                (stream::ENDPOINT_NAME, Part::End, b"r".to_vec()),
+                (stream::PRODUCT_INSTANCE_ID, Part::Complete, b"0001".to_vec()),
+            ]
+        );
+        assert_eq!(replies[5..], [Stream::ConfigNotification { protocol: 0x02 }]);
+
+        let replies = streams(&mut endpoint, Stream::FunctionBlockDiscovery { block: 0xFF, filter: 0x03 });
+        let Stream::FunctionBlockInfo(block) = replies[0] else { panic!("{:?}", replies[0]) };
+        assert_eq!((block.active, block.direction, block.groups, block.ci_version), (true, 3, 1, 2));
+        assert!(matches!(replies[1], Stream::Text { status: stream::FUNCTION_BLOCK_NAME, part: Part::Complete, .. }));
+        assert!(streams(&mut endpoint, Stream::FunctionBlockDiscovery { block: 1, filter: 0x03 }).is_empty());
+    }
+
+    #[test]
+    fn negotiates_the_protocol() {
+        let mut endpoint = Endpoint::new(&INFO);
+        let midi1 = [Stream::ConfigNotification { protocol: 0x01 }];
+        assert_eq!(streams(&mut endpoint, Stream::ConfigRequest { protocol: 0x01 }), midi1);
+        assert_eq!(endpoint.protocol, Protocol::Midi1);
+        // Unsupported protocols keep the current one
+        assert_eq!(streams(&mut endpoint, Stream::ConfigRequest { protocol: 0x11 }), midi1);
+        assert_eq!(endpoint.protocol, Protocol::Midi1);
+    }
+}
@@ -1,6 +1,7 @@
 //! USB Descriptors
 //! 
-//! This module contains USB device descriptors for CDC serial port functionality.
+//! This module contains the USB string descriptors of the composite device and
+//! the callbacks of its CDC serial port function.
 
 use core::ffi::c_void;
 
@@ -20,12 +21,13 @@ pub const MANUFACTURER_STRING: [u8; 36] = [
     b'm', 0, b'e', 0, b'n', 0, b't', 0, b's', 0,
 ];
 
-// Product string: "Virtual COM Port"
-pub const PRODUCT_STRING: [u8; 34] = [
-    34,                         // bLength (16 chars * 2 + 2)
+// Product string: "Tiva MIDI Controller"
+pub const PRODUCT_STRING: [u8; 42] = [
+    42,                         // bLength (20 chars * 2 + 2)
     3,                          // bDescriptorType (STRING)
-    b'V', 0, b'i', 0, b'r', 0, b't', 0, b'u', 0, b'a', 0, b'l', 0, b' ', 0,
-    b'C', 0, b'O', 0, b'M', 0, b' ', 0, b'P', 0, b'o', 0, b'r', 0, b't', 0,
+    b'T', 0, b'i', 0, b'v', 0, b'a', 0, b' ', 0, b'M', 0, b'I', 0, b'D', 0,
+    b'I', 0, b' ', 0, b'C', 0, b'o', 0, b'n', 0, b't', 0, b'r', 0, b'o', 0,
+    b'l', 0, b'l', 0, b'e', 0, b'r', 0,
 ];
 
 // Serial number string: "12345678"
@@ -101,38 +103,19 @@ pub fn get_string_descriptors() -> *const *const u8 {
 }
 
 // USB callback functions
-// These are minimal implementations that just return success
+// These forward the events the CDC serial port cares about to `cdc`
 
 /// Control handler callback
 #[no_mangle]
 pub unsafe extern "C" fn control_handler(
     _pv_cb_data: *mut c_void,
-    _ui32_event: u32,
+    ui32_event: u32,
     _ui32_msg_value: u32,
     _pv_msg_data: *mut c_void,
 ) -> u32 {
-    // Event 7 = USB_EVENT_CONNECTED (device connected to host)
-    // Event values from usblib.h:
-    // 0 = USB_EVENT_POWER_FAULT
-    // 1 = USB_EVENT_VBUS_ERR
-    // 2 = USB_EVENT_POWER_ENABLE
-    // 3 = USB_EVENT_POWER_DISABLE
-    // 4 = USB_EVENT_OVRCURRENT_FAULT
-    // 5 = USB_EVENT_OVRCURRENT_FAULT_CLEAR
-    // 6 = USB_EVENT_SUSPEND
-    // 7 = USB_EVENT_CONNECTED
-    // 8 = USB_EVENT_DISCONNECTED
-    // 9 = USB_EVENT_RESUME
-    // 10 = USB_EVENT_RESET
-    // 11 = USB_EVENT_SOF
-    // 12 = USB_EVENT_SESSION_REQUEST
-    // 13 = USB_EVENT_SESSION_VALID
-    // 14 = USB_EVENT_ID_STATUS_CHANGE
-    // 15 = USB_EVENT_VBUS_REQUEST
-    // 16 = USB_EVENT_VBUS_RELEASE
-    
-    // For now, just return success for all events
-    // The USB library handles descriptor requests internally
+    // Event values are the generic ones from usblib.h (see usb_device::usb_events);
+    // line coding and control line state requests are simply accepted.
+    crate::cdc::on_control_event(ui32_event);
     0 // Success
 }
 
@@ -140,10 +123,13 @@ pub unsafe extern "C" fn control_handler(
 #[no_mangle]
 pub unsafe extern "C" fn rx_handler(
     _pv_cb_data: *mut c_void,
-    _ui32_event: u32,
+    ui32_event: u32,
     _ui32_msg_value: u32,
     _pv_msg_data: *mut c_void,
 ) -> u32 {
+    if ui32_event == crate::usb_device::usb_events::USB_EVENT_RX_AVAILABLE {
+        crate::cdc::on_rx_available();
+    }
     0 // Success
 }
 
@@ -157,4 +143,3 @@ pub unsafe extern "C" fn tx_handler(
 ) -> u32 {
     0 // Success
 }
-
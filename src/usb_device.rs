@@ -45,27 +45,35 @@ pub struct tDescriptorHeader {
     pub bDescriptorType: u8,
 }
 
-/// Configuration header (matches tConfigHeader from usbdevice.h)
+/// One block of a configuration descriptor (matches tConfigSection from usblib.h)
+#[repr(C)]
+pub struct tConfigSection {
+    pub ui16Size: u16,
+    pub pui8Data: *const u8,
+}
+
+/// Configuration descriptor made of sections (matches tConfigHeader from usblib.h)
 #[repr(C)]
 pub struct tConfigHeader {
-    pub ui16TotalSize: u16,
-    pub ui16NumInterfaces: u8,
-    pub ui8ConfigIndex: u8,
+    pub ui8NumSections: u8,
+    pub psSections: *const *const tConfigSection,
 }
 
-/// Custom handlers structure (matches tCustomHandlers from usbdevice.h)
+/// Class driver callbacks (matches tCustomHandlers from usblib.h)
 #[repr(C)]
 pub struct tCustomHandlers {
-    pub pfnGetDescriptor: Option<unsafe extern "C" fn(*const c_void, *mut tUSBRequest, *const u8, *mut u32) -> u32>,
-    pub pfnRequestHandler: Option<unsafe extern "C" fn(*const c_void, *mut tUSBRequest)>,
-    pub pfnConfigChange: Option<unsafe extern "C" fn(*const c_void, u32)>,
-    pub pfnDataReceived: Option<unsafe extern "C" fn(*const c_void, u32, u32)>,
-    pub pfnDataSent: Option<unsafe extern "C" fn(*const c_void, u32, u32)>,
-    pub pfnResetHandler: Option<unsafe extern "C" fn(*const c_void)>,
-    pub pfnSuspendHandler: Option<unsafe extern "C" fn(*const c_void)>,
-    pub pfnResumeHandler: Option<unsafe extern "C" fn(*const c_void)>,
-    pub pfnDisconnectHandler: Option<unsafe extern "C" fn(*const c_void)>,
-    pub pfnConfigDescGet: Option<unsafe extern "C" fn(*const c_void, *const tConfigHeader, u32, u32, *mut u32) -> *const tDescriptorHeader>,
+    pub pfnGetDescriptor: Option<unsafe extern "C" fn(*mut c_void, *mut tUSBRequest)>,
+    pub pfnRequestHandler: Option<unsafe extern "C" fn(*mut c_void, *mut tUSBRequest)>,
+    pub pfnInterfaceChange: Option<unsafe extern "C" fn(*mut c_void, u8, u8)>,
+    pub pfnConfigChange: Option<unsafe extern "C" fn(*mut c_void, u32)>,
+    pub pfnDataReceived: Option<unsafe extern "C" fn(*mut c_void, u32)>,
+    pub pfnDataSent: Option<unsafe extern "C" fn(*mut c_void, u32)>,
+    pub pfnResetHandler: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub pfnSuspendHandler: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub pfnResumeHandler: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub pfnDisconnectHandler: Option<unsafe extern "C" fn(*mut c_void)>,
+    pub pfnEndpointHandler: Option<unsafe extern "C" fn(*mut c_void, u32)>,
+    pub pfnDeviceHandler: Option<unsafe extern "C" fn(*mut c_void, u32, *mut c_void)>,
 }
 
 /// Device info structure (matches tDeviceInfo from usbdevice.h)
@@ -300,6 +308,123 @@ extern "C" {
     pub fn USBDCDCRemoteWakeupRequest(pvCDCDevice: *mut c_void) -> bool;
 }
 
+// ============================================================================
+// USB Composite Device Functions
+// ============================================================================
+
+/// Configuration descriptor (matches tConfigDescriptor from usblib.h)
+#[repr(C, packed)]
+pub struct tConfigDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub wTotalLength: u16,
+    pub bNumInterfaces: u8,
+    pub bConfigurationValue: u8,
+    pub iConfiguration: u8,
+    pub bmAttributes: u8,
+    pub bMaxPower: u8,
+}
+
+/// Device descriptor (matches tDeviceDescriptor from usblib.h)
+#[repr(C, packed)]
+pub struct tDeviceDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bcdUSB: u16,
+    pub bDeviceClass: u8,
+    pub bDeviceSubClass: u8,
+    pub bDeviceProtocol: u8,
+    pub bMaxPacketSize0: u8,
+    pub idVendor: u16,
+    pub idProduct: u16,
+    pub bcdDevice: u16,
+    pub iManufacturer: u8,
+    pub iProduct: u8,
+    pub iSerialNumber: u8,
+    pub bNumConfigurations: u8,
+}
+
+/// Composite instance (matches tCompositeInstance from usbdcomp.h)
+#[repr(C)]
+pub struct tCompositeInstance {
+    pub ui32USBBase: u32,
+    pub sDevInfo: tDeviceInfo,
+    pub sConfigDescriptor: tConfigDescriptor,
+    pub sDeviceDescriptor: tDeviceDescriptor,
+    pub sCompConfigHeader: tConfigHeader,
+    pub psCompSections: [tConfigSection; 2],
+    pub ppsCompSections: [*mut tConfigSection; 2],
+    pub ui32DataSize: u32,
+    pub pui8Data: *mut u8,
+    pub ui32EP0Owner: u32,
+}
+
+/// USB composite device structure (matches tUSBDCompositeDevice from usbdcomp.h)
+#[repr(C)]
+pub struct tUSBDCompositeDevice {
+    pub ui16VID: u16,
+    pub ui16PID: u16,
+    pub ui16MaxPowermA: u16,
+    pub ui8PwrAttributes: u8,
+    pub pfnCallback: tUSBCallback,
+    pub ppui8StringDescriptors: *const *const u8,
+    pub ui32NumStringDescriptors: u32,
+    pub ui32NumDevices: u32,
+    pub psDevices: *mut tCompositeEntry,
+    pub sPrivateData: tCompositeInstance,
+}
+
+extern "C" {
+    /// Initialize a composite device from the entries filled in by the
+    /// class `...CompositeInit` functions
+    ///
+    /// # Arguments
+    /// * `ui32Size` / `pui8Data` - Workspace for the merged configuration
+    ///   descriptor, at least the sum of all class configuration descriptors
+    ///
+    /// # Returns
+    /// Pointer to the composite instance or NULL on error
+    pub fn USBDCompositeInit(
+        ui32Index: u32,
+        psCompDevice: *mut tUSBDCompositeDevice,
+        ui32Size: u32,
+        pui8Data: *mut u8,
+    ) -> *mut c_void;
+}
+
+// ============================================================================
+// USB Endpoint Functions (driverlib)
+// ============================================================================
+
+extern "C" {
+    /// Status flags of an endpoint
+    pub fn USBEndpointStatus(ui32Base: u32, ui32Endpoint: u32) -> u32;
+
+    /// Clear endpoint status flags
+    pub fn USBDevEndpointStatusClear(ui32Base: u32, ui32Endpoint: u32, ui32Flags: u32);
+
+    /// Number of bytes waiting in an OUT endpoint FIFO
+    pub fn USBEndpointDataAvail(ui32Base: u32, ui32Endpoint: u32) -> u32;
+
+    /// Read from an OUT endpoint FIFO; `pui32Size` is the buffer size on
+    /// entry and the number of bytes read on return
+    pub fn USBEndpointDataGet(
+        ui32Base: u32,
+        ui32Endpoint: u32,
+        pui8Data: *mut u8,
+        pui32Size: *mut u32,
+    ) -> i32;
+
+    /// Acknowledge an OUT packet so the FIFO can take the next one
+    pub fn USBDevEndpointDataAck(ui32Base: u32, ui32Endpoint: u32, bIsLastPacket: bool);
+
+    /// Write to an IN endpoint FIFO
+    pub fn USBEndpointDataPut(ui32Base: u32, ui32Endpoint: u32, pui8Data: *const u8, ui32Size: u32) -> i32;
+
+    /// Start sending what was written to an IN endpoint FIFO
+    pub fn USBEndpointDataSend(ui32Base: u32, ui32Endpoint: u32, ui32TransType: u32) -> i32;
+}
+
 // ============================================================================
 // USB Library Functions
 // ============================================================================
@@ -367,6 +492,35 @@ pub mod usblib_features {
     pub const USBLIB_FEATURE_USBPLL: u32 = 4;
 }
 
+/// Generic USB events passed to class callbacks (from usblib.h)
+pub mod usb_events {
+    pub const USB_EVENT_CONNECTED: u32 = 0;
+    pub const USB_EVENT_DISCONNECTED: u32 = 1;
+    pub const USB_EVENT_RX_AVAILABLE: u32 = 2;
+    pub const USB_EVENT_DATA_REMAINING: u32 = 3;
+    pub const USB_EVENT_REQUEST_BUFFER: u32 = 4;
+    pub const USB_EVENT_TX_COMPLETE: u32 = 5;
+    pub const USB_EVENT_ERROR: u32 = 6;
+    pub const USB_EVENT_SUSPEND: u32 = 7;
+    pub const USB_EVENT_RESUME: u32 = 8;
+    pub const USB_EVENT_COMP_IFACE_CHANGE: u32 = 14;
+    pub const USB_EVENT_COMP_EP_CHANGE: u32 = 15;
+    pub const USB_EVENT_COMP_CONFIG: u32 = 17;
+}
+
+/// Endpoint numbers, status flags and transaction types (from usb.h)
+pub mod usb_endpoint {
+    pub const USB_EP_0: u32 = 0x00;
+    pub const USB_DEV_TX_TXPKTRDY: u32 = 0x0000_0001;
+    pub const USB_DEV_RX_PKT_RDY: u32 = 0x0001_0000;
+    pub const USB_TRANS_IN: u32 = 0x0102;
+
+    /// Endpoint number to the `USB_EP_n` value used by driverlib
+    pub const fn index_to_ep(index: u8) -> u32 {
+        (index as u32) << 4
+    }
+}
+
 /// USB CDC serial state flags
 pub mod usb_cdc_serial_state {
     pub const USB_CDC_SERIAL_STATE_DCD: u16 = 0x0001;
@@ -441,6 +595,12 @@ extern "C" {
     
     /// Register USB interrupt handler
     pub fn USBIntRegister(ui32Base: u32, pfnHandler: unsafe extern "C" fn());
+
+    /// Register a handler in the RAM vector table
+    pub fn IntRegister(ui32Interrupt: u32, pfnHandler: unsafe extern "C" fn());
+
+    /// Enable an interrupt at the NVIC
+    pub fn IntEnable(ui32Interrupt: u32);
     
     /// Connect USB device to bus (soft connect)
     pub fn USBDevConnect(ui32Base: u32);
@@ -473,6 +633,13 @@ pub mod sysctl_periph {
     pub const SYSCTL_PERIPH_USB0: u32 = 0x01000010;
 }
 
+// Interrupt numbers (from hw_ints.h)
+pub mod ints {
+    pub const INT_TIMER1A: u32 = 37;
+    pub const INT_ADC1SS1: u32 = 65;
+    pub const INT_I2C3: u32 = 85;
+}
+
 // System clock configuration constants (from sysctl.h)
 pub mod sysctl_clock {
     pub const SYSCTL_SYSDIV_4: u32 = 0x01C00000;  // Processor clock is osc/pll /4
@@ -490,6 +657,9 @@ pub mod gpio {
 pub mod usb_ids {
     pub const USB_VID_TI_1CBE: u16 = 0x1cbe;
     pub const USB_PID_SERIAL: u16 = 0x0002;
+    /// CDC serial + USB-MIDI composite. Not a TI assigned product id; a
+    /// product should use its own VID/PID.
+    pub const USB_PID_COMP_MIDI_SERIAL: u16 = 0x0011;
 }
 
 pub mod usb_conf {
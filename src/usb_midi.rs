@@ -0,0 +1,479 @@
+//! USB-MIDI Class
+//!
+//! MIDI streaming function of the composite device, written against the
+//! TivaWare class driver interface (`tCustomHandlers`). Alternate setting 0
+//! of the streaming interface is USB-MIDI 1.0 with four-byte event packets,
+//! alternate setting 1 is USB-MIDI 2.0 carrying Universal MIDI Packets on one
+//! bidirectional Group Terminal Block.
+//!
+//! The class callbacks run in the USB interrupt and only track state; the
+//! bulk endpoints are polled from the main loop by `UsbMidi::poll`, which
+//! translates between the wire format and the router.
+
+use core::ffi::c_void;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::midi::{self, MidiMessage};
+use crate::ring::Queue;
+use crate::router::{Port, Router};
+use crate::ump::{self, Endpoint, EndpointInfo, FromUmp, Message, Protocol, ToUmp, Ump};
+use crate::usb_device::{
+    self, tCompositeEntry, tConfigHeader, tConfigSection, tCustomHandlers, tDeviceInfo, tUSBRequest,
+    usb_base::USB0_BASE, usb_endpoint,
+};
+
+/// Max packet size of the bulk endpoints
+const PACKET_SIZE: usize = 64;
+
+/// Virtual cable used on alternate setting 0
+const CABLE: u8 = 0;
+
+/// UMP group of the function block
+const GROUP: u8 = 0;
+
+/// Packets waiting for the IN endpoint
+const TX_DEPTH: usize = 32;
+
+// ============================================================================
+// Descriptors
+// ============================================================================
+
+const DTYPE_IAD: u8 = 0x0B;
+const DTYPE_INTERFACE: u8 = 4;
+const DTYPE_ENDPOINT: u8 = 5;
+const DTYPE_CS_INTERFACE: u8 = 0x24;
+/// Group Terminal Block descriptor type, requested with GET_DESCRIPTOR
+const DTYPE_CS_GR_TRM_BLOCK: u8 = 0x26;
+
+/// Offsets of the interface numbers in `FUNCTION_DESCRIPTOR` that the
+/// composite device has to renumber
+const IAD_FIRST_INTERFACE: usize = 2;
+const AC_HEADER_INTERFACE: usize = 25;
+
+const CONFIG_DESCRIPTOR: [u8; 9] = [
+    9,                          // bLength
+    2,                          // bDescriptorType (CONFIGURATION)
+    (9 + FUNCTION_DESCRIPTOR.len()) as u8,
+    0,                          // wTotalLength
+    2,                          // bNumInterfaces
+    1,                          // bConfigurationValue
+    0,                          // iConfiguration
+    0xC0,                       // bmAttributes (self powered)
+    0,                          // bMaxPower
+];
+
+const FUNCTION_DESCRIPTOR: [u8; 140] = [
+    // Interface association: audio control + MIDI streaming
+    8, DTYPE_IAD, 0, 2, 0x01, 0x03, 0x00, 0,
+
+    // Audio control interface, no endpoints
+    9, DTYPE_INTERFACE, 0, 0, 0, 0x01, 0x01, 0x00, 0,
+    // Class-specific AC header: ADC 1.0, one streaming interface
+    9, DTYPE_CS_INTERFACE, 0x01, 0x00, 0x01, 9, 0, 1, 1,
+
+    // MIDI streaming interface, alternate setting 0: USB-MIDI 1.0
+    9, DTYPE_INTERFACE, 1, 0, 2, 0x01, 0x03, 0x00, 0,
+    // MS header: MSC 1.0, total length of the class-specific descriptors
+    7, DTYPE_CS_INTERFACE, 0x01, 0x00, 0x01, 65, 0,
+    // Embedded IN jack 1 (from the host), external IN jack 2
+    6, DTYPE_CS_INTERFACE, 0x02, 0x01, 1, 0,
+    6, DTYPE_CS_INTERFACE, 0x02, 0x02, 2, 0,
+    // Embedded OUT jack 3 (to the host) fed by jack 2, external OUT jack 4 fed by jack 1
+    9, DTYPE_CS_INTERFACE, 0x03, 0x01, 3, 1, 2, 1, 0,
+    9, DTYPE_CS_INTERFACE, 0x03, 0x02, 4, 1, 1, 1, 0,
+    // Bulk OUT endpoint with its embedded jack
+    9, DTYPE_ENDPOINT, 0x01, 0x02, PACKET_SIZE as u8, 0, 0, 0, 0,
+    5, 0x25, 0x01, 1, 1,
+    // Bulk IN endpoint with its embedded jack
+    9, DTYPE_ENDPOINT, 0x81, 0x02, PACKET_SIZE as u8, 0, 0, 0, 0,
+    5, 0x25, 0x01, 1, 3,
+
+    // MIDI streaming interface, alternate setting 1: USB-MIDI 2.0
+    9, DTYPE_INTERFACE, 1, 1, 2, 0x01, 0x03, 0x00, 0,
+    // MS header: MSC 2.0
+    7, DTYPE_CS_INTERFACE, 0x01, 0x00, 0x02, 7, 0,
+    // Bulk OUT endpoint on group terminal block 1
+    7, DTYPE_ENDPOINT, 0x01, 0x02, PACKET_SIZE as u8, 0, 0,
+    5, 0x25, 0x02, 1, 1,
+    // Bulk IN endpoint on group terminal block 1
+    7, DTYPE_ENDPOINT, 0x81, 0x02, PACKET_SIZE as u8, 0, 0,
+    5, 0x25, 0x02, 1, 1,
+];
+
+/// Group Terminal Block descriptors of alternate setting 1
+static GROUP_TERMINAL_BLOCKS: [u8; 18] = [
+    // Header with the total length
+    5, DTYPE_CS_GR_TRM_BLOCK, 0x01, 18, 0,
+    // Block 1: bidirectional, group 1 only, MIDI 2.0 protocol, unknown bandwidth
+    13, DTYPE_CS_GR_TRM_BLOCK, 0x02, 1, 0x00, GROUP, 1, 0, 0x11, 0, 0, 0, 0,
+];
+
+/// Identity reported in UMP stream messages and MIDI-CI
+pub static ENDPOINT_INFO: EndpointInfo = EndpointInfo {
+    name: "Tiva MIDI Controller",
+    product_instance_id: "12345678",
+    // Non-commercial SysEx id
+    manufacturer: [0x7D, 0, 0],
+    family: 0,
+    model: 0,
+    revision: [0, 0, 0, 1],
+    block_name: "Controls",
+    // MIDI-CI message version 1.2
+    ci_version: 1,
+};
+
+// Descriptor tables handed to TivaWare hold raw pointers; they are never
+// written after link time.
+#[repr(transparent)]
+struct Shared<T>(T);
+unsafe impl<T> Sync for Shared<T> {}
+
+static CONFIG_BYTES: [u8; 9] = CONFIG_DESCRIPTOR;
+static FUNCTION_BYTES: [u8; FUNCTION_DESCRIPTOR.len()] = FUNCTION_DESCRIPTOR;
+
+static SECTIONS: [Shared<tConfigSection>; 2] = [
+    Shared(tConfigSection { ui16Size: CONFIG_BYTES.len() as u16, pui8Data: CONFIG_BYTES.as_ptr() }),
+    Shared(tConfigSection { ui16Size: FUNCTION_BYTES.len() as u16, pui8Data: FUNCTION_BYTES.as_ptr() }),
+];
+
+static SECTION_PTRS: Shared<[*const tConfigSection; 2]> = Shared([&SECTIONS[0].0, &SECTIONS[1].0]);
+
+static CONFIG_HEADER: Shared<tConfigHeader> =
+    Shared(tConfigHeader { ui8NumSections: 2, psSections: SECTION_PTRS.0.as_ptr() });
+
+static CONFIG_HEADERS: Shared<[*const tConfigHeader; 1]> = Shared([&CONFIG_HEADER.0]);
+
+static HANDLERS: tCustomHandlers = tCustomHandlers {
+    pfnGetDescriptor: Some(get_descriptor),
+    pfnRequestHandler: None,
+    pfnInterfaceChange: Some(interface_change),
+    pfnConfigChange: Some(config_change),
+    pfnDataReceived: None,
+    pfnDataSent: None,
+    pfnResetHandler: Some(bus_reset),
+    pfnSuspendHandler: None,
+    pfnResumeHandler: None,
+    pfnDisconnectHandler: Some(bus_reset),
+    pfnEndpointHandler: None,
+    pfnDeviceHandler: Some(device_event),
+};
+
+static DEVICE_INFO: Shared<tDeviceInfo> = Shared(tDeviceInfo {
+    psCallbacks: &HANDLERS,
+    // Only used stand-alone; the composite device has its own
+    pui8DeviceDescriptor: ptr::null(),
+    ppsConfigDescriptors: CONFIG_HEADERS.0.as_ptr(),
+    ppui8StringDescriptors: ptr::null(),
+    ui32NumStringDescriptors: 0,
+});
+
+/// Entry for `tUSBDCompositeDevice::psDevices`.
+pub fn composite_entry() -> tCompositeEntry {
+    tCompositeEntry { psDevInfo: &DEVICE_INFO.0, pvInstance: ptr::null_mut(), ui32DeviceWorkspace: 0 }
+}
+
+// ============================================================================
+// Class Callbacks (USB interrupt)
+// ============================================================================
+
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+static ALT_SETTING: AtomicU8 = AtomicU8::new(0);
+/// Streaming interface number and endpoint numbers in the composite device
+static INTERFACE: AtomicU8 = AtomicU8::new(1);
+static OUT_ENDPOINT: AtomicU8 = AtomicU8::new(1);
+static IN_ENDPOINT: AtomicU8 = AtomicU8::new(1);
+
+/// Serve the Group Terminal Block descriptors; anything else is stalled.
+unsafe extern "C" fn get_descriptor(_instance: *mut c_void, request: *mut tUSBRequest) {
+    let request = &*request;
+    let (value, index, length) = (request.wValue, request.wIndex, request.wLength);
+    let interface = INTERFACE.load(Ordering::Relaxed) as u16;
+    if (value >> 8) as u8 == DTYPE_CS_GR_TRM_BLOCK && index & 0xFF == interface && value & 0xFF == 1 {
+        let len = (length as usize).min(GROUP_TERMINAL_BLOCKS.len());
+        usb_device::USBDCDSendDataEP0(0, GROUP_TERMINAL_BLOCKS.as_ptr(), len as u32);
+    } else {
+        usb_device::USBDCDStallEP0(0);
+    }
+}
+
+/// SET_INTERFACE, called for every interface of the composite device.
+unsafe extern "C" fn interface_change(_instance: *mut c_void, interface: u8, alt_setting: u8) {
+    if interface == INTERFACE.load(Ordering::Relaxed) {
+        ALT_SETTING.store(alt_setting, Ordering::Release);
+    }
+}
+
+unsafe extern "C" fn config_change(_instance: *mut c_void, value: u32) {
+    ALT_SETTING.store(0, Ordering::Release);
+    CONFIGURED.store(value != 0, Ordering::Release);
+}
+
+unsafe extern "C" fn bus_reset(_instance: *mut c_void) {
+    CONFIGURED.store(false, Ordering::Release);
+    ALT_SETTING.store(0, Ordering::Release);
+}
+
+/// Composite device events. The composite driver renumbers interfaces and
+/// every endpoint descriptor it finds, including the ones of alternate
+/// setting 1, so the copy of `FUNCTION_DESCRIPTOR` it hands over is fixed up
+/// here: both alternate settings share one pair of endpoints and the
+/// association and AC header point at the new interface numbers.
+unsafe extern "C" fn device_event(_instance: *mut c_void, request: u32, data: *mut c_void) {
+    if request != usb_device::usb_events::USB_EVENT_COMP_CONFIG {
+        return;
+    }
+    let descriptor = core::slice::from_raw_parts_mut(data as *mut u8, FUNCTION_DESCRIPTOR.len());
+
+    let mut interfaces = [0u8; 2];
+    let mut endpoints = [0u8; 2];
+    let (mut count, mut alt_setting) = (0, 0);
+    let mut offset = 0;
+    while offset + 1 < descriptor.len() && descriptor[offset] != 0 {
+        let len = descriptor[offset] as usize;
+        match descriptor[offset + 1] {
+            DTYPE_INTERFACE => {
+                alt_setting = descriptor[offset + 3];
+                if alt_setting == 0 && count < interfaces.len() {
+                    interfaces[count] = descriptor[offset + 2];
+                    count += 1;
+                }
+            }
+            DTYPE_ENDPOINT => {
+                let address = &mut descriptor[offset + 2];
+                let direction = (*address >> 7) as usize;
+                if alt_setting == 0 {
+                    endpoints[direction] = *address;
+                } else {
+                    *address = endpoints[direction];
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    descriptor[IAD_FIRST_INTERFACE] = interfaces[0];
+    descriptor[AC_HEADER_INTERFACE] = interfaces[1];
+    INTERFACE.store(interfaces[1], Ordering::Relaxed);
+    OUT_ENDPOINT.store(endpoints[0] & 0x7F, Ordering::Relaxed);
+    IN_ENDPOINT.store(endpoints[1] & 0x7F, Ordering::Relaxed);
+}
+
+// ============================================================================
+// Transport (main loop)
+// ============================================================================
+
+/// Wire format of the selected alternate setting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Not configured by a host
+    Off,
+    /// USB-MIDI 1.0 event packets
+    EventPackets,
+    /// Universal MIDI Packets
+    Ump,
+}
+
+impl Format {
+    pub fn current() -> Format {
+        if !CONFIGURED.load(Ordering::Acquire) {
+            return Format::Off;
+        }
+        match ALT_SETTING.load(Ordering::Acquire) {
+            0 => Format::EventPackets,
+            _ => Format::Ump,
+        }
+    }
+}
+
+/// One queued transfer unit: a whole event packet or UMP
+#[derive(Clone, Copy)]
+enum Packet {
+    Event([u8; 4]),
+    Ump(Ump),
+}
+
+impl Packet {
+    fn len(&self) -> usize {
+        match self {
+            Packet::Event(_) => 4,
+            Packet::Ump(ump) => ump.words().len() * 4,
+        }
+    }
+
+    /// Little-endian bytes as sent on the bus
+    fn write(&self, out: &mut [u8]) {
+        match self {
+            Packet::Event(bytes) => out[..4].copy_from_slice(bytes),
+            Packet::Ump(ump) => {
+                for (chunk, word) in out.chunks_mut(4).zip(ump.words()) {
+                    chunk.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Moves MIDI between the bulk endpoints and the router
+pub struct UsbMidi {
+    format: Format,
+    endpoint: Endpoint,
+    to_ump: ToUmp,
+    from_ump: FromUmp,
+    /// Partial UMP received so far
+    rx_words: [u32; 4],
+    rx_count: usize,
+    tx: Queue<Packet, TX_DEPTH>,
+}
+
+impl UsbMidi {
+    pub const fn new() -> Self {
+        Self {
+            format: Format::Off,
+            endpoint: Endpoint::new(&ENDPOINT_INFO),
+            to_ump: ToUmp::new(),
+            from_ump: FromUmp::new(),
+            rx_words: [0; 4],
+            rx_count: 0,
+            tx: Queue::new(),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Protocol negotiated on the UMP endpoint
+    pub fn protocol(&self) -> Protocol {
+        self.endpoint.protocol
+    }
+
+    /// Switch the protocol of the UMP endpoint, as negotiated over MIDI-CI
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.endpoint.protocol = protocol;
+    }
+
+    /// Main loop work: follow configuration changes, pass received packets
+    /// to the router and send what the router queued for the host.
+    pub fn poll(&mut self, now: u32, router: &mut Router) {
+        let format = Format::current();
+        if format != self.format {
+            self.format = format;
+            self.endpoint.protocol = Protocol::Midi2;
+            self.to_ump.reset();
+            self.from_ump.reset();
+            self.rx_count = 0;
+            self.tx.clear();
+            if format != Format::Off {
+                router.attach(Port::Usb);
+            }
+        }
+        if format == Format::Off {
+            // Nobody is listening, throw away pending output
+            while router.pop(Port::Usb).is_some() {}
+            return;
+        }
+
+        self.receive(now, router);
+
+        // A message turns into at most two packets
+        while self.tx.free() >= 2 {
+            let Some(message) = router.pop(Port::Usb) else {
+                break;
+            };
+            self.queue(&message);
+        }
+        self.transmit();
+    }
+
+    fn queue(&mut self, message: &MidiMessage) {
+        let Self { tx, to_ump, endpoint, .. } = self;
+        match self.format {
+            Format::EventPackets => {
+                tx.push(Packet::Event(midi::to_usb_packet(CABLE, message)));
+            }
+            Format::Ump => to_ump.translate(GROUP, message, endpoint.protocol, &mut |ump| {
+                tx.push(Packet::Ump(ump));
+            }),
+            Format::Off => {}
+        }
+    }
+
+    fn receive(&mut self, now: u32, router: &mut Router) {
+        let ep = usb_endpoint::index_to_ep(OUT_ENDPOINT.load(Ordering::Relaxed));
+        let mut packet = [0u8; PACKET_SIZE];
+        let mut len = PACKET_SIZE as u32;
+        unsafe {
+            if usb_device::USBEndpointStatus(USB0_BASE, ep) & usb_endpoint::USB_DEV_RX_PKT_RDY == 0 {
+                return;
+            }
+            // UMP stream replies need room in the transmit queue
+            if self.tx.free() < TX_DEPTH / 2 {
+                return;
+            }
+            usb_device::USBEndpointDataGet(USB0_BASE, ep, packet.as_mut_ptr(), &mut len);
+            usb_device::USBDevEndpointDataAck(USB0_BASE, ep, true);
+        }
+
+        for &bytes in packet[..len as usize].as_chunks::<4>().0 {
+            match self.format {
+                Format::EventPackets => {
+                    if let Some(message) = midi::from_usb_packet(bytes) {
+                        router.send(now, Port::Usb, message);
+                    }
+                }
+                Format::Ump => self.receive_word(now, u32::from_le_bytes(bytes), router),
+                Format::Off => {}
+            }
+        }
+    }
+
+    fn receive_word(&mut self, now: u32, word: u32, router: &mut Router) {
+        self.rx_words[self.rx_count] = word;
+        self.rx_count += 1;
+        if self.rx_count < ump::packet_words((self.rx_words[0] >> 28) as u8) {
+            return;
+        }
+        self.rx_count = 0;
+        let Some(packet) = Ump::from_words(&self.rx_words) else {
+            return;
+        };
+
+        let Self { tx, endpoint, from_ump, .. } = self;
+        match packet.decode() {
+            Message::Stream(message) => endpoint.handle(&message, &mut |reply| {
+                tx.push(Packet::Ump(reply));
+            }),
+            _ if packet.group() == GROUP => from_ump.translate(&packet, &mut |message| {
+                router.send(now, Port::Usb, message);
+            }),
+            _ => {}
+        }
+    }
+
+    /// Send one transfer of whole packets once the IN endpoint is free.
+    fn transmit(&mut self) {
+        let ep = usb_endpoint::index_to_ep(IN_ENDPOINT.load(Ordering::Relaxed));
+        if self.tx.is_empty()
+            || unsafe { usb_device::USBEndpointStatus(USB0_BASE, ep) } & usb_endpoint::USB_DEV_TX_TXPKTRDY != 0
+        {
+            return;
+        }
+
+        let mut transfer = [0u8; PACKET_SIZE];
+        let mut len = 0;
+        while let Some(packet) = self.tx.peek() {
+            if len + packet.len() > PACKET_SIZE {
+                break;
+            }
+            packet.write(&mut transfer[len..]);
+            len += packet.len();
+            self.tx.pop();
+        }
+        unsafe {
+            usb_device::USBEndpointDataPut(USB0_BASE, ep, transfer.as_ptr(), len as u32);
+            usb_device::USBEndpointDataSend(USB0_BASE, ep, usb_endpoint::USB_TRANS_IN);
+        }
+    }
+}